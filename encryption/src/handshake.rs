@@ -0,0 +1,470 @@
+//! Mutual challenge-response handshake between the server and a device.
+//!
+//! Each step is one line of text:
+//!
+//! ```text
+//! server -> device   HELLO 1 <key id> <server nonce>
+//! device -> server   CHALLENGE <device nonce> <device proof>
+//! server -> device   PROOF <server proof>
+//! device -> server   WELCOME | DENIED <reason>
+//! ```
+//!
+//! Nonces are 16 random bytes and proofs HMAC-SHA256 tags, both in base64.
+//! A proof covers the key id and both nonces under a key derived from the
+//! shared secret, with a different label per direction so neither side's
+//! proof can be reflected back as the other's. The device proves itself
+//! first; the server only reveals its proof to a device that holds the key.
+//!
+//! Both sides then derive one session key per direction from the shared
+//! secret and the nonces, and encrypt everything else on the connection
+//! with them (see [`session_cipher`]).
+
+use base64::{engine::general_purpose, Engine as _};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+use zeroize::Zeroize;
+
+use crate::{Cipher, CipherKey, KeyId, Keyring, WireVersion};
+
+const PROTOCOL_VERSION: &str = "1";
+const NONCE_LEN: usize = 16;
+const HKDF_SALT: &[u8] = b"birdhouse-rs";
+const HKDF_INFO_AUTH: &[u8] = b"birdhouse handshake v1 auth";
+const HKDF_INFO_TO_DEVICE: &[u8] = b"birdhouse handshake v1 server to device";
+const HKDF_INFO_TO_SERVER: &[u8] = b"birdhouse handshake v1 device to server";
+const DEVICE_LABEL: &[u8] = b"device";
+const SERVER_LABEL: &[u8] = b"server";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeError {
+    /// A line did not have the expected form; holds the step that was
+    /// expected.
+    Malformed(&'static str),
+    /// The peer speaks a handshake version we do not.
+    UnsupportedVersion(String),
+    /// The server asked for a key that is not in the device's keyring.
+    UnknownKeyId(KeyId),
+    /// The peer's proof did not verify: it does not hold the key.
+    BadProof,
+    /// The device refused the handshake.
+    Denied(String),
+    /// The operating system could not provide random bytes.
+    RandomUnavailable,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed(step) => write!(f, "Malformed handshake message, expected {}", step),
+            Self::UnsupportedVersion(v) => write!(f, "Unsupported handshake version '{}'", v),
+            Self::UnknownKeyId(id) => write!(f, "Unknown key id {}", id),
+            Self::BadProof => write!(f, "Peer failed to prove it holds the key"),
+            Self::Denied(reason) => write!(f, "Device denied the handshake: {}", reason),
+            Self::RandomUnavailable => write!(f, "Random number generator unavailable"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// Keys for the rest of the connection, one per direction.
+#[derive(Debug)]
+pub struct SessionKeys {
+    pub outbound: CipherKey,
+    pub inbound: CipherKey,
+}
+
+/// A cipher for a session key. Sessions always use the authenticated V2
+/// format and refuse anything older than V1.
+pub fn session_cipher(key: CipherKey, nonce_expiration_seconds: u64) -> Cipher {
+    Cipher::with_key(key, nonce_expiration_seconds)
+        .with_version(WireVersion::V2)
+        .with_min_version(WireVersion::V1)
+}
+
+/// The server's side of the handshake.
+pub struct ServerHandshake {
+    key_id: KeyId,
+    key: CipherKey,
+    server_nonce: [u8; NONCE_LEN],
+    device_nonce: Option<[u8; NONCE_LEN]>,
+}
+
+impl ServerHandshake {
+    /// Starts a handshake with the active key of `keyring` and returns the
+    /// `HELLO` line to send.
+    pub fn start(keyring: &Keyring) -> Result<(Self, String), HandshakeError> {
+        let server_nonce = random_nonce()?;
+        let hello = format!(
+            "HELLO {} {} {}",
+            PROTOCOL_VERSION,
+            keyring.active_id(),
+            encode(&server_nonce)
+        );
+        Ok((
+            Self {
+                key_id: keyring.active_id(),
+                key: keyring.active().clone(),
+                server_nonce,
+                device_nonce: None,
+            },
+            hello,
+        ))
+    }
+
+    /// Checks the device's `CHALLENGE` and returns the `PROOF` line to send.
+    pub fn answer_challenge(&mut self, line: &str) -> Result<String, HandshakeError> {
+        if let Some(reason) = strip_denied(line) {
+            return Err(HandshakeError::Denied(reason));
+        }
+        let fields = fields(line, "CHALLENGE", 2)?;
+        let device_nonce = decode_nonce(fields[0], "CHALLENGE")?;
+        let device_proof = decode(fields[1], "CHALLENGE")?;
+
+        proof_mac(
+            &self.key,
+            DEVICE_LABEL,
+            self.key_id,
+            &self.server_nonce,
+            &device_nonce,
+        )
+        .verify_slice(&device_proof)
+        .map_err(|_| HandshakeError::BadProof)?;
+        self.device_nonce = Some(device_nonce);
+
+        let proof = proof_mac(
+            &self.key,
+            SERVER_LABEL,
+            self.key_id,
+            &self.server_nonce,
+            &device_nonce,
+        )
+        .finalize()
+        .into_bytes();
+        Ok(format!("PROOF {}", encode(&proof)))
+    }
+
+    /// Reads the device's verdict and derives the session keys.
+    pub fn finish(self, line: &str) -> Result<SessionKeys, HandshakeError> {
+        if let Some(reason) = strip_denied(line) {
+            return Err(HandshakeError::Denied(reason));
+        }
+        fields(line, "WELCOME", 0)?;
+        let device_nonce = self
+            .device_nonce
+            .ok_or(HandshakeError::Malformed("CHALLENGE"))?;
+        Ok(SessionKeys {
+            outbound: session_key(
+                &self.key,
+                &self.server_nonce,
+                &device_nonce,
+                HKDF_INFO_TO_DEVICE,
+            ),
+            inbound: session_key(
+                &self.key,
+                &self.server_nonce,
+                &device_nonce,
+                HKDF_INFO_TO_SERVER,
+            ),
+        })
+    }
+}
+
+/// The device's side of the handshake.
+pub struct DeviceHandshake {
+    key_id: KeyId,
+    key: CipherKey,
+    server_nonce: [u8; NONCE_LEN],
+    device_nonce: [u8; NONCE_LEN],
+}
+
+impl DeviceHandshake {
+    /// Answers the server's `HELLO` with a `CHALLENGE` that proves the
+    /// device holds the requested key. On error, send [`deny`] instead.
+    pub fn accept(keyring: &Keyring, line: &str) -> Result<(Self, String), HandshakeError> {
+        let fields = fields(line, "HELLO", 3)?;
+        if fields[0] != PROTOCOL_VERSION {
+            return Err(HandshakeError::UnsupportedVersion(fields[0].to_string()));
+        }
+        let key_id: KeyId = fields[1]
+            .parse()
+            .map_err(|_| HandshakeError::Malformed("HELLO"))?;
+        let server_nonce = decode_nonce(fields[2], "HELLO")?;
+        let key = keyring
+            .get(key_id)
+            .ok_or(HandshakeError::UnknownKeyId(key_id))?
+            .clone();
+        let device_nonce = random_nonce()?;
+
+        let proof = proof_mac(&key, DEVICE_LABEL, key_id, &server_nonce, &device_nonce)
+            .finalize()
+            .into_bytes();
+        let challenge = format!("CHALLENGE {} {}", encode(&device_nonce), encode(&proof));
+        Ok((
+            Self {
+                key_id,
+                key,
+                server_nonce,
+                device_nonce,
+            },
+            challenge,
+        ))
+    }
+
+    /// Checks the server's `PROOF`. On success, send [`WELCOME`] and use the
+    /// returned keys; otherwise send [`deny`].
+    pub fn verify(self, line: &str) -> Result<SessionKeys, HandshakeError> {
+        let fields = fields(line, "PROOF", 1)?;
+        let proof = decode(fields[0], "PROOF")?;
+        proof_mac(
+            &self.key,
+            SERVER_LABEL,
+            self.key_id,
+            &self.server_nonce,
+            &self.device_nonce,
+        )
+        .verify_slice(&proof)
+        .map_err(|_| HandshakeError::BadProof)?;
+        Ok(SessionKeys {
+            outbound: session_key(
+                &self.key,
+                &self.server_nonce,
+                &self.device_nonce,
+                HKDF_INFO_TO_SERVER,
+            ),
+            inbound: session_key(
+                &self.key,
+                &self.server_nonce,
+                &self.device_nonce,
+                HKDF_INFO_TO_DEVICE,
+            ),
+        })
+    }
+}
+
+/// Sent by the device once the server's proof checks out.
+pub const WELCOME: &str = "WELCOME";
+
+/// The line a device sends to refuse a handshake.
+pub fn deny(error: &HandshakeError) -> String {
+    format!("DENIED {}", error)
+}
+
+fn proof_mac(
+    key: &CipherKey,
+    label: &[u8],
+    key_id: KeyId,
+    server_nonce: &[u8; NONCE_LEN],
+    device_nonce: &[u8; NONCE_LEN],
+) -> HmacSha256 {
+    let mut auth_key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(HKDF_SALT), key.as_bytes())
+        .expand(HKDF_INFO_AUTH, &mut auth_key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    let mut mac = HmacSha256::new_from_slice(&auth_key).expect("HMAC accepts keys of any length");
+    auth_key.zeroize();
+    mac.update(label);
+    mac.update(&key_id.to_le_bytes());
+    mac.update(server_nonce);
+    mac.update(device_nonce);
+    mac
+}
+
+fn session_key(
+    key: &CipherKey,
+    server_nonce: &[u8; NONCE_LEN],
+    device_nonce: &[u8; NONCE_LEN],
+    info: &[u8],
+) -> CipherKey {
+    let mut salt = [0u8; 2 * NONCE_LEN];
+    salt[..NONCE_LEN].copy_from_slice(server_nonce);
+    salt[NONCE_LEN..].copy_from_slice(device_nonce);
+    let mut bytes = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), key.as_bytes())
+        .expand(info, &mut bytes)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    let session = CipherKey::from_bytes(&bytes).expect("32 bytes is a valid key");
+    bytes.zeroize();
+    session
+}
+
+fn random_nonce() -> Result<[u8; NONCE_LEN], HandshakeError> {
+    let mut nonce = [0u8; NONCE_LEN];
+    getrandom::fill(&mut nonce).map_err(|_| HandshakeError::RandomUnavailable)?;
+    Ok(nonce)
+}
+
+/// Splits `<keyword> <field>...` into exactly `count` fields.
+fn fields<'a>(
+    line: &'a str,
+    keyword: &'static str,
+    count: usize,
+) -> Result<Vec<&'a str>, HandshakeError> {
+    let mut parts = line.split_whitespace();
+    if parts.next() != Some(keyword) {
+        return Err(HandshakeError::Malformed(keyword));
+    }
+    let fields: Vec<&str> = parts.collect();
+    if fields.len() != count {
+        return Err(HandshakeError::Malformed(keyword));
+    }
+    Ok(fields)
+}
+
+fn strip_denied(line: &str) -> Option<String> {
+    let line = line.trim();
+    let rest = line.strip_prefix("DENIED")?;
+    if !rest.is_empty() && !rest.starts_with(' ') {
+        return None;
+    }
+    Some(rest.trim().to_string())
+}
+
+fn encode(bytes: &[u8]) -> String {
+    general_purpose::STANDARD.encode(bytes)
+}
+
+fn decode(value: &str, step: &'static str) -> Result<Vec<u8>, HandshakeError> {
+    general_purpose::STANDARD
+        .decode(value)
+        .map_err(|_| HandshakeError::Malformed(step))
+}
+
+fn decode_nonce(value: &str, step: &'static str) -> Result<[u8; NONCE_LEN], HandshakeError> {
+    decode(value, step)?
+        .try_into()
+        .map_err(|_| HandshakeError::Malformed(step))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyring(spec: &str) -> Keyring {
+        Keyring::parse(spec).unwrap()
+    }
+
+    const KEY: &str = "e10adc3949ba59abbe56e057f20f883e";
+
+    #[test]
+    fn test_handshake_derives_matching_session_keys() {
+        let ring = keyring(KEY);
+        let (mut server, hello) = ServerHandshake::start(&ring).unwrap();
+        let (device, challenge) = DeviceHandshake::accept(&ring, &hello).unwrap();
+        let proof = server.answer_challenge(&challenge).unwrap();
+        let device_keys = device.verify(&proof).unwrap();
+        let server_keys = server.finish(WELCOME).unwrap();
+
+        assert_eq!(
+            server_keys.outbound.as_bytes(),
+            device_keys.inbound.as_bytes()
+        );
+        assert_eq!(
+            server_keys.inbound.as_bytes(),
+            device_keys.outbound.as_bytes()
+        );
+        assert_ne!(
+            server_keys.outbound.as_bytes(),
+            server_keys.inbound.as_bytes()
+        );
+        assert_ne!(server_keys.outbound.as_bytes(), ring.active().as_bytes());
+
+        let sender = session_cipher(server_keys.outbound, 30);
+        let mut receiver = session_cipher(device_keys.inbound, 30);
+        let message = sender.encrypt_message("[CMD] IR ON").unwrap();
+        assert_eq!(receiver.decrypt_message(&message).unwrap(), "[CMD] IR ON");
+    }
+
+    #[test]
+    fn test_sessions_are_fresh() {
+        let ring = keyring(KEY);
+        let run = || {
+            let (mut server, hello) = ServerHandshake::start(&ring).unwrap();
+            let (device, challenge) = DeviceHandshake::accept(&ring, &hello).unwrap();
+            device
+                .verify(&server.answer_challenge(&challenge).unwrap())
+                .unwrap();
+            server.finish(WELCOME).unwrap()
+        };
+        assert_ne!(run().outbound.as_bytes(), run().outbound.as_bytes());
+    }
+
+    #[test]
+    fn test_impostor_device_is_detected() {
+        let (mut server, hello) = ServerHandshake::start(&keyring(KEY)).unwrap();
+        let impostor = keyring("passphrase:not the key");
+        let (_, challenge) = DeviceHandshake::accept(&impostor, &hello).unwrap();
+        assert_eq!(
+            server.answer_challenge(&challenge).unwrap_err(),
+            HandshakeError::BadProof
+        );
+    }
+
+    #[test]
+    fn test_impostor_server_is_detected() {
+        let ring = keyring(KEY);
+        let (mut impostor, hello) =
+            ServerHandshake::start(&keyring("passphrase:not the key")).unwrap();
+        let (device, challenge) = DeviceHandshake::accept(&ring, &hello).unwrap();
+        // The impostor cannot check the device's proof, and its own fails.
+        assert_eq!(
+            impostor.answer_challenge(&challenge).unwrap_err(),
+            HandshakeError::BadProof
+        );
+        let forged = format!("PROOF {}", encode(&[0u8; 32]));
+        assert_eq!(
+            device.verify(&forged).unwrap_err(),
+            HandshakeError::BadProof
+        );
+    }
+
+    #[test]
+    fn test_device_proof_cannot_be_reflected() {
+        let ring = keyring(KEY);
+        let (mut server, hello) = ServerHandshake::start(&ring).unwrap();
+        let (device, challenge) = DeviceHandshake::accept(&ring, &hello).unwrap();
+        server.answer_challenge(&challenge).unwrap();
+        let device_proof = challenge.split_whitespace().nth(2).unwrap();
+        assert_eq!(
+            device
+                .verify(&format!("PROOF {}", device_proof))
+                .unwrap_err(),
+            HandshakeError::BadProof
+        );
+    }
+
+    #[test]
+    fn test_key_ids_and_errors() {
+        let server_ring = keyring(&format!("2:passphrase:new,1:{}", KEY));
+        let device_ring = keyring(&format!("1:{}", KEY));
+        let (mut server, hello) = ServerHandshake::start(&server_ring).unwrap();
+        assert_eq!(
+            DeviceHandshake::accept(&device_ring, &hello).err(),
+            Some(HandshakeError::UnknownKeyId(2))
+        );
+        let denied = deny(&HandshakeError::UnknownKeyId(2));
+        assert_eq!(
+            server.answer_challenge(&denied).unwrap_err(),
+            HandshakeError::Denied("Unknown key id 2".to_string())
+        );
+
+        for line in ["", "HELLO", "HELLO 1 0", "HELLO 1 x AAAA", "HELLO 1 0 AAAA"] {
+            assert!(
+                matches!(
+                    DeviceHandshake::accept(&device_ring, line),
+                    Err(HandshakeError::Malformed("HELLO"))
+                ),
+                "{:?}",
+                line
+            );
+        }
+        assert_eq!(
+            DeviceHandshake::accept(&device_ring, "HELLO 9 1 AAAAAAAAAAAAAAAAAAAAAA==").err(),
+            Some(HandshakeError::UnsupportedVersion("9".to_string()))
+        );
+    }
+}
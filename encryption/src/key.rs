@@ -0,0 +1,170 @@
+use base64::{engine::general_purpose, Engine as _};
+use sha2::Sha256;
+use std::fmt;
+use zeroize::Zeroize;
+
+use crate::EncryptionError;
+
+pub const KEY_LEN: usize = 32;
+const PASSPHRASE_SALT: &[u8] = b"birdhouse-rs passphrase";
+const PASSPHRASE_ROUNDS: u32 = 100_000;
+
+/// A 32-byte shared secret. The bytes are wiped when the key is dropped.
+///
+/// Keys are written as one of
+/// - `hex:<64 hex digits>`
+/// - `base64:<44 base64 characters>`
+/// - `passphrase:<any text>`, stretched with PBKDF2-HMAC-SHA256
+///   (100 000 rounds, salt `birdhouse-rs passphrase`)
+/// - exactly 32 plain characters, used as raw bytes. This is how keys were
+///   configured before, so existing deployments keep working.
+#[derive(Clone)]
+pub struct CipherKey {
+    bytes: [u8; KEY_LEN],
+}
+
+impl CipherKey {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EncryptionError> {
+        let bytes: [u8; KEY_LEN] = bytes.try_into().map_err(|_| {
+            EncryptionError::InvalidKey(format!("expected {} bytes, got {}", KEY_LEN, bytes.len()))
+        })?;
+        Ok(Self { bytes })
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self, EncryptionError> {
+        let mut decoded = hex::decode(hex.trim())
+            .map_err(|e| EncryptionError::InvalidKey(format!("invalid hex: {}", e)))?;
+        let key = Self::from_bytes(&decoded);
+        decoded.zeroize();
+        key
+    }
+
+    pub fn from_base64(base64: &str) -> Result<Self, EncryptionError> {
+        let mut decoded = general_purpose::STANDARD
+            .decode(base64.trim())
+            .map_err(|e| EncryptionError::InvalidKey(format!("invalid base64: {}", e)))?;
+        let key = Self::from_bytes(&decoded);
+        decoded.zeroize();
+        key
+    }
+
+    pub fn from_passphrase(passphrase: &str) -> Result<Self, EncryptionError> {
+        if passphrase.is_empty() {
+            return Err(EncryptionError::InvalidKey(
+                "passphrase is empty".to_string(),
+            ));
+        }
+        let mut bytes = [0u8; KEY_LEN];
+        pbkdf2::pbkdf2_hmac::<Sha256>(
+            passphrase.as_bytes(),
+            PASSPHRASE_SALT,
+            PASSPHRASE_ROUNDS,
+            &mut bytes,
+        );
+        Ok(Self { bytes })
+    }
+
+    /// Parses a key in any of the formats listed on [`CipherKey`].
+    pub fn parse(spec: &str) -> Result<Self, EncryptionError> {
+        if let Some(hex) = spec.strip_prefix("hex:") {
+            return Self::from_hex(hex);
+        }
+        if let Some(base64) = spec.strip_prefix("base64:") {
+            return Self::from_base64(base64);
+        }
+        if let Some(passphrase) = spec.strip_prefix("passphrase:") {
+            return Self::from_passphrase(passphrase);
+        }
+        if spec.len() == KEY_LEN {
+            return Self::from_bytes(spec.as_bytes());
+        }
+        Err(EncryptionError::InvalidKey(format!(
+            "expected 'hex:', 'base64:' or 'passphrase:' prefix, or exactly {} characters, got {} characters",
+            KEY_LEN,
+            spec.len()
+        )))
+    }
+
+    /// A fresh random key.
+    pub fn generate() -> Result<Self, EncryptionError> {
+        let mut bytes = [0u8; KEY_LEN];
+        getrandom::fill(&mut bytes).map_err(|_| EncryptionError::RandomUnavailable)?;
+        Ok(Self { bytes })
+    }
+
+    /// The key in base64, without the `base64:` prefix.
+    pub fn to_base64(&self) -> String {
+        general_purpose::STANDARD.encode(self.bytes)
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.bytes
+    }
+}
+
+impl Drop for CipherKey {
+    fn drop(&mut self) {
+        self.bytes.zeroize();
+    }
+}
+
+impl fmt::Debug for CipherKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CipherKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CipherKey;
+    use crate::EncryptionError;
+
+    #[test]
+    fn test_key_formats() {
+        let raw = CipherKey::parse("e10adc3949ba59abbe56e057f20f883e").unwrap();
+        assert_eq!(raw.as_bytes(), b"e10adc3949ba59abbe56e057f20f883e");
+
+        let hex = CipherKey::parse(&format!("hex:{}", "ab".repeat(32))).unwrap();
+        assert_eq!(hex.as_bytes(), &[0xab; 32]);
+
+        let base64 =
+            CipherKey::parse("base64:AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=").unwrap();
+        assert_eq!(base64.as_bytes(), &[0x01; 32]);
+        assert_eq!(
+            base64.to_base64(),
+            "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE="
+        );
+
+        let a = CipherKey::parse("passphrase:correct horse battery staple").unwrap();
+        let b = CipherKey::from_passphrase("correct horse battery staple").unwrap();
+        let c = CipherKey::from_passphrase("correct horse battery stapler").unwrap();
+        assert_eq!(a.as_bytes(), b.as_bytes());
+        assert_ne!(a.as_bytes(), c.as_bytes());
+    }
+
+    #[test]
+    fn test_rejects_malformed_keys() {
+        for spec in [
+            "",
+            "too short",
+            "hex:abcd",
+            "hex:zz",
+            "base64:AQID",
+            "base64:!!",
+            "passphrase:",
+            "e10adc3949ba59abbe56e057f20f883e0",
+        ] {
+            assert!(
+                matches!(CipherKey::parse(spec), Err(EncryptionError::InvalidKey(_))),
+                "{:?}",
+                spec
+            );
+        }
+    }
+
+    #[test]
+    fn test_debug_hides_key() {
+        let key = CipherKey::parse("e10adc3949ba59abbe56e057f20f883e").unwrap();
+        assert_eq!(format!("{:?}", key), "CipherKey(..)");
+    }
+}
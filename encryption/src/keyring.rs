@@ -0,0 +1,187 @@
+use crate::{CipherKey, EncryptionError};
+
+/// Identifies a key in a [`Keyring`]. V2 messages carry it in their header.
+pub type KeyId = u32;
+
+/// Previous keys kept for decryption by default.
+pub const DEFAULT_RETAINED_KEYS: usize = 2;
+
+/// The active key plus a few previous ones.
+///
+/// Messages are encrypted with the active key; decryption accepts any key in
+/// the ring, so messages sent just before a rotation, or by a peer that has
+/// not switched yet, still arrive.
+///
+/// Written as `<id>:<key>` entries separated by commas, active key first,
+/// e.g. `2:base64:...,1:hex:...` (see [`CipherKey`] for key formats). A plain
+/// key without an id is a keyring holding only that key, with id 0.
+#[derive(Clone, Debug)]
+pub struct Keyring {
+    // Active key first, then previous keys from newest to oldest.
+    keys: Vec<(KeyId, CipherKey)>,
+    retained: usize,
+}
+
+impl Keyring {
+    pub fn new(id: KeyId, key: CipherKey) -> Self {
+        Self {
+            keys: vec![(id, key)],
+            retained: DEFAULT_RETAINED_KEYS,
+        }
+    }
+
+    /// Keeps `retained` previous keys after a rotation.
+    pub fn with_retained(mut self, retained: usize) -> Self {
+        self.retained = retained;
+        self.keys.truncate(retained + 1);
+        self
+    }
+
+    pub fn parse(spec: &str) -> Result<Self, EncryptionError> {
+        let spec = spec.trim();
+        if split_id(spec).is_none() {
+            return Ok(Self::new(0, CipherKey::parse(spec)?));
+        }
+
+        let mut keys: Vec<(KeyId, CipherKey)> = Vec::new();
+        for entry in spec.split(',').map(str::trim) {
+            let (id, key) = split_id(entry).ok_or_else(|| {
+                EncryptionError::InvalidKey(format!(
+                    "keyring entry is not '<id>:<key>': {} characters",
+                    entry.len()
+                ))
+            })?;
+            if keys.iter().any(|(existing, _)| *existing == id) {
+                return Err(EncryptionError::InvalidKey(format!(
+                    "key id {} appears twice in the keyring",
+                    id
+                )));
+            }
+            keys.push((id, CipherKey::parse(key)?));
+        }
+
+        let retained = DEFAULT_RETAINED_KEYS.max(keys.len() - 1);
+        Ok(Self { keys, retained })
+    }
+
+    pub fn active(&self) -> &CipherKey {
+        &self.keys[0].1
+    }
+
+    pub fn active_id(&self) -> KeyId {
+        self.keys[0].0
+    }
+
+    pub fn get(&self, id: KeyId) -> Option<&CipherKey> {
+        self.keys
+            .iter()
+            .find(|(key_id, _)| *key_id == id)
+            .map(|(_, key)| key)
+    }
+
+    /// All keys, active first.
+    pub fn iter(&self) -> impl Iterator<Item = (KeyId, &CipherKey)> {
+        self.keys.iter().map(|(id, key)| (*id, key))
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Makes `key` the active key and forgets keys beyond the retained ones.
+    pub fn rotate(&mut self, id: KeyId, key: CipherKey) -> Result<(), EncryptionError> {
+        if self.get(id).is_some() {
+            return Err(EncryptionError::InvalidKey(format!(
+                "key id {} is already in the keyring",
+                id
+            )));
+        }
+        self.keys.insert(0, (id, key));
+        self.keys.truncate(self.retained + 1);
+        Ok(())
+    }
+
+    /// The keyring in the format [`Keyring::parse`] reads, with every key in
+    /// base64. This contains the secrets themselves.
+    pub fn to_spec(&self) -> String {
+        self.keys
+            .iter()
+            .map(|(id, key)| format!("{}:base64:{}", id, key.to_base64()))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// Splits `<digits>:<rest>`.
+fn split_id(entry: &str) -> Option<(KeyId, &str)> {
+    let (id, rest) = entry.split_once(':')?;
+    if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((id.parse().ok()?, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Keyring;
+    use crate::{CipherKey, EncryptionError};
+
+    const RAW: &str = "e10adc3949ba59abbe56e057f20f883e";
+
+    #[test]
+    fn test_plain_key_is_id_zero() {
+        let keyring = Keyring::parse(RAW).unwrap();
+        assert_eq!(keyring.active_id(), 0);
+        assert_eq!(keyring.len(), 1);
+        assert_eq!(keyring.active().as_bytes(), RAW.as_bytes());
+
+        // A passphrase may contain commas.
+        let keyring = Keyring::parse("passphrase:one, two").unwrap();
+        assert_eq!(keyring.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_and_format_keyring() {
+        let spec = format!("7:hex:{},3:{}", "ab".repeat(32), RAW);
+        let keyring = Keyring::parse(&spec).unwrap();
+        assert_eq!(keyring.active_id(), 7);
+        assert_eq!(keyring.active().as_bytes(), &[0xab; 32]);
+        assert_eq!(keyring.get(3).unwrap().as_bytes(), RAW.as_bytes());
+        assert!(keyring.get(4).is_none());
+
+        let reparsed = Keyring::parse(&keyring.to_spec()).unwrap();
+        let ids: Vec<_> = reparsed.iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![7, 3]);
+        assert_eq!(reparsed.get(3).unwrap().as_bytes(), RAW.as_bytes());
+
+        for bad in [
+            "1:hex:abcd",
+            &format!("1:{},1:{}", RAW, RAW),
+            &format!("1:{},{}", RAW, RAW),
+        ] {
+            assert!(
+                matches!(Keyring::parse(bad), Err(EncryptionError::InvalidKey(_))),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_rotation_keeps_retained_keys() {
+        let mut keyring = Keyring::new(1, CipherKey::generate().unwrap()).with_retained(2);
+        for id in 2..=5 {
+            keyring.rotate(id, CipherKey::generate().unwrap()).unwrap();
+        }
+        let ids: Vec<_> = keyring.iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![5, 4, 3]);
+        assert!(matches!(
+            keyring.rotate(4, CipherKey::generate().unwrap()),
+            Err(EncryptionError::InvalidKey(_))
+        ));
+    }
+}
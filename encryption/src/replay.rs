@@ -0,0 +1,325 @@
+use std::collections::HashSet;
+
+use crate::EncryptionError;
+
+/// Longest nonce the cache stores; the last byte of a key holds the length.
+const MAX_NONCE_LEN: usize = 15;
+
+pub const DEFAULT_REPLAY_CAPACITY: usize = 65_536;
+
+/// What [`ReplayCache`] does with a new message once it holds `capacity`
+/// entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayFullPolicy {
+    /// Refuse the message with [`EncryptionError::ReplayCacheFull`] until
+    /// older entries expire.
+    RejectNew,
+    /// Forget the oldest second of entries and from then on refuse anything
+    /// that old or older, as it can no longer be checked.
+    EvictOldest,
+}
+
+type NonceKey = [u8; MAX_NONCE_LEN + 1];
+
+struct Bucket {
+    timestamp: u64,
+    nonces: HashSet<NonceKey>,
+}
+
+/// Remembers the `(timestamp, nonce)` pairs seen within the acceptance
+/// window. Entries live in one bucket per second, in a ring that covers the
+/// window, so expiring them is O(1) amortized and never scans every entry.
+pub struct ReplayCache {
+    window_secs: u64,
+    capacity: usize,
+    policy: ReplayFullPolicy,
+    // Indexed by `timestamp % buckets.len()`. A bucket whose timestamp does
+    // not match is stale and treated as empty.
+    buckets: Vec<Bucket>,
+    len: usize,
+    // Every bucket for a timestamp below this has been cleared.
+    pruned_below: u64,
+    // Timestamps below this were evicted and can no longer be checked.
+    floor: u64,
+}
+
+impl ReplayCache {
+    pub fn new(window_secs: u64, capacity: usize, policy: ReplayFullPolicy) -> Self {
+        // Timestamps are accepted from `now - window` to `now + window`.
+        let slots = window_secs.saturating_mul(2).saturating_add(1);
+        let slots = usize::try_from(slots).unwrap_or(usize::MAX).min(1 << 20);
+        Self {
+            window_secs,
+            capacity,
+            policy,
+            buckets: (0..slots)
+                .map(|_| Bucket {
+                    timestamp: u64::MAX,
+                    nonces: HashSet::new(),
+                })
+                .collect(),
+            len: 0,
+            pruned_below: 0,
+            floor: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn window_secs(&self) -> u64 {
+        self.window_secs
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Whether `timestamp` is inside the acceptance window around `now`.
+    pub fn in_window(&self, timestamp: u64, now: u64) -> bool {
+        timestamp >= now.saturating_sub(self.window_secs)
+            && timestamp <= now.saturating_add(self.window_secs)
+            && timestamp >= self.floor
+    }
+
+    /// Records `(timestamp, nonce)` if it has not been seen before.
+    pub fn check_and_insert(
+        &mut self,
+        timestamp: u64,
+        nonce: &[u8],
+        now: u64,
+    ) -> Result<(), EncryptionError> {
+        if !self.in_window(timestamp, now) {
+            return Err(EncryptionError::ExpiredTimestamp);
+        }
+        self.prune(now);
+
+        let key = nonce_key(nonce);
+        let index = self.index(timestamp);
+        {
+            let bucket = &mut self.buckets[index];
+            if bucket.timestamp != timestamp {
+                self.len -= bucket.nonces.len();
+                bucket.nonces.clear();
+                bucket.timestamp = timestamp;
+            }
+            if bucket.nonces.contains(&key) {
+                return Err(EncryptionError::ReplayAttack);
+            }
+        }
+
+        if self.len >= self.capacity {
+            match self.policy {
+                ReplayFullPolicy::RejectNew => return Err(EncryptionError::ReplayCacheFull),
+                ReplayFullPolicy::EvictOldest => {
+                    if !self.evict_oldest_before(timestamp) {
+                        return Err(EncryptionError::ReplayCacheFull);
+                    }
+                }
+            }
+        }
+
+        self.buckets[index].nonces.insert(key);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn index(&self, timestamp: u64) -> usize {
+        (timestamp % self.buckets.len() as u64) as usize
+    }
+
+    /// Clears the buckets that fell out of the window since the last call.
+    fn prune(&mut self, now: u64) {
+        let oldest_live = now.saturating_sub(self.window_secs);
+        if oldest_live <= self.pruned_below {
+            return;
+        }
+        // Visit the slot of every second that expired, but never more than
+        // one pass over the ring after a long pause.
+        let from = self
+            .pruned_below
+            .max(oldest_live.saturating_sub(self.buckets.len() as u64));
+        for timestamp in from..oldest_live {
+            let index = self.index(timestamp);
+            let bucket = &mut self.buckets[index];
+            if bucket.timestamp < oldest_live {
+                self.len -= bucket.nonces.len();
+                bucket.nonces.clear();
+                bucket.timestamp = u64::MAX;
+            }
+        }
+        self.pruned_below = oldest_live;
+    }
+
+    /// Drops the oldest non-empty bucket if it is older than `timestamp`.
+    fn evict_oldest_before(&mut self, timestamp: u64) -> bool {
+        let Some(index) = (0..self.buckets.len())
+            .filter(|&i| {
+                let bucket = &self.buckets[i];
+                bucket.timestamp < timestamp && !bucket.nonces.is_empty()
+            })
+            .min_by_key(|&i| self.buckets[i].timestamp)
+        else {
+            return false;
+        };
+
+        let bucket = &mut self.buckets[index];
+        self.len -= bucket.nonces.len();
+        self.floor = self.floor.max(bucket.timestamp + 1);
+        bucket.nonces.clear();
+        bucket.timestamp = u64::MAX;
+        true
+    }
+}
+
+fn nonce_key(nonce: &[u8]) -> NonceKey {
+    let mut key = [0u8; MAX_NONCE_LEN + 1];
+    let len = nonce.len().min(MAX_NONCE_LEN);
+    key[..len].copy_from_slice(&nonce[..len]);
+    key[MAX_NONCE_LEN] = len as u8;
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReplayCache, ReplayFullPolicy};
+    use crate::EncryptionError;
+    use proptest::prelude::*;
+    use std::collections::HashSet;
+
+    const NOW: u64 = 1_700_000_000;
+
+    #[test]
+    fn test_rejects_replays_and_expired_timestamps() {
+        let mut cache = ReplayCache::new(30, 100, ReplayFullPolicy::RejectNew);
+        assert_eq!(cache.check_and_insert(NOW, b"a", NOW), Ok(()));
+        assert_eq!(
+            cache.check_and_insert(NOW, b"a", NOW + 5),
+            Err(EncryptionError::ReplayAttack)
+        );
+        assert_eq!(cache.check_and_insert(NOW + 1, b"a", NOW + 5), Ok(()));
+        assert_eq!(
+            cache.check_and_insert(NOW - 31, b"b", NOW),
+            Err(EncryptionError::ExpiredTimestamp)
+        );
+        assert_eq!(
+            cache.check_and_insert(NOW + 31, b"b", NOW),
+            Err(EncryptionError::ExpiredTimestamp)
+        );
+        assert_eq!(cache.len(), 2);
+
+        // Entries leave the cache once their second is out of the window.
+        assert_eq!(cache.check_and_insert(NOW + 31, b"c", NOW + 31), Ok(()));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.check_and_insert(NOW + 200, b"d", NOW + 200), Ok(()));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_small_timestamps_do_not_underflow() {
+        let mut cache = ReplayCache::new(30, 100, ReplayFullPolicy::RejectNew);
+        assert_eq!(cache.check_and_insert(0, b"a", 5), Ok(()));
+        assert_eq!(
+            cache.check_and_insert(u64::MAX, b"a", 5),
+            Err(EncryptionError::ExpiredTimestamp)
+        );
+        assert_eq!(cache.check_and_insert(u64::MAX, b"a", u64::MAX), Ok(()));
+    }
+
+    #[test]
+    fn test_full_policies() {
+        let mut reject = ReplayCache::new(30, 2, ReplayFullPolicy::RejectNew);
+        assert_eq!(reject.check_and_insert(NOW - 1, b"a", NOW), Ok(()));
+        assert_eq!(reject.check_and_insert(NOW, b"b", NOW), Ok(()));
+        assert_eq!(
+            reject.check_and_insert(NOW, b"c", NOW),
+            Err(EncryptionError::ReplayCacheFull)
+        );
+
+        let mut evict = ReplayCache::new(30, 2, ReplayFullPolicy::EvictOldest);
+        assert_eq!(evict.check_and_insert(NOW - 1, b"a", NOW), Ok(()));
+        assert_eq!(evict.check_and_insert(NOW, b"b", NOW), Ok(()));
+        assert_eq!(evict.check_and_insert(NOW, b"c", NOW), Ok(()));
+        assert_eq!(evict.len(), 2);
+        // The evicted second can no longer be checked, so it is refused.
+        assert_eq!(
+            evict.check_and_insert(NOW - 1, b"a", NOW),
+            Err(EncryptionError::ExpiredTimestamp)
+        );
+        // Nothing older than the new message left to evict.
+        assert_eq!(
+            evict.check_and_insert(NOW, b"d", NOW),
+            Err(EncryptionError::ReplayCacheFull)
+        );
+    }
+
+    /// Seconds between messages: mostly a steady stream, with some pauses.
+    fn tick() -> impl Strategy<Value = u64> {
+        prop_oneof![9 => 0u64..3, 1 => 30u64..200]
+    }
+
+    fn policy() -> impl Strategy<Value = ReplayFullPolicy> {
+        prop_oneof![
+            Just(ReplayFullPolicy::RejectNew),
+            Just(ReplayFullPolicy::EvictOldest)
+        ]
+    }
+
+    proptest! {
+        /// A flood of messages, fresh and replayed, arriving over time.
+        #[test]
+        fn prop_flood_never_exceeds_capacity_or_accepts_a_replay(
+            capacity in 1usize..64,
+            policy in policy(),
+            messages in prop::collection::vec((tick(), -40i64..40, 0u8..32), 1..2000),
+        ) {
+            let mut cache = ReplayCache::new(30, capacity, policy);
+            let mut accepted = HashSet::new();
+            let mut now = NOW;
+
+            for (tick, offset, nonce) in messages {
+                now += tick;
+                let timestamp = now.checked_add_signed(offset).unwrap();
+                let result = cache.check_and_insert(timestamp, &[nonce], now);
+
+                prop_assert!(cache.len() <= capacity);
+                if result.is_ok() {
+                    prop_assert!(accepted.insert((timestamp, nonce)), "replay accepted");
+                }
+                if offset.abs() > 30 {
+                    prop_assert_eq!(result, Err(EncryptionError::ExpiredTimestamp));
+                }
+            }
+        }
+
+        /// Below capacity the cache behaves exactly like an unbounded set
+        /// that forgets entries once they leave the window.
+        #[test]
+        fn prop_matches_unbounded_set_below_capacity(
+            messages in prop::collection::vec((tick(), -30i64..=30, 0u8..8), 1..500),
+        ) {
+            let mut cache = ReplayCache::new(30, usize::MAX, ReplayFullPolicy::RejectNew);
+            let mut seen = HashSet::new();
+            let mut now = NOW;
+
+            for (tick, offset, nonce) in messages {
+                now += tick;
+                let timestamp = now.checked_add_signed(offset).unwrap();
+                let expected = if seen.insert((timestamp, nonce)) {
+                    Ok(())
+                } else {
+                    Err(EncryptionError::ReplayAttack)
+                };
+                prop_assert_eq!(cache.check_and_insert(timestamp, &[nonce], now), expected);
+
+                let live = seen.iter().filter(|(ts, _)| *ts + 30 >= now).count();
+                prop_assert_eq!(cache.len(), live);
+            }
+        }
+    }
+}
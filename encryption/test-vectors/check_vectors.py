@@ -0,0 +1,169 @@
+"""Checks cipher.json against an independent Python implementation: every
+vector decrypts as listed and every rejected vector fails with its error.
+
+The same checks belong in birdhouse-python's test suite. Needs the
+`cryptography` package:
+
+    python3 check_vectors.py
+"""
+
+import base64
+import hashlib
+import json
+import pathlib
+import struct
+
+from cryptography.exceptions import InvalidTag
+from cryptography.hazmat.primitives import hashes, padding
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
+from cryptography.hazmat.primitives.ciphers.aead import ChaCha20Poly1305
+from cryptography.hazmat.primitives.kdf.hkdf import HKDF
+
+SUPPORTED_VERSION = 1
+AEAD_TAG_LEN = 16
+V1_HEADER_LEN = 1 + 8 + 12
+V2_HEADER_LEN = 1 + 4 + 8 + 12
+
+
+def key_bytes(spec):
+    if spec.startswith("hex:"):
+        return bytes.fromhex(spec[4:])
+    if spec.startswith("base64:"):
+        return base64.b64decode(spec[7:])
+    if spec.startswith("passphrase:"):
+        return hashlib.pbkdf2_hmac(
+            "sha256", spec[11:].encode(), b"birdhouse-rs passphrase", 100_000, 32
+        )
+    assert len(spec) == 32, "raw keys are exactly 32 characters"
+    return spec.encode()
+
+
+def keyring(spec):
+    """`<id>:<key>` entries, active first, or a plain key with id 0."""
+    head, _, rest = spec.partition(":")
+    if not (head.isdigit() and rest):
+        return [(0, key_bytes(spec))]
+    entries = []
+    for entry in spec.split(","):
+        key_id, key_spec = entry.strip().split(":", 1)
+        entries.append((int(key_id), key_bytes(key_spec)))
+    return entries
+
+
+def decrypt_legacy(key, message):
+    decryptor = Cipher(algorithms.AES(key), modes.CBC(message[:16])).decryptor()
+    padded = decryptor.update(message[16:]) + decryptor.finalize()
+    unpadder = padding.PKCS7(128).unpadder()
+    timestamp = struct.unpack("<Q", message[:8])[0]
+    return timestamp, message[8:16], unpadder.update(padded) + unpadder.finalize()
+
+
+def decrypt_aead(key, message, header_len):
+    aead_key = HKDF(
+        hashes.SHA256(), 32, b"birdhouse-rs", b"birdhouse v1 chacha20poly1305"
+    ).derive(key)
+    aad = message[:header_len]
+    nonce = message[header_len:header_len + 12]
+    sealed = message[header_len + 12:]
+    timestamp = struct.unpack("<Q", aad[-8:])[0]
+    return timestamp, nonce, ChaCha20Poly1305(aead_key).decrypt(nonce, sealed, aad)
+
+
+def check(vector):
+    key_spec = vector["key"]
+    key_id = None
+    if vector["format"] == "v2":
+        key_id, key_spec = key_spec.split(":", 1)
+    key = key_bytes(key_spec)
+    message = base64.b64decode(vector["ciphertext"])
+
+    if vector["format"] == "legacy":
+        timestamp, iv, plaintext = decrypt_legacy(key, message)
+    elif vector["format"] == "v1":
+        assert message[0] == 1
+        timestamp, iv, plaintext = decrypt_aead(key, message, 1 + 8)
+    elif vector["format"] == "v2":
+        assert message[0] == 2
+        assert struct.unpack("<I", message[1:5])[0] == int(key_id)
+        timestamp, iv, plaintext = decrypt_aead(key, message, 1 + 4 + 8)
+    else:
+        raise ValueError(f"unknown format {vector['format']}")
+
+    assert timestamp == vector["timestamp"], vector["name"]
+    assert iv.hex() == vector["iv"], vector["name"]
+    assert plaintext.decode() == vector["plaintext"], vector["name"]
+
+
+def rejection(keys, message, now, window):
+    """Why a receiver refuses `message`, in the order `decrypt_message`
+    checks, or None if it would accept it."""
+
+    def fresh(timestamp):
+        return now - window <= timestamp <= now + window
+
+    def legacy():
+        if len(message) < 16:
+            return "InvalidTimestampLen"
+        if not fresh(struct.unpack("<Q", message[:8])[0]):
+            return "ExpiredTimestamp"
+        try:
+            plaintext = decrypt_legacy(keys[0][1], message)[2]
+        except ValueError:
+            return "DecryptError"
+        try:
+            plaintext.decode()
+        except UnicodeDecodeError:
+            return "UTF8Error"
+        return None
+
+    def aead(candidates, header_len):
+        for key in candidates:
+            try:
+                timestamp, _, plaintext = decrypt_aead(key, message, header_len)
+            except InvalidTag:
+                continue
+            if not fresh(timestamp):
+                return "ExpiredTimestamp"
+            try:
+                plaintext.decode()
+            except UnicodeDecodeError:
+                return "UTF8Error"
+            return None
+        return "AuthenticationFailed"
+
+    if message[:1] == b"\x01" and len(message) >= V1_HEADER_LEN + AEAD_TAG_LEN:
+        error = aead([key for _, key in keys], 1 + 8)
+    elif message[:1] == b"\x02" and len(message) >= V2_HEADER_LEN + AEAD_TAG_LEN:
+        key_id = struct.unpack("<I", message[1:5])[0]
+        candidates = [key for i, key in keys if i == key_id]
+        error = aead(candidates, 1 + 4 + 8) if candidates else "UnknownKeyId"
+    else:
+        return legacy()
+    # A legacy message can start with a version byte; it is tried last.
+    if error in ("AuthenticationFailed", "UnknownKeyId") and legacy() is None:
+        return None
+    return error
+
+
+def check_rejected(vector, window):
+    message = base64.b64decode(vector["ciphertext"])
+    error = rejection(keyring(vector["key"]), message, vector["now"], window)
+    assert error == vector["error"], f"{vector['name']}: {error}"
+
+
+def main():
+    path = pathlib.Path(__file__).with_name("cipher.json")
+    vectors = json.loads(path.read_text(encoding="utf-8"))
+    assert vectors["version"] == SUPPORTED_VERSION, "unknown test vector version"
+    for vector in vectors["vectors"]:
+        check(vector)
+    for vector in vectors["rejected"]:
+        check_rejected(vector, vectors["window_seconds"])
+    print(
+        f"{len(vectors['vectors'])} vectors ok, "
+        f"{len(vectors['rejected'])} rejected vectors ok"
+    )
+
+
+if __name__ == "__main__":
+    main()
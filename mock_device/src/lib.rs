@@ -0,0 +1,715 @@
+//! A stand-in for the birdhouse-python daemon. It speaks the same TCP
+//! protocol as the Pi (encrypted authentication, `[CMD]` commands, message
+//! ids echoed on replies), emits fake sensor events and serves a fake
+//! spectrogram websocket, so the web server can run without hardware.
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use encryption::handshake::{self, DeviceHandshake};
+use encryption::{Cipher, CipherKey, KeyId, Keyring, WireVersion};
+use std::collections::BTreeSet;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+/// Number of bins in a spectrogram frame (FFT size 1024).
+pub const SPECTROGRAM_BINS: usize = 512;
+
+/// Faults applied to everything the mock sends after authentication.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    /// Probability (0..1) that an outgoing message is silently dropped.
+    pub drop_rate: f64,
+    /// Probability (0..1) that an outgoing message is replaced by random bytes.
+    pub garbage_rate: f64,
+    /// Probability (0..1) that one character of an outgoing message is
+    /// changed, which breaks the authentication tag of an encrypted one.
+    pub tamper_rate: f64,
+    /// Probability (0..1) that an outgoing message is sent twice, which a
+    /// server with inbound encryption must drop as a replay.
+    pub replay_rate: f64,
+    /// Every outgoing message is delayed by a random duration up to this.
+    pub max_delay: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct MockConfig {
+    /// A key or keyring, as in `TCP_ENCRYPTION_KEY`.
+    pub key: String,
+    pub tcp_addr: SocketAddr,
+    /// Where to serve the spectrogram websocket (`/ws`), if anywhere.
+    pub spectrogram_addr: Option<SocketAddr>,
+    /// Encrypt outgoing messages, for servers running with
+    /// `TCP_INBOUND_ENCRYPTION` enabled.
+    pub encrypt_replies: bool,
+    /// How often to emit unsolicited IR and sensor events, if at all.
+    pub event_interval: Option<Duration>,
+    /// Offer message ids when authenticating and echo them on replies. Off,
+    /// the mock behaves like firmware that predates them.
+    pub message_ids: bool,
+    pub faults: Faults,
+}
+
+impl MockConfig {
+    /// A config for tests: random local port, no events, no faults.
+    pub fn new(key: &str) -> Self {
+        Self {
+            key: key.to_string(),
+            tcp_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            spectrogram_addr: None,
+            encrypt_replies: false,
+            event_interval: None,
+            message_ids: true,
+            faults: Faults::default(),
+        }
+    }
+
+    /// Reads `TCP_ENCRYPTION_KEY`, `TCP_INBOUND_ENCRYPTION` and the
+    /// `MOCK_DEVICE_*` variables.
+    pub fn from_env() -> Result<Self, String> {
+        fn var<T: std::str::FromStr>(name: &str, default: T) -> Result<T, String> {
+            match std::env::var(name) {
+                Ok(value) => value
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("Invalid value for {}: {:?}", name, value)),
+                Err(_) => Ok(default),
+            }
+        }
+
+        let key = std::env::var("TCP_ENCRYPTION_KEY")
+            .map_err(|_| "TCP_ENCRYPTION_KEY is not set".to_string())?
+            .trim()
+            .to_string();
+        let event_secs: u64 = var("MOCK_DEVICE_EVENT_INTERVAL_SECS", 5)?;
+        let spectrogram_addr: String =
+            var("MOCK_DEVICE_SPECTROGRAM_ADDR", "127.0.0.1:8000".to_string())?;
+
+        Ok(Self {
+            key,
+            tcp_addr: var(
+                "MOCK_DEVICE_ADDR",
+                SocketAddr::from(([127, 0, 0, 1], 65432)),
+            )?,
+            spectrogram_addr: match spectrogram_addr.as_str() {
+                "" | "off" => None,
+                addr => Some(addr.parse().map_err(|_| {
+                    format!("Invalid value for MOCK_DEVICE_SPECTROGRAM_ADDR: {:?}", addr)
+                })?),
+            },
+            encrypt_replies: matches!(
+                std::env::var("TCP_INBOUND_ENCRYPTION")
+                    .unwrap_or_default()
+                    .trim()
+                    .to_ascii_lowercase()
+                    .as_str(),
+                "1" | "true" | "yes" | "on"
+            ),
+            event_interval: (event_secs > 0).then(|| Duration::from_secs(event_secs)),
+            message_ids: var("MOCK_DEVICE_MESSAGE_IDS", true)?,
+            faults: Faults {
+                drop_rate: var("MOCK_DEVICE_DROP_RATE", 0.0)?,
+                garbage_rate: var("MOCK_DEVICE_GARBAGE_RATE", 0.0)?,
+                tamper_rate: var("MOCK_DEVICE_TAMPER_RATE", 0.0)?,
+                replay_rate: var("MOCK_DEVICE_REPLAY_RATE", 0.0)?,
+                max_delay: Duration::from_millis(var("MOCK_DEVICE_MAX_DELAY_MS", 0)?),
+            },
+        })
+    }
+}
+
+/// What the fake Pi remembers between commands.
+#[derive(Debug, Default)]
+pub struct DeviceState {
+    pub ir_enabled: bool,
+    pub images_saved: u32,
+    pub subscribers: BTreeSet<String>,
+}
+
+/// Handle to a running mock device. The listeners stop with the runtime.
+pub struct MockDevice {
+    tcp_addr: SocketAddr,
+    spectrogram_addr: Option<SocketAddr>,
+    state: Arc<Mutex<DeviceState>>,
+    keyring: Arc<Mutex<Keyring>>,
+}
+
+impl MockDevice {
+    pub fn tcp_addr(&self) -> SocketAddr {
+        self.tcp_addr
+    }
+
+    pub fn spectrogram_url(&self) -> Option<String> {
+        self.spectrogram_addr
+            .map(|addr| format!("ws://{}/ws", addr))
+    }
+
+    pub fn ir_enabled(&self) -> bool {
+        self.lock_state().ir_enabled
+    }
+
+    pub fn images_saved(&self) -> u32 {
+        self.lock_state().images_saved
+    }
+
+    pub fn subscribers(&self) -> Vec<String> {
+        self.lock_state().subscribers.iter().cloned().collect()
+    }
+
+    /// Id of the key the mock encrypts with, which changes when the server
+    /// rotates keys.
+    pub fn active_key_id(&self) -> KeyId {
+        self.keyring
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .active_id()
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, DeviceState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+struct Shared {
+    config: MockConfig,
+    keyring: Arc<Mutex<Keyring>>,
+    state: Arc<Mutex<DeviceState>>,
+}
+
+impl Shared {
+    fn keyring(&self) -> Keyring {
+        self.keyring
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+/// Binds the listeners and serves connections in the background.
+pub async fn spawn(config: MockConfig) -> io::Result<MockDevice> {
+    let keyring = Keyring::parse(&config.key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let keyring = Arc::new(Mutex::new(keyring));
+    let listener = TcpListener::bind(config.tcp_addr).await?;
+    let tcp_addr = listener.local_addr()?;
+
+    let spectrogram_addr = match config.spectrogram_addr {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            let addr = listener.local_addr()?;
+            let router = Router::new().route("/ws", get(spectrogram_handler));
+            tokio::spawn(async move {
+                if let Err(e) = axum::serve(listener, router).await {
+                    eprintln!("[Mock] Spectrogram server stopped: {}", e);
+                }
+            });
+            Some(addr)
+        }
+        None => None,
+    };
+
+    let state = Arc::new(Mutex::new(DeviceState::default()));
+    let shared = Arc::new(Shared {
+        config,
+        keyring: keyring.clone(),
+        state: state.clone(),
+    });
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    println!("[Mock] Connection from {}", peer);
+                    let shared = shared.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(stream, shared).await {
+                            eprintln!("[Mock] Connection from {} ended: {}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("[Mock] Accept failed: {}", e),
+            }
+        }
+    });
+
+    Ok(MockDevice {
+        tcp_addr,
+        spectrogram_addr,
+        state,
+        keyring,
+    })
+}
+
+async fn handle_connection(stream: TcpStream, shared: Arc<Shared>) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    // Faults are not applied while connecting so it stays deterministic.
+    let Some(first) = lines.next_line().await? else {
+        return Ok(());
+    };
+
+    let (mut cipher, reply_cipher, encrypt, session) = if first.starts_with("HELLO ") {
+        // Mutual handshake: everything after it is encrypted with the
+        // session keys, replies included.
+        let (device, challenge) = match DeviceHandshake::accept(&shared.keyring(), first.trim()) {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                writer
+                    .write_all(format!("{}\n", handshake::deny(&e)).as_bytes())
+                    .await?;
+                return Ok(());
+            }
+        };
+        writer
+            .write_all(format!("{}\n", challenge).as_bytes())
+            .await?;
+        let Some(proof) = lines.next_line().await? else {
+            return Ok(());
+        };
+        let keys = match device.verify(proof.trim()) {
+            Ok(keys) => keys,
+            Err(e) => {
+                writer
+                    .write_all(format!("{}\n", handshake::deny(&e)).as_bytes())
+                    .await?;
+                return Ok(());
+            }
+        };
+        writer
+            .write_all(format!("{}\n", handshake::WELCOME).as_bytes())
+            .await?;
+        (
+            handshake::session_cipher(keys.inbound, 30),
+            handshake::session_cipher(keys.outbound, 30),
+            true,
+            true,
+        )
+    } else {
+        // The client authenticates by sending its own IP, encrypted.
+        let mut cipher = Cipher::with_keyring(shared.keyring(), 30);
+        let authenticated = cipher
+            .decrypt_message(first.trim())
+            .ok()
+            .is_some_and(|ip| ip.trim().parse::<IpAddr>().is_ok());
+        // Like the Pi, answer in the wire format the client used.
+        let reply_cipher = Cipher::with_keyring(shared.keyring(), 30)
+            .with_version(cipher.peer_version().unwrap_or(WireVersion::Legacy));
+        let encrypt = shared.config.encrypt_replies;
+        let reply = if !authenticated {
+            "authentication failed"
+        } else if shared.config.message_ids {
+            "authentication successful; message ids"
+        } else {
+            "authentication successful"
+        };
+        let reply = if encrypt {
+            reply_cipher
+                .encrypt_message(reply)
+                .map_err(|e| io::Error::other(e.to_string()))?
+        } else {
+            reply.to_string()
+        };
+        writer.write_all(format!("{}\n", reply).as_bytes()).await?;
+        if !authenticated {
+            return Ok(());
+        }
+        (cipher, reply_cipher, encrypt, false)
+    };
+
+    let reply_cipher = Arc::new(Mutex::new(reply_cipher));
+    let encode_cipher = reply_cipher.clone();
+    let encode = move |message: &str| -> io::Result<String> {
+        if encrypt {
+            encode_cipher
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .encrypt_message(message)
+                .map_err(|e| io::Error::other(e.to_string()))
+        } else {
+            Ok(message.to_string())
+        }
+    };
+
+    let (tx, mut rx) = mpsc::channel::<String>(64);
+    let faults = shared.config.faults.clone();
+    let writer_task = tokio::spawn(async move {
+        while let Some(message) = rx.recv().await {
+            let encoded = match encode(&message) {
+                Ok(encoded) => encoded,
+                Err(e) => {
+                    eprintln!("[Mock] Failed to encrypt reply: {}", e);
+                    continue;
+                }
+            };
+            let Some(bytes) = apply_faults(&faults, encoded).await else {
+                continue;
+            };
+            if writer.write_all(&bytes).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    if let Some(interval) = shared.config.event_interval {
+        let tx = tx.clone();
+        let state = shared.state.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let ir_enabled = state.lock().unwrap_or_else(|e| e.into_inner()).ir_enabled;
+                for event in sensor_events(ir_enabled, unix_secs()) {
+                    if tx.send(event).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let payload = match cipher.decrypt_message(line.trim()) {
+            Ok(payload) => payload,
+            Err(e) => {
+                eprintln!("[Mock] Ignoring message that failed to decrypt: {:?}", e);
+                continue;
+            }
+        };
+
+        // Firmware without message ids takes a tagged command as unknown.
+        let (id, command) = if session || shared.config.message_ids {
+            split_message_id(&payload)
+        } else {
+            (None, payload.as_str())
+        };
+        let mut rotated = None;
+        let reply = match parse_rotate_key(command) {
+            // Like the Pi, only take a new key over an authenticated link.
+            Some(_) if !session && cipher.peer_version() == Some(WireVersion::Legacy) => {
+                "ERROR: key rotation needs an authenticated wire format".to_string()
+            }
+            Some(Ok((key_id, key))) => {
+                let mut keyring = shared.keyring();
+                match keyring.rotate(key_id, key) {
+                    Ok(()) => {
+                        rotated = Some(keyring);
+                        format!("KEY ROTATED={}", key_id)
+                    }
+                    Err(e) => format!("ERROR: {}", e),
+                }
+            }
+            Some(Err(e)) => format!("ERROR: {}", e),
+            None => {
+                let mut state = shared.state.lock().unwrap_or_else(|e| e.into_inner());
+                handle_command(&mut state, command)
+            }
+        };
+        let reply = match id {
+            Some(id) => format!("#{} {}", id, reply),
+            None => reply,
+        };
+        if tx.send(reply).await.is_err() {
+            break;
+        }
+
+        // Switch after acknowledging; the previous key stays accepted.
+        if let Some(keyring) = rotated {
+            println!("[Mock] Rotated to key {}", keyring.active_id());
+            // A session keeps its keys; the new key is used from the next
+            // handshake on.
+            if !session {
+                cipher.set_keyring(keyring.clone());
+                reply_cipher
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .set_keyring(keyring.clone());
+            }
+            *shared.keyring.lock().unwrap_or_else(|e| e.into_inner()) = keyring;
+        }
+    }
+
+    drop(tx);
+    writer_task.abort();
+    Ok(())
+}
+
+/// Returns the bytes to write for `message`, or `None` if it is dropped.
+async fn apply_faults(faults: &Faults, message: String) -> Option<Vec<u8>> {
+    if !faults.max_delay.is_zero() {
+        let max_ms = faults.max_delay.as_millis() as u64;
+        let delay = rand::random_range(0..=max_ms);
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+    }
+    if rand::random::<f64>() < faults.drop_rate {
+        return None;
+    }
+    if rand::random::<f64>() < faults.garbage_rate {
+        let len = rand::random_range(1..64);
+        let mut bytes: Vec<u8> = (0..len)
+            .map(|_| rand::random::<u8>())
+            .filter(|b| *b != b'\n')
+            .collect();
+        bytes.push(b'\n');
+        return Some(bytes);
+    }
+    let mut message = message;
+    if rand::random::<f64>() < faults.tamper_rate && !message.is_empty() {
+        let mid = message.len() / 2;
+        if message.is_char_boundary(mid) && message.is_char_boundary(mid + 1) {
+            let swapped = if &message[mid..mid + 1] == "A" {
+                "B"
+            } else {
+                "A"
+            };
+            message.replace_range(mid..mid + 1, swapped);
+        }
+    }
+    let mut frame = format!("{}\n", message);
+    if rand::random::<f64>() < faults.replay_rate {
+        frame = frame.repeat(2);
+    }
+    Some(frame.into_bytes())
+}
+
+fn split_message_id(payload: &str) -> (Option<u64>, &str) {
+    let Some(rest) = payload.trim_start().strip_prefix('#') else {
+        return (None, payload);
+    };
+    match rest.split_once(' ') {
+        Some((id, command)) => match id.parse() {
+            Ok(id) => (Some(id), command),
+            Err(_) => (None, payload),
+        },
+        None => (None, payload),
+    }
+}
+
+/// Executes a single `[CMD]` line and returns the reply the Pi would send.
+/// The firmware has no fixed reply for saving an image or the newsletter,
+/// so the mock answers those in free text that the server must not rely on.
+pub fn handle_command(state: &mut DeviceState, command: &str) -> String {
+    let command = command.trim();
+    let Some(body) = strip_prefix_ignore_case(command, "[CMD]") else {
+        return format!("ERROR: not a command: {}", command);
+    };
+    let body = body.trim();
+    let upper = body.to_ascii_uppercase();
+    let on_off = |enabled: bool| if enabled { "ON" } else { "OFF" };
+
+    match upper.as_str() {
+        "IR ON" => {
+            state.ir_enabled = true;
+            return "IR STATE IS ON".to_string();
+        }
+        "IR OFF" => {
+            state.ir_enabled = false;
+            return "IR STATE IS OFF".to_string();
+        }
+        "GET IR STATE" => return format!("IR STATE IS {}", on_off(state.ir_enabled)),
+        "SAVE IMAGE" => {
+            state.images_saved += 1;
+            return format!("saved image {}", state.images_saved);
+        }
+        _ => {}
+    }
+
+    if let Some(email) = strip_prefix_ignore_case(body, "add newsletter=") {
+        let email = email.trim();
+        if email.is_empty() {
+            return "ERROR: missing email".to_string();
+        }
+        state.subscribers.insert(email.to_lowercase());
+        return format!("added {} to the newsletter", email);
+    }
+    if let Some(email) = strip_prefix_ignore_case(body, "remove newsletter=") {
+        let email = email.trim();
+        if email.is_empty() {
+            return "ERROR: missing email".to_string();
+        }
+        state.subscribers.remove(&email.to_lowercase());
+        return format!("removed {} from the newsletter", email);
+    }
+
+    format!("ERROR: unknown command: {}", body)
+}
+
+/// Parses `[CMD] rotate key=<id>:<base64 key>`, or returns `None` for any
+/// other command.
+fn parse_rotate_key(command: &str) -> Option<Result<(KeyId, CipherKey), String>> {
+    let body = strip_prefix_ignore_case(command.trim(), "[CMD]")?.trim();
+    let value = strip_prefix_ignore_case(body, "rotate key=")?.trim();
+    let Some((id, key)) = value.split_once(':') else {
+        return Some(Err("expected rotate key=<id>:<key>".to_string()));
+    };
+    let Ok(id) = id.parse::<KeyId>() else {
+        return Some(Err(format!("invalid key id: {}", id)));
+    };
+    Some(
+        CipherKey::from_base64(key)
+            .map(|key| (id, key))
+            .map_err(|e| e.to_string()),
+    )
+}
+
+fn strip_prefix_ignore_case<'a>(value: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = value.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix)
+        .then(|| &value[prefix.len()..])
+}
+
+/// Unsolicited state and sensor messages, following a slow fake day cycle.
+fn sensor_events(ir_enabled: bool, now: u64) -> Vec<String> {
+    let phase = (now % 600) as f64 / 600.0 * std::f64::consts::TAU;
+    let daylight = phase.sin().max(0.0);
+    let noise = || rand::random::<f64>() - 0.5;
+
+    let luminosity = (daylight * 2000.0 + noise() * 20.0).max(0.0);
+    let outside = 8.0 + daylight * 10.0 + noise();
+    let inside = 18.0 + daylight * 4.0 + noise() * 0.5;
+    let ir_filter = luminosity < 300.0;
+    let on_off = |enabled: bool| if enabled { "ON" } else { "OFF" };
+
+    vec![
+        format!("IR LED STATE: {}", on_off(ir_enabled)),
+        format!("IR FILTER STATE: {}", on_off(ir_filter)),
+        format!("SENSOR inside_temperature={:.2}", inside),
+        format!("SENSOR outside_temperature={:.2}", outside),
+        format!("SENSOR luminosity={:.1}", luminosity),
+    ]
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+async fn spectrogram_handler(ws: WebSocketUpgrade) -> impl IntoResponse {
+    ws.on_upgrade(stream_spectrogram)
+}
+
+/// Sends log-magnitude frames at roughly 30 fps: noise with an occasional
+/// rising chirp, in the same JSON format as the Pi.
+async fn stream_spectrogram(mut socket: WebSocket) {
+    let mut ticker = tokio::time::interval(Duration::from_millis(33));
+    let mut frame_index = 0u64;
+
+    loop {
+        ticker.tick().await;
+        let frame = spectrogram_frame(frame_index);
+        frame_index += 1;
+
+        let text = serde_json::to_string(&frame).unwrap_or_default();
+        if socket.send(Message::Text(text.into())).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn spectrogram_frame(frame_index: u64) -> Vec<f64> {
+    // A chirp every 90 frames (~3 s) sweeping upwards for 12 frames.
+    let chirp_step = frame_index % 90;
+    let chirp_bin = (chirp_step < 12).then(|| 60 + chirp_step as usize * 10);
+
+    (0..SPECTROGRAM_BINS)
+        .map(|bin| {
+            let floor = -5.0 + rand::random::<f64>() * 0.8 - bin as f64 / 1000.0;
+            match chirp_bin {
+                Some(center) if bin.abs_diff(center) <= 3 => -0.5 - rand::random::<f64>() * 0.5,
+                _ => floor,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handle_command() {
+        let mut state = DeviceState::default();
+        assert_eq!(handle_command(&mut state, "[CMD] IR ON"), "IR STATE IS ON");
+        assert!(state.ir_enabled);
+        assert_eq!(
+            handle_command(&mut state, "[cmd] get ir state"),
+            "IR STATE IS ON"
+        );
+        assert_eq!(
+            handle_command(&mut state, "[CMD] IR OFF"),
+            "IR STATE IS OFF"
+        );
+        assert_eq!(
+            handle_command(&mut state, "[CMD] save image"),
+            "saved image 1"
+        );
+        assert_eq!(state.images_saved, 1);
+        assert_eq!(
+            handle_command(&mut state, "[CMD] add newsletter=Bird@Example.ch"),
+            "added Bird@Example.ch to the newsletter"
+        );
+        assert_eq!(
+            state.subscribers.iter().collect::<Vec<_>>(),
+            vec!["bird@example.ch"]
+        );
+        assert_eq!(
+            handle_command(&mut state, "[CMD] remove newsletter=bird@example.ch"),
+            "removed bird@example.ch from the newsletter"
+        );
+        assert!(state.subscribers.is_empty());
+    }
+
+    #[test]
+    fn test_handle_command_errors() {
+        let mut state = DeviceState::default();
+        assert!(handle_command(&mut state, "IR ON").starts_with("ERROR:"));
+        assert!(handle_command(&mut state, "[CMD] dance").starts_with("ERROR:"));
+        assert!(handle_command(&mut state, "[CMD] add newsletter=").starts_with("ERROR:"));
+    }
+
+    #[test]
+    fn test_parse_rotate_key() {
+        let key = CipherKey::generate().unwrap();
+        let command = format!("[CMD] rotate key=4:{}", key.to_base64());
+        let (id, parsed) = parse_rotate_key(&command).unwrap().unwrap();
+        assert_eq!(id, 4);
+        assert_eq!(parsed.to_base64(), key.to_base64());
+
+        assert!(parse_rotate_key("[CMD] IR ON").is_none());
+        assert!(parse_rotate_key("[CMD] rotate key=4").unwrap().is_err());
+        assert!(parse_rotate_key("[CMD] rotate key=x:AAAA")
+            .unwrap()
+            .is_err());
+        assert!(parse_rotate_key("[CMD] rotate key=4:AAAA")
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn test_split_message_id() {
+        assert_eq!(split_message_id("#5 [CMD] IR ON"), (Some(5), "[CMD] IR ON"));
+        assert_eq!(split_message_id("[CMD] IR ON"), (None, "[CMD] IR ON"));
+        assert_eq!(split_message_id("#x [CMD] IR ON"), (None, "#x [CMD] IR ON"));
+    }
+
+    #[test]
+    fn test_spectrogram_frame_shape() {
+        let quiet = spectrogram_frame(50);
+        assert_eq!(quiet.len(), SPECTROGRAM_BINS);
+        assert!(quiet.iter().all(|v| (-6.0..=0.0).contains(v)));
+
+        let chirp = spectrogram_frame(0);
+        assert!(chirp[60] > -1.5);
+    }
+}
@@ -0,0 +1,39 @@
+use mock_device::MockConfig;
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = dotenv::dotenv() {
+        eprintln!("Failed to load .env: {e}");
+    }
+
+    let config = match MockConfig::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let device = match mock_device::spawn(config.clone()).await {
+        Ok(device) => device,
+        Err(e) => {
+            eprintln!("Failed to start mock device: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    println!("Mock device listening on {}", device.tcp_addr());
+    if let Some(url) = device.spectrogram_url() {
+        println!("Spectrogram websocket at {}", url);
+    }
+    if config.faults.drop_rate > 0.0
+        || config.faults.garbage_rate > 0.0
+        || !config.faults.max_delay.is_zero()
+    {
+        println!("Fault injection: {:?}", config.faults);
+    }
+
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        eprintln!("Failed to wait for Ctrl-C: {}", e);
+    }
+}
@@ -1,5 +1,5 @@
 #[cfg(feature = "server")]
-use chrono::Utc;
+use crate::clock::{self, SharedClock};
 #[cfg(feature = "server")]
 use dashmap::DashMap;
 #[cfg(feature = "server")]
@@ -34,6 +34,61 @@ struct AdminSession {
     last_seen: i64,
 }
 
+/// Logged-in admins by session token. A session expires once it has not
+/// been used for `ADMIN_SESSION_TTL_SECS`.
+#[cfg(feature = "server")]
+struct AdminSessions {
+    sessions: DashMap<String, AdminSession>,
+    clock: SharedClock,
+}
+
+#[cfg(feature = "server")]
+impl AdminSessions {
+    fn new(clock: SharedClock) -> Self {
+        Self {
+            sessions: DashMap::new(),
+            clock,
+        }
+    }
+
+    fn now(&self) -> i64 {
+        clock::unix_secs(self.clock.as_ref())
+    }
+
+    fn create(&self) -> String {
+        let token = Uuid::new_v4().to_string();
+        self.sessions.insert(
+            token.clone(),
+            AdminSession {
+                last_seen: self.now(),
+            },
+        );
+        token
+    }
+
+    fn remove(&self, token: &str) {
+        self.sessions.remove(token);
+    }
+
+    /// Whether `token` is a live session; using it keeps it alive.
+    fn validate(&self, token: &str) -> bool {
+        let now = self.now();
+        self.prune(now);
+
+        if let Some(mut entry) = self.sessions.get_mut(token) {
+            entry.last_seen = now;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn prune(&self, now: i64) {
+        self.sessions
+            .retain(|_, session| now - session.last_seen <= ADMIN_SESSION_TTL_SECS);
+    }
+}
+
 #[cfg(feature = "server")]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct AdminCredentials {
@@ -89,7 +144,9 @@ struct PasskeyAuthenticationFlow {
 }
 
 #[cfg(feature = "server")]
-static ADMIN_SESSIONS: Lazy<DashMap<String, AdminSession>> = Lazy::new(DashMap::new);
+static ADMIN_CLOCK: Lazy<SharedClock> = Lazy::new(clock::system);
+#[cfg(feature = "server")]
+static ADMIN_SESSIONS: Lazy<AdminSessions> = Lazy::new(|| AdminSessions::new(ADMIN_CLOCK.clone()));
 #[cfg(feature = "server")]
 static ADMIN_CREDENTIALS_CACHE: Lazy<RwLock<Option<AdminCredentials>>> =
     Lazy::new(|| RwLock::new(None));
@@ -176,6 +233,11 @@ fn admin_webauthn() -> Result<&'static Webauthn, String> {
     }
 }
 
+#[cfg(feature = "server")]
+fn unix_now() -> i64 {
+    clock::unix_secs(ADMIN_CLOCK.as_ref())
+}
+
 #[cfg(feature = "server")]
 fn hash_secret(secret: &str, salt: &str) -> String {
     let mut hasher = Sha256::new();
@@ -207,7 +269,7 @@ fn default_credentials() -> AdminCredentials {
         password_salt,
         user_uuid: Uuid::new_v4().to_string(),
         passkey: None,
-        updated_at: Utc::now().timestamp(),
+        updated_at: unix_now(),
     }
 }
 
@@ -277,7 +339,7 @@ fn read_or_create_credentials() -> Result<AdminCredentials, String> {
                 password_hash: String::new(),
                 user_uuid: Uuid::new_v4().to_string(),
                 passkey: None,
-                updated_at: Utc::now().timestamp(),
+                updated_at: unix_now(),
             };
             let migrated = AdminCredentials {
                 password_hash: hash_secret(&password, &migrated.password_salt),
@@ -331,19 +393,6 @@ fn save_credentials(credentials: &AdminCredentials) -> Result<(), String> {
     Ok(())
 }
 
-#[cfg(feature = "server")]
-fn prune_sessions(now: i64) {
-    let mut stale = Vec::new();
-    for entry in ADMIN_SESSIONS.iter() {
-        if now - entry.value().last_seen > ADMIN_SESSION_TTL_SECS {
-            stale.push(entry.key().clone());
-        }
-    }
-    for token in stale {
-        ADMIN_SESSIONS.remove(&token);
-    }
-}
-
 #[cfg(feature = "server")]
 fn prune_passkey_flows(now: i64) {
     let mut stale_reg = Vec::new();
@@ -399,8 +448,8 @@ fn clear_login_attempts(email: &str) {
 #[cfg(feature = "server")]
 pub fn admin_login_password(email: &str, password: &str) -> Result<String, String> {
     let credentials = load_credentials()?;
-    let now = Utc::now().timestamp();
-    prune_sessions(now);
+    let now = unix_now();
+    ADMIN_SESSIONS.prune(now);
     prune_passkey_flows(now);
     check_login_rate_limit(email, now)?;
 
@@ -417,9 +466,7 @@ pub fn admin_login_password(email: &str, password: &str) -> Result<String, Strin
     }
 
     clear_login_attempts(email);
-    let token = Uuid::new_v4().to_string();
-    ADMIN_SESSIONS.insert(token.clone(), AdminSession { last_seen: now });
-    Ok(token)
+    Ok(ADMIN_SESSIONS.create())
 }
 
 #[cfg(feature = "server")]
@@ -434,15 +481,7 @@ pub fn admin_validate_session(token: &str) -> bool {
         return false;
     }
 
-    let now = Utc::now().timestamp();
-    prune_sessions(now);
-
-    if let Some(mut entry) = ADMIN_SESSIONS.get_mut(token) {
-        entry.last_seen = now;
-        true
-    } else {
-        false
-    }
+    ADMIN_SESSIONS.validate(token)
 }
 
 #[cfg(feature = "server")]
@@ -485,7 +524,7 @@ pub fn admin_update_credentials(
         }
     }
 
-    credentials.updated_at = Utc::now().timestamp();
+    credentials.updated_at = unix_now();
     save_credentials(&credentials)
 }
 
@@ -495,7 +534,7 @@ pub fn admin_begin_passkey_registration(token: &str) -> Result<PasskeyBeginResul
         return Err("Unauthorized".to_string());
     }
 
-    let now = Utc::now().timestamp();
+    let now = unix_now();
     prune_passkey_flows(now);
 
     let mut credentials = load_credentials()?;
@@ -538,7 +577,7 @@ pub fn admin_finish_passkey_registration(
         return Err("Unauthorized".to_string());
     }
 
-    let now = Utc::now().timestamp();
+    let now = unix_now();
     prune_passkey_flows(now);
 
     let (_, flow) = PASSKEY_REGISTRATION_FLOWS
@@ -565,8 +604,8 @@ pub fn admin_finish_passkey_registration(
 
 #[cfg(feature = "server")]
 pub fn admin_begin_passkey_login(email: &str) -> Result<PasskeyBeginResult, String> {
-    let now = Utc::now().timestamp();
-    prune_sessions(now);
+    let now = unix_now();
+    ADMIN_SESSIONS.prune(now);
     prune_passkey_flows(now);
     check_login_rate_limit(email, now)?;
 
@@ -605,8 +644,8 @@ pub fn admin_begin_passkey_login(email: &str) -> Result<PasskeyBeginResult, Stri
 
 #[cfg(feature = "server")]
 pub fn admin_finish_passkey_login(flow_id: &str, credential_json: &str) -> Result<String, String> {
-    let now = Utc::now().timestamp();
-    prune_sessions(now);
+    let now = unix_now();
+    ADMIN_SESSIONS.prune(now);
     prune_passkey_flows(now);
 
     let (_, flow) = PASSKEY_AUTH_FLOWS
@@ -627,7 +666,37 @@ pub fn admin_finish_passkey_login(flow_id: &str, credential_json: &str) -> Resul
         return Err("Invalid admin credentials.".to_string());
     }
 
-    let token = Uuid::new_v4().to_string();
-    ADMIN_SESSIONS.insert(token.clone(), AdminSession { last_seen: now });
-    Ok(token)
+    Ok(ADMIN_SESSIONS.create())
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::{AdminSessions, ADMIN_SESSION_TTL_SECS};
+    use crate::clock::MockClock;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_sessions_expire_after_ttl_without_use() {
+        let clock = MockClock::at_unix_secs(1_700_000_000);
+        let sessions = AdminSessions::new(Arc::new(clock.clone()));
+        let ttl = Duration::from_secs(ADMIN_SESSION_TTL_SECS as u64);
+
+        let token = sessions.create();
+        assert!(sessions.validate(&token));
+        assert!(!sessions.validate("not-a-token"));
+
+        // Using the session keeps it alive.
+        clock.advance(ttl);
+        assert!(sessions.validate(&token));
+        clock.advance(ttl);
+        assert!(sessions.validate(&token));
+
+        clock.advance(ttl + Duration::from_secs(1));
+        assert!(!sessions.validate(&token));
+
+        let token = sessions.create();
+        sessions.remove(&token);
+        assert!(!sessions.validate(&token));
+    }
 }
@@ -0,0 +1,28 @@
+use crate::{postgres_store, write_buffer};
+use axum::{
+    extract::{Json, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct TokenParams {
+    token: String,
+}
+
+/// `GET /api/admin/database-stats?token=..`: connection pool state, wait
+/// times and write buffer backlog as JSON.
+pub async fn admin_database_stats(Query(params): Query<TokenParams>) -> Response {
+    if !crate::admin::admin_validate_session(&params.token) {
+        return (StatusCode::UNAUTHORIZED, "unauthorized").into_response();
+    }
+    match (postgres_store::shared(), write_buffer::shared()) {
+        (Ok(store), Ok(buffer)) => Json(serde_json::json!({
+            "pool": store.pool_stats(),
+            "write_buffer": buffer.stats(),
+        }))
+        .into_response(),
+        (Err(e), _) | (_, Err(e)) => (StatusCode::SERVICE_UNAVAILABLE, e).into_response(),
+    }
+}
@@ -0,0 +1,221 @@
+use crate::client_ip::client_ip;
+use crate::clock;
+use crate::export::{
+    ExportEncoder, ExportFormat, ExportLimiter, ExportQuery, EXPORTS_PER_HOUR, EXPORT_PAGE_ROWS,
+};
+use crate::postgres_store::{self, parse_duration, Point, PostgresTimeSeriesStore};
+use crate::time_series::check_history_field;
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::net::SocketAddr;
+
+static EXPORT_LIMITER: Lazy<ExportLimiter> =
+    Lazy::new(|| ExportLimiter::new(clock::system(), EXPORTS_PER_HOUR));
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    measurement: Option<String>,
+    /// Comma separated history fields.
+    fields: String,
+    /// Unix seconds; defaults to `window` before `to`.
+    from: Option<i64>,
+    /// Unix seconds; defaults to now.
+    to: Option<i64>,
+    /// How far back from `to` to start without `from`, e.g. `7d`.
+    window: Option<String>,
+    format: Option<String>,
+}
+
+fn bad_request(message: String) -> Response {
+    (StatusCode::BAD_REQUEST, message).into_response()
+}
+
+fn parse_query(params: ExportParams) -> Result<(ExportQuery, ExportFormat), String> {
+    let format = params
+        .format
+        .as_deref()
+        .unwrap_or("csv")
+        .parse::<ExportFormat>()?;
+    let fields: Vec<String> = params
+        .fields
+        .split(',')
+        .map(str::trim)
+        .filter(|f| !f.is_empty())
+        .map(str::to_string)
+        .collect();
+    if fields.is_empty() {
+        return Err("Name at least one field.".to_string());
+    }
+    for field in &fields {
+        check_history_field(field)?;
+    }
+    let measurement = params
+        .measurement
+        .map(|m| m.trim().to_string())
+        .filter(|m| !m.is_empty());
+    if measurement.as_ref().is_some_and(|m| m.len() > 64) {
+        return Err("The measurement name is too long.".to_string());
+    }
+
+    let to = match params.to {
+        Some(ts) => DateTime::from_timestamp(ts, 0),
+        None => Some(Utc::now()),
+    }
+    .ok_or("Timestamp out of range")?;
+    let from = match params.from {
+        Some(ts) => DateTime::from_timestamp(ts, 0).ok_or("Timestamp out of range")?,
+        None => to - parse_duration(params.window.as_deref().unwrap_or("24h"))?,
+    };
+    if from >= to {
+        return Err("The range must end after it starts.".to_string());
+    }
+
+    Ok((
+        ExportQuery {
+            measurement,
+            fields,
+            from,
+            to,
+        },
+        format,
+    ))
+}
+
+fn file_name(query: &ExportQuery, format: ExportFormat) -> String {
+    let name: String = query
+        .measurement
+        .as_deref()
+        .unwrap_or("history")
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!(
+        "birdhouse-{}-{}-{}.{}",
+        name,
+        query.from.format("%Y%m%d"),
+        query.to.format("%Y%m%d"),
+        format.extension()
+    )
+}
+
+struct ExportState {
+    store: PostgresTimeSeriesStore,
+    query: ExportQuery,
+    /// `None` once the export is finished.
+    encoder: Option<ExportEncoder>,
+    /// A page read before the response started.
+    pending: Option<Vec<(i64, Point)>>,
+    after: Option<(DateTime<Utc>, i64)>,
+    exhausted: bool,
+}
+
+impl ExportState {
+    async fn next_chunk(mut self) -> Result<Option<(Bytes, Self)>, String> {
+        let Some(mut encoder) = self.encoder.take() else {
+            return Ok(None);
+        };
+        if self.exhausted {
+            return Ok(Some((Bytes::from(encoder.finish()?), self)));
+        }
+
+        let page = match self.pending.take() {
+            Some(page) => page,
+            None => {
+                self.store
+                    .export_page(&self.query, self.after, EXPORT_PAGE_ROWS)
+                    .await?
+            }
+        };
+        self.exhausted = (page.len() as i64) < EXPORT_PAGE_ROWS;
+        if let Some((id, point)) = page.last() {
+            self.after = Some((point.ts, *id));
+        }
+        let points: Vec<Point> = page.into_iter().map(|(_, point)| point).collect();
+        let chunk = Bytes::from(encoder.page(&points)?);
+        self.encoder = Some(encoder);
+        Ok(Some((chunk, self)))
+    }
+}
+
+/// `GET /api/export?fields=a,b&window=7d&format=csv`: raw points of public
+/// history fields as CSV, NDJSON or Parquet, optionally of one
+/// `measurement` and between `from` and `to`. The file is streamed a page at
+/// a time. Each client may start `EXPORTS_PER_HOUR` exports per hour.
+pub async fn export_history(
+    Query(params): Query<ExportParams>,
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> Response {
+    let (query, format) = match parse_query(params) {
+        Ok(parsed) => parsed,
+        Err(e) => return bad_request(e),
+    };
+    if let Err(retry_after) = EXPORT_LIMITER.try_start(&client_ip(&headers, addr.ip()).to_string())
+    {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after.to_string())],
+            format!(
+                "At most {} exports per hour. Try again later.",
+                EXPORTS_PER_HOUR
+            ),
+        )
+            .into_response();
+    }
+
+    let unavailable = |e: String| {
+        eprintln!("[Export] Failed to start export: {}", e);
+        (StatusCode::SERVICE_UNAVAILABLE, "history store unavailable").into_response()
+    };
+    let store = match postgres_store::shared() {
+        Ok(store) => store,
+        Err(e) => return unavailable(e),
+    };
+    // Read the first page up front, so an unreachable database is a 503
+    // instead of a broken download.
+    let first = match store.export_page(&query, None, EXPORT_PAGE_ROWS).await {
+        Ok(page) => page,
+        Err(e) => return unavailable(e),
+    };
+    let encoder = match ExportEncoder::new(format) {
+        Ok(encoder) => encoder,
+        Err(e) => return unavailable(e),
+    };
+
+    let disposition = format!("attachment; filename=\"{}\"", file_name(&query, format));
+    let state = ExportState {
+        store,
+        query,
+        encoder: Some(encoder),
+        pending: Some(first),
+        after: None,
+        exhausted: false,
+    };
+    let stream = futures_util::stream::try_unfold(state, |state| async move {
+        state.next_chunk().await.inspect_err(|e| {
+            eprintln!("[Export] Export aborted: {}", e);
+        })
+    });
+
+    (
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        Body::from_stream(stream),
+    )
+        .into_response()
+}
@@ -0,0 +1,108 @@
+use crate::postgres_store;
+use crate::time_series::{check_history_field, Aggregate};
+use axum::{
+    extract::{Json, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct RangeParams {
+    field: String,
+    /// Unix seconds; defaults to 24 hours before `to`.
+    from: Option<i64>,
+    /// Unix seconds; defaults to now.
+    to: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct AggregateParams {
+    field: String,
+    #[serde(default = "default_window")]
+    window: String,
+    #[serde(default = "default_bucket")]
+    bucket: String,
+    #[serde(default = "default_agg")]
+    agg: String,
+}
+
+fn default_window() -> String {
+    "24h".to_string()
+}
+
+fn default_bucket() -> String {
+    "10m".to_string()
+}
+
+fn default_agg() -> String {
+    "mean".to_string()
+}
+
+fn bad_request(message: String) -> Response {
+    (StatusCode::BAD_REQUEST, message).into_response()
+}
+
+fn store_unavailable(message: String) -> Response {
+    eprintln!("History query failed: {}", message);
+    (StatusCode::SERVICE_UNAVAILABLE, "history store unavailable").into_response()
+}
+
+/// `GET /api/history/range?field=..&from=..&to=..`: raw values as JSON.
+pub async fn history_range(Query(params): Query<RangeParams>) -> Response {
+    if let Err(e) = check_history_field(&params.field) {
+        return bad_request(e);
+    }
+    let to = match params.to {
+        Some(ts) => DateTime::from_timestamp(ts, 0),
+        None => Some(Utc::now()),
+    };
+    let from = match (params.from, to) {
+        (Some(ts), _) => DateTime::from_timestamp(ts, 0),
+        (None, Some(to)) => Some(to - ChronoDuration::hours(24)),
+        (None, None) => None,
+    };
+    let (Some(from), Some(to)) = (from, to) else {
+        return bad_request("Timestamp out of range".to_string());
+    };
+    if from >= to {
+        return bad_request("The range must end after it starts.".to_string());
+    }
+
+    let store = match postgres_store::shared() {
+        Ok(store) => store,
+        Err(e) => return store_unavailable(e),
+    };
+    match store.query_range(&params.field, from, to).await {
+        Ok(points) => Json(points).into_response(),
+        Err(e) => store_unavailable(e),
+    }
+}
+
+/// `GET /api/history/aggregate?field=..&window=24h&bucket=10m&agg=mean`:
+/// one value per bucket as JSON.
+pub async fn history_aggregate(Query(params): Query<AggregateParams>) -> Response {
+    if let Err(e) = check_history_field(&params.field) {
+        return bad_request(e);
+    }
+    let agg = match params.agg.parse::<Aggregate>() {
+        Ok(agg) => agg,
+        Err(e) => return bad_request(e),
+    };
+    if let Err(e) = postgres_store::aggregate_span(&params.window, &params.bucket) {
+        return bad_request(e);
+    }
+
+    let store = match postgres_store::shared() {
+        Ok(store) => store,
+        Err(e) => return store_unavailable(e),
+    };
+    match store
+        .query_aggregate(&params.field, &params.window, &params.bucket, agg)
+        .await
+    {
+        Ok(points) => Json(points).into_response(),
+        Err(e) => store_unavailable(e),
+    }
+}
@@ -0,0 +1,119 @@
+use crate::ingest;
+use crate::line_protocol::{self, Precision};
+use crate::{postgres_store, write_buffer};
+use axum::{
+    extract::{Json, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+/// Buckets writes may name besides the store's own, from `INFLUX_BUCKETS`.
+static EXTRA_BUCKETS: Lazy<Vec<String>> = Lazy::new(|| {
+    std::env::var("INFLUX_BUCKETS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|b| !b.is_empty())
+        .map(str::to_string)
+        .collect()
+});
+
+/// Query parameters of InfluxDB's `POST /api/v2/write`. `org` is accepted
+/// but ignored.
+#[derive(Deserialize)]
+pub struct WriteParams {
+    bucket: Option<String>,
+    precision: Option<String>,
+}
+
+/// An error body in the shape Influx clients expect.
+fn influx_error(status: StatusCode, code: &str, message: String) -> Response {
+    (
+        status,
+        Json(serde_json::json!({ "code": code, "message": message })),
+    )
+        .into_response()
+}
+
+/// Influx clients send `Token ..`; `Bearer ..` works as well.
+fn api_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    value
+        .strip_prefix("Token ")
+        .or_else(|| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// `POST /api/v2/write?bucket=..&precision=..`: InfluxDB line protocol,
+/// authenticated with a token from `INGEST_TOKENS`. Gzip bodies are
+/// decompressed by the route. Only the store's bucket and those in
+/// `INFLUX_BUCKETS` can be written. Answers 204 once every point is queued
+/// for writing, like InfluxDB, or 400 naming the first bad line.
+pub async fn influx_write(
+    Query(params): Query<WriteParams>,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    let Some(device) = api_token(&headers).and_then(|t| ingest::tokens().device_for(t)) else {
+        return influx_error(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "unauthorized access".to_string(),
+        );
+    };
+
+    let precision = match params
+        .precision
+        .as_deref()
+        .unwrap_or("ns")
+        .parse::<Precision>()
+    {
+        Ok(precision) => precision,
+        Err(e) => return influx_error(StatusCode::BAD_REQUEST, "invalid", e),
+    };
+    let unavailable = |e: String| {
+        eprintln!("[Influx] Store unavailable: {}", e);
+        influx_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "unavailable",
+            "store unavailable".to_string(),
+        )
+    };
+    let store = match postgres_store::shared() {
+        Ok(store) => store,
+        Err(e) => return unavailable(e),
+    };
+    let buffer = match write_buffer::shared() {
+        Ok(buffer) => buffer,
+        Err(e) => return unavailable(e),
+    };
+
+    let bucket = params.bucket.unwrap_or_else(|| store.bucket().to_string());
+    if bucket != store.bucket() && !EXTRA_BUCKETS.contains(&bucket) {
+        return influx_error(
+            StatusCode::NOT_FOUND,
+            "not found",
+            format!("bucket {:?} not found", bucket),
+        );
+    }
+
+    match line_protocol::parse(&body, &bucket, precision, Utc::now()) {
+        Ok(points) => {
+            println!(
+                "[Influx] {} wrote {} points to {}",
+                device,
+                points.len(),
+                bucket
+            );
+            buffer.extend(points);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            eprintln!("[Influx] Rejected a write from {}: {}", device, e);
+            influx_error(StatusCode::BAD_REQUEST, "invalid", e)
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use crate::clock;
+use crate::ingest::{
+    self, Claim, IdempotencyKeys, IngestReceipt, IngestTokens, MAX_IDEMPOTENCY_KEYS,
+    MAX_IDEMPOTENCY_KEY_LEN,
+};
+use crate::write_buffer::{PointSink, WriteBuffer};
+use crate::{postgres_store, write_buffer};
+use axum::{
+    body::Bytes,
+    extract::Json,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::sync::Arc;
+
+static IDEMPOTENCY_KEYS: Lazy<IdempotencyKeys> =
+    Lazy::new(|| IdempotencyKeys::new(clock::system(), MAX_IDEMPOTENCY_KEYS));
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// `POST /api/ingest`: one `{device, data}` payload or an array of them,
+/// authenticated with the device's token from `INGEST_TOKENS` as
+/// `Authorization: Bearer ..`. An `Idempotency-Key` header makes retries
+/// safe: a repeat of a finished request gets the first answer back.
+/// Answers 202 with the number of points queued for writing, or 422 with a
+/// list of `{index, field, message}` errors.
+pub async fn ingest(headers: HeaderMap, body: Bytes) -> Response {
+    let target = postgres_store::shared().and_then(|store| {
+        Ok(Target {
+            bucket: store.bucket().to_string(),
+            buffer: write_buffer::shared()?,
+        })
+    });
+    handle(ingest::tokens(), &IDEMPOTENCY_KEYS, target, &headers, &body).await
+}
+
+/// Where the points of an accepted request go.
+struct Target<S: PointSink> {
+    bucket: String,
+    buffer: Arc<WriteBuffer<S>>,
+}
+
+async fn handle<S: PointSink>(
+    tokens: &IngestTokens,
+    keys: &IdempotencyKeys,
+    target: Result<Target<S>, String>,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Response {
+    let Some(device) = bearer_token(headers).and_then(|t| tokens.device_for(t)) else {
+        return (StatusCode::UNAUTHORIZED, "unauthorized").into_response();
+    };
+    let payload: Value = match serde_json::from_slice(body) {
+        Ok(payload) => payload,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("invalid JSON: {}", e)).into_response(),
+    };
+
+    let key = match headers.get("idempotency-key").map(|v| v.to_str()) {
+        None => None,
+        Some(Ok(key)) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN => Some(key),
+        Some(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Idempotency-Key must be 1 to {} visible ASCII characters",
+                    MAX_IDEMPOTENCY_KEY_LEN
+                ),
+            )
+                .into_response()
+        }
+    };
+    if let Some(key) = key {
+        match keys.claim(device, key, body) {
+            Claim::New => {}
+            Claim::Replay(receipt) => {
+                return (
+                    StatusCode::ACCEPTED,
+                    [("idempotent-replayed", "true")],
+                    Json(receipt),
+                )
+                    .into_response()
+            }
+            Claim::InFlight => {
+                return (
+                    StatusCode::CONFLICT,
+                    "a request with this Idempotency-Key is still being processed",
+                )
+                    .into_response()
+            }
+            Claim::Mismatch => {
+                return (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "Idempotency-Key was already used for a different request",
+                )
+                    .into_response()
+            }
+        }
+    }
+
+    let response = store(device, &payload, target).await;
+    if let Some(key) = key {
+        match &response {
+            Ok(receipt) => keys.complete(device, key, receipt.clone()),
+            Err(_) => keys.release(device, key),
+        }
+    }
+    match response {
+        Ok(receipt) => (StatusCode::ACCEPTED, Json(receipt)).into_response(),
+        Err(response) => response,
+    }
+}
+
+async fn store<S: PointSink>(
+    device: &str,
+    payload: &Value,
+    target: Result<Target<S>, String>,
+) -> Result<IngestReceipt, Response> {
+    let readings = ingest::validate(payload, device, Utc::now()).map_err(|errors| {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({ "errors": errors })),
+        )
+            .into_response()
+    })?;
+
+    let unavailable = |e: String| {
+        eprintln!("[Ingest] Store unavailable: {}", e);
+        (StatusCode::SERVICE_UNAVAILABLE, "store unavailable").into_response()
+    };
+    let target = target.map_err(unavailable)?;
+
+    let mut points = Vec::new();
+    for reading in &readings {
+        points.extend(
+            postgres_store::device_points(&target.bucket, &reading.payload, None, reading.ts)
+                .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e).into_response())?,
+        );
+    }
+    let receipt = IngestReceipt {
+        payloads: readings.len(),
+        points: points.len(),
+    };
+    target.buffer.extend(points);
+    Ok(receipt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{handle, Target};
+    use crate::clock::MockClock;
+    use crate::ingest::{IdempotencyKeys, IngestTokens, MAX_IDEMPOTENCY_KEYS};
+    use crate::postgres_store::{InsertError, Point};
+    use crate::write_buffer::{PointSink, WriteBuffer, WriteBufferConfig};
+    use axum::http::{HeaderMap, HeaderValue, StatusCode};
+    use axum::response::Response;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    struct NullSink;
+
+    impl PointSink for NullSink {
+        async fn insert_points(&self, _points: &[Point]) -> Result<(), InsertError> {
+            Ok(())
+        }
+    }
+
+    fn headers(token: &str, key: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "authorization",
+            HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
+        );
+        if let Some(key) = key {
+            headers.insert("idempotency-key", HeaderValue::from_str(key).unwrap());
+        }
+        headers
+    }
+
+    async fn json_body(response: Response) -> Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_ingest_request() {
+        let tokens = IngestTokens::parse("birdhouse:s3cret").unwrap();
+        let keys = IdempotencyKeys::new(
+            Arc::new(MockClock::at_unix_secs(1_700_000_000)),
+            MAX_IDEMPOTENCY_KEYS,
+        );
+        let buffer = Arc::new(WriteBuffer::new(
+            NullSink,
+            WriteBufferConfig {
+                max_points: 100,
+                flush_interval: Duration::from_secs(5),
+                journal_path: std::env::temp_dir()
+                    .join(format!("birdhouse-ingest-{}.ndjson", uuid::Uuid::new_v4())),
+                journal_max_bytes: 1 << 20,
+            },
+        ));
+        let send = |headers: HeaderMap, body: Value| {
+            let target = Ok(Target {
+                bucket: "voegeli".to_string(),
+                buffer: buffer.clone(),
+            });
+            let (tokens, keys) = (&tokens, &keys);
+            async move { handle(tokens, keys, target, &headers, body.to_string().as_bytes()).await }
+        };
+        let reading =
+            json!({"device": "birdhouse", "data": {"temp": 21.5, "temp_unit": "°C", "ir": true}});
+
+        let response = send(HeaderMap::new(), reading.clone()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = send(headers("wrong", None), reading.clone()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let bad = json!({"device": "birdhouse", "data": {"note": "a\0b"}});
+        let response = send(headers("s3cret", None), bad).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(json_body(response).await["errors"][0]["field"], "data.note");
+        assert_eq!(buffer.stats().pending, 0);
+
+        let response = send(headers("s3cret", Some("k1")), reading.clone()).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(response.headers().get("idempotent-replayed").is_none());
+        assert_eq!(
+            json_body(response).await,
+            json!({"payloads": 1, "points": 2})
+        );
+
+        let response = send(headers("s3cret", Some("k1")), reading.clone()).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(response.headers()["idempotent-replayed"], "true");
+        assert_eq!(
+            json_body(response).await,
+            json!({"payloads": 1, "points": 2})
+        );
+        assert_eq!(buffer.stats().pending, 2);
+
+        let other = json!({"device": "birdhouse", "data": {"temp": 22.0}});
+        let response = send(headers("s3cret", Some("k1")), other).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
@@ -1,2 +1,12 @@
 #[cfg(feature = "server")]
+pub mod database;
+#[cfg(feature = "server")]
+pub mod export;
+#[cfg(feature = "server")]
 pub mod gallery;
+#[cfg(feature = "server")]
+pub mod history;
+#[cfg(feature = "server")]
+pub mod influx;
+#[cfg(feature = "server")]
+pub mod ingest;
@@ -0,0 +1,186 @@
+#![cfg(feature = "server")]
+
+//! The address a request comes from, for per-client rate limits. Reverse
+//! proxies name the client in `cf-connecting-ip`, `x-real-ip` or
+//! `x-forwarded-for`, but any client can send those headers itself, so they
+//! are only believed on connections from a proxy listed in `TRUSTED_PROXIES`
+//! (addresses or networks such as `127.0.0.1,10.0.0.0/8`).
+
+use axum::http::HeaderMap;
+use once_cell::sync::Lazy;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An address, or a network in `address/prefix` notation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("Invalid address {:?}.", addr))?
+            .to_canonical();
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            None => bits,
+            Some(prefix) => prefix
+                .parse::<u32>()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or_else(|| format!("Invalid prefix length {:?} for {}.", prefix, addr))?,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (network, ip, bits) = match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                (u32::from(network) as u128, u32::from(ip) as u128, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+            _ => return false,
+        };
+        let shift = bits - self.prefix;
+        shift >= 128 || network >> shift == ip >> shift
+    }
+}
+
+/// Parses the comma separated entries of `TRUSTED_PROXIES`.
+pub fn parse_networks(spec: &str) -> Result<Vec<Network>, String> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+static TRUSTED_PROXIES: Lazy<Vec<Network>> = Lazy::new(|| {
+    let spec = std::env::var("TRUSTED_PROXIES").unwrap_or_default();
+    parse_networks(&spec).unwrap_or_else(|e| {
+        eprintln!(
+            "Invalid TRUSTED_PROXIES, ignoring forwarded client addresses: {}",
+            e
+        );
+        Vec::new()
+    })
+});
+
+fn is_trusted(trusted: &[Network], ip: IpAddr) -> bool {
+    trusted.iter().any(|network| network.contains(ip))
+}
+
+/// The client named by the first forwarding header present.
+fn forwarded_ip(trusted: &[Network], headers: &HeaderMap) -> Option<IpAddr> {
+    for header_name in [
+        "cf-connecting-ip",
+        "x-real-ip",
+        "x-forwarded-for",
+        "forwarded",
+    ] {
+        if let Some(value) = headers.get(header_name).and_then(|v| v.to_str().ok()) {
+            if header_name == "x-forwarded-for" {
+                // Each proxy appends the address it saw, so anything left of
+                // the last proxy we trust may have come from the client.
+                let hop = value
+                    .rsplit(',')
+                    .filter_map(|hop| hop.trim().parse::<IpAddr>().ok())
+                    .find(|ip| !is_trusted(trusted, *ip));
+                if hop.is_some() {
+                    return hop;
+                }
+            } else if let Ok(ip) = value.trim().parse::<IpAddr>() {
+                return Some(ip);
+            }
+        }
+    }
+    None
+}
+
+/// The client behind a connection from `peer`: the forwarded address when
+/// `peer` is one of `trusted`, otherwise `peer` itself.
+fn client_ip_behind(trusted: &[Network], headers: &HeaderMap, peer: IpAddr) -> IpAddr {
+    let peer = peer.to_canonical();
+    if is_trusted(trusted, peer) {
+        forwarded_ip(trusted, headers).unwrap_or(peer)
+    } else {
+        peer
+    }
+}
+
+/// The client behind a connection from `peer`, trusting the forwarding
+/// headers only from `TRUSTED_PROXIES`.
+pub fn client_ip(headers: &HeaderMap, peer: IpAddr) -> IpAddr {
+    client_ip_behind(&TRUSTED_PROXIES, headers, peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{client_ip_behind, parse_networks, Network};
+    use axum::http::{HeaderMap, HeaderValue};
+    use std::net::IpAddr;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_networks() {
+        let networks = parse_networks("127.0.0.1, 10.0.0.0/8,fd00::/8").unwrap();
+        assert!(networks[0].contains(ip("127.0.0.1")));
+        assert!(networks[0].contains(ip("::ffff:127.0.0.1")));
+        assert!(!networks[0].contains(ip("127.0.0.2")));
+        assert!(networks[1].contains(ip("10.20.30.40")));
+        assert!(!networks[1].contains(ip("11.0.0.1")));
+        assert!(networks[2].contains(ip("fd12::1")));
+        assert!(!networks[2].contains(ip("10.0.0.1")));
+        assert!("0.0.0.0/0"
+            .parse::<Network>()
+            .unwrap()
+            .contains(ip("8.8.8.8")));
+
+        assert!(parse_networks("").unwrap().is_empty());
+        assert!(parse_networks("10.0.0.0/33").is_err());
+        assert!(parse_networks("proxy.local").is_err());
+    }
+
+    #[test]
+    fn test_forwarded_headers_need_a_trusted_proxy() {
+        let mut headers = HeaderMap::new();
+        // The client made up the first entry; the proxies appended the rest.
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("192.0.2.1, 203.0.113.7, 10.0.0.3"),
+        );
+        let trusted = parse_networks("10.0.0.0/8").unwrap();
+
+        assert_eq!(
+            client_ip_behind(&trusted, &headers, ip("10.0.0.2")),
+            ip("203.0.113.7")
+        );
+        // Anyone else could have made the header up.
+        assert_eq!(
+            client_ip_behind(&trusted, &headers, ip("198.51.100.1")),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            client_ip_behind(&[], &headers, ip("10.0.0.2")),
+            ip("10.0.0.2")
+        );
+        assert_eq!(
+            client_ip_behind(&trusted, &HeaderMap::new(), ip("10.0.0.2")),
+            ip("10.0.0.2")
+        );
+    }
+}
@@ -0,0 +1,24 @@
+//! The time source behind session expiry, TTLs and rate limits. Components
+//! take a [`SharedClock`] so tests can use a [`MockClock`] and move time by
+//! hand instead of sleeping.
+
+#[cfg(test)]
+pub use encryption::MockClock;
+pub use encryption::{Clock, SystemClock};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+pub type SharedClock = Arc<dyn Clock>;
+
+pub fn system() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+/// Whole seconds since the Unix epoch; 0 for a clock set before it.
+pub fn unix_secs(clock: &dyn Clock) -> i64 {
+    clock
+        .now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or(0)
+}
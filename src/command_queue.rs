@@ -0,0 +1,568 @@
+#![cfg(feature = "server")]
+
+use crate::device_link::DeviceLinkState;
+use crate::device_protocol::{DeviceCommand, DeviceError};
+use crate::tcp_client;
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::fs;
+use tokio::sync::{Mutex, MutexGuard, OnceCell};
+use uuid::Uuid;
+
+const COMMAND_QUEUE_FILE: &str = "data/command_queue.json";
+const MAX_DELIVERY_ATTEMPTS: u32 = 5;
+const FLUSH_INTERVAL_SECS: u64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueuedCommandStatus {
+    Pending,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedCommand {
+    pub id: String,
+    // Entries written before devices had ids belong to the default device.
+    #[serde(default = "default_device_id")]
+    pub device_id: String,
+    pub dedupe_key: String,
+    pub command: DeviceCommand,
+    pub status: QueuedCommandStatus,
+    pub enqueued_at: i64,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+pub enum SubmitOutcome {
+    Delivered,
+    Queued,
+}
+
+// Loaded on first use. The async lock is held across the file write so
+// snapshots reach the disk in the order they were taken.
+static COMMAND_QUEUE: OnceCell<Mutex<CommandQueue>> = OnceCell::const_new();
+
+// Serializes flushes so commands are never delivered out of order.
+static FLUSH_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+fn default_device_id() -> String {
+    tcp_client::default_device_id().unwrap_or_default()
+}
+
+/// Errors after which the command may not have reached the Pi and is worth
+/// sending again. Anything else means the Pi answered.
+fn is_delivery_failure(error: &DeviceError) -> bool {
+    matches!(
+        error,
+        DeviceError::NotConnected | DeviceError::Timeout | DeviceError::Io(_)
+    )
+}
+
+/// The queued commands of all devices, mirrored to a JSON file.
+struct CommandQueue {
+    path: PathBuf,
+    // Kept in delivery order.
+    entries: Vec<QueuedCommand>,
+}
+
+impl CommandQueue {
+    /// Reads the queue saved at `path`, or starts empty if there is none.
+    /// A file that does not parse is moved aside first, so the next save
+    /// cannot overwrite the commands in it.
+    async fn load(path: PathBuf) -> Result<Self, String> {
+        let data = match fs::read_to_string(&path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self {
+                    path,
+                    entries: Vec::new(),
+                })
+            }
+            Err(e) => return Err(format!("Failed to read {:?}: {}", path, e)),
+        };
+        let entries = match serde_json::from_str(&data) {
+            Ok(entries) => entries,
+            Err(e) => {
+                let corrupt_path = path.with_extension(format!(
+                    "json.corrupt-{}",
+                    Utc::now().format("%Y%m%dT%H%M%S")
+                ));
+                fs::rename(&path, &corrupt_path)
+                    .await
+                    .map_err(|rename_error| {
+                        format!(
+                            "Failed to parse {:?} ({}) or to move it aside: {}",
+                            path, e, rename_error
+                        )
+                    })?;
+                eprintln!(
+                    "[Queue] Failed to parse {:?}, moved it to {:?} and starting empty: {}",
+                    path, corrupt_path, e
+                );
+                Vec::new()
+            }
+        };
+        Ok(Self { path, entries })
+    }
+
+    async fn save(&self) {
+        if let Some(parent) = self.path.parent() {
+            if let Err(e) = fs::create_dir_all(parent).await {
+                eprintln!("[Queue] Failed to create directory {:?}: {}", parent, e);
+                return;
+            }
+        }
+
+        // Write to a temp file first so a crash never leaves a truncated queue.
+        let tmp_path = self.path.with_extension("json.tmp");
+        let result = match serde_json::to_string_pretty(&self.entries) {
+            Ok(json) => match fs::write(&tmp_path, json).await {
+                Ok(()) => fs::rename(&tmp_path, &self.path)
+                    .await
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            },
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = result {
+            eprintln!("[Queue] Failed to write {:?}: {}", self.path, e);
+        }
+    }
+
+    fn has_pending(&self, device_id: Option<&str>) -> bool {
+        self.entries.iter().any(|entry| {
+            entry.status == QueuedCommandStatus::Pending
+                && device_id.is_none_or(|id| entry.device_id == id)
+        })
+    }
+
+    fn pending_for_key(&self, device_id: &str, dedupe_key: &str) -> Option<usize> {
+        self.entries.iter().position(|entry| {
+            entry.device_id == device_id
+                && entry.dedupe_key == dedupe_key
+                && entry.status == QueuedCommandStatus::Pending
+        })
+    }
+
+    fn enqueue(&mut self, device_id: &str, command: DeviceCommand, dedupe_key: String) {
+        // A newer command for the same key supersedes anything still pending.
+        if let Some(pos) = self.pending_for_key(device_id, &dedupe_key) {
+            self.entries.remove(pos);
+        }
+        self.entries.push(QueuedCommand {
+            id: Uuid::new_v4().to_string(),
+            device_id: device_id.to_string(),
+            dedupe_key,
+            command,
+            status: QueuedCommandStatus::Pending,
+            enqueued_at: Utc::now().timestamp(),
+            attempts: 0,
+            last_error: None,
+        });
+    }
+
+    fn next_pending(&self, device_id: &str) -> Option<QueuedCommand> {
+        self.entries
+            .iter()
+            .find(|entry| {
+                entry.device_id == device_id && entry.status == QueuedCommandStatus::Pending
+            })
+            .cloned()
+    }
+
+    /// Records a delivery attempt: a confirmed command leaves the queue, a
+    /// failed one stays pending until it was refused or ran out of attempts.
+    fn record_attempt(&mut self, id: &str, error: Option<&DeviceError>) {
+        let Some(pos) = self.entries.iter().position(|e| e.id == id) else {
+            return;
+        };
+        let Some(error) = error else {
+            self.entries.remove(pos);
+            return;
+        };
+        let queued = &mut self.entries[pos];
+        queued.attempts += 1;
+        queued.last_error = Some(error.to_string());
+        if !is_delivery_failure(error) || queued.attempts >= MAX_DELIVERY_ATTEMPTS {
+            queued.status = QueuedCommandStatus::Failed;
+        }
+    }
+
+    fn retry(&mut self, id: &str) -> Result<String, String> {
+        let pos = self
+            .entries
+            .iter()
+            .position(|entry| entry.id == id)
+            .ok_or_else(|| "Queued command not found".to_string())?;
+        let entry = &self.entries[pos];
+        // Retrying must not undo a newer command for the same key, e.g. an
+        // old subscribe overtaking a pending unsubscribe.
+        if self
+            .pending_for_key(&entry.device_id, &entry.dedupe_key)
+            .is_some_and(|pending| pending != pos)
+        {
+            return Err("A newer command with the same key is still pending".to_string());
+        }
+        let mut entry = self.entries.remove(pos);
+        entry.status = QueuedCommandStatus::Pending;
+        entry.attempts = 0;
+        let device_id = entry.device_id.clone();
+        self.entries.push(entry);
+        Ok(device_id)
+    }
+
+    fn discard(&mut self, id: &str) -> Result<(), String> {
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.id != id);
+        if self.entries.len() == before {
+            return Err("Queued command not found".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// The loaded queue. Fails while the file cannot be read, and tries again
+/// on the next call.
+async fn queue() -> Result<MutexGuard<'static, CommandQueue>, String> {
+    let queue = COMMAND_QUEUE
+        .get_or_try_init(|| async {
+            CommandQueue::load(PathBuf::from(COMMAND_QUEUE_FILE))
+                .await
+                .map(Mutex::new)
+        })
+        .await?;
+    Ok(queue.lock().await)
+}
+
+async fn with_queue<T>(f: impl FnOnce(&mut CommandQueue) -> T) -> Result<T, String> {
+    let mut queue = queue().await?;
+    let result = f(&mut queue);
+    queue.save().await;
+    Ok(result)
+}
+
+/// Sends `command` to a device. Idempotent commands are queued on disk when
+/// the device is unreachable and delivered once its link comes back; other
+/// commands behave like `tcp_client::send_command`.
+pub async fn submit(device_id: &str, command: DeviceCommand) -> Result<SubmitOutcome, DeviceError> {
+    let Some(dedupe_key) = command.dedupe_key() else {
+        return tcp_client::send_command(device_id, &command)
+            .await
+            .map(|_| SubmitOutcome::Delivered);
+    };
+
+    // Keep ordering: never overtake commands that are still waiting.
+    if queue()
+        .await
+        .map_err(DeviceError::Io)?
+        .has_pending(Some(device_id))
+    {
+        with_queue(|queue| queue.enqueue(device_id, command, dedupe_key))
+            .await
+            .map_err(DeviceError::Io)?;
+        tokio::spawn(flush(device_id.to_string()));
+        return Ok(SubmitOutcome::Queued);
+    }
+
+    match tcp_client::send_command(device_id, &command).await {
+        Ok(_) => Ok(SubmitOutcome::Delivered),
+        Err(e) if is_delivery_failure(&e) => {
+            println!(
+                "[Queue] Queueing {:?} for {} for later delivery: {}",
+                command.to_log(),
+                device_id,
+                e
+            );
+            with_queue(|queue| queue.enqueue(device_id, command, dedupe_key))
+                .await
+                .map_err(DeviceError::Io)?;
+            Ok(SubmitOutcome::Queued)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Delivers a device's pending commands in order. Stops at the first command
+/// that could not reach the device; a command is only removed once the device
+/// confirmed it.
+pub async fn flush(device_id: String) {
+    let _guard = FLUSH_LOCK.lock().await;
+
+    loop {
+        let next = match queue().await {
+            Ok(queue) => queue.next_pending(&device_id),
+            Err(e) => {
+                eprintln!("[Queue] Cannot deliver to {}: {}", device_id, e);
+                return;
+            }
+        };
+        let Some(entry) = next else {
+            return;
+        };
+
+        let result = tcp_client::send_command(&device_id, &entry.command).await;
+        let delivery_failed = matches!(&result, Err(e) if is_delivery_failure(e));
+
+        if let Err(e) =
+            with_queue(|queue| queue.record_attempt(&entry.id, result.as_ref().err())).await
+        {
+            eprintln!("[Queue] Failed to record delivery to {}: {}", device_id, e);
+            return;
+        }
+
+        match result {
+            Ok(_) => println!(
+                "[Queue] Delivered {:?} to {}",
+                entry.command.to_log(),
+                device_id
+            ),
+            Err(e) => {
+                eprintln!(
+                    "[Queue] Delivery of {:?} to {} failed: {}",
+                    entry.command.to_log(),
+                    device_id,
+                    e
+                );
+                if delivery_failed {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Flushes each registered device's queue whenever its link comes up, and
+/// periodically while it stays up.
+pub fn spawn_flusher() {
+    for device in tcp_client::devices() {
+        let Some(mut link_rx) = tcp_client::subscribe_to_link_state(&device.id) else {
+            continue;
+        };
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(FLUSH_INTERVAL_SECS));
+
+            loop {
+                tokio::select! {
+                    changed = link_rx.changed() => {
+                        if changed.is_err() {
+                            return;
+                        }
+                    }
+                    _ = ticker.tick() => {}
+                }
+
+                let online = matches!(
+                    *link_rx.borrow_and_update(),
+                    DeviceLinkState::Connected { .. }
+                );
+                if !online {
+                    continue;
+                }
+                let pending = match queue().await {
+                    Ok(queue) => queue.has_pending(Some(&device.id)),
+                    // flush() reports why the queue cannot be loaded.
+                    Err(_) => true,
+                };
+                if pending {
+                    flush(device.id.clone()).await;
+                }
+            }
+        });
+    }
+}
+
+pub async fn list() -> Result<Vec<QueuedCommand>, String> {
+    Ok(queue().await?.entries.clone())
+}
+
+/// Moves a failed command back to pending, at the end of the queue. Returns
+/// the id of the device it is queued for. Refused while a newer command for
+/// the same key is pending.
+pub async fn retry(id: &str) -> Result<String, String> {
+    with_queue(|queue| queue.retry(id)).await?
+}
+
+pub async fn discard(id: &str) -> Result<(), String> {
+    with_queue(|queue| queue.discard(id)).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CommandQueue, QueuedCommandStatus};
+    use crate::device_protocol::{DeviceCommand, DeviceError};
+
+    fn add(email: &str) -> DeviceCommand {
+        DeviceCommand::AddNewsletter {
+            email: email.to_string(),
+        }
+    }
+
+    fn remove(email: &str) -> DeviceCommand {
+        DeviceCommand::RemoveNewsletter {
+            email: email.to_string(),
+        }
+    }
+
+    fn temp_queue() -> CommandQueue {
+        CommandQueue {
+            path: std::env::temp_dir().join(format!(
+                "birdhouse-queue-{}/command_queue.json",
+                uuid::Uuid::new_v4()
+            )),
+            entries: Vec::new(),
+        }
+    }
+
+    fn push(queue: &mut CommandQueue, device_id: &str, command: DeviceCommand) -> String {
+        let key = command.dedupe_key().unwrap();
+        queue.enqueue(device_id, command, key);
+        queue.entries.last().unwrap().id.clone()
+    }
+
+    fn commands(queue: &CommandQueue) -> Vec<DeviceCommand> {
+        queue.entries.iter().map(|e| e.command.clone()).collect()
+    }
+
+    fn rejected() -> DeviceError {
+        DeviceError::Rejected {
+            command: add("a@b.ch"),
+            reason: "full".into(),
+        }
+    }
+
+    #[test]
+    fn test_commands_are_delivered_in_order() {
+        let mut queue = temp_queue();
+        let first = push(&mut queue, "pi", add("a@b.ch"));
+        push(&mut queue, "other", add("a@b.ch"));
+        push(&mut queue, "pi", add("c@d.ch"));
+
+        assert!(queue.has_pending(Some("pi")));
+        assert!(!queue.has_pending(Some("none")));
+        assert_eq!(queue.next_pending("pi").unwrap().id, first);
+        queue.record_attempt(&first, None);
+        assert_eq!(queue.next_pending("pi").unwrap().command, add("c@d.ch"));
+        assert_eq!(queue.next_pending("other").unwrap().command, add("a@b.ch"));
+    }
+
+    #[test]
+    fn test_newer_command_supersedes_pending_one() {
+        let mut queue = temp_queue();
+        push(&mut queue, "pi", add("A@b.ch"));
+        push(&mut queue, "pi", add("c@d.ch"));
+        push(&mut queue, "pi", remove("a@b.ch"));
+        assert_eq!(commands(&queue), [add("c@d.ch"), remove("a@b.ch")]);
+
+        // Failed commands are kept for the admin, not superseded.
+        let failed = queue.entries[1].id.clone();
+        queue.record_attempt(&failed, Some(&rejected()));
+        push(&mut queue, "pi", add("a@b.ch"));
+        assert_eq!(
+            commands(&queue),
+            [add("c@d.ch"), remove("a@b.ch"), add("a@b.ch")]
+        );
+        assert_eq!(queue.entries[1].status, QueuedCommandStatus::Failed);
+    }
+
+    #[test]
+    fn test_delivery_failures_are_retried_until_the_limit() {
+        let mut queue = temp_queue();
+        let id = push(&mut queue, "pi", add("a@b.ch"));
+        for _ in 1..super::MAX_DELIVERY_ATTEMPTS {
+            queue.record_attempt(&id, Some(&DeviceError::Timeout));
+            assert_eq!(queue.entries[0].status, QueuedCommandStatus::Pending);
+        }
+        queue.record_attempt(&id, Some(&DeviceError::Timeout));
+        assert_eq!(queue.entries[0].status, QueuedCommandStatus::Failed);
+        assert_eq!(
+            queue.entries[0].last_error.as_deref(),
+            Some(DeviceError::Timeout.to_string().as_str())
+        );
+    }
+
+    #[test]
+    fn test_retry_moves_to_the_end_and_keeps_newer_commands() {
+        let mut queue = temp_queue();
+        let old = push(&mut queue, "pi", add("a@b.ch"));
+        queue.record_attempt(&old, Some(&rejected()));
+        push(&mut queue, "pi", remove("a@b.ch"));
+
+        // An old subscribe must not delete the pending unsubscribe.
+        assert!(queue.retry(&old).is_err());
+        assert_eq!(commands(&queue), [add("a@b.ch"), remove("a@b.ch")]);
+        assert_eq!(queue.entries[1].status, QueuedCommandStatus::Pending);
+
+        let newer = queue.entries[1].id.clone();
+        queue.record_attempt(&newer, None);
+        push(&mut queue, "pi", add("c@d.ch"));
+        assert_eq!(queue.retry(&old), Ok("pi".to_string()));
+        assert_eq!(commands(&queue), [add("c@d.ch"), add("a@b.ch")]);
+        assert_eq!(queue.entries[1].status, QueuedCommandStatus::Pending);
+        assert_eq!(queue.entries[1].attempts, 0);
+
+        assert!(queue.retry("missing").is_err());
+    }
+
+    #[test]
+    fn test_discard() {
+        let mut queue = temp_queue();
+        let id = push(&mut queue, "pi", add("a@b.ch"));
+        push(&mut queue, "pi", add("c@d.ch"));
+        assert_eq!(queue.discard(&id), Ok(()));
+        assert_eq!(commands(&queue), [add("c@d.ch")]);
+        assert!(queue.discard(&id).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_queue_survives_a_restart() {
+        let mut queue = temp_queue();
+        let failed = push(&mut queue, "pi", add("a@b.ch"));
+        queue.record_attempt(&failed, Some(&rejected()));
+        push(&mut queue, "pi", remove("c@d.ch"));
+        queue.save().await;
+
+        let reloaded = CommandQueue::load(queue.path.clone()).await.unwrap();
+        assert_eq!(commands(&reloaded), commands(&queue));
+        assert_eq!(reloaded.entries[0].status, QueuedCommandStatus::Failed);
+        assert_eq!(reloaded.entries[0].attempts, 1);
+        assert_eq!(reloaded.next_pending("pi").unwrap().id, queue.entries[1].id);
+        let _ = std::fs::remove_dir_all(queue.path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_queue_is_kept_aside() {
+        let queue = temp_queue();
+        let dir = queue.path.parent().unwrap().to_path_buf();
+        assert!(CommandQueue::load(queue.path.clone())
+            .await
+            .unwrap()
+            .entries
+            .is_empty());
+
+        // A file that does not parse is moved aside before starting empty.
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&queue.path, "{").unwrap();
+        let loaded = CommandQueue::load(queue.path.clone()).await.unwrap();
+        assert!(loaded.entries.is_empty());
+        assert!(!queue.path.exists());
+        let kept: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(kept.len(), 1);
+        assert!(kept[0]
+            .to_string_lossy()
+            .contains("command_queue.json.corrupt-"));
+        assert_eq!(std::fs::read_to_string(&kept[0]).unwrap(), "{");
+
+        // Any other read error is reported instead of starting empty.
+        std::fs::create_dir(&queue.path).unwrap();
+        assert!(CommandQueue::load(queue.path.clone()).await.is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
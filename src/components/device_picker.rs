@@ -0,0 +1,50 @@
+use crate::device_link::DeviceSummary;
+use dioxus::prelude::*;
+
+#[server]
+pub async fn list_devices() -> Result<Vec<DeviceSummary>, ServerFnError> {
+    Ok(crate::tcp_client::devices())
+}
+
+/// Drop-down for choosing which device a page talks to. Renders nothing while
+/// only a single device is configured. `selected: None` means the default
+/// (first) device.
+#[component]
+pub fn DevicePicker(selected: Option<String>, onselect: EventHandler<String>) -> Element {
+    let devices = use_resource(|| async move { list_devices().await.unwrap_or_default() });
+
+    let devices = devices.read();
+    let Some(devices) = devices.as_ref().filter(|devices| devices.len() > 1) else {
+        return rsx! {};
+    };
+    let selected = selected
+        .filter(|id| devices.iter().any(|device| &device.id == id))
+        .unwrap_or_else(|| devices[0].id.clone());
+
+    rsx! {
+        div {
+            class: "flex items-center gap-2",
+            label {
+                class: "text-white font-small whitespace-nowrap",
+                "Device"
+            }
+            select {
+                class: "rounded-md bg-slate-700 px-2 py-1 text-sm text-white",
+                value: "{selected}",
+                onchange: move |evt| onselect.call(evt.value()),
+                for device in devices.iter() {
+                    option {
+                        key: "{device.id}",
+                        value: "{device.id}",
+                        selected: device.id == selected,
+                        if device.link.is_online() {
+                            "{device.name}"
+                        } else {
+                            "{device.name} (offline)"
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
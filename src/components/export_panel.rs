@@ -0,0 +1,112 @@
+use crate::components::get_history_fields;
+use dioxus::prelude::*;
+use std::collections::HashSet;
+
+const WINDOWS: [(&str, &str); 4] = [
+    ("24h", "Last 24 hours"),
+    ("7d", "Last 7 days"),
+    ("30d", "Last 30 days"),
+    ("365d", "Last year"),
+];
+const FORMATS: [(&str, &str); 3] = [
+    ("csv", "CSV"),
+    ("ndjson", "NDJSON"),
+    ("parquet", "Parquet"),
+];
+
+/// `/api/export` link for the chosen fields, window and format.
+fn export_url(fields: &[String], window: &str, format: &str) -> String {
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("fields", &fields.join(","))
+        .append_pair("window", window)
+        .append_pair("format", format)
+        .finish();
+    format!("/api/export?{}", query)
+}
+
+/// Download form for the raw history of the public fields. Every field is
+/// ticked until the visitor unticks it.
+#[component]
+pub fn ExportPanel() -> Element {
+    let fields = use_resource(|| async move { get_history_fields().await.unwrap_or_default() });
+    let mut unticked = use_signal(HashSet::<String>::new);
+    let mut window = use_signal(|| WINDOWS[1].0.to_string());
+    let mut format = use_signal(|| FORMATS[0].0.to_string());
+
+    let fields = fields.read();
+    let Some(fields) = fields.as_ref().filter(|fields| !fields.is_empty()) else {
+        return rsx! {};
+    };
+    let chosen: Vec<String> = fields
+        .iter()
+        .map(|f| f.field.clone())
+        .filter(|f| !unticked().contains(f))
+        .collect();
+
+    rsx! {
+        div {
+            class: "w-full rounded-lg border-2 border-slate-700 bg-slate-800 p-4 flex flex-col gap-4 text-white",
+            style: "width: var(--content-width);",
+            p {
+                class: "text-sm text-slate-300",
+                "The raw sensor readings behind the charts, one row per reading. At most 10 downloads per hour."
+            }
+            div {
+                class: "flex flex-wrap gap-4",
+                for field in fields.iter() {
+                    label {
+                        key: "{field.field}",
+                        class: "flex items-center gap-2 text-sm",
+                        input {
+                            r#type: "checkbox",
+                            class: "h-4 w-4 accent-sky-400",
+                            checked: !unticked().contains(&field.field),
+                            onchange: {
+                                let name = field.field.clone();
+                                move |evt: FormEvent| {
+                                    let mut next = unticked();
+                                    if evt.checked() {
+                                        next.remove(&name);
+                                    } else {
+                                        next.insert(name.clone());
+                                    }
+                                    unticked.set(next);
+                                }
+                            }
+                        }
+                        "{field.label}"
+                    }
+                }
+            }
+            div {
+                class: "flex flex-wrap items-center gap-4",
+                select {
+                    class: "rounded-md bg-slate-700 px-2 py-1 text-sm text-white",
+                    value: "{window}",
+                    onchange: move |evt| window.set(evt.value()),
+                    for (value, label) in WINDOWS {
+                        option { key: "{value}", value: "{value}", selected: window() == value, "{label}" }
+                    }
+                }
+                select {
+                    class: "rounded-md bg-slate-700 px-2 py-1 text-sm text-white",
+                    value: "{format}",
+                    onchange: move |evt| format.set(evt.value()),
+                    for (value, label) in FORMATS {
+                        option { key: "{value}", value: "{value}", selected: format() == value, "{label}" }
+                    }
+                }
+                if chosen.is_empty() {
+                    span { class: "text-sm text-slate-400", "Pick at least one field" }
+                } else {
+                    a {
+                        class: "rounded-md bg-sky-600 px-3 py-1 text-sm font-semibold hover:bg-sky-500",
+                        href: export_url(&chosen, &window(), &format()),
+                        download: "",
+                        "Download"
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use crate::time_series::{Aggregate, AggregatePoint, HistoryField};
+use dioxus::prelude::*;
+
+const CHART_WIDTH: f64 = 300.0;
+const CHART_HEIGHT: f64 = 100.0;
+
+#[server]
+pub async fn get_history_fields() -> Result<Vec<HistoryField>, ServerFnError> {
+    Ok(crate::time_series::history_fields())
+}
+
+/// A public history field over the last `window`, combined per `bucket_size`.
+#[server]
+pub async fn get_history_aggregate(
+    field: String,
+    window: String,
+    bucket_size: String,
+    agg: Aggregate,
+) -> Result<Vec<AggregatePoint>, ServerFnError> {
+    crate::time_series::check_history_field(&field).map_err(ServerFnError::new)?;
+    crate::postgres_store::shared()
+        .map_err(ServerFnError::new)?
+        .query_aggregate(&field, &window, &bucket_size, agg)
+        .await
+        .map_err(ServerFnError::new)
+}
+
+/// SVG `points` for a line through `points`, scaled to fill the chart.
+fn polyline_points(points: &[AggregatePoint]) -> String {
+    let (Some(first), Some(last)) = (points.first(), points.last()) else {
+        return String::new();
+    };
+    let span = (last.bucket_start - first.bucket_start).max(1) as f64;
+    let (min, max) = points.iter().fold((f64::MAX, f64::MIN), |(min, max), p| {
+        (min.min(p.value), max.max(p.value))
+    });
+    let range = if max > min { max - min } else { 1.0 };
+
+    points
+        .iter()
+        .map(|p| {
+            let x = (p.bucket_start - first.bucket_start) as f64 / span * CHART_WIDTH;
+            let y = CHART_HEIGHT - (p.value - min) / range * CHART_HEIGHT;
+            format!("{:.1},{:.1}", x, y)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The last 24 hours of one field as a line chart, refreshed every minute.
+#[component]
+pub fn HistoryChart(field: HistoryField) -> Element {
+    let mut refresh = use_signal(|| 0u64);
+    let name = field.field.clone();
+    let history = use_resource(move || {
+        let _ = refresh();
+        let name = name.clone();
+        async move {
+            get_history_aggregate(name, "24h".to_string(), "10m".to_string(), Aggregate::Mean).await
+        }
+    });
+
+    use_effect(move || {
+        let _handle = spawn(async move {
+            loop {
+                #[cfg(target_arch = "wasm32")]
+                gloo_timers::future::sleep(std::time::Duration::from_secs(60)).await;
+                refresh += 1;
+            }
+        });
+    });
+
+    let history = history.read();
+    let points = history.as_ref().and_then(|h| h.as_ref().ok());
+    let latest = points
+        .and_then(|points| points.last())
+        .map(|p| format!("{:.1} {}", p.value, field.unit))
+        .unwrap_or_else(|| "–".to_string());
+
+    rsx! {
+        div {
+            class: "w-full h-64 rounded-lg border-2 border-slate-700 bg-slate-800 p-4 flex flex-col gap-2",
+            div {
+                class: "flex items-baseline justify-between text-white",
+                span { class: "font-semibold", "{field.label}" }
+                span { class: "text-lg", "{latest}" }
+            }
+            match history.as_ref() {
+                Some(Ok(points)) if !points.is_empty() => rsx! {
+                    svg {
+                        class: "w-full flex-1",
+                        view_box: "0 0 {CHART_WIDTH} {CHART_HEIGHT}",
+                        preserve_aspect_ratio: "none",
+                        polyline {
+                            points: polyline_points(points),
+                            fill: "none",
+                            stroke: "#38bdf8",
+                            stroke_width: "2",
+                            vector_effect: "non-scaling-stroke",
+                        }
+                    }
+                },
+                Some(Ok(_)) => rsx! {
+                    p { class: "flex-1 flex items-center justify-center text-sm text-slate-400", "No data in the last 24 hours" }
+                },
+                Some(Err(_)) => rsx! {
+                    p { class: "flex-1 flex items-center justify-center text-sm text-amber-300", "History is unavailable right now" }
+                },
+                None => rsx! {
+                    p { class: "flex-1 flex items-center justify-center text-sm text-slate-400", "Loading..." }
+                },
+            }
+            p { class: "text-xs text-slate-400", "Last 24 hours, 10 minute averages" }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{polyline_points, CHART_HEIGHT};
+    use crate::time_series::AggregatePoint;
+
+    #[test]
+    fn test_polyline_points_fill_the_chart() {
+        let points = [
+            AggregatePoint {
+                bucket_start: 0,
+                value: 10.0,
+            },
+            AggregatePoint {
+                bucket_start: 600,
+                value: 20.0,
+            },
+            AggregatePoint {
+                bucket_start: 1200,
+                value: 15.0,
+            },
+        ];
+        assert_eq!(polyline_points(&points), "0.0,100.0 150.0,0.0 300.0,50.0");
+
+        let flat = [AggregatePoint {
+            bucket_start: 0,
+            value: 3.0,
+        }];
+        assert_eq!(polyline_points(&flat), format!("0.0,{:.1}", CHART_HEIGHT));
+        assert_eq!(polyline_points(&[]), "");
+    }
+}
@@ -1,3 +1,10 @@
 //! The components module contains all shared components for our app. Components are the building blocks of dioxus apps.
 //! They can be used to defined common UI elements like buttons, forms, and modals. In this template, we define a Hero
 //! component and an Echo component for fullstack apps to be used in our app.
+
+mod device_picker;
+pub use device_picker::DevicePicker;
+mod export_panel;
+pub use export_panel::ExportPanel;
+mod history_chart;
+pub use history_chart::{get_history_fields, HistoryChart};
@@ -0,0 +1,56 @@
+use serde::{Deserialize, Serialize};
+
+/// Health of the TCP link between the web server and the birdhouse Pi.
+/// Timestamps are unix seconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum DeviceLinkState {
+    Disconnected,
+    Connecting,
+    Authenticating,
+    Connected { since: i64 },
+    Backoff { attempt: u32, next_retry: i64 },
+}
+
+/// A registered device as shown in the device picker.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceSummary {
+    pub id: String,
+    pub name: String,
+    pub link: DeviceLinkState,
+}
+
+impl DeviceLinkState {
+    pub fn is_online(&self) -> bool {
+        matches!(self, Self::Connected { .. })
+    }
+
+    pub fn describe(&self, now: i64) -> String {
+        match self {
+            Self::Disconnected => "disconnected".to_string(),
+            Self::Connecting => "connecting...".to_string(),
+            Self::Authenticating => "authenticating...".to_string(),
+            Self::Connected { since } => {
+                format!("connected for {}", format_secs(now.saturating_sub(*since)))
+            }
+            Self::Backoff {
+                attempt,
+                next_retry,
+            } => format!(
+                "offline, retry #{} in {}",
+                attempt,
+                format_secs(next_retry.saturating_sub(now).max(0))
+            ),
+        }
+    }
+}
+
+fn format_secs(secs: i64) -> String {
+    if secs < 60 {
+        format!("{}s", secs)
+    } else if secs < 3600 {
+        format!("{}m {}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h {}m", secs / 3600, (secs % 3600) / 60)
+    }
+}
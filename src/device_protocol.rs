@@ -0,0 +1,430 @@
+#![cfg(feature = "server")]
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Commands understood by the birdhouse-python daemon on the Raspberry Pi.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum DeviceCommand {
+    IrOn,
+    IrOff,
+    GetIrState,
+    SaveImage,
+    AddNewsletter {
+        email: String,
+    },
+    RemoveNewsletter {
+        email: String,
+    },
+    /// Announces a new active key. `key` is base64; the Pi keeps accepting
+    /// its previous keys and switches to this one after acknowledging.
+    RotateKey {
+        key_id: u32,
+        key: String,
+    },
+}
+
+/// Replies and state messages sent back by the Raspberry Pi.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceResponse {
+    /// `message_ids` is set when the Pi answers `authentication successful;
+    /// message ids`, i.e. it echoes the id of a command on its reply.
+    AuthenticationSuccessful {
+        message_ids: bool,
+    },
+    IrState(bool),
+    IrFilterState(bool),
+    KeyRotated(u32),
+    Error(String),
+    /// The answer to a command the firmware has no fixed reply for: whatever
+    /// it sent, or `None` if it stayed silent.
+    Acknowledged(Option<String>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceError {
+    /// No device with this id is registered.
+    UnknownDevice(String),
+    NotConnected,
+    Timeout,
+    Io(String),
+    /// The device sent something that does not match any known response.
+    UnknownResponse {
+        raw: String,
+    },
+    /// The device sent a known response, but not one that answers the command.
+    UnexpectedResponse {
+        command: DeviceCommand,
+        response: DeviceResponse,
+    },
+    /// The device explicitly reported a failure.
+    Rejected {
+        command: DeviceCommand,
+        reason: String,
+    },
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownDevice(id) => write!(f, "Unknown device '{}'", id),
+            Self::NotConnected => write!(f, "Not connected"),
+            Self::Timeout => write!(f, "Timed out waiting for the device"),
+            Self::Io(e) => write!(f, "{}", e),
+            Self::UnknownResponse { raw } => write!(f, "Unknown response from device: {:?}", raw),
+            Self::UnexpectedResponse { command, response } => write!(
+                f,
+                "Unexpected response to {:?}: {:?}",
+                command.to_log(),
+                response
+            ),
+            Self::Rejected { command, reason } => {
+                write!(f, "Device rejected {:?}: {}", command.to_log(), reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DeviceError {}
+
+impl DeviceCommand {
+    /// Serializes the command into the plaintext form expected by the Pi.
+    pub fn to_wire(&self) -> String {
+        match self {
+            Self::IrOn => "[CMD] IR ON".to_string(),
+            Self::IrOff => "[CMD] IR OFF".to_string(),
+            Self::GetIrState => "[CMD] GET IR STATE".to_string(),
+            Self::SaveImage => "[CMD] save image".to_string(),
+            Self::AddNewsletter { email } => format!("[CMD] add newsletter={}", email),
+            Self::RemoveNewsletter { email } => format!("[CMD] remove newsletter={}", email),
+            Self::RotateKey { key_id, key } => format!("[CMD] rotate key={}:{}", key_id, key),
+        }
+    }
+
+    /// Like `to_wire`, but without secrets, for logs and error messages.
+    pub fn to_log(&self) -> String {
+        match self {
+            Self::RotateKey { key_id, .. } => format!("[CMD] rotate key={}:<redacted>", key_id),
+            other => other.to_wire(),
+        }
+    }
+
+    pub fn ir(enabled: bool) -> Self {
+        if enabled {
+            Self::IrOn
+        } else {
+            Self::IrOff
+        }
+    }
+
+    /// Key used to collapse queued commands that target the same state on the
+    /// Pi. Only idempotent commands have one and may be queued while offline.
+    pub fn dedupe_key(&self) -> Option<String> {
+        match self {
+            Self::AddNewsletter { email } | Self::RemoveNewsletter { email } => {
+                Some(format!("newsletter:{}", email.to_lowercase()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the Pi answers this command in a fixed wording. The current
+    /// firmware confirms saving an image and newsletter changes in no
+    /// documented way, if at all, so for those any reply but `ERROR: ..`,
+    /// and silence, count as success.
+    pub fn has_fixed_reply(&self) -> bool {
+        match self {
+            Self::IrOn | Self::IrOff | Self::GetIrState | Self::RotateKey { .. } => true,
+            Self::SaveImage | Self::AddNewsletter { .. } | Self::RemoveNewsletter { .. } => false,
+        }
+    }
+
+    /// Whether `response` is a valid answer to this command.
+    pub fn accepts(&self, response: &DeviceResponse) -> bool {
+        match (self, response) {
+            (Self::IrOn, DeviceResponse::IrState(state)) => *state,
+            (Self::IrOff, DeviceResponse::IrState(state)) => !*state,
+            (Self::GetIrState, DeviceResponse::IrState(_)) => true,
+            (Self::RotateKey { key_id, .. }, DeviceResponse::KeyRotated(id)) => key_id == id,
+            (_, DeviceResponse::Acknowledged(_)) => !self.has_fixed_reply(),
+            _ => false,
+        }
+    }
+
+    /// The outcome of this command given the Pi's reply, or `None` if none
+    /// arrived in time.
+    pub fn interpret(&self, reply: Option<&str>) -> Result<DeviceResponse, DeviceError> {
+        if self.has_fixed_reply() {
+            let reply = reply.ok_or(DeviceError::Timeout)?;
+            return DeviceResponse::parse(reply).and_then(|response| self.expect(response));
+        }
+        match reply.map(DeviceResponse::parse) {
+            Some(Ok(DeviceResponse::Error(reason))) => Err(DeviceError::Rejected {
+                command: self.clone(),
+                reason,
+            }),
+            _ => Ok(DeviceResponse::Acknowledged(
+                reply.map(|r| r.trim().to_string()),
+            )),
+        }
+    }
+
+    /// Checks a parsed reply against this command.
+    pub fn expect(&self, response: DeviceResponse) -> Result<DeviceResponse, DeviceError> {
+        if let DeviceResponse::Error(reason) = response {
+            return Err(DeviceError::Rejected {
+                command: self.clone(),
+                reason,
+            });
+        }
+        if self.accepts(&response) {
+            Ok(response)
+        } else {
+            Err(DeviceError::UnexpectedResponse {
+                command: self.clone(),
+                response,
+            })
+        }
+    }
+}
+
+/// Prefixes an outbound command with its message id, e.g. `#12 [CMD] IR ON`.
+/// Only sent to a Pi that offered message ids when authenticating; it echoes
+/// the id on the matching reply.
+pub fn tag_message(id: u64, payload: &str) -> String {
+    format!("#{} {}", id, payload)
+}
+
+/// Splits an inbound message into its message id (if it is a reply) and the
+/// remaining payload. Messages without an id are unsolicited device events.
+pub fn split_message_id(raw: &str) -> (Option<u64>, &str) {
+    let trimmed = raw.trim_start();
+    let Some(rest) = trimmed.strip_prefix('#') else {
+        return (None, raw);
+    };
+    let Some((id, payload)) = rest.split_once(' ') else {
+        return (None, raw);
+    };
+    match id.parse::<u64>() {
+        Ok(id) => (Some(id), payload),
+        Err(_) => (None, raw),
+    }
+}
+
+impl DeviceResponse {
+    /// Parses a single message from the Pi. Matching is case-insensitive and
+    /// ignores surrounding whitespace, but otherwise strict.
+    pub fn parse(raw: &str) -> Result<Self, DeviceError> {
+        let line = raw.trim();
+        let upper = line.to_ascii_uppercase();
+        let unknown = || DeviceError::UnknownResponse {
+            raw: raw.to_string(),
+        };
+
+        if let Some(rest) = upper.strip_prefix("AUTHENTICATION SUCCESSFUL") {
+            return match rest.trim_start() {
+                "" => Ok(Self::AuthenticationSuccessful { message_ids: false }),
+                "; MESSAGE IDS" => Ok(Self::AuthenticationSuccessful { message_ids: true }),
+                _ => Err(unknown()),
+            };
+        }
+        if let Some(state) = strip_state(&upper, &["IR STATE IS ", "IR LED STATE: "]) {
+            return state.map(Self::IrState).ok_or_else(unknown);
+        }
+        if let Some(state) = strip_state(&upper, &["IR FILTER STATE IS ", "IR FILTER STATE: "]) {
+            return state.map(Self::IrFilterState).ok_or_else(unknown);
+        }
+        if let Some(id) = strip_value(line, "KEY ROTATED=") {
+            return id.parse().map(Self::KeyRotated).map_err(|_| unknown());
+        }
+        if let Some(reason) = strip_value(line, "ERROR:") {
+            return Ok(Self::Error(reason));
+        }
+
+        Err(unknown())
+    }
+}
+
+fn strip_state(upper: &str, prefixes: &[&str]) -> Option<Option<bool>> {
+    prefixes.iter().find_map(|prefix| {
+        upper.strip_prefix(prefix).map(|rest| match rest.trim() {
+            "ON" => Some(true),
+            "OFF" => Some(false),
+            _ => None,
+        })
+    })
+}
+
+fn strip_value(line: &str, prefix: &str) -> Option<String> {
+    let head = line.get(..prefix.len())?;
+    if !head.eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+    let value = line[prefix.len()..].trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_wire_format() {
+        assert_eq!(DeviceCommand::IrOn.to_wire(), "[CMD] IR ON");
+        assert_eq!(DeviceCommand::IrOff.to_wire(), "[CMD] IR OFF");
+        assert_eq!(DeviceCommand::GetIrState.to_wire(), "[CMD] GET IR STATE");
+        assert_eq!(DeviceCommand::SaveImage.to_wire(), "[CMD] save image");
+        assert_eq!(
+            DeviceCommand::AddNewsletter {
+                email: "a@b.ch".into()
+            }
+            .to_wire(),
+            "[CMD] add newsletter=a@b.ch"
+        );
+        assert_eq!(
+            DeviceCommand::RemoveNewsletter {
+                email: "a@b.ch".into()
+            }
+            .to_wire(),
+            "[CMD] remove newsletter=a@b.ch"
+        );
+
+        let rotate = DeviceCommand::RotateKey {
+            key_id: 3,
+            key: "c2VjcmV0".into(),
+        };
+        assert_eq!(rotate.to_wire(), "[CMD] rotate key=3:c2VjcmV0");
+        assert_eq!(rotate.to_log(), "[CMD] rotate key=3:<redacted>");
+        assert!(!DeviceError::Rejected {
+            command: rotate,
+            reason: "no".into()
+        }
+        .to_string()
+        .contains("c2VjcmV0"));
+    }
+
+    #[test]
+    fn test_parse_known_responses() {
+        assert_eq!(
+            DeviceResponse::parse("authentication successful\n"),
+            Ok(DeviceResponse::AuthenticationSuccessful { message_ids: false })
+        );
+        assert_eq!(
+            DeviceResponse::parse("Authentication successful; message ids"),
+            Ok(DeviceResponse::AuthenticationSuccessful { message_ids: true })
+        );
+        assert_eq!(
+            DeviceResponse::parse("IR STATE IS ON"),
+            Ok(DeviceResponse::IrState(true))
+        );
+        assert_eq!(
+            DeviceResponse::parse("IR LED state: off\r\n"),
+            Ok(DeviceResponse::IrState(false))
+        );
+        assert_eq!(
+            DeviceResponse::parse("IR FILTER STATE: ON"),
+            Ok(DeviceResponse::IrFilterState(true))
+        );
+        assert_eq!(
+            DeviceResponse::parse("ERROR: camera busy"),
+            Ok(DeviceResponse::Error("camera busy".into()))
+        );
+        assert_eq!(
+            DeviceResponse::parse("KEY ROTATED=3"),
+            Ok(DeviceResponse::KeyRotated(3))
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_unknown_wording() {
+        for raw in [
+            "",
+            "IR STATE IS MAYBE",
+            "IR IS ON",
+            "the IR STATE IS ON",
+            "image saved",
+            "authentication successful!",
+            "authentication successful; ids",
+            "key rotated=next",
+        ] {
+            assert_eq!(
+                DeviceResponse::parse(raw),
+                Err(DeviceError::UnknownResponse { raw: raw.into() })
+            );
+        }
+    }
+
+    #[test]
+    fn test_message_ids() {
+        assert_eq!(tag_message(7, "[CMD] IR ON"), "#7 [CMD] IR ON");
+        assert_eq!(
+            split_message_id("#7 IR STATE IS ON\n"),
+            (Some(7), "IR STATE IS ON\n")
+        );
+        assert_eq!(
+            split_message_id("IR LED STATE: ON"),
+            (None, "IR LED STATE: ON")
+        );
+        assert_eq!(
+            split_message_id("#x IR STATE IS ON"),
+            (None, "#x IR STATE IS ON")
+        );
+        assert_eq!(split_message_id("#12"), (None, "#12"));
+    }
+
+    #[test]
+    fn test_expect_matches_command() {
+        assert!(DeviceCommand::IrOn
+            .expect(DeviceResponse::IrState(true))
+            .is_ok());
+        assert_eq!(
+            DeviceCommand::IrOn.expect(DeviceResponse::IrState(false)),
+            Err(DeviceError::UnexpectedResponse {
+                command: DeviceCommand::IrOn,
+                response: DeviceResponse::IrState(false),
+            })
+        );
+        assert_eq!(
+            DeviceCommand::SaveImage.expect(DeviceResponse::Error("disk full".into())),
+            Err(DeviceError::Rejected {
+                command: DeviceCommand::SaveImage,
+                reason: "disk full".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_interpret_replies() {
+        let add = DeviceCommand::AddNewsletter {
+            email: "bird@example.ch".into(),
+        };
+        // Without a fixed reply anything but an error, or silence, is success.
+        for reply in [None, Some("ok\n"), Some("subscribed bird@example.ch")] {
+            assert_eq!(
+                add.interpret(reply),
+                Ok(DeviceResponse::Acknowledged(reply.map(|r| r.trim().into())))
+            );
+        }
+        assert_eq!(
+            DeviceCommand::SaveImage.interpret(Some("ERROR: camera busy")),
+            Err(DeviceError::Rejected {
+                command: DeviceCommand::SaveImage,
+                reason: "camera busy".into(),
+            })
+        );
+
+        assert_eq!(
+            DeviceCommand::IrOn.interpret(Some("IR STATE IS ON")),
+            Ok(DeviceResponse::IrState(true))
+        );
+        assert_eq!(
+            DeviceCommand::IrOn.interpret(None),
+            Err(DeviceError::Timeout)
+        );
+        assert!(matches!(
+            DeviceCommand::GetIrState.interpret(Some("ok")),
+            Err(DeviceError::UnknownResponse { .. })
+        ));
+    }
+}
@@ -0,0 +1,425 @@
+#![cfg(feature = "server")]
+
+//! Exports of raw sensor history as CSV, NDJSON or Parquet. Rows are read a
+//! page at a time and encoded as they arrive, so memory use does not grow
+//! with the size of an export.
+
+use crate::clock::{self, SharedClock};
+use crate::postgres_store::{Point, TimeSeriesValue};
+use chrono::{DateTime, SecondsFormat, Utc};
+use dashmap::DashMap;
+use parquet::basic::Compression;
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, DataType, DoubleType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedColumnWriter, SerializedFileWriter};
+use parquet::schema::parser::parse_message_type;
+use std::borrow::Cow;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Rows per database page, and per Parquet row group.
+pub const EXPORT_PAGE_ROWS: i64 = 10_000;
+/// Exports one client may start per hour.
+pub const EXPORTS_PER_HOUR: usize = 10;
+
+const CSV_HEADER: &str = "ts,measurement,field,value,unit,location,type\n";
+const PARQUET_SCHEMA: &str = "message export {
+    REQUIRED INT64 ts (TIMESTAMP(MICROS,true));
+    REQUIRED BYTE_ARRAY measurement (UTF8);
+    REQUIRED BYTE_ARRAY field (UTF8);
+    OPTIONAL DOUBLE value_double;
+    OPTIONAL BOOLEAN value_bool;
+    OPTIONAL BYTE_ARRAY value_text (UTF8);
+    OPTIONAL BYTE_ARRAY unit (UTF8);
+    OPTIONAL BYTE_ARRAY location (UTF8);
+    OPTIONAL BYTE_ARRAY type (UTF8);
+}";
+
+/// Which points to export.
+#[derive(Debug, Clone)]
+pub struct ExportQuery {
+    /// `None` exports the fields of every measurement.
+    pub measurement: Option<String>,
+    pub fields: Vec<String>,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+    Parquet,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "csv" => Ok(Self::Csv),
+            "ndjson" | "jsonl" => Ok(Self::Ndjson),
+            "parquet" => Ok(Self::Parquet),
+            other => Err(format!(
+                "Unknown export format '{}' (expected csv, ndjson or parquet).",
+                other
+            )),
+        }
+    }
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Ndjson => "application/x-ndjson",
+            Self::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Ndjson => "ndjson",
+            Self::Parquet => "parquet",
+        }
+    }
+}
+
+fn format_ts(ts: DateTime<Utc>) -> String {
+    ts.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+/// Quotes a CSV cell when it contains a separator, quote or line break.
+fn csv_cell(text: &str) -> Cow<'_, str> {
+    if text.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", text.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+fn csv_line(point: &Point) -> String {
+    let value = match &point.value {
+        TimeSeriesValue::Double(v) => v.to_string(),
+        TimeSeriesValue::Bool(v) => v.to_string(),
+        TimeSeriesValue::Text(v) => v.clone(),
+    };
+    let optional = |v: &Option<String>| csv_cell(v.as_deref().unwrap_or_default()).into_owned();
+    format!(
+        "{},{},{},{},{},{},{}\n",
+        format_ts(point.ts),
+        csv_cell(&point.measurement),
+        csv_cell(&point.field),
+        csv_cell(&value),
+        optional(&point.unit),
+        optional(&point.location),
+        optional(&point.value_type),
+    )
+}
+
+fn ndjson_line(point: &Point) -> String {
+    let value = match &point.value {
+        TimeSeriesValue::Double(v) => serde_json::json!(v),
+        TimeSeriesValue::Bool(v) => serde_json::json!(v),
+        TimeSeriesValue::Text(v) => serde_json::json!(v),
+    };
+    let line = serde_json::json!({
+        "ts": format_ts(point.ts),
+        "measurement": point.measurement,
+        "field": point.field,
+        "value": value,
+        "unit": point.unit,
+        "location": point.location,
+        "type": point.value_type,
+    });
+    format!("{}\n", line)
+}
+
+fn write_required<T: DataType>(
+    column: &mut SerializedColumnWriter<'_>,
+    values: Vec<T::T>,
+) -> parquet::errors::Result<()> {
+    column.typed::<T>().write_batch(&values, None, None)?;
+    Ok(())
+}
+
+fn write_optional<T: DataType>(
+    column: &mut SerializedColumnWriter<'_>,
+    values: impl Iterator<Item = Option<T::T>>,
+) -> parquet::errors::Result<()> {
+    let mut present = Vec::new();
+    let mut levels = Vec::new();
+    for value in values {
+        levels.push(i16::from(value.is_some()));
+        present.extend(value);
+    }
+    column
+        .typed::<T>()
+        .write_batch(&present, Some(&levels), None)?;
+    Ok(())
+}
+
+fn text(value: &Option<String>) -> Option<ByteArray> {
+    value.as_deref().map(ByteArray::from)
+}
+
+fn write_row_group(
+    writer: &mut SerializedFileWriter<Vec<u8>>,
+    points: &[Point],
+) -> parquet::errors::Result<()> {
+    let mut group = writer.next_row_group()?;
+    let mut index = 0;
+    while let Some(mut column) = group.next_column()? {
+        match index {
+            0 => write_required::<Int64Type>(
+                &mut column,
+                points.iter().map(|p| p.ts.timestamp_micros()).collect(),
+            )?,
+            1 => write_required::<ByteArrayType>(
+                &mut column,
+                points
+                    .iter()
+                    .map(|p| ByteArray::from(p.measurement.as_str()))
+                    .collect(),
+            )?,
+            2 => write_required::<ByteArrayType>(
+                &mut column,
+                points
+                    .iter()
+                    .map(|p| ByteArray::from(p.field.as_str()))
+                    .collect(),
+            )?,
+            3 => write_optional::<DoubleType>(
+                &mut column,
+                points.iter().map(|p| match p.value {
+                    TimeSeriesValue::Double(v) => Some(v),
+                    _ => None,
+                }),
+            )?,
+            4 => write_optional::<BoolType>(
+                &mut column,
+                points.iter().map(|p| match p.value {
+                    TimeSeriesValue::Bool(v) => Some(v),
+                    _ => None,
+                }),
+            )?,
+            5 => write_optional::<ByteArrayType>(
+                &mut column,
+                points.iter().map(|p| match &p.value {
+                    TimeSeriesValue::Text(v) => Some(ByteArray::from(v.as_str())),
+                    _ => None,
+                }),
+            )?,
+            6 => {
+                write_optional::<ByteArrayType>(&mut column, points.iter().map(|p| text(&p.unit)))?
+            }
+            7 => write_optional::<ByteArrayType>(
+                &mut column,
+                points.iter().map(|p| text(&p.location)),
+            )?,
+            _ => write_optional::<ByteArrayType>(
+                &mut column,
+                points.iter().map(|p| text(&p.value_type)),
+            )?,
+        }
+        column.close()?;
+        index += 1;
+    }
+    group.close()?;
+    Ok(())
+}
+
+/// Turns pages of points into the bytes of one export.
+pub enum ExportEncoder {
+    Csv { header_pending: bool },
+    Ndjson,
+    Parquet(Box<SerializedFileWriter<Vec<u8>>>),
+}
+
+impl ExportEncoder {
+    pub fn new(format: ExportFormat) -> Result<Self, String> {
+        Ok(match format {
+            ExportFormat::Csv => Self::Csv {
+                header_pending: true,
+            },
+            ExportFormat::Ndjson => Self::Ndjson,
+            ExportFormat::Parquet => {
+                let schema = parse_message_type(PARQUET_SCHEMA)
+                    .map_err(|e| format!("Invalid Parquet schema: {e}"))?;
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                let writer =
+                    SerializedFileWriter::new(Vec::new(), Arc::new(schema), Arc::new(properties))
+                        .map_err(|e| format!("Failed to start Parquet file: {e}"))?;
+                Self::Parquet(Box::new(writer))
+            }
+        })
+    }
+
+    /// The bytes for one page; Parquet writes each page as a row group.
+    pub fn page(&mut self, points: &[Point]) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Csv { header_pending } => {
+                let mut out = String::new();
+                if std::mem::take(header_pending) {
+                    out.push_str(CSV_HEADER);
+                }
+                out.extend(points.iter().map(csv_line));
+                Ok(out.into_bytes())
+            }
+            Self::Ndjson => Ok(points
+                .iter()
+                .map(ndjson_line)
+                .collect::<String>()
+                .into_bytes()),
+            Self::Parquet(writer) => {
+                if !points.is_empty() {
+                    write_row_group(writer, points)
+                        .map_err(|e| format!("Failed to write Parquet row group: {e}"))?;
+                }
+                Ok(std::mem::take(writer.inner_mut()))
+            }
+        }
+    }
+
+    /// The bytes that end the export, such as the Parquet footer.
+    pub fn finish(self) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Csv { header_pending } => Ok(if header_pending {
+                CSV_HEADER.as_bytes().to_vec()
+            } else {
+                Vec::new()
+            }),
+            Self::Ndjson => Ok(Vec::new()),
+            Self::Parquet(mut writer) => {
+                writer
+                    .finish()
+                    .map_err(|e| format!("Failed to finish Parquet file: {e}"))?;
+                Ok(std::mem::take(writer.inner_mut()))
+            }
+        }
+    }
+}
+
+/// Exports started per client over the last hour.
+pub struct ExportLimiter {
+    starts: DashMap<String, Vec<i64>>,
+    limit_per_hour: usize,
+    clock: SharedClock,
+}
+
+impl ExportLimiter {
+    pub fn new(clock: SharedClock, limit_per_hour: usize) -> Self {
+        Self {
+            starts: DashMap::new(),
+            limit_per_hour,
+            clock,
+        }
+    }
+
+    /// Counts an export for `client` unless it reached the limit; then
+    /// returns the seconds until it may start another.
+    pub fn try_start(&self, client: &str) -> Result<(), i64> {
+        let now = clock::unix_secs(self.clock.as_ref());
+        let mut starts = self.starts.entry(client.to_string()).or_default();
+        starts.retain(|started| now - *started < 3600);
+        if starts.len() >= self.limit_per_hour {
+            let oldest = starts.iter().min().copied().unwrap_or(now);
+            return Err((oldest + 3600 - now).max(1));
+        }
+        starts.push(now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ExportEncoder, ExportFormat, ExportLimiter};
+    use crate::clock::MockClock;
+    use crate::postgres_store::{Point, TimeSeriesValue};
+    use chrono::DateTime;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn points() -> Vec<Point> {
+        let ts = DateTime::from_timestamp(1_700_000_000, 500_000).unwrap();
+        let mut temperature = Point::now(
+            "voegeli",
+            "birdhouse",
+            "inside_temperature",
+            TimeSeriesValue::Double(21.5),
+        );
+        temperature.ts = ts;
+        temperature.unit = Some("°C".to_string());
+        let mut note = Point::now(
+            "voegeli",
+            "birdhouse",
+            "note",
+            TimeSeriesValue::Text("tit, \"blue\"".to_string()),
+        );
+        note.ts = ts;
+        vec![temperature, note]
+    }
+
+    fn export(format: ExportFormat, pages: &[&[Point]]) -> Vec<u8> {
+        let mut encoder = ExportEncoder::new(format).unwrap();
+        let mut out = Vec::new();
+        for page in pages {
+            out.extend(encoder.page(page).unwrap());
+        }
+        out.extend(encoder.finish().unwrap());
+        out
+    }
+
+    #[test]
+    fn test_csv_and_ndjson_lines() {
+        let points = points();
+        let csv = String::from_utf8(export(ExportFormat::Csv, &[&points])).unwrap();
+        assert_eq!(
+            csv,
+            "ts,measurement,field,value,unit,location,type\n\
+             2023-11-14T22:13:20.000500Z,birdhouse,inside_temperature,21.5,°C,,\n\
+             2023-11-14T22:13:20.000500Z,birdhouse,note,\"tit, \"\"blue\"\"\",,,\n"
+        );
+        assert_eq!(
+            export(ExportFormat::Csv, &[]),
+            b"ts,measurement,field,value,unit,location,type\n"
+        );
+
+        let ndjson = String::from_utf8(export(ExportFormat::Ndjson, &[&points])).unwrap();
+        let first: serde_json::Value =
+            serde_json::from_str(ndjson.lines().next().unwrap()).unwrap();
+        assert_eq!(first["value"], 21.5);
+        assert_eq!(first["unit"], "°C");
+        assert_eq!(first["location"], serde_json::Value::Null);
+        assert_eq!(ndjson.lines().count(), 2);
+    }
+
+    #[test]
+    fn test_parquet_row_group_per_page() {
+        let points = points();
+        let file = export(ExportFormat::Parquet, &[&points, &points[..1], &[]]);
+        let reader = SerializedFileReader::new(bytes::Bytes::from(file)).unwrap();
+        let metadata = reader.metadata();
+        assert_eq!(metadata.num_row_groups(), 2);
+        assert_eq!(metadata.file_metadata().num_rows(), 3);
+        assert_eq!(metadata.file_metadata().schema_descr().num_columns(), 9);
+        assert!("xlsx".parse::<ExportFormat>().is_err());
+    }
+
+    #[test]
+    fn test_limiter_counts_per_client_per_hour() {
+        let clock = MockClock::at_unix_secs(1_700_000_000);
+        let limiter = ExportLimiter::new(Arc::new(clock.clone()), 2);
+        assert!(limiter.try_start("1.2.3.4").is_ok());
+        clock.advance(Duration::from_secs(600));
+        assert!(limiter.try_start("1.2.3.4").is_ok());
+        assert_eq!(limiter.try_start("1.2.3.4"), Err(3000));
+        assert!(limiter.try_start("5.6.7.8").is_ok());
+        clock.advance(Duration::from_secs(3000));
+        assert!(limiter.try_start("1.2.3.4").is_ok());
+    }
+}
@@ -0,0 +1,516 @@
+#![cfg(feature = "server")]
+
+//! Device tokens, validation and idempotency for `POST /api/ingest`, where
+//! devices push readings in the `{device, data}` shape understood by
+//! [`device_points`](crate::postgres_store::device_points).
+
+use crate::clock::{self, SharedClock};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Payloads accepted in one request.
+pub const MAX_BATCH: usize = 1000;
+/// How long a finished request can be replayed by its idempotency key.
+pub const IDEMPOTENCY_TTL_SECS: i64 = 24 * 3600;
+/// Idempotency keys remembered at once; past this the oldest are forgotten
+/// before their time.
+pub const MAX_IDEMPOTENCY_KEYS: usize = 100_000;
+pub const MAX_IDEMPOTENCY_KEY_LEN: usize = 128;
+pub const MAX_FIELD_NAME_LEN: usize = 64;
+pub const MAX_TEXT_LEN: usize = 256;
+/// Readings may carry their own `ts`, but not one further ahead than this.
+const MAX_CLOCK_SKEW_SECS: i64 = 300;
+const METADATA_SUFFIXES: [&str; 3] = ["_unit", "_location", "_type"];
+
+fn digest(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Per-device ingest tokens from `INGEST_TOKENS`, `device:token` pairs
+/// separated by commas. Only digests are kept, so lookups do not leak the
+/// tokens through timing.
+#[derive(Debug, Clone, Default)]
+pub struct IngestTokens {
+    devices: Vec<(String, [u8; 32])>,
+}
+
+impl IngestTokens {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut devices: Vec<(String, [u8; 32])> = Vec::new();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (device, token) = entry
+                .split_once(':')
+                .map(|(d, t)| (d.trim(), t.trim()))
+                .filter(|(d, t)| !d.is_empty() && !t.is_empty())
+                .ok_or_else(|| format!("Ingest token entry {:?} must be device:token.", entry))?;
+            let token_digest = digest(token.as_bytes());
+            if devices.iter().any(|(_, d)| *d == token_digest) {
+                return Err(format!(
+                    "Device {:?} reuses another device's token.",
+                    device
+                ));
+            }
+            devices.push((device.to_string(), token_digest));
+        }
+        Ok(Self { devices })
+    }
+
+    pub fn from_env() -> Self {
+        let spec = std::env::var("INGEST_TOKENS").unwrap_or_default();
+        Self::parse(&spec).unwrap_or_else(|e| {
+            eprintln!("[Ingest] Invalid INGEST_TOKENS, ingest is disabled: {}", e);
+            Self::default()
+        })
+    }
+
+    /// The device `token` belongs to.
+    pub fn device_for(&self, token: &str) -> Option<&str> {
+        let wanted = digest(token.as_bytes());
+        self.devices
+            .iter()
+            .find(|(_, d)| *d == wanted)
+            .map(|(device, _)| device.as_str())
+    }
+}
+
+static TOKENS: Lazy<IngestTokens> = Lazy::new(IngestTokens::from_env);
+
+/// The tokens from `INGEST_TOKENS`, shared by every ingest route.
+pub fn tokens() -> &'static IngestTokens {
+    &TOKENS
+}
+
+/// One problem with a request, e.g. `{index: 2, field: "data.temperature"}`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldError {
+    /// Position of the payload in the batch; 0 for a single payload.
+    pub index: usize,
+    pub field: String,
+    pub message: String,
+}
+
+/// A payload that passed validation, with the time its readings were taken.
+#[derive(Debug, Clone)]
+pub struct Reading {
+    pub payload: Value,
+    pub ts: DateTime<Utc>,
+}
+
+pub fn is_valid_field_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_FIELD_NAME_LEN
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// What is wrong with a text value, if anything. PostgreSQL refuses NUL in
+/// TEXT columns, and the other control characters have no place in sensor
+/// metadata either.
+pub fn text_error(text: &str) -> Option<String> {
+    if text.len() > MAX_TEXT_LEN {
+        Some(format!("Text is limited to {} bytes.", MAX_TEXT_LEN))
+    } else if text.chars().any(char::is_control) {
+        Some("Text must not contain control characters.".to_string())
+    } else {
+        None
+    }
+}
+
+fn validate_payload(
+    index: usize,
+    payload: &Value,
+    device: &str,
+    now: DateTime<Utc>,
+    errors: &mut Vec<FieldError>,
+) -> Option<DateTime<Utc>> {
+    let mut error = |field: &str, message: String| {
+        errors.push(FieldError {
+            index,
+            field: field.to_string(),
+            message,
+        })
+    };
+
+    let Some(root) = payload.as_object() else {
+        error("", "Each payload must be an object.".to_string());
+        return None;
+    };
+
+    match root.get("device").and_then(Value::as_str) {
+        None => error("device", "Required, a non-empty string.".to_string()),
+        Some(name) if name.trim().is_empty() => {
+            error("device", "Required, a non-empty string.".to_string())
+        }
+        Some(name) if name != device => error(
+            "device",
+            format!("The token belongs to device {:?}.", device),
+        ),
+        Some(_) => {}
+    }
+
+    let ts = match root.get("ts") {
+        None | Some(Value::Null) => Some(now),
+        Some(value) => match value
+            .as_f64()
+            .and_then(|secs| DateTime::from_timestamp_micros((secs * 1e6) as i64))
+        {
+            Some(ts) if (ts - now).num_seconds() > MAX_CLOCK_SKEW_SECS => {
+                error("ts", "Lies in the future.".to_string());
+                None
+            }
+            Some(ts) => Some(ts),
+            None => {
+                error("ts", "Must be Unix seconds.".to_string());
+                None
+            }
+        },
+    };
+
+    match root.get("data") {
+        Some(Value::Object(data)) => validate_data(data, &mut error),
+        _ => error("data", "Required, an object of field values.".to_string()),
+    }
+    ts
+}
+
+fn validate_data(data: &Map<String, Value>, error: &mut impl FnMut(&str, String)) {
+    let mut values = 0;
+    for (name, value) in data {
+        let field = format!("data.{}", name);
+        if !is_valid_field_name(name) {
+            error(
+                &field,
+                format!(
+                    "Field names use letters, digits and '_', at most {} characters.",
+                    MAX_FIELD_NAME_LEN
+                ),
+            );
+            continue;
+        }
+
+        if let Some(base) = METADATA_SUFFIXES
+            .iter()
+            .find_map(|suffix| name.strip_suffix(suffix))
+        {
+            if !data.contains_key(base) {
+                error(
+                    &field,
+                    format!("Describes {:?}, which is not in data.", base),
+                );
+            }
+            match value {
+                Value::Null => {}
+                Value::String(text) => {
+                    if let Some(message) = text_error(text) {
+                        error(&field, message);
+                    }
+                }
+                _ => error(&field, "Must be a string or null.".to_string()),
+            }
+            continue;
+        }
+
+        match value {
+            Value::Null => {}
+            Value::Bool(_) | Value::Number(_) => values += 1,
+            Value::String(text) => match text_error(text) {
+                Some(message) => error(&field, message),
+                None => values += 1,
+            },
+            Value::Array(_) | Value::Object(_) => error(
+                &field,
+                "Must be a number, boolean, string or null.".to_string(),
+            ),
+        }
+    }
+    if data.is_empty() {
+        error("data", "Is empty.".to_string());
+    } else if values == 0 {
+        error("data", "Contains no values.".to_string());
+    }
+}
+
+/// Checks a single payload or an array of them, sent with `device`'s token.
+/// Either every payload is valid or all problems are returned.
+pub fn validate(
+    body: &Value,
+    device: &str,
+    now: DateTime<Utc>,
+) -> Result<Vec<Reading>, Vec<FieldError>> {
+    let payloads = match body {
+        Value::Array(items) if items.is_empty() => {
+            return Err(vec![FieldError {
+                index: 0,
+                field: String::new(),
+                message: "The batch is empty.".to_string(),
+            }])
+        }
+        Value::Array(items) if items.len() > MAX_BATCH => {
+            return Err(vec![FieldError {
+                index: MAX_BATCH,
+                field: String::new(),
+                message: format!("At most {} payloads per request.", MAX_BATCH),
+            }])
+        }
+        Value::Array(items) => items.as_slice(),
+        single => std::slice::from_ref(single),
+    };
+
+    let mut errors = Vec::new();
+    let mut readings = Vec::with_capacity(payloads.len());
+    for (index, payload) in payloads.iter().enumerate() {
+        if let Some(ts) = validate_payload(index, payload, device, now, &mut errors) {
+            readings.push(Reading {
+                payload: payload.clone(),
+                ts,
+            });
+        }
+    }
+    if errors.is_empty() {
+        Ok(readings)
+    } else {
+        Err(errors)
+    }
+}
+
+/// What a successful ingest request answered; replayed for repeats of its
+/// idempotency key.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IngestReceipt {
+    pub payloads: usize,
+    pub points: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Claim {
+    /// First use of the key; the caller must `complete` or `release` it.
+    New,
+    /// The key already succeeded with the same body.
+    Replay(IngestReceipt),
+    /// A request with the key is still being handled.
+    InFlight,
+    /// The key was used for a different body.
+    Mismatch,
+}
+
+struct IdempotencyEntry {
+    body_digest: [u8; 32],
+    // Tells this claim of the key apart from earlier, released ones.
+    claim: u64,
+    receipt: Option<IngestReceipt>,
+}
+
+#[derive(Default)]
+struct KeyTable {
+    entries: HashMap<(String, String), IdempotencyEntry>,
+    // Every claim as `(created_at, claim, key)`, oldest first. A released
+    // key stays queued until it reaches the front.
+    order: VecDeque<(i64, u64, (String, String))>,
+    next_claim: u64,
+}
+
+impl KeyTable {
+    /// Forgets the oldest claim, unless its key was claimed again since.
+    fn pop_oldest(&mut self) {
+        if let Some((_, claim, key)) = self.order.pop_front() {
+            if self
+                .entries
+                .get(&key)
+                .is_some_and(|entry| entry.claim == claim)
+            {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+/// Remembers idempotency keys per device for [`IDEMPOTENCY_TTL_SECS`], so a
+/// device retrying after a lost response does not store its readings twice.
+/// Kept in memory: the retries this covers happen within minutes. Keys
+/// expire from the front of a queue in claim order, so a claim only looks at
+/// the keys that actually expired.
+pub struct IdempotencyKeys {
+    table: Mutex<KeyTable>,
+    capacity: usize,
+    clock: SharedClock,
+}
+
+impl IdempotencyKeys {
+    /// Remembers at most `capacity` keys, normally [`MAX_IDEMPOTENCY_KEYS`].
+    pub fn new(clock: SharedClock, capacity: usize) -> Self {
+        Self {
+            table: Mutex::new(KeyTable::default()),
+            capacity: capacity.max(1),
+            clock,
+        }
+    }
+
+    fn table(&self) -> MutexGuard<'_, KeyTable> {
+        self.table.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn claim(&self, device: &str, key: &str, body: &[u8]) -> Claim {
+        let now = clock::unix_secs(self.clock.as_ref());
+        let mut table = self.table();
+        while table
+            .order
+            .front()
+            .is_some_and(|(created_at, ..)| now - created_at >= IDEMPOTENCY_TTL_SECS)
+        {
+            table.pop_oldest();
+        }
+
+        let key = (device.to_string(), key.to_string());
+        let body_digest = digest(body);
+        if let Some(entry) = table.entries.get(&key) {
+            return if entry.body_digest != body_digest {
+                Claim::Mismatch
+            } else {
+                entry.receipt.clone().map_or(Claim::InFlight, Claim::Replay)
+            };
+        }
+
+        while table.order.len() >= self.capacity {
+            table.pop_oldest();
+        }
+        let claim = table.next_claim;
+        table.next_claim += 1;
+        table.order.push_back((now, claim, key.clone()));
+        table.entries.insert(
+            key,
+            IdempotencyEntry {
+                body_digest,
+                claim,
+                receipt: None,
+            },
+        );
+        Claim::New
+    }
+
+    pub fn complete(&self, device: &str, key: &str, receipt: IngestReceipt) {
+        if let Some(entry) = self
+            .table()
+            .entries
+            .get_mut(&(device.to_string(), key.to_string()))
+        {
+            entry.receipt = Some(receipt);
+        }
+    }
+
+    /// Forgets a claimed key after a failed request so it can be retried.
+    pub fn release(&self, device: &str, key: &str) {
+        self.table()
+            .entries
+            .remove(&(device.to_string(), key.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate, Claim, IdempotencyKeys, IngestReceipt, IngestTokens};
+    use crate::clock::MockClock;
+    use chrono::DateTime;
+    use serde_json::json;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_tokens_map_to_devices() {
+        let tokens = IngestTokens::parse("birdhouse:s3cret, feeder:other").unwrap();
+        assert_eq!(tokens.device_for("s3cret"), Some("birdhouse"));
+        assert_eq!(tokens.device_for("other"), Some("feeder"));
+        assert_eq!(tokens.device_for("s3cre"), None);
+        assert!(IngestTokens::parse("birdhouse").is_err());
+        assert!(IngestTokens::parse("a:same,b:same").is_err());
+        assert_eq!(IngestTokens::parse("").unwrap().device_for(""), None);
+    }
+
+    #[test]
+    fn test_validate_lists_every_problem() {
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let good = json!({
+            "device": "birdhouse",
+            "ts": 1_699_999_000,
+            "data": {"temperature": 21.5, "temperature_unit": "°C", "ir": true, "note": null}
+        });
+        let readings = validate(&good, "birdhouse", now).unwrap();
+        assert_eq!(readings.len(), 1);
+        assert_eq!(readings[0].ts.timestamp(), 1_699_999_000);
+
+        let batch = json!([
+            good,
+            {"device": "feeder", "data": {"bad name": 1, "humidity_unit": "%", "list": [1]}},
+            {"device": "birdhouse", "ts": 1_800_000_000, "data": {}},
+            {"device": "birdhouse", "data": {"note": "a\0b", "temp": 1, "temp_unit": "°\nC"}},
+        ]);
+        let errors = validate(&batch, "birdhouse", now).unwrap_err();
+        let found: Vec<(usize, &str)> =
+            errors.iter().map(|e| (e.index, e.field.as_str())).collect();
+        assert_eq!(
+            found,
+            [
+                (1, "device"),
+                (1, "data.bad name"),
+                (1, "data.humidity_unit"),
+                (1, "data.list"),
+                (1, "data"),
+                (2, "ts"),
+                (2, "data"),
+                (3, "data.note"),
+                (3, "data.temp_unit"),
+            ]
+        );
+
+        assert!(validate(&json!([]), "birdhouse", now).is_err());
+    }
+
+    #[test]
+    fn test_idempotency_keys_replay_and_expire() {
+        let clock = MockClock::at_unix_secs(1_700_000_000);
+        let keys = IdempotencyKeys::new(Arc::new(clock.clone()), super::MAX_IDEMPOTENCY_KEYS);
+        let receipt = IngestReceipt {
+            payloads: 1,
+            points: 3,
+        };
+
+        assert_eq!(keys.claim("birdhouse", "k1", b"body"), Claim::New);
+        assert_eq!(keys.claim("birdhouse", "k1", b"body"), Claim::InFlight);
+        keys.complete("birdhouse", "k1", receipt.clone());
+        assert_eq!(
+            keys.claim("birdhouse", "k1", b"body"),
+            Claim::Replay(receipt)
+        );
+        assert_eq!(keys.claim("birdhouse", "k1", b"other"), Claim::Mismatch);
+        // Keys are per device.
+        assert_eq!(keys.claim("feeder", "k1", b"other"), Claim::New);
+
+        keys.release("feeder", "k1");
+        assert_eq!(keys.claim("feeder", "k1", b"body"), Claim::New);
+
+        clock.advance(Duration::from_secs(super::IDEMPOTENCY_TTL_SECS as u64));
+        assert_eq!(keys.claim("birdhouse", "k1", b"other"), Claim::New);
+    }
+
+    #[test]
+    fn test_idempotency_keys_are_bounded() {
+        let clock = MockClock::at_unix_secs(1_700_000_000);
+        let keys = IdempotencyKeys::new(Arc::new(clock), 2);
+
+        // A key claimed again after a release survives its old claim.
+        assert_eq!(keys.claim("birdhouse", "k1", b"body"), Claim::New);
+        keys.release("birdhouse", "k1");
+        assert_eq!(keys.claim("birdhouse", "k1", b"body"), Claim::New);
+        assert_eq!(keys.claim("birdhouse", "k2", b"body"), Claim::New);
+        assert_eq!(keys.claim("birdhouse", "k1", b"body"), Claim::InFlight);
+
+        // Past the capacity the oldest key is forgotten first.
+        assert_eq!(keys.claim("birdhouse", "k3", b"body"), Claim::New);
+        assert_eq!(keys.claim("birdhouse", "k2", b"body"), Claim::InFlight);
+        assert_eq!(keys.claim("birdhouse", "k3", b"body"), Claim::InFlight);
+        assert_eq!(keys.claim("birdhouse", "k1", b"body"), Claim::New);
+    }
+}
@@ -0,0 +1,384 @@
+#![cfg(feature = "server")]
+
+//! InfluxDB line protocol, as sent to `/api/v2/write` by Telegraf and the
+//! Influx client libraries:
+//!
+//! ```text
+//! measurement[,tag=value...] field=value[,field=value...] [timestamp]
+//! ```
+//!
+//! The `unit`, `location` and `type` tags fill the columns of the same name;
+//! other tags have no column and are dropped. Integer fields are stored as
+//! doubles. Field names may hold any printable character, as Telegraf's do
+//! (`usage_guest-nice`, `ifHCInOctets.1`); otherwise names and text are held
+//! to the same limits as `/api/ingest`.
+
+use crate::ingest::{text_error, MAX_FIELD_NAME_LEN};
+use crate::postgres_store::{Point, TimeSeriesValue};
+use chrono::{DateTime, Utc};
+use std::str::FromStr;
+
+/// Unit of the timestamps in a write, the `precision` query parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precision {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+}
+
+impl FromStr for Precision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ns" | "n" => Ok(Self::Nanoseconds),
+            "us" | "u" => Ok(Self::Microseconds),
+            "ms" => Ok(Self::Milliseconds),
+            "s" => Ok(Self::Seconds),
+            other => Err(format!(
+                "Unknown precision {:?} (expected ns, us, ms or s).",
+                other
+            )),
+        }
+    }
+}
+
+impl Precision {
+    pub fn timestamp(self, value: i64) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Nanoseconds => Some(DateTime::from_timestamp_nanos(value)),
+            Self::Microseconds => DateTime::from_timestamp_micros(value),
+            Self::Milliseconds => DateTime::from_timestamp_millis(value),
+            Self::Seconds => DateTime::from_timestamp(value, 0),
+        }
+    }
+}
+
+/// Splits at `sep` outside backslash escapes and, with `quotes`, outside
+/// double-quoted strings.
+fn split_unescaped(text: &str, sep: char, quotes: bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut start, mut escaped, mut quoted) = (0, false, false);
+    for (i, c) in text.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' => escaped = true,
+            '"' if quotes => quoted = !quoted,
+            c if c == sep && !quoted => {
+                parts.push(&text[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+fn split_pair(text: &str, quotes: bool) -> Option<(&str, &str)> {
+    let key = split_unescaped(text, '=', quotes).into_iter().next()?;
+    let value = text.get(key.len() + 1..)?;
+    Some((key, value))
+}
+
+/// Drops the backslash in front of characters the protocol escapes.
+fn unescape(text: &str, escapable: &[char]) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match chars.peek() {
+            Some(next) if c == '\\' && escapable.contains(next) => {
+                out.push(*next);
+                chars.next();
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+const NAME_ESCAPES: &[char] = &[',', '=', ' ', '\\'];
+const STRING_ESCAPES: &[char] = &['"', '\\'];
+
+fn parse_field_value(text: &str) -> Result<TimeSeriesValue, String> {
+    if let Some(inner) = text
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+    {
+        let text = unescape(inner, STRING_ESCAPES);
+        if let Some(message) = text_error(&text) {
+            return Err(format!("invalid string value: {}", message));
+        }
+        return Ok(TimeSeriesValue::Text(text));
+    }
+    match text {
+        "t" | "T" | "true" | "True" | "TRUE" => return Ok(TimeSeriesValue::Bool(true)),
+        "f" | "F" | "false" | "False" | "FALSE" => return Ok(TimeSeriesValue::Bool(false)),
+        _ => {}
+    }
+    let number = if let Some(int) = text.strip_suffix('i') {
+        int.parse::<i64>().map(|v| v as f64).ok()
+    } else if let Some(uint) = text.strip_suffix('u') {
+        uint.parse::<u64>().map(|v| v as f64).ok()
+    } else {
+        text.parse::<f64>().ok().filter(|v| v.is_finite())
+    };
+    number
+        .map(TimeSeriesValue::Double)
+        .ok_or_else(|| format!("invalid field value {:?}", text))
+}
+
+/// Measurement, tag and field names: anything printable up to the length of
+/// an `/api/ingest` field name.
+fn check_name(kind: &str, name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_FIELD_NAME_LEN || name.chars().any(char::is_control) {
+        return Err(format!(
+            "{} {:?} must be 1 to {} bytes without control characters",
+            kind, name, MAX_FIELD_NAME_LEN
+        ));
+    }
+    Ok(())
+}
+
+fn parse_line(
+    line: &str,
+    bucket: &str,
+    precision: Precision,
+    now: DateTime<Utc>,
+) -> Result<Vec<Point>, String> {
+    let sections: Vec<&str> = split_unescaped(line, ' ', true)
+        .into_iter()
+        .filter(|s| !s.is_empty())
+        .collect();
+    let (series, fields, timestamp) = match sections.as_slice() {
+        [series, fields] => (*series, *fields, None),
+        [series, fields, timestamp] => (*series, *fields, Some(*timestamp)),
+        _ => return Err("expected measurement, fields and an optional timestamp".to_string()),
+    };
+
+    let mut series = split_unescaped(series, ',', false).into_iter();
+    let measurement = unescape(series.next().unwrap_or_default(), NAME_ESCAPES);
+    if measurement.is_empty() {
+        return Err("missing measurement".to_string());
+    }
+    check_name("measurement", &measurement)?;
+    let (mut unit, mut location, mut value_type) = (None, None, None);
+    for tag in series {
+        let (key, value) = split_pair(tag, false)
+            .filter(|(k, v)| !k.is_empty() && !v.is_empty())
+            .ok_or_else(|| format!("invalid tag {:?}", tag))?;
+        let key = unescape(key, NAME_ESCAPES);
+        check_name("tag", &key)?;
+        let value = unescape(value, NAME_ESCAPES);
+        if let Some(message) = text_error(&value) {
+            return Err(format!("invalid value for tag {:?}: {}", key, message));
+        }
+        let value = Some(value);
+        match key.as_str() {
+            "unit" => unit = value,
+            "location" => location = value,
+            "type" => value_type = value,
+            _ => {}
+        }
+    }
+
+    let ts = match timestamp {
+        None => now,
+        Some(text) => text
+            .parse::<i64>()
+            .ok()
+            .and_then(|value| precision.timestamp(value))
+            .ok_or_else(|| format!("invalid timestamp {:?}", text))?,
+    };
+
+    split_unescaped(fields, ',', true)
+        .into_iter()
+        .map(|pair| {
+            let (key, value) = split_pair(pair, true)
+                .filter(|(k, v)| !k.is_empty() && !v.is_empty())
+                .ok_or_else(|| format!("invalid field {:?}", pair))?;
+            let field = unescape(key, NAME_ESCAPES);
+            check_name("field", &field)?;
+            Ok(Point {
+                bucket: bucket.to_string(),
+                ts,
+                measurement: measurement.clone(),
+                field,
+                value: parse_field_value(value)?,
+                unit: unit.clone(),
+                location: location.clone(),
+                value_type: value_type.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Splits a body into lines, each with the number of the line it starts on.
+/// A newline inside a quoted string or after a backslash does not end a
+/// line; a comment always ends at the next newline.
+fn split_lines(body: &str) -> Vec<(usize, &str)> {
+    let mut lines = Vec::new();
+    let (mut start, mut number, mut joined) = (0, 1, 0);
+    let (mut escaped, mut quoted, mut comment, mut blank) = (false, false, false, true);
+    for (i, c) in body.char_indices() {
+        if escaped {
+            escaped = false;
+            joined += usize::from(c == '\n');
+            continue;
+        }
+        match c {
+            '\n' if quoted => joined += 1,
+            '\n' => {
+                lines.push((number, &body[start..i]));
+                start = i + 1;
+                number += joined + 1;
+                joined = 0;
+                (quoted, comment, blank) = (false, false, true);
+            }
+            _ if comment => {}
+            '#' if blank => comment = true,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            _ => {}
+        }
+        blank &= c.is_whitespace();
+    }
+    lines.push((number, &body[start..]));
+    lines
+}
+
+/// Every point in a line protocol body, one per field. Lines without a
+/// timestamp get `now`. Fails on the first bad line, naming it, so a write is
+/// stored completely or not at all.
+pub fn parse(
+    body: &str,
+    bucket: &str,
+    precision: Precision,
+    now: DateTime<Utc>,
+) -> Result<Vec<Point>, String> {
+    let mut points = Vec::new();
+    for (number, line) in split_lines(body) {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        points.extend(
+            parse_line(line, bucket, precision, now)
+                .map_err(|e| format!("line {}: {}", number, e))?,
+        );
+    }
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Precision};
+    use crate::postgres_store::TimeSeriesValue;
+    use chrono::DateTime;
+
+    #[test]
+    fn test_parse_maps_tags_and_field_types() {
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let body = "# written by telegraf\n\
+            sensors,location=inside,unit=°C,host=pi temperature=21.5,count=3i,ok=t,note=\"a \\\"b\\\" c\" 1700000001000000000\n\
+            \n\
+            my\\ room,type=dht22 humidity=40u";
+        let points = parse(body, "voegeli", Precision::Nanoseconds, now).unwrap();
+        assert_eq!(points.len(), 5);
+
+        let temperature = &points[0];
+        assert_eq!(temperature.bucket, "voegeli");
+        assert_eq!(temperature.measurement, "sensors");
+        assert_eq!(temperature.field, "temperature");
+        assert_eq!(temperature.value, TimeSeriesValue::Double(21.5));
+        assert_eq!(temperature.unit.as_deref(), Some("°C"));
+        assert_eq!(temperature.location.as_deref(), Some("inside"));
+        assert_eq!(temperature.ts.timestamp(), 1_700_000_001);
+        assert_eq!(points[1].value, TimeSeriesValue::Double(3.0));
+        assert_eq!(points[2].value, TimeSeriesValue::Bool(true));
+        assert_eq!(
+            points[3].value,
+            TimeSeriesValue::Text("a \"b\" c".to_string())
+        );
+
+        let humidity = &points[4];
+        assert_eq!(humidity.measurement, "my room");
+        assert_eq!(humidity.value_type.as_deref(), Some("dht22"));
+        assert_eq!(humidity.ts, now);
+    }
+
+    #[test]
+    fn test_parse_accepts_telegraf_field_names() {
+        let now = DateTime::from_timestamp(0, 0).unwrap();
+        let body = "cpu usage_guest-nice=0.5\nnet ifHCInOctets.1=12i,in\\ bytes=3i";
+        let fields: Vec<String> = parse(body, "b", Precision::Seconds, now)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.field)
+            .collect();
+        assert_eq!(fields, ["usage_guest-nice", "ifHCInOctets.1", "in bytes"]);
+    }
+
+    #[test]
+    fn test_quoted_newlines_do_not_split_lines() {
+        let now = DateTime::from_timestamp(0, 0).unwrap();
+        let parse = |body: &str| parse(body, "b", Precision::Seconds, now);
+        // The string stays one value, and is held to the text rules of
+        // /api/ingest instead of being cut into two broken lines.
+        assert!(parse("m note=\"a\nb v=1\"")
+            .unwrap_err()
+            .starts_with("line 1: invalid string value"));
+        // A quote in a comment does not carry over, and line numbers count
+        // the newlines inside strings.
+        let body = "# \"quoted\nm v=1\nm note=\"a\\\"\nb\"\nm";
+        assert!(parse(body)
+            .unwrap_err()
+            .starts_with("line 3: invalid string"));
+        assert!(parse("# \"quoted\nm v=1\nm\n")
+            .unwrap_err()
+            .starts_with("line 3:"));
+        assert_eq!(parse("m v=1\r\n# \"\r\nm v=2\r\n").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_precision_scales_timestamps() {
+        let now = DateTime::from_timestamp(0, 0).unwrap();
+        for (precision, ts) in [
+            ("s", "1700000000"),
+            ("ms", "1700000000000"),
+            ("us", "1700000000000000"),
+            ("ns", "1700000000000000000"),
+        ] {
+            let precision = precision.parse::<Precision>().unwrap();
+            let points = parse(&format!("m v=1 {}", ts), "b", precision, now).unwrap();
+            assert_eq!(points[0].ts.timestamp(), 1_700_000_000);
+        }
+        assert!("h".parse::<Precision>().is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_lines() {
+        let now = DateTime::from_timestamp(0, 0).unwrap();
+        let parse = |body: &str| parse(body, "b", Precision::Seconds, now);
+        assert!(parse("m v=1\nm").unwrap_err().starts_with("line 2:"));
+        assert!(parse("m v=abc").is_err());
+        assert!(parse("m,tag v=1").is_err());
+        assert!(parse("m v=1 soon").is_err());
+        assert!(parse("m v=1 2 3").is_err());
+        assert!(parse(",t=a v=1").is_err());
+        assert!(parse("").unwrap().is_empty());
+
+        // The limits of /api/ingest apply.
+        assert!(parse("m note=\"a\0b\"").is_err());
+        assert!(parse(&format!("m note=\"{}\"", "x".repeat(257))).is_err());
+        assert!(parse("m bad\tname=1").is_err());
+        assert!(parse(&format!("m {}=1", "f".repeat(65))).is_err());
+        assert!(parse(&format!("{} v=1", "m".repeat(65))).is_err());
+        assert!(parse("m,location=in\tside v=1").is_err());
+        assert!(parse("m,lo\x00cation=x v=1").is_err());
+    }
+}
@@ -12,21 +12,51 @@ use std::net::IpAddr;
 #[cfg(feature = "server")]
 use uuid::Uuid;
 use views::{
-    Admin, Birds, ForNerds, Gallery, Home, HowItWorks, MakingOf, Navbar, Newsletter, Unsubscribe,
-    VoguGuru,
+    Admin, Birds, Device, ForNerds, Gallery, Home, HowItWorks, MakingOf, Navbar, Newsletter,
+    Unsubscribe, VoguGuru,
 };
 
 mod admin;
 mod api;
+#[cfg(feature = "server")]
+mod client_ip;
+#[cfg(feature = "server")]
+mod clock;
+#[cfg(feature = "server")]
+mod command_queue;
 mod components;
+mod device_link;
+#[cfg(feature = "server")]
+mod device_protocol;
+#[cfg(feature = "server")]
+mod export;
+#[cfg(feature = "server")]
+mod ingest;
+#[cfg(feature = "server")]
+mod line_protocol;
 mod newsletter;
 #[cfg(feature = "server")]
+mod postgres_migrations;
+#[cfg(feature = "server")]
+mod postgres_pool;
+#[cfg(feature = "server")]
 mod postgres_store;
+#[cfg(feature = "server")]
+mod retention;
+#[cfg(any(feature = "server", target_arch = "wasm32"))]
+mod spectrogram_frame;
+#[cfg(feature = "server")]
+mod spectrogram_relay;
 mod views;
 
 #[cfg(feature = "server")]
 mod tcp_client;
 mod tcp_state;
+mod time_series;
+#[cfg(feature = "server")]
+mod viewer_sessions;
+#[cfg(feature = "server")]
+mod write_buffer;
 
 #[cfg(feature = "server")]
 use axum::extract::Query;
@@ -36,6 +66,8 @@ use std::collections::HashMap;
 use std::fs;
 #[cfg(feature = "server")]
 use std::sync::RwLock;
+#[cfg(feature = "server")]
+use viewer_sessions::ViewerSessions;
 
 #[cfg(feature = "server")]
 const LOCATION_FILE: &str = "data/locations.json";
@@ -56,7 +88,8 @@ pub static CURRENT_LUMINOSITY: Lazy<RwLock<Option<f64>>> = Lazy::new(|| RwLock::
 static USER_LOCATIONS: Lazy<DashMap<Uuid, UserLocation>> = Lazy::new(DashMap::new);
 
 #[cfg(feature = "server")]
-static ACTIVE_SESSIONS: Lazy<DashMap<String, SessionEntry>> = Lazy::new(DashMap::new);
+static ACTIVE_SESSIONS: Lazy<ViewerSessions> =
+    Lazy::new(|| ViewerSessions::new(clock::system(), viewer_sessions::SESSION_TTL_SECS));
 
 #[cfg(feature = "server")]
 static STORED_LOCATIONS: Lazy<DashMap<String, StoredLocation>> = Lazy::new(DashMap::new);
@@ -80,13 +113,6 @@ struct UserLocation {
     connected_at: i64,
 }
 
-#[cfg(feature = "server")]
-#[derive(Clone)]
-struct SessionEntry {
-    connections: usize,
-    last_seen: i64,
-}
-
 #[derive(Deserialize, Clone)]
 struct IpGeoResponse {
     lat: f64,
@@ -122,6 +148,9 @@ enum WsMsg {
 
     #[serde(rename = "disconnect")]
     Disconnect { id: String, key: String },
+
+    #[serde(rename = "device_link")]
+    DeviceLink { link: device_link::DeviceLinkState },
 }
 
 #[derive(Debug, Clone, Routable, PartialEq)]
@@ -131,6 +160,9 @@ enum Route {
         #[route("/")]
         Home {},
 
+        #[route("/devices/:device_id")]
+        Device { device_id: String },
+
         #[route("/gallery")]
         Gallery {},
 
@@ -264,11 +296,16 @@ fn is_private_ip(ip: &IpAddr) -> bool {
 #[cfg(feature = "server")]
 #[tokio::main]
 async fn main() {
-    use crate::postgres_store::{PostgresTimeSeriesStore, TimeSeriesValue};
+    use crate::postgres_store::{Point, PostgresTimeSeriesStore, TimeSeriesValue};
     use api::gallery::{
         admin_download_gallery_selection, download_live_photo_bundle, serve_gallery_thumbnail,
         upload_image_multipart,
     };
+    use api::database::admin_database_stats;
+    use api::export::export_history;
+    use api::history::{history_aggregate, history_range};
+    use api::influx::influx_write;
+    use api::ingest::ingest;
     use axum::extract::DefaultBodyLimit;
     use axum::routing::post;
     use axum::Router;
@@ -868,8 +905,32 @@ async fn main() {
             .get("session_id")
             .cloned()
             .unwrap_or_else(|| "missing".to_string());
+        let device_id = tcp_client::resolve_device_id(params.get("device").map(String::as_str)).ok();
         let ip = extract_real_ip(&headers).unwrap_or_else(|| addr.ip());
-        ws.on_upgrade(move |socket| handle_tcp_socket(socket, ip, role, session_id))
+        ws.on_upgrade(move |socket| handle_tcp_socket(socket, ip, role, session_id, device_id))
+    }
+
+    /// Next event from the selected device; pends forever without one.
+    async fn recv_device_message(
+        rx: &mut Option<broadcast::Receiver<String>>,
+    ) -> Result<String, broadcast::error::RecvError> {
+        match rx {
+            Some(rx) => rx.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Next link state of the selected device, `None` once the device is gone.
+    async fn next_link_state(
+        rx: &mut Option<tokio::sync::watch::Receiver<device_link::DeviceLinkState>>,
+    ) -> Option<device_link::DeviceLinkState> {
+        match rx {
+            Some(rx) => {
+                rx.changed().await.ok()?;
+                Some(rx.borrow_and_update().clone())
+            }
+            None => std::future::pending().await,
+        }
     }
 
     async fn handle_tcp_socket(
@@ -877,6 +938,7 @@ async fn main() {
         ip: IpAddr,
         role: String,
         session_id: String,
+        device_id: Option<String>,
     ) {
         println!("New WS from IP: {} (session_id={})", ip, session_id);
 
@@ -935,16 +997,8 @@ async fn main() {
         }
 
         if role == "viewer" {
-            let now = chrono::Utc::now().timestamp();
-            let mut entry = ACTIVE_SESSIONS
-                .entry(session_id.clone())
-                .or_insert(SessionEntry {
-                    connections: 0,
-                    last_seen: now,
-                });
-            entry.connections += 1;
-            entry.last_seen = now;
-            if entry.connections == 1 {
+            let connections = ACTIVE_SESSIONS.connect(&session_id);
+            if connections == 1 {
                 ACTIVE_USERS.fetch_add(1, Ordering::SeqCst);
             }
             counted_viewer = true;
@@ -952,7 +1006,7 @@ async fn main() {
                 "User connected, active users = {} (session_id={}, connections={})",
                 ACTIVE_USERS.load(Ordering::Relaxed),
                 session_id,
-                entry.connections
+                connections
             );
         }
 
@@ -1009,6 +1063,19 @@ async fn main() {
             }
         }
 
+        // ---- Then the current state of the link to the selected device
+        let device_id = device_id.unwrap_or_default();
+        let mut device_rx = tcp_client::subscribe_to_tcp_messages(&device_id);
+        let mut link_rx = tcp_client::subscribe_to_link_state(&device_id);
+        let link_msg = WsMsg::DeviceLink {
+            link: tcp_client::link_state(&device_id),
+        };
+        let _ = socket
+            .send(axum::extract::ws::Message::Text(
+                serde_json::to_string(&link_msg).unwrap().into(),
+            ))
+            .await;
+
         // Main loop: ping + broadcast fanout
         loop {
             tokio::select! {
@@ -1023,9 +1090,7 @@ async fn main() {
                     }
 
                     if counted_viewer {
-                        if let Some(mut entry) = ACTIVE_SESSIONS.get_mut(&session_id) {
-                            entry.last_seen = chrono::Utc::now().timestamp();
-                        }
+                        ACTIVE_SESSIONS.touch(&session_id);
                     }
                 }
 
@@ -1042,6 +1107,41 @@ async fn main() {
                     }
                 }
 
+                msg = recv_device_message(&mut device_rx) => {
+                    match msg {
+                        Ok(message) => {
+                            if socket
+                                .send(axum::extract::ws::Message::Text(message.into()))
+                                .await
+                                .is_err()
+                            {
+                                println!("WS send failed (session_id={})", session_id);
+                                break;
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Lagged(_)) => {}
+                        Err(broadcast::error::RecvError::Closed) => device_rx = None,
+                    }
+                }
+
+                link = next_link_state(&mut link_rx) => {
+                    let Some(link) = link else {
+                        link_rx = None;
+                        continue;
+                    };
+                    let msg = WsMsg::DeviceLink { link };
+                    if socket
+                        .send(axum::extract::ws::Message::Text(
+                            serde_json::to_string(&msg).unwrap().into(),
+                        ))
+                        .await
+                        .is_err()
+                    {
+                        println!("WS send failed (session_id={})", session_id);
+                        break;
+                    }
+                }
+
                 result = socket.recv() => {
                     match result {
                         None => {
@@ -1063,9 +1163,7 @@ async fn main() {
                                 );
                             }
                             if counted_viewer {
-                                if let Some(mut entry) = ACTIVE_SESSIONS.get_mut(&session_id) {
-                                    entry.last_seen = chrono::Utc::now().timestamp();
-                                }
+                                ACTIVE_SESSIONS.touch(&session_id);
                             }
                         }
                         Some(Ok(axum::extract::ws::Message::Binary(data))) => {
@@ -1075,25 +1173,19 @@ async fn main() {
                                 session_id
                             );
                             if counted_viewer {
-                                if let Some(mut entry) = ACTIVE_SESSIONS.get_mut(&session_id) {
-                                    entry.last_seen = chrono::Utc::now().timestamp();
-                                }
+                                ACTIVE_SESSIONS.touch(&session_id);
                             }
                         }
                         Some(Ok(axum::extract::ws::Message::Ping(_))) => {
                             println!("WS recv Ping (session_id={})", session_id);
                             if counted_viewer {
-                                if let Some(mut entry) = ACTIVE_SESSIONS.get_mut(&session_id) {
-                                    entry.last_seen = chrono::Utc::now().timestamp();
-                                }
+                                ACTIVE_SESSIONS.touch(&session_id);
                             }
                         }
                         Some(Ok(axum::extract::ws::Message::Pong(_))) => {
                             println!("WS recv Pong (session_id={})", session_id);
                             if counted_viewer {
-                                if let Some(mut entry) = ACTIVE_SESSIONS.get_mut(&session_id) {
-                                    entry.last_seen = chrono::Utc::now().timestamp();
-                                }
+                                ACTIVE_SESSIONS.touch(&session_id);
                             }
                         }
                         Some(Err(err)) => {
@@ -1131,25 +1223,16 @@ async fn main() {
                 println!("Disconnect pre state missing (session_id={})", session_id);
             }
 
-            let should_remove = if let Some(mut entry) = ACTIVE_SESSIONS.get_mut(&session_id) {
-                if entry.connections > 1 {
-                    entry.connections -= 1;
-                    println!(
-                        "Disconnect decremented (session_id={}, connections={})",
-                        session_id, entry.connections
-                    );
-                    false
-                } else {
-                    true
+            match ACTIVE_SESSIONS.disconnect(&session_id) {
+                Some(0) => {
+                    ACTIVE_USERS.fetch_sub(1, Ordering::SeqCst);
+                    println!("Disconnect removed session (session_id={})", session_id);
                 }
-            } else {
-                false
-            };
-
-            if should_remove {
-                ACTIVE_SESSIONS.remove(&session_id);
-                ACTIVE_USERS.fetch_sub(1, Ordering::SeqCst);
-                println!("Disconnect removed session (session_id={})", session_id);
+                Some(connections) => println!(
+                    "Disconnect decremented (session_id={}, connections={})",
+                    session_id, connections
+                ),
+                None => {}
             }
             println!(
                 "User disconnected, active users = {} (session_id={})",
@@ -1159,8 +1242,7 @@ async fn main() {
         }
     }
 
-    let postgres_store =
-        PostgresTimeSeriesStore::from_env().expect("PostgreSQL env vars are invalid");
+    let postgres_store = postgres_store::shared().expect("PostgreSQL env vars are invalid");
     let postgres_bucket = postgres_store.bucket().to_string();
 
     #[cfg(feature = "server")]
@@ -1259,26 +1341,32 @@ async fn main() {
         });
     }
 
-    // Initialize TCP connection with encryption key
-    if let (Ok(tcp_addr), Ok(tcp_key)) = (
-        std::env::var("TCP_SERVER_ADDR"),
-        std::env::var("TCP_ENCRYPTION_KEY"),
-    ) {
-        match tcp_client::connect(&tcp_addr, &tcp_key.trim()).await {
-            Ok(_) => println!("Connected to TCP server: {}", tcp_addr),
-            Err(e) => eprintln!("Failed to connect to TCP server: {}", e),
+    // Connect to every configured device; each keeps reconnecting on its own
+    match tcp_client::DeviceConfig::all_from_env() {
+        Ok(configs) => {
+            for config in configs {
+                let (id, addr) = (config.id.clone(), config.addr.clone());
+                match tcp_client::connect(config).await {
+                    Ok(_) => println!("Connected to device '{}' at {}", id, addr),
+                    Err(e) => eprintln!("Failed to connect to device '{}': {}", id, e),
+                }
+            }
         }
+        Err(e) => eprintln!("Invalid device configuration: {}", e),
     }
 
-    tokio::spawn(async {
-        let mut rx = tcp_client::subscribe_to_tcp_messages();
-        while let Ok(message) = rx.recv().await {
-            let _ = TCP_BROADCAST.send(message);
-        }
-    });
+    command_queue::spawn_flusher();
+
+    // A single upstream subscription to the Pi's spectrogram, shared by all viewers
+    spectrogram_relay::spawn_upstream(
+        std::env::var("WEBSOCKET_URL").unwrap_or_else(|_| "ws://127.0.0.1:8000/ws".to_string()),
+    );
+
+    let write_buffer = write_buffer::shared().expect("PostgreSQL env vars are invalid");
+    write_buffer.clone().spawn_flusher();
+    retention::spawn_compactor(postgres_store.clone());
 
     tokio::spawn({
-        let store = postgres_store.clone();
         let bucket = postgres_bucket.clone();
 
         async move {
@@ -1288,48 +1376,23 @@ async fn main() {
                 ticker.tick().await;
 
                 let users = ACTIVE_USERS.load(Ordering::Relaxed);
-
-                if let Err(e) = store
-                    .write_field(
-                        "voegeli",
-                        "visitors",
-                        TimeSeriesValue::Double(users as f64),
-                        None,
-                        None,
-                        None,
-                        Some(&bucket),
-                    )
-                    .await
-                {
-                    eprintln!("PostgreSQL write failed: {}", e);
-                }
+                write_buffer.push(Point::now(
+                    &bucket,
+                    "voegeli",
+                    "visitors",
+                    TimeSeriesValue::Double(users as f64),
+                ));
             }
         }
     });
 
     tokio::spawn(async {
-        const SESSION_TTL_SECS: i64 = 90;
         let mut ticker = interval(Duration::from_secs(5));
 
         loop {
             ticker.tick().await;
 
-            let now = chrono::Utc::now().timestamp();
-            let mut removed = 0usize;
-            let mut stale_keys = Vec::new();
-
-            for entry in ACTIVE_SESSIONS.iter() {
-                if now - entry.value().last_seen > SESSION_TTL_SECS {
-                    stale_keys.push(entry.key().clone());
-                }
-            }
-
-            for key in stale_keys {
-                if ACTIVE_SESSIONS.remove(&key).is_some() {
-                    removed += 1;
-                }
-            }
-
+            let removed = ACTIVE_SESSIONS.prune();
             if removed > 0 {
                 let current = ACTIVE_USERS.load(Ordering::SeqCst);
                 let to_sub = removed.min(current);
@@ -1386,6 +1449,15 @@ async fn main() {
             "/api/admin/gallery-download-selection",
             get(admin_download_gallery_selection),
         )
+        .route("/api/admin/database-stats", get(admin_database_stats))
+        .route("/api/history/range", get(history_range))
+        .route("/api/history/aggregate", get(history_aggregate))
+        .route("/api/export", get(export_history))
+        .route("/api/ingest", post(ingest))
+        .route(
+            "/api/v2/write",
+            post(influx_write).layer(tower_http::decompression::RequestDecompressionLayer::new()),
+        )
         .route("/api/stream-proxy/{*path}", get(stream_proxy))
         .route("/voegeli", get(|| async { Redirect::temporary("/") }))
         .route("/unsubscribe/{encoded_email}/", get(redirect_unsubscribe))
@@ -1398,6 +1470,7 @@ async fn main() {
             get(redirect_unsubscribe),
         )
         .route("/ws/tcp", get(tcp_websocket_handler))
+        .route("/ws/spectrogram", get(spectrogram_relay::websocket_handler))
         //.nest_service("/assets", ServeDir::new("public/assets"))
         .nest_service("/gallery-assets", ServeDir::new("gallery"))
         .serve_dioxus_application(ServeConfig::default(), App);
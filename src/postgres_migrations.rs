@@ -0,0 +1,361 @@
+#![cfg(feature = "server")]
+
+//! Versioned schema changes for the time-series table. Applied versions are
+//! recorded in `<table>_migrations`; each migration runs in its own
+//! transaction under an advisory lock, so several instances starting at once
+//! apply it exactly once.
+
+use tokio_postgres::{Client, GenericClient};
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    /// Statements with `{table}` standing for the table name.
+    pub statements: &'static [&'static str],
+}
+
+/// Append only: never edit or reorder a migration once it shipped.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_points_table",
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS {table} (
+                id BIGSERIAL PRIMARY KEY,
+                bucket TEXT NOT NULL,
+                ts TIMESTAMPTZ NOT NULL,
+                measurement TEXT NOT NULL,
+                field TEXT NOT NULL,
+                value_double DOUBLE PRECISION,
+                value_bool BOOLEAN,
+                value_text TEXT,
+                unit TEXT,
+                location TEXT,
+                type TEXT,
+                CHECK (num_nonnulls(value_double, value_bool, value_text) = 1)
+            )",
+            "CREATE INDEX IF NOT EXISTS {table}_ts_idx ON {table} (ts DESC)",
+            "CREATE INDEX IF NOT EXISTS {table}_measurement_field_ts_idx
+                ON {table} (measurement, field, ts DESC)",
+        ],
+    },
+    Migration {
+        version: 2,
+        name: "bucket_field_ts_index",
+        statements: &["CREATE INDEX IF NOT EXISTS {table}_bucket_field_ts_idx
+            ON {table} (bucket, field, ts DESC)"],
+    },
+    Migration {
+        version: 3,
+        name: "brin_ts_index",
+        // Rows arrive in time order, so a BRIN index stays tiny even after
+        // years of data and still narrows range scans down to a few pages.
+        statements: &["CREATE INDEX IF NOT EXISTS {table}_ts_brin_idx
+            ON {table} USING BRIN (ts) WITH (pages_per_range = 32)"],
+    },
+    Migration {
+        version: 4,
+        name: "rollups_and_compactions",
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS {table}_rollups (
+                bucket TEXT NOT NULL,
+                measurement TEXT NOT NULL,
+                field TEXT NOT NULL,
+                resolution_secs BIGINT NOT NULL,
+                bucket_start TIMESTAMPTZ NOT NULL,
+                count BIGINT NOT NULL,
+                value_min DOUBLE PRECISION NOT NULL,
+                value_max DOUBLE PRECISION NOT NULL,
+                value_mean DOUBLE PRECISION NOT NULL,
+                value_last DOUBLE PRECISION NOT NULL,
+                last_ts TIMESTAMPTZ NOT NULL,
+                PRIMARY KEY (bucket, measurement, field, resolution_secs, bucket_start)
+            )",
+            "CREATE INDEX IF NOT EXISTS {table}_rollups_start_brin_idx
+                ON {table}_rollups USING BRIN (bucket_start)",
+            "CREATE TABLE IF NOT EXISTS {table}_compactions (
+                id BIGSERIAL PRIMARY KEY,
+                started_at TIMESTAMPTZ NOT NULL,
+                finished_at TIMESTAMPTZ NOT NULL,
+                deleted_raw BIGINT NOT NULL,
+                rolled_up BIGINT NOT NULL,
+                expired_rollups BIGINT NOT NULL,
+                error TEXT
+            )",
+        ],
+    },
+];
+
+/// Whether to turn the table into a TimescaleDB hypertable. Converting
+/// rewrites the primary key and the whole table, so it is opt-in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimescaleMode {
+    /// Convert when the extension is installed in the database.
+    On,
+    Off,
+}
+
+impl TimescaleMode {
+    pub fn from_env() -> Self {
+        match std::env::var("POSTGRES_TIMESCALEDB")
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "1" | "true" | "yes" | "on" => Self::On,
+            _ => Self::Off,
+        }
+    }
+}
+
+pub fn render(statement: &str, table: &str) -> String {
+    statement.replace("{table}", table)
+}
+
+fn history_table(table: &str) -> String {
+    format!("{}_migrations", table)
+}
+
+/// The migrations still to apply, given the `(version, name)` pairs already
+/// recorded. Fails if a recorded version was renamed, which means the code
+/// and the database disagree about what that version did.
+pub fn pending<'a>(
+    migrations: &'a [Migration],
+    applied: &[(i64, String)],
+) -> Result<Vec<&'a Migration>, String> {
+    for (version, name) in applied {
+        if let Some(known) = migrations.iter().find(|m| m.version == *version) {
+            if known.name != name {
+                return Err(format!(
+                    "Migration {} is recorded as '{}' but this build calls it '{}'.",
+                    version, name, known.name
+                ));
+            }
+        }
+    }
+    Ok(migrations
+        .iter()
+        .filter(|m| !applied.iter().any(|(version, _)| *version == m.version))
+        .collect())
+}
+
+async fn applied(client: &impl GenericClient, table: &str) -> Result<Vec<(i64, String)>, String> {
+    let sql = format!(
+        "SELECT version, name FROM {} ORDER BY version",
+        history_table(table)
+    );
+    let rows = client
+        .query(&sql, &[])
+        .await
+        .map_err(|e| format!("Failed to read migration history: {e}"))?;
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+async fn ensure_history_table(client: &Client, table: &str) -> Result<(), String> {
+    let sql = format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+            version BIGINT PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        )",
+        history_table(table)
+    );
+    client
+        .execute(&sql, &[])
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Failed to create migration history table: {e}"))
+}
+
+/// Key for the advisory lock, so only one instance migrates a table at once.
+fn lock_key(table: &str) -> i64 {
+    table.bytes().fold(0x6269_7264_i64, |key, b| {
+        key.wrapping_mul(31).wrapping_add(b as i64)
+    })
+}
+
+/// Applies every pending migration, then converts the table to a hypertable
+/// if `timescale` asks for it. A failed conversion only logs a warning: the
+/// plain table works all the same. Returns the versions applied.
+pub async fn run(
+    client: &mut Client,
+    table: &str,
+    timescale: TimescaleMode,
+) -> Result<Vec<i64>, String> {
+    ensure_history_table(client, table).await?;
+    let mut done = Vec::new();
+
+    for migration in MIGRATIONS {
+        let tx = client
+            .transaction()
+            .await
+            .map_err(|e| format!("Failed to start migration: {e}"))?;
+        tx.execute("SELECT pg_advisory_xact_lock($1)", &[&lock_key(table)])
+            .await
+            .map_err(|e| format!("Failed to take the migration lock: {e}"))?;
+
+        // Re-read under the lock: another instance may have just applied it.
+        let recorded = applied(&tx, table).await?;
+        if pending(std::slice::from_ref(migration), &recorded)?.is_empty() {
+            continue;
+        }
+
+        for statement in migration.statements {
+            tx.batch_execute(&render(statement, table))
+                .await
+                .map_err(|e| {
+                    format!(
+                        "Migration {} ({}) failed: {e}",
+                        migration.version, migration.name
+                    )
+                })?;
+        }
+        tx.execute(
+            &format!(
+                "INSERT INTO {} (version, name) VALUES ($1, $2)",
+                history_table(table)
+            ),
+            &[&migration.version, &migration.name],
+        )
+        .await
+        .map_err(|e| format!("Failed to record migration {}: {e}", migration.version))?;
+        tx.commit()
+            .await
+            .map_err(|e| format!("Failed to commit migration {}: {e}", migration.version))?;
+
+        println!(
+            "Applied PostgreSQL migration {} ({}) to {}",
+            migration.version, migration.name, table
+        );
+        done.push(migration.version);
+    }
+
+    if timescale == TimescaleMode::On {
+        if let Err(e) = ensure_hypertable(client, table).await {
+            eprintln!("Warning: keeping {} a plain table: {}", table, e);
+        }
+    }
+    Ok(done)
+}
+
+/// The pending migrations, without applying them. Used when
+/// `POSTGRES_AUTO_CREATE` is off.
+pub async fn check(client: &Client, table: &str) -> Result<Vec<&'static Migration>, String> {
+    let exists: bool = client
+        .query_one(
+            "SELECT to_regclass($1) IS NOT NULL",
+            &[&history_table(table)],
+        )
+        .await
+        .map_err(|e| format!("Failed to look up migration history: {e}"))?
+        .get(0);
+    let recorded = if exists {
+        applied(client, table).await?
+    } else {
+        Vec::new()
+    };
+    pending(MIGRATIONS, &recorded)
+}
+
+/// Converts the table to a hypertable unless it is one already. Fails when
+/// TimescaleDB is not installed. Unique indexes of a hypertable must contain the time
+/// column, so the primary key becomes `(id, ts)`.
+async fn ensure_hypertable(client: &mut Client, table: &str) -> Result<(), String> {
+    let installed = client
+        .query_opt(
+            "SELECT 1 FROM pg_extension WHERE extname = 'timescaledb'",
+            &[],
+        )
+        .await
+        .map_err(|e| format!("Failed to look up TimescaleDB: {e}"))?
+        .is_some();
+    if !installed {
+        return Err("the timescaledb extension is not installed".to_string());
+    }
+
+    let converted = client
+        .query_opt(
+            "SELECT 1 FROM timescaledb_information.hypertables WHERE hypertable_name = $1",
+            &[&table],
+        )
+        .await
+        .map_err(|e| format!("Failed to look up hypertables: {e}"))?
+        .is_some();
+    if converted {
+        return Ok(());
+    }
+
+    let tx = client
+        .transaction()
+        .await
+        .map_err(|e| format!("Failed to start hypertable conversion: {e}"))?;
+    tx.execute("SELECT pg_advisory_xact_lock($1)", &[&lock_key(table)])
+        .await
+        .map_err(|e| format!("Failed to take the migration lock: {e}"))?;
+    tx.batch_execute(&render(
+        "ALTER TABLE {table} DROP CONSTRAINT IF EXISTS {table}_pkey;
+         ALTER TABLE {table} ADD PRIMARY KEY (id, ts);",
+        table,
+    ))
+    .await
+    .map_err(|e| format!("Failed to prepare {} for TimescaleDB: {e}", table))?;
+    tx.execute(
+        "SELECT create_hypertable($1::text::regclass, 'ts', migrate_data => true, if_not_exists => true)",
+        &[&table],
+    )
+    .await
+    .map_err(|e| format!("Failed to convert {} to a hypertable: {e}", table))?;
+    tx.commit()
+        .await
+        .map_err(|e| format!("Failed to commit hypertable conversion: {e}"))?;
+
+    println!("Converted {} to a TimescaleDB hypertable", table);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pending, render, Migration, MIGRATIONS};
+
+    #[test]
+    fn test_migrations_are_ordered_and_unique() {
+        for pair in MIGRATIONS.windows(2) {
+            assert!(pair[0].version < pair[1].version);
+            assert_ne!(pair[0].name, pair[1].name);
+        }
+        for migration in MIGRATIONS {
+            for statement in migration.statements {
+                assert!(!render(statement, "points").contains("{table}"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_pending_skips_applied_and_detects_drift() {
+        let migrations = [
+            Migration {
+                version: 1,
+                name: "one",
+                statements: &[],
+            },
+            Migration {
+                version: 2,
+                name: "two",
+                statements: &[],
+            },
+        ];
+
+        let all = pending(&migrations, &[]).unwrap();
+        assert_eq!(all.len(), 2);
+
+        let rest = pending(&migrations, &[(1, "one".to_string())]).unwrap();
+        assert_eq!(rest.iter().map(|m| m.version).collect::<Vec<_>>(), [2]);
+
+        // A version from a newer build is left alone.
+        let rest = pending(&migrations, &[(1, "one".to_string()), (9, "x".to_string())]).unwrap();
+        assert_eq!(rest.len(), 1);
+
+        assert!(pending(&migrations, &[(1, "uno".to_string())]).is_err());
+    }
+}
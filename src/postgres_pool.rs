@@ -0,0 +1,395 @@
+#![cfg(feature = "server")]
+
+//! A bounded pool of PostgreSQL connections. Connections are opened lazily,
+//! checked with a round trip when they sat idle for a while and dropped when
+//! they break, so callers reconnect transparently after an outage.
+
+use serde::Serialize;
+use std::future::Future;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::timeout;
+
+/// Opens and checks the pooled connections.
+pub trait Connector: Send + Sync + 'static {
+    type Conn: Send + Sync + 'static;
+
+    fn connect(&self) -> impl Future<Output = Result<Self::Conn, String>> + Send;
+
+    /// A cheap round trip, used on connections that sat idle.
+    fn ping(&self, conn: &Self::Conn) -> impl Future<Output = Result<(), String>> + Send;
+
+    fn is_closed(&self, conn: &Self::Conn) -> bool;
+}
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub max_size: usize,
+    /// How long a caller waits for a free connection.
+    pub acquire_timeout: Duration,
+    /// How long a single query (or health check) may take.
+    pub query_timeout: Duration,
+    /// Idle connections older than this are pinged before being handed out.
+    pub check_idle_after: Duration,
+}
+
+impl PoolConfig {
+    pub fn from_env() -> Self {
+        let secs = |name: &str, default: u64| {
+            Duration::from_secs(
+                std::env::var(name)
+                    .ok()
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .unwrap_or(default),
+            )
+        };
+        Self {
+            max_size: std::env::var("POSTGRES_POOL_SIZE")
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+                .filter(|size| *size > 0)
+                .unwrap_or(4),
+            acquire_timeout: secs("POSTGRES_ACQUIRE_TIMEOUT", 5),
+            query_timeout: secs("POSTGRES_QUERY_TIMEOUT", 10),
+            check_idle_after: secs("POSTGRES_IDLE_CHECK_AFTER", 30),
+        }
+    }
+}
+
+/// A snapshot of the pool's state and counters since startup.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PoolStats {
+    pub max_size: usize,
+    pub in_use: usize,
+    pub idle: usize,
+    pub acquired: u64,
+    pub mean_wait_ms: f64,
+    pub max_wait_ms: f64,
+    pub acquire_timeouts: u64,
+    pub connects: u64,
+    pub connect_failures: u64,
+    pub failed_health_checks: u64,
+    pub query_timeouts: u64,
+}
+
+#[derive(Default)]
+struct Metrics {
+    acquired: AtomicU64,
+    wait_micros_total: AtomicU64,
+    wait_micros_max: AtomicU64,
+    acquire_timeouts: AtomicU64,
+    connects: AtomicU64,
+    connect_failures: AtomicU64,
+    failed_health_checks: AtomicU64,
+    query_timeouts: AtomicU64,
+}
+
+impl Metrics {
+    fn record_wait(&self, waited: Duration) {
+        let micros = waited.as_micros().min(u64::MAX as u128) as u64;
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+        self.wait_micros_total.fetch_add(micros, Ordering::Relaxed);
+        self.wait_micros_max.fetch_max(micros, Ordering::Relaxed);
+    }
+}
+
+struct IdleConn<C> {
+    conn: C,
+    since: Instant,
+}
+
+struct PoolInner<M: Connector> {
+    connector: M,
+    config: PoolConfig,
+    permits: Arc<Semaphore>,
+    idle: Mutex<Vec<IdleConn<M::Conn>>>,
+    metrics: Metrics,
+}
+
+impl<M: Connector> PoolInner<M> {
+    fn idle(&self) -> std::sync::MutexGuard<'_, Vec<IdleConn<M::Conn>>> {
+        self.idle.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+pub struct Pool<M: Connector> {
+    inner: Arc<PoolInner<M>>,
+}
+
+impl<M: Connector> Clone for Pool<M> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<M: Connector> Pool<M> {
+    pub fn new(connector: M, config: PoolConfig) -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                connector,
+                permits: Arc::new(Semaphore::new(config.max_size)),
+                config,
+                idle: Mutex::new(Vec::new()),
+                metrics: Metrics::default(),
+            }),
+        }
+    }
+
+    pub fn connector(&self) -> &M {
+        &self.inner.connector
+    }
+
+    /// Waits for a free slot, then hands out an idle connection or opens a
+    /// new one.
+    pub async fn get(&self) -> Result<PooledConn<M>, String> {
+        let started = Instant::now();
+        let permit = match timeout(
+            self.inner.config.acquire_timeout,
+            Arc::clone(&self.inner.permits).acquire_owned(),
+        )
+        .await
+        {
+            Ok(Ok(permit)) => permit,
+            Ok(Err(_)) => return Err("PostgreSQL pool is closed.".to_string()),
+            Err(_) => {
+                self.inner
+                    .metrics
+                    .acquire_timeouts
+                    .fetch_add(1, Ordering::Relaxed);
+                return Err(format!(
+                    "Timed out after {}s waiting for a PostgreSQL connection.",
+                    self.inner.config.acquire_timeout.as_secs_f64()
+                ));
+            }
+        };
+        self.inner.metrics.record_wait(started.elapsed());
+
+        let conn = self.checkout().await?;
+        Ok(PooledConn {
+            conn: Some(conn),
+            pool: Arc::clone(&self.inner),
+            broken: AtomicBool::new(false),
+            _permit: permit,
+        })
+    }
+
+    async fn checkout(&self) -> Result<M::Conn, String> {
+        let inner = &self.inner;
+        loop {
+            let Some(idle) = inner.idle().pop() else {
+                break;
+            };
+            if inner.connector.is_closed(&idle.conn) {
+                continue;
+            }
+            if idle.since.elapsed() < inner.config.check_idle_after {
+                return Ok(idle.conn);
+            }
+            match timeout(inner.config.query_timeout, inner.connector.ping(&idle.conn)).await {
+                Ok(Ok(())) => return Ok(idle.conn),
+                _ => {
+                    inner
+                        .metrics
+                        .failed_health_checks
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        match inner.connector.connect().await {
+            Ok(conn) => {
+                inner.metrics.connects.fetch_add(1, Ordering::Relaxed);
+                Ok(conn)
+            }
+            Err(e) => {
+                inner
+                    .metrics
+                    .connect_failures
+                    .fetch_add(1, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let metrics = &self.inner.metrics;
+        let acquired = metrics.acquired.load(Ordering::Relaxed);
+        let wait_total = metrics.wait_micros_total.load(Ordering::Relaxed);
+        PoolStats {
+            max_size: self.inner.config.max_size,
+            in_use: self.inner.config.max_size - self.inner.permits.available_permits(),
+            idle: self.inner.idle().len(),
+            acquired,
+            mean_wait_ms: if acquired == 0 {
+                0.0
+            } else {
+                wait_total as f64 / acquired as f64 / 1000.0
+            },
+            max_wait_ms: metrics.wait_micros_max.load(Ordering::Relaxed) as f64 / 1000.0,
+            acquire_timeouts: metrics.acquire_timeouts.load(Ordering::Relaxed),
+            connects: metrics.connects.load(Ordering::Relaxed),
+            connect_failures: metrics.connect_failures.load(Ordering::Relaxed),
+            failed_health_checks: metrics.failed_health_checks.load(Ordering::Relaxed),
+            query_timeouts: metrics.query_timeouts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A checked-out connection. It goes back to the pool when dropped unless it
+/// closed or a query on it timed out.
+pub struct PooledConn<M: Connector> {
+    conn: Option<M::Conn>,
+    pool: Arc<PoolInner<M>>,
+    broken: AtomicBool,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl<M: Connector> PooledConn<M> {
+    /// Runs `query` under the pool's query timeout. A timed-out connection
+    /// is discarded, since the query may still be running on it.
+    pub async fn timed<T>(
+        &self,
+        query: impl Future<Output = Result<T, String>>,
+    ) -> Result<T, String> {
+        match timeout(self.pool.config.query_timeout, query).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.broken.store(true, Ordering::Relaxed);
+                self.pool
+                    .metrics
+                    .query_timeouts
+                    .fetch_add(1, Ordering::Relaxed);
+                Err(format!(
+                    "PostgreSQL query timed out after {}s.",
+                    self.pool.config.query_timeout.as_secs_f64()
+                ))
+            }
+        }
+    }
+}
+
+impl<M: Connector> Deref for PooledConn<M> {
+    type Target = M::Conn;
+
+    fn deref(&self) -> &Self::Target {
+        self.conn
+            .as_ref()
+            .expect("connection is present until drop")
+    }
+}
+
+impl<M: Connector> Drop for PooledConn<M> {
+    fn drop(&mut self) {
+        let Some(conn) = self.conn.take() else {
+            return;
+        };
+        if self.broken.load(Ordering::Relaxed) || self.pool.connector.is_closed(&conn) {
+            return;
+        }
+        self.pool.idle().push(IdleConn {
+            conn,
+            since: Instant::now(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Connector, Pool, PoolConfig};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct FakeConnector {
+        opened: AtomicUsize,
+        healthy: Arc<AtomicBool>,
+    }
+
+    struct FakeConn {
+        id: usize,
+        healthy: Arc<AtomicBool>,
+    }
+
+    impl Connector for FakeConnector {
+        type Conn = FakeConn;
+
+        async fn connect(&self) -> Result<FakeConn, String> {
+            Ok(FakeConn {
+                id: self.opened.fetch_add(1, Ordering::SeqCst),
+                healthy: Arc::clone(&self.healthy),
+            })
+        }
+
+        async fn ping(&self, conn: &FakeConn) -> Result<(), String> {
+            if conn.healthy.load(Ordering::SeqCst) {
+                Ok(())
+            } else {
+                Err("gone".to_string())
+            }
+        }
+
+        fn is_closed(&self, _conn: &FakeConn) -> bool {
+            false
+        }
+    }
+
+    fn config(max_size: usize) -> PoolConfig {
+        PoolConfig {
+            max_size,
+            acquire_timeout: Duration::from_millis(50),
+            query_timeout: Duration::from_millis(50),
+            check_idle_after: Duration::from_secs(60),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pool_is_bounded_and_reuses_connections() {
+        let pool = Pool::new(FakeConnector::default(), config(2));
+
+        let a = pool.get().await.unwrap();
+        let b = pool.get().await.unwrap();
+        assert_ne!(a.id, b.id);
+        assert!(pool.get().await.is_err());
+        assert_eq!(pool.stats().acquire_timeouts, 1);
+        assert_eq!(pool.stats().in_use, 2);
+
+        let reused = a.id;
+        drop(a);
+        assert_eq!(pool.get().await.unwrap().id, reused);
+        drop(b);
+
+        let stats = pool.stats();
+        assert_eq!((stats.in_use, stats.idle, stats.connects), (0, 2, 2));
+        assert_eq!(stats.acquired, 3);
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_and_unhealthy_connections_are_replaced() {
+        let connector = FakeConnector::default();
+        connector.healthy.store(true, Ordering::SeqCst);
+        let healthy = Arc::clone(&connector.healthy);
+        let mut cfg = config(1);
+        cfg.check_idle_after = Duration::ZERO;
+        let pool = Pool::new(connector, cfg);
+
+        let conn = pool.get().await.unwrap();
+        let slow = conn.timed(async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok(())
+        });
+        assert!(slow.await.is_err());
+        drop(conn);
+        assert_eq!(pool.stats().query_timeouts, 1);
+        assert_eq!(pool.get().await.unwrap().id, 1);
+
+        healthy.store(false, Ordering::SeqCst);
+        assert_eq!(pool.get().await.unwrap().id, 2);
+        assert_eq!(pool.stats().failed_health_checks, 1);
+    }
+}
@@ -1,30 +1,206 @@
 #![cfg(feature = "server")]
 
-use chrono::{Duration as ChronoDuration, Utc};
+use crate::export::ExportQuery;
+use crate::postgres_migrations::{self, TimescaleMode};
+use crate::postgres_pool::{Connector, Pool, PoolConfig, PoolStats, PooledConn};
+use crate::retention::{CompactionRun, TableSize};
+use crate::time_series::{Aggregate, AggregatePoint, RangePoint};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::fmt;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::Mutex;
-use tokio_postgres::{Client, NoTls};
-
-#[derive(Debug, Clone)]
-pub enum TimeSeriesValue {
-    Double(f64),
-    Bool(bool),
-    Text(String),
+use tokio::sync::OnceCell;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Client, NoTls, Row};
+
+pub use crate::time_series::TimeSeriesValue;
+
+/// Most rows a single range query returns.
+pub const MAX_RANGE_ROWS: i64 = 10_000;
+/// Most buckets a single aggregate query may span.
+pub const MAX_AGGREGATE_BUCKETS: i64 = 10_000;
+/// Raw rows are compacted one window of this length at a time, so a first
+/// run over years of data does not become one huge transaction.
+const COMPACTION_STEP_SECS: i64 = 86_400;
+/// Rows per multi-row INSERT. At ten parameters a row this stays well below
+/// PostgreSQL's limit of 65535 parameters per statement.
+pub const INSERT_BATCH_ROWS: usize = 1_000;
+const INSERT_COLUMNS: usize = 10;
+
+/// Why [`PostgresTimeSeriesStore::insert_points`] failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InsertError {
+    /// PostgreSQL could not be reached, timed out or is temporarily unable
+    /// to work. The same rows may go through later.
+    Unavailable(String),
+    /// PostgreSQL refused the rows themselves, e.g. a NUL byte in a TEXT
+    /// column. Sending them again fails the same way.
+    Rejected(String),
 }
 
-impl TimeSeriesValue {
-    fn split(self) -> (Option<f64>, Option<bool>, Option<String>) {
+impl fmt::Display for InsertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Double(v) => (Some(v), None, None),
-            Self::Bool(v) => (None, Some(v), None),
-            Self::Text(v) => (None, None, Some(v)),
+            Self::Unavailable(e) => write!(f, "{}", e),
+            Self::Rejected(e) => write!(f, "PostgreSQL rejected the insert: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for InsertError {}
+
+/// Sorts a failed INSERT. Errors PostgreSQL reports about the statement are
+/// rejections, except for the SQLSTATE classes that describe the server's
+/// state rather than the rows: connection exceptions (08), rollbacks (40),
+/// insufficient resources (53) and operator intervention (57).
+fn classify_insert_error(e: tokio_postgres::Error) -> InsertError {
+    match e.as_db_error() {
+        Some(db) if !matches!(&db.code().code()[..2], "08" | "40" | "53" | "57") => {
+            InsertError::Rejected(db.to_string())
+        }
+        _ => InsertError::Unavailable(format!("PostgreSQL insert failed: {e}")),
+    }
+}
+
+static SHARED_STORE: Lazy<Result<PostgresTimeSeriesStore, String>> =
+    Lazy::new(PostgresTimeSeriesStore::from_env);
+
+/// The store configured from the environment, shared by the background
+/// writers and the history endpoints so they use one connection.
+pub fn shared() -> Result<PostgresTimeSeriesStore, String> {
+    SHARED_STORE.clone()
+}
+
+/// One row to insert.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Point {
+    pub bucket: String,
+    #[serde(with = "chrono::serde::ts_microseconds")]
+    pub ts: DateTime<Utc>,
+    pub measurement: String,
+    pub field: String,
+    pub value: TimeSeriesValue,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value_type: Option<String>,
+}
+
+impl Point {
+    /// A point stamped with the current time, without unit, location or type.
+    pub fn now(bucket: &str, measurement: &str, field: &str, value: TimeSeriesValue) -> Self {
+        Self {
+            bucket: bucket.to_string(),
+            ts: Utc::now(),
+            measurement: measurement.to_string(),
+            field: field.to_string(),
+            value,
+            unit: None,
+            location: None,
+            value_type: None,
         }
     }
 }
 
+fn split_value(value: TimeSeriesValue) -> (Option<f64>, Option<bool>, Option<String>) {
+    match value {
+        TimeSeriesValue::Double(v) => (Some(v), None, None),
+        TimeSeriesValue::Bool(v) => (None, Some(v), None),
+        TimeSeriesValue::Text(v) => (None, None, Some(v)),
+    }
+}
+
+/// Reads `value_double, value_bool, value_text` starting at column `first`.
+fn value_from_row(row: &Row, first: usize) -> Option<TimeSeriesValue> {
+    let value_double: Option<f64> = row.get(first);
+    let value_bool: Option<bool> = row.get(first + 1);
+    let value_text: Option<String> = row.get(first + 2);
+
+    if let Some(v) = value_double {
+        return Some(TimeSeriesValue::Double(v));
+    }
+    if let Some(v) = value_bool {
+        return Some(TimeSeriesValue::Bool(v));
+    }
+    value_text.map(TimeSeriesValue::Text)
+}
+
+/// The SQL that combines one bucket's rows for `agg`. Raw points and
+/// rollups are both read as `(at, count, value_min, value_max, value_sum,
+/// value_last)`, a raw point being a rollup of one.
+fn aggregate_sql(agg: Aggregate) -> &'static str {
+    match agg {
+        Aggregate::Min => "MIN(value_min)",
+        Aggregate::Max => "MAX(value_max)",
+        Aggregate::Mean => "SUM(value_sum) / NULLIF(SUM(count), 0)",
+        Aggregate::Last => "(ARRAY_AGG(value_last ORDER BY at DESC))[1]",
+        Aggregate::Count => "SUM(count)::DOUBLE PRECISION",
+    }
+}
+
+/// Checks a `window`/`bucket_size` pair and returns both in seconds.
+pub fn aggregate_span(window: &str, bucket_size: &str) -> Result<(i64, i64), String> {
+    let window_secs = parse_duration(window)?.num_seconds();
+    let bucket_secs = parse_duration(bucket_size)?.num_seconds();
+    if window_secs <= 0 || bucket_secs <= 0 {
+        return Err("Window and bucket size must be positive.".to_string());
+    }
+    if window_secs / bucket_secs > MAX_AGGREGATE_BUCKETS {
+        return Err(format!(
+            "A {} window in {} buckets is more than {} buckets.",
+            window, bucket_size, MAX_AGGREGATE_BUCKETS
+        ));
+    }
+    Ok((window_secs, bucket_secs))
+}
+
+/// Opens plain-TCP connections from a DSN.
+pub struct PgConnector {
+    dsn: String,
+    connect_timeout_s: u64,
+}
+
+impl Connector for PgConnector {
+    type Conn = Client;
+
+    async fn connect(&self) -> Result<Client, String> {
+        let mut cfg = tokio_postgres::Config::from_str(&self.dsn)
+            .map_err(|e| format!("Invalid PostgreSQL DSN: {e}"))?;
+        cfg.connect_timeout(Duration::from_secs(self.connect_timeout_s));
+
+        let (client, connection) = cfg
+            .connect(NoTls)
+            .await
+            .map_err(|e| format!("Failed to connect to PostgreSQL: {e}"))?;
+
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                eprintln!("PostgreSQL connection task failed: {e}");
+            }
+        });
+
+        Ok(client)
+    }
+
+    async fn ping(&self, client: &Client) -> Result<(), String> {
+        client
+            .simple_query("SELECT 1")
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("PostgreSQL health check failed: {e}"))
+    }
+
+    fn is_closed(&self, client: &Client) -> bool {
+        client.is_closed()
+    }
+}
+
 #[derive(Clone)]
 pub struct PostgresTimeSeriesStore {
     inner: Arc<Inner>,
@@ -33,10 +209,14 @@ pub struct PostgresTimeSeriesStore {
 struct Inner {
     table: String,
     bucket: String,
-    dsn: String,
     auto_create: bool,
-    connect_timeout_s: u64,
-    connection: Mutex<Option<Arc<Client>>>,
+    timescale: TimescaleMode,
+    pool: Pool<PgConnector>,
+    /// Set once migrations have run; they are not repeated on reconnects.
+    schema_ready: OnceCell<()>,
+    /// How long queries wait for migrations before failing; the next query
+    /// tries again.
+    migration_timeout: Duration,
 }
 
 impl PostgresTimeSeriesStore {
@@ -58,15 +238,26 @@ impl PostgresTimeSeriesStore {
             .ok()
             .and_then(|v| v.parse::<u64>().ok())
             .unwrap_or(5);
+        let migration_timeout_s = std::env::var("POSTGRES_MIGRATION_TIMEOUT")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(120);
 
         Ok(Self {
             inner: Arc::new(Inner {
                 table,
                 bucket,
-                dsn,
                 auto_create,
-                connect_timeout_s,
-                connection: Mutex::new(None),
+                timescale: TimescaleMode::from_env(),
+                pool: Pool::new(
+                    PgConnector {
+                        dsn,
+                        connect_timeout_s,
+                    },
+                    PoolConfig::from_env(),
+                ),
+                schema_ready: OnceCell::new(),
+                migration_timeout: Duration::from_secs(migration_timeout_s),
             }),
         })
     }
@@ -75,118 +266,44 @@ impl PostgresTimeSeriesStore {
         &self.inner.bucket
     }
 
-    pub async fn close(&self) {
-        let mut lock = self.inner.connection.lock().await;
-        *lock = None;
-    }
-
-    pub async fn write_field(
-        &self,
-        measurement: &str,
-        field: &str,
-        value: TimeSeriesValue,
-        unit: Option<&str>,
-        location: Option<&str>,
-        value_type: Option<&str>,
-        bucket: Option<&str>,
-    ) -> Result<(), String> {
-        let client = self.ensure_connection().await?;
-        let ts = Utc::now();
-        let bucket_value = bucket.unwrap_or(&self.inner.bucket).to_string();
-        let unit_value = unit.map(str::to_string);
-        let location_value = location.map(str::to_string);
-        let type_value = value_type.map(str::to_string);
-        let (value_double, value_bool, value_text) = value.split();
-
-        client
-            .execute(
-                &self.insert_sql(),
-                &[
-                    &bucket_value,
-                    &ts,
-                    &measurement,
-                    &field,
-                    &value_double,
-                    &value_bool,
-                    &value_text,
-                    &unit_value,
-                    &location_value,
-                    &type_value,
-                ],
-            )
-            .await
-            .map_err(|e| format!("PostgreSQL insert failed: {e}"))?;
-
-        Ok(())
+    pub fn pool_stats(&self) -> PoolStats {
+        self.inner.pool.stats()
     }
 
-    pub async fn write_device_data(
-        &self,
-        device_data: &Value,
-        measurement: Option<&str>,
-    ) -> Result<(), String> {
-        let root = device_data
-            .as_object()
-            .ok_or_else(|| "device_data must be an object.".to_string())?;
-
-        let measurement_value = if let Some(m) = measurement {
-            m.to_string()
-        } else {
-            root.get("device")
-                .and_then(Value::as_str)
-                .filter(|v| !v.trim().is_empty())
-                .map(str::to_string)
-                .ok_or_else(|| "device_data must contain a non-empty 'device' value.".to_string())?
-        };
-
-        let data = root
-            .get("data")
-            .and_then(Value::as_object)
-            .ok_or_else(|| "device_data['data'] must be a dictionary/object.".to_string())?;
-
-        let ts = Utc::now();
-        let bucket = self.inner.bucket.clone();
-        let sql = self.insert_sql();
-        let client = self.ensure_connection().await?;
+    /// Inserts `points` with one multi-row INSERT per [`INSERT_BATCH_ROWS`].
+    /// Each batch is written completely or not at all.
+    pub async fn insert_points(&self, points: &[Point]) -> Result<(), InsertError> {
+        if points.is_empty() {
+            return Ok(());
+        }
+        let client = self.connection().await.map_err(InsertError::Unavailable)?;
 
-        for (field, value) in data {
-            if field.ends_with("_unit") || field.ends_with("_location") || field.ends_with("_type")
-            {
-                continue;
-            }
-            if value.is_null() {
-                continue;
+        for chunk in points.chunks(INSERT_BATCH_ROWS) {
+            let values: Vec<_> = chunk.iter().map(|p| split_value(p.value.clone())).collect();
+            let mut params: Vec<&(dyn ToSql + Sync)> =
+                Vec::with_capacity(chunk.len() * INSERT_COLUMNS);
+            for (point, (value_double, value_bool, value_text)) in chunk.iter().zip(&values) {
+                let row: [&(dyn ToSql + Sync); INSERT_COLUMNS] = [
+                    &point.bucket,
+                    &point.ts,
+                    &point.measurement,
+                    &point.field,
+                    value_double,
+                    value_bool,
+                    value_text,
+                    &point.unit,
+                    &point.location,
+                    &point.value_type,
+                ];
+                params.extend_from_slice(&row);
             }
 
-            let (value_double, value_bool, value_text) = split_json_value(value);
-            let unit = data
-                .get(&format!("{field}_unit"))
-                .and_then(json_value_to_string);
-            let location = data
-                .get(&format!("{field}_location"))
-                .and_then(json_value_to_string);
-            let value_type = data
-                .get(&format!("{field}_type"))
-                .and_then(json_value_to_string);
-
+            let sql = self.insert_sql(chunk.len());
             client
-                .execute(
-                    &sql,
-                    &[
-                        &bucket,
-                        &ts,
-                        &measurement_value,
-                        &field,
-                        &value_double,
-                        &value_bool,
-                        &value_text,
-                        &unit,
-                        &location,
-                        &value_type,
-                    ],
-                )
+                .timed(async { Ok(client.execute(&sql, &params).await) })
                 .await
-                .map_err(|e| format!("PostgreSQL insert failed: {e}"))?;
+                .map_err(InsertError::Unavailable)?
+                .map_err(classify_insert_error)?;
         }
 
         Ok(())
@@ -201,7 +318,7 @@ impl PostgresTimeSeriesStore {
     ) -> Result<Option<TimeSeriesValue>, String> {
         let since = Utc::now() - parse_duration(data_since)?;
         let query_bucket = bucket.unwrap_or(&self.inner.bucket);
-        let client = self.ensure_connection().await?;
+        let client = self.connection().await?;
 
         let row = if let Some(unit) = unit {
             let sql = format!(
@@ -216,9 +333,13 @@ impl PostgresTimeSeriesStore {
                 self.inner.table
             );
             client
-                .query_opt(&sql, &[&query_bucket, &field, &since, &unit])
-                .await
-                .map_err(|e| format!("PostgreSQL query failed: {e}"))?
+                .timed(async {
+                    client
+                        .query_opt(&sql, &[&query_bucket, &field, &since, &unit])
+                        .await
+                        .map_err(|e| format!("PostgreSQL query failed: {e}"))
+                })
+                .await?
         } else {
             let sql = format!(
                 "SELECT value_double, value_bool, value_text
@@ -231,27 +352,483 @@ impl PostgresTimeSeriesStore {
                 self.inner.table
             );
             client
-                .query_opt(&sql, &[&query_bucket, &field, &since])
-                .await
-                .map_err(|e| format!("PostgreSQL query failed: {e}"))?
+                .timed(async {
+                    client
+                        .query_opt(&sql, &[&query_bucket, &field, &since])
+                        .await
+                        .map_err(|e| format!("PostgreSQL query failed: {e}"))
+                })
+                .await?
         };
 
-        let Some(row) = row else {
-            return Ok(None);
+        Ok(row.and_then(|row| value_from_row(&row, 0)))
+    }
+
+    /// Values of `field` with `from <= ts < to`, oldest first, at most
+    /// [`MAX_RANGE_ROWS`]. Where retention has replaced raw points by
+    /// rollups, each rollup gives its mean at the start of its bucket.
+    pub async fn query_range(
+        &self,
+        field: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<RangePoint>, String> {
+        if from >= to {
+            return Err("The range must end after it starts.".to_string());
+        }
+        let client = self.connection().await?;
+        let sql = format!(
+            "SELECT ts, value_double, value_bool, value_text
+             FROM {0}
+             WHERE bucket = $1
+               AND field = $2
+               AND ts >= $3
+               AND ts < $4
+             UNION ALL
+             SELECT bucket_start, value_mean, NULL, NULL
+             FROM {0}_rollups
+             WHERE bucket = $1
+               AND field = $2
+               AND bucket_start >= $3
+               AND bucket_start < $4
+             ORDER BY 1 ASC
+             LIMIT $5",
+            self.inner.table
+        );
+        let rows = client
+            .timed(async {
+                client
+                    .query(
+                        &sql,
+                        &[&self.inner.bucket, &field, &from, &to, &MAX_RANGE_ROWS],
+                    )
+                    .await
+                    .map_err(|e| format!("PostgreSQL query failed: {e}"))
+            })
+            .await?;
+
+        Ok(rows
+            .iter()
+            .filter_map(|row| {
+                let ts: DateTime<Utc> = row.get(0);
+                Some(RangePoint {
+                    ts: ts.timestamp(),
+                    value: value_from_row(row, 1)?,
+                })
+            })
+            .collect())
+    }
+
+    /// Up to `limit` points matching `query`, ordered by time, each with its
+    /// row id. Pass the `(ts, id)` of the last row as `after` to get the next
+    /// page; each page is a short query of its own, so an export of any size
+    /// never holds a connection for long.
+    ///
+    /// Where retention has replaced raw points by rollups, each rollup gives
+    /// its mean at the start of its bucket, with a negative id numbering the
+    /// rollups that start at the same time.
+    pub async fn export_page(
+        &self,
+        query: &ExportQuery,
+        after: Option<(DateTime<Utc>, i64)>,
+        limit: i64,
+    ) -> Result<Vec<(i64, Point)>, String> {
+        let client = self.connection().await?;
+        let sql = format!(
+            "SELECT id, ts, measurement, field, value_double, value_bool, value_text,
+                    unit, location, type
+             FROM (
+                SELECT id, ts, measurement, field, value_double, value_bool, value_text,
+                       unit, location, type
+                FROM {0}
+                WHERE bucket = $1
+                  AND field = ANY($2)
+                  AND ts >= $3
+                  AND ts < $4
+                  AND ($5::TEXT IS NULL OR measurement = $5)
+                UNION ALL
+                SELECT -ROW_NUMBER() OVER (
+                           PARTITION BY bucket_start ORDER BY measurement, field, resolution_secs
+                       ),
+                       bucket_start, measurement, field, value_mean, NULL, NULL,
+                       NULL, NULL, NULL
+                FROM {0}_rollups
+                WHERE bucket = $1
+                  AND field = ANY($2)
+                  AND bucket_start >= $3
+                  AND bucket_start < $4
+                  AND ($5::TEXT IS NULL OR measurement = $5)
+                  AND ($6::TIMESTAMPTZ IS NULL OR bucket_start >= $6)
+             ) points
+             WHERE ($6::TIMESTAMPTZ IS NULL OR (ts, id) > ($6, $7))
+             ORDER BY ts, id
+             LIMIT $8",
+            self.inner.table
+        );
+        let (after_ts, after_id) = match after {
+            Some((ts, id)) => (Some(ts), id),
+            None => (None, 0),
         };
+        let rows = client
+            .timed(async {
+                client
+                    .query(
+                        &sql,
+                        &[
+                            &self.inner.bucket,
+                            &query.fields,
+                            &query.from,
+                            &query.to,
+                            &query.measurement,
+                            &after_ts,
+                            &after_id,
+                            &limit,
+                        ],
+                    )
+                    .await
+                    .map_err(|e| format!("PostgreSQL query failed: {e}"))
+            })
+            .await?;
 
-        let value_double: Option<f64> = row.get(0);
-        let value_bool: Option<bool> = row.get(1);
-        let value_text: Option<String> = row.get(2);
+        Ok(rows
+            .iter()
+            .filter_map(|row| {
+                Some((
+                    row.get(0),
+                    Point {
+                        bucket: self.inner.bucket.clone(),
+                        ts: row.get(1),
+                        measurement: row.get(2),
+                        field: row.get(3),
+                        value: value_from_row(row, 4)?,
+                        unit: row.get(7),
+                        location: row.get(8),
+                        value_type: row.get(9),
+                    },
+                ))
+            })
+            .collect())
+    }
 
-        if let Some(v) = value_double {
-            return Ok(Some(TimeSeriesValue::Double(v)));
-        }
-        if let Some(v) = value_bool {
-            return Ok(Some(TimeSeriesValue::Bool(v)));
+    /// `field` over the last `window` (e.g. `24h`), combined per
+    /// `bucket_size` (e.g. `10m`) with `agg`, oldest bucket first. Rollups
+    /// count towards the bucket they start in, so a window reaching past raw
+    /// retention still has data, at the rollup resolution.
+    pub async fn query_aggregate(
+        &self,
+        field: &str,
+        window: &str,
+        bucket_size: &str,
+        agg: Aggregate,
+    ) -> Result<Vec<AggregatePoint>, String> {
+        let (window_secs, bucket_secs) = aggregate_span(window, bucket_size)?;
+        let since = Utc::now() - ChronoDuration::seconds(window_secs);
+        let client = self.connection().await?;
+        let numeric_only = if agg == Aggregate::Count {
+            ""
+        } else {
+            "AND value_double IS NOT NULL"
+        };
+        let sql = format!(
+            "WITH points AS (
+                SELECT ts, ts AS at, 1::BIGINT AS count, value_double AS value_min,
+                       value_double AS value_max, value_double AS value_sum,
+                       value_double AS value_last
+                FROM {1}
+                WHERE bucket = $1
+                  AND field = $2
+                  AND ts >= $4
+                  {2}
+                UNION ALL
+                SELECT bucket_start, last_ts, count, value_min, value_max,
+                       value_mean * count, value_last
+                FROM {1}_rollups
+                WHERE bucket = $1
+                  AND field = $2
+                  AND bucket_start >= $4
+             )
+             SELECT (FLOOR(EXTRACT(EPOCH FROM ts)::DOUBLE PRECISION / $3) * $3)::BIGINT AS bucket_start,
+                    {0}
+             FROM points
+             GROUP BY 1
+             ORDER BY 1",
+            aggregate_sql(agg),
+            self.inner.table,
+            numeric_only
+        );
+        let rows = client
+            .timed(async {
+                client
+                    .query(
+                        &sql,
+                        &[&self.inner.bucket, &field, &(bucket_secs as f64), &since],
+                    )
+                    .await
+                    .map_err(|e| format!("PostgreSQL query failed: {e}"))
+            })
+            .await?;
+
+        Ok(rows
+            .iter()
+            .filter_map(|row| {
+                Some(AggregatePoint {
+                    bucket_start: row.get(0),
+                    value: row.get::<_, Option<f64>>(1)?,
+                })
+            })
+            .collect())
+    }
+
+    /// Every `(bucket, measurement)` pair with raw points or rollups.
+    pub async fn series(&self) -> Result<Vec<(String, String)>, String> {
+        let client = self.connection().await?;
+        let sql = format!(
+            "SELECT DISTINCT bucket, measurement FROM {0}
+             UNION
+             SELECT DISTINCT bucket, measurement FROM {0}_rollups",
+            self.inner.table
+        );
+        let rows = client
+            .timed(async {
+                client
+                    .query(&sql, &[])
+                    .await
+                    .map_err(|e| format!("PostgreSQL query failed: {e}"))
+            })
+            .await?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Deletes the raw points of one series older than `before`. With a
+    /// `resolution`, numeric points are rolled up into `<table>_rollups` by
+    /// the same statement, merging into rollups that already exist; boolean
+    /// and text points have no rollup, so they are kept. Returns the rows
+    /// deleted and the rollup rows written.
+    pub async fn compact_raw(
+        &self,
+        bucket: &str,
+        measurement: &str,
+        before: DateTime<Utc>,
+        resolution: Option<ChronoDuration>,
+    ) -> Result<(i64, i64), String> {
+        let client = self.connection().await?;
+        let table = &self.inner.table;
+        let numeric_only = if resolution.is_some() {
+            "AND value_double IS NOT NULL"
+        } else {
+            ""
+        };
+        // The oldest point to compact before `before`, at or after `after`
+        // if given.
+        let oldest_sql = format!(
+            "SELECT MIN(ts) FROM {}
+             WHERE bucket = $1 AND measurement = $2
+               AND ($3::TIMESTAMPTZ IS NULL OR ts >= $3) AND ts < $4
+               {}",
+            table, numeric_only
+        );
+        let oldest = |after: Option<DateTime<Utc>>| {
+            let client = &client;
+            let oldest_sql = &oldest_sql;
+            async move {
+                client
+                    .timed(async {
+                        client
+                            .query_one(oldest_sql, &[&bucket, &measurement, &after, &before])
+                            .await
+                            .map(|row| row.get::<_, Option<DateTime<Utc>>>(0))
+                            .map_err(|e| format!("PostgreSQL query failed: {e}"))
+                    })
+                    .await
+            }
+        };
+        let mut next = oldest(None).await?;
+
+        let delete_sql = format!(
+            "DELETE FROM {} WHERE bucket = $1 AND measurement = $2 AND ts >= $3 AND ts < $4",
+            table
+        );
+        let rollup_sql = format!(
+            "WITH moved AS (
+                DELETE FROM {0}
+                WHERE bucket = $1 AND measurement = $2 AND ts >= $3 AND ts < $4
+                  AND value_double IS NOT NULL
+                RETURNING ts, field, value_double
+             ), rolled AS (
+                INSERT INTO {0}_rollups AS r
+                    (bucket, measurement, field, resolution_secs, bucket_start, count,
+                     value_min, value_max, value_mean, value_last, last_ts)
+                SELECT $1::TEXT, $2::TEXT, field, $6::BIGINT,
+                       to_timestamp(FLOOR(EXTRACT(EPOCH FROM ts)::DOUBLE PRECISION / $5) * $5),
+                       COUNT(*), MIN(value_double), MAX(value_double), AVG(value_double),
+                       (ARRAY_AGG(value_double ORDER BY ts DESC))[1], MAX(ts)
+                FROM moved
+                GROUP BY field, 5
+                ON CONFLICT (bucket, measurement, field, resolution_secs, bucket_start) DO UPDATE SET
+                    count = r.count + EXCLUDED.count,
+                    value_min = LEAST(r.value_min, EXCLUDED.value_min),
+                    value_max = GREATEST(r.value_max, EXCLUDED.value_max),
+                    value_mean = (r.value_mean * r.count + EXCLUDED.value_mean * EXCLUDED.count)
+                        / (r.count + EXCLUDED.count),
+                    value_last = CASE WHEN EXCLUDED.last_ts >= r.last_ts
+                        THEN EXCLUDED.value_last ELSE r.value_last END,
+                    last_ts = GREATEST(r.last_ts, EXCLUDED.last_ts)
+                RETURNING 1
+             )
+             SELECT (SELECT COUNT(*) FROM moved), (SELECT COUNT(*) FROM rolled)",
+            table
+        );
+
+        let (mut deleted, mut rolled) = (0, 0);
+        while let Some(from) = next {
+            let to = (from + ChronoDuration::seconds(COMPACTION_STEP_SECS)).min(before);
+            let (step_deleted, step_rolled) = client
+                .timed(async {
+                    match resolution {
+                        Some(resolution) => {
+                            let secs = resolution.num_seconds();
+                            client
+                                .query_one(
+                                    &rollup_sql,
+                                    &[&bucket, &measurement, &from, &to, &(secs as f64), &secs],
+                                )
+                                .await
+                                .map(|row| (row.get::<_, i64>(0), row.get::<_, i64>(1)))
+                        }
+                        None => client
+                            .execute(&delete_sql, &[&bucket, &measurement, &from, &to])
+                            .await
+                            .map(|count| (count as i64, 0)),
+                    }
+                    .map_err(|e| format!("PostgreSQL compaction failed: {e}"))
+                })
+                .await?;
+            deleted += step_deleted;
+            rolled += step_rolled;
+            // Skip over gaps in the data instead of stepping through them.
+            next = if to >= before {
+                None
+            } else if step_deleted > 0 {
+                Some(to)
+            } else {
+                oldest(Some(to)).await?
+            };
         }
 
-        Ok(value_text.map(TimeSeriesValue::Text))
+        Ok((deleted, rolled))
+    }
+
+    /// Deletes rollups of one series, at any resolution, that start before
+    /// `before`.
+    pub async fn expire_rollups(
+        &self,
+        bucket: &str,
+        measurement: &str,
+        before: DateTime<Utc>,
+    ) -> Result<i64, String> {
+        let client = self.connection().await?;
+        let sql = format!(
+            "DELETE FROM {}_rollups
+             WHERE bucket = $1 AND measurement = $2 AND bucket_start < $3",
+            self.inner.table
+        );
+        let deleted = client
+            .timed(async {
+                client
+                    .execute(&sql, &[&bucket, &measurement, &before])
+                    .await
+                    .map_err(|e| format!("PostgreSQL delete failed: {e}"))
+            })
+            .await?;
+        Ok(deleted as i64)
+    }
+
+    pub async fn record_compaction(&self, run: &CompactionRun) -> Result<(), String> {
+        let client = self.connection().await?;
+        let sql = format!(
+            "INSERT INTO {}_compactions
+             (started_at, finished_at, deleted_raw, rolled_up, expired_rollups, error)
+             VALUES ($1, $2, $3, $4, $5, $6)",
+            self.inner.table
+        );
+        client
+            .timed(async {
+                client
+                    .execute(
+                        &sql,
+                        &[
+                            &run.started_at,
+                            &run.finished_at,
+                            &run.deleted_raw,
+                            &run.rolled_up,
+                            &run.expired_rollups,
+                            &run.error,
+                        ],
+                    )
+                    .await
+                    .map_err(|e| format!("PostgreSQL insert failed: {e}"))
+            })
+            .await?;
+        Ok(())
+    }
+
+    pub async fn last_compaction(&self) -> Result<Option<CompactionRun>, String> {
+        let client = self.connection().await?;
+        let sql = format!(
+            "SELECT started_at, finished_at, deleted_raw, rolled_up, expired_rollups, error
+             FROM {}_compactions
+             ORDER BY id DESC
+             LIMIT 1",
+            self.inner.table
+        );
+        let row = client
+            .timed(async {
+                client
+                    .query_opt(&sql, &[])
+                    .await
+                    .map_err(|e| format!("PostgreSQL query failed: {e}"))
+            })
+            .await?;
+        Ok(row.map(|row| CompactionRun {
+            started_at: row.get(0),
+            finished_at: row.get(1),
+            deleted_raw: row.get(2),
+            rolled_up: row.get(3),
+            expired_rollups: row.get(4),
+            error: row.get(5),
+        }))
+    }
+
+    /// On-disk size and estimated row count of the store's tables.
+    pub async fn table_sizes(&self) -> Result<Vec<TableSize>, String> {
+        let client = self.connection().await?;
+        let names: Vec<String> = ["", "_rollups", "_compactions", "_migrations"]
+            .iter()
+            .map(|suffix| format!("{}{}", self.inner.table, suffix))
+            .collect();
+        let rows = client
+            .timed(async {
+                client
+                    .query(
+                        "SELECT c.relname::TEXT, pg_total_relation_size(c.oid),
+                                GREATEST(c.reltuples, 0)::BIGINT
+                         FROM pg_class c
+                         WHERE c.relkind IN ('r', 'p') AND c.relname::TEXT = ANY($1)
+                         ORDER BY 1",
+                        &[&names],
+                    )
+                    .await
+                    .map_err(|e| format!("PostgreSQL query failed: {e}"))
+            })
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| TableSize {
+                name: row.get(0),
+                bytes: row.get(1),
+                rows: row.get(2),
+            })
+            .collect())
     }
 
     pub async fn query_last_f64(
@@ -268,96 +845,60 @@ impl PostgresTimeSeriesStore {
         })
     }
 
-    async fn ensure_connection(&self) -> Result<Arc<Client>, String> {
-        let mut lock = self.inner.connection.lock().await;
-        let needs_connect = lock.as_ref().map(|c| c.is_closed()).unwrap_or(true);
-
-        if needs_connect {
-            let client = self.connect_new().await?;
-            if self.inner.auto_create {
-                self.initialize_schema(client.as_ref()).await?;
-            }
-            *lock = Some(client);
-        }
-
-        lock.as_ref()
-            .map(Arc::clone)
-            .ok_or_else(|| "PostgreSQL connection is not available.".to_string())
+    /// A pooled connection, after the one-time schema check.
+    async fn connection(&self) -> Result<PooledConn<PgConnector>, String> {
+        self.inner
+            .schema_ready
+            .get_or_try_init(|| async {
+                tokio::time::timeout(self.inner.migration_timeout, self.prepare_schema())
+                    .await
+                    .map_err(|_| {
+                        format!(
+                            "PostgreSQL schema setup for {} did not finish within {}s",
+                            self.inner.table,
+                            self.inner.migration_timeout.as_secs()
+                        )
+                    })?
+            })
+            .await?;
+        self.inner.pool.get().await
     }
 
-    async fn connect_new(&self) -> Result<Arc<Client>, String> {
-        let mut cfg = tokio_postgres::Config::from_str(&self.inner.dsn)
-            .map_err(|e| format!("Invalid PostgreSQL DSN: {e}"))?;
-        cfg.connect_timeout(Duration::from_secs(self.inner.connect_timeout_s));
-
-        let (client, connection) = cfg
-            .connect(NoTls)
-            .await
-            .map_err(|e| format!("Failed to connect to PostgreSQL: {e}"))?;
-
-        tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                eprintln!("PostgreSQL connection task failed: {e}");
+    /// Runs pending migrations on a dedicated connection, or with
+    /// `POSTGRES_AUTO_CREATE` off only reports them.
+    async fn prepare_schema(&self) -> Result<(), String> {
+        let mut client = self.inner.pool.connector().connect().await?;
+        if self.inner.auto_create {
+            postgres_migrations::run(&mut client, &self.inner.table, self.inner.timescale).await?;
+        } else {
+            let pending = postgres_migrations::check(&client, &self.inner.table).await?;
+            if !pending.is_empty() {
+                eprintln!(
+                    "PostgreSQL schema for {} is missing migrations {:?}; set POSTGRES_AUTO_CREATE=true to apply them.",
+                    self.inner.table,
+                    pending.iter().map(|m| m.version).collect::<Vec<_>>()
+                );
             }
-        });
-
-        Ok(Arc::new(client))
-    }
-
-    async fn initialize_schema(&self, client: &Client) -> Result<(), String> {
-        let ts_idx = format!("{}_ts_idx", self.inner.table);
-        let mf_idx = format!("{}_measurement_field_ts_idx", self.inner.table);
-
-        let create_table_sql = format!(
-            r#"
-            CREATE TABLE IF NOT EXISTS {} (
-                id BIGSERIAL PRIMARY KEY,
-                bucket TEXT NOT NULL,
-                ts TIMESTAMPTZ NOT NULL,
-                measurement TEXT NOT NULL,
-                field TEXT NOT NULL,
-                value_double DOUBLE PRECISION,
-                value_bool BOOLEAN,
-                value_text TEXT,
-                unit TEXT,
-                location TEXT,
-                type TEXT,
-                CHECK (num_nonnulls(value_double, value_bool, value_text) = 1)
-            )
-            "#,
-            self.inner.table
-        );
-        let create_ts_idx_sql = format!(
-            "CREATE INDEX IF NOT EXISTS {} ON {} (ts DESC)",
-            ts_idx, self.inner.table
-        );
-        let create_mf_idx_sql = format!(
-            "CREATE INDEX IF NOT EXISTS {} ON {} (measurement, field, ts DESC)",
-            mf_idx, self.inner.table
-        );
-
-        client
-            .execute(&create_table_sql, &[])
-            .await
-            .map_err(|e| format!("Failed to initialize PostgreSQL schema: {e}"))?;
-        client
-            .execute(&create_ts_idx_sql, &[])
-            .await
-            .map_err(|e| format!("Failed to create ts index: {e}"))?;
-        client
-            .execute(&create_mf_idx_sql, &[])
-            .await
-            .map_err(|e| format!("Failed to create measurement/field index: {e}"))?;
-
+        }
         Ok(())
     }
 
-    fn insert_sql(&self) -> String {
+    /// An INSERT with `rows` rows of placeholders.
+    fn insert_sql(&self, rows: usize) -> String {
+        let values = (0..rows)
+            .map(|row| {
+                let placeholders = (1..=INSERT_COLUMNS)
+                    .map(|col| format!("${}", row * INSERT_COLUMNS + col))
+                    .collect::<Vec<_>>();
+                format!("({})", placeholders.join(", "))
+            })
+            .collect::<Vec<_>>();
         format!(
             "INSERT INTO {}
              (bucket, ts, measurement, field, value_double, value_bool, value_text, unit, location, type)
-             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
-            self.inner.table
+             VALUES {}",
+            self.inner.table,
+            values.join(", ")
         )
     }
 }
@@ -408,15 +949,74 @@ fn build_dsn_from_env() -> Result<String, String> {
     }
 }
 
-fn split_json_value(value: &Value) -> (Option<f64>, Option<bool>, Option<String>) {
+/// The points in a device payload: `{device, data: {field, field_unit,
+/// field_location, field_type}}`, stored in `bucket` under `measurement` or
+/// else the device name. Null values are skipped.
+pub fn device_points(
+    bucket: &str,
+    device_data: &Value,
+    measurement: Option<&str>,
+    ts: DateTime<Utc>,
+) -> Result<Vec<Point>, String> {
+    let root = device_data
+        .as_object()
+        .ok_or_else(|| "device_data must be an object.".to_string())?;
+
+    let measurement_value = if let Some(m) = measurement {
+        m.to_string()
+    } else {
+        root.get("device")
+            .and_then(Value::as_str)
+            .filter(|v| !v.trim().is_empty())
+            .map(str::to_string)
+            .ok_or_else(|| "device_data must contain a non-empty 'device' value.".to_string())?
+    };
+
+    let data = root
+        .get("data")
+        .and_then(Value::as_object)
+        .ok_or_else(|| "device_data['data'] must be a dictionary/object.".to_string())?;
+
+    let mut points = Vec::new();
+
+    for (field, value) in data {
+        if field.ends_with("_unit") || field.ends_with("_location") || field.ends_with("_type") {
+            continue;
+        }
+        if value.is_null() {
+            continue;
+        }
+
+        points.push(Point {
+            bucket: bucket.to_string(),
+            ts,
+            measurement: measurement_value.clone(),
+            field: field.clone(),
+            value: json_to_value(value),
+            unit: data
+                .get(&format!("{field}_unit"))
+                .and_then(json_value_to_string),
+            location: data
+                .get(&format!("{field}_location"))
+                .and_then(json_value_to_string),
+            value_type: data
+                .get(&format!("{field}_type"))
+                .and_then(json_value_to_string),
+        });
+    }
+
+    Ok(points)
+}
+
+fn json_to_value(value: &Value) -> TimeSeriesValue {
     match value {
-        Value::Bool(v) => (None, Some(*v), None),
+        Value::Bool(v) => TimeSeriesValue::Bool(*v),
         Value::Number(v) => match v.as_f64() {
-            Some(num) => (Some(num), None, None),
-            None => (None, None, Some(v.to_string())),
+            Some(num) => TimeSeriesValue::Double(num),
+            None => TimeSeriesValue::Text(v.to_string()),
         },
-        Value::String(v) => (None, None, Some(v.clone())),
-        _ => (None, None, Some(value.to_string())),
+        Value::String(v) => TimeSeriesValue::Text(v.clone()),
+        _ => TimeSeriesValue::Text(value.to_string()),
     }
 }
 
@@ -428,7 +1028,7 @@ fn json_value_to_string(value: &Value) -> Option<String> {
     }
 }
 
-fn parse_duration(duration: &str) -> Result<ChronoDuration, String> {
+pub(crate) fn parse_duration(duration: &str) -> Result<ChronoDuration, String> {
     let normalized: String = duration
         .chars()
         .filter(|c| !c.is_ascii_whitespace())
@@ -458,3 +1058,26 @@ fn parse_duration(duration: &str) -> Result<ChronoDuration, String> {
         )),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{aggregate_span, parse_duration, MAX_AGGREGATE_BUCKETS};
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("10s").unwrap().num_seconds(), 10);
+        assert_eq!(parse_duration(" 2 h").unwrap().num_seconds(), 7200);
+        assert_eq!(parse_duration("7d").unwrap().num_seconds(), 7 * 86_400);
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("10y").is_err());
+    }
+
+    #[test]
+    fn test_aggregate_span() {
+        assert_eq!(aggregate_span("24h", "10m"), Ok((86_400, 600)));
+        assert!(aggregate_span("24h", "0m").is_err());
+        assert!(aggregate_span("-1h", "1m").is_err());
+        let too_fine = format!("{}s", MAX_AGGREGATE_BUCKETS + 1);
+        assert!(aggregate_span(&too_fine, "1s").is_err());
+    }
+}
@@ -0,0 +1,345 @@
+#![cfg(feature = "server")]
+
+//! Retention for the time-series table. Policies say how long raw points of
+//! a bucket or measurement are kept and, optionally, at which resolution they
+//! are rolled up before deletion and how long those rollups are kept. A
+//! background task applies them periodically.
+
+use crate::postgres_store::{parse_duration, PostgresTimeSeriesStore};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyTarget {
+    /// Everything no other policy matches.
+    Default,
+    Bucket(String),
+    Measurement(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rollup {
+    pub resolution: ChronoDuration,
+    /// `None` keeps rollups forever.
+    pub keep: Option<ChronoDuration>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetentionPolicy {
+    pub target: PolicyTarget,
+    /// `None` keeps raw points forever.
+    pub raw: Option<ChronoDuration>,
+    pub rollup: Option<Rollup>,
+}
+
+/// One compaction pass, as recorded in `<table>_compactions`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionRun {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub deleted_raw: i64,
+    pub rolled_up: i64,
+    pub expired_rollups: i64,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableSize {
+    pub name: String,
+    pub bytes: i64,
+    /// PostgreSQL's estimate; exact counts are too slow on large tables.
+    pub rows: i64,
+}
+
+fn format_keep(keep: Option<ChronoDuration>) -> String {
+    keep.map(|d| format!("{}d", d.num_days()))
+        .unwrap_or_else(|| "forever".to_string())
+}
+
+impl fmt::Display for RetentionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.target {
+            PolicyTarget::Default => write!(f, "everything else")?,
+            PolicyTarget::Bucket(name) => write!(f, "bucket {}", name)?,
+            PolicyTarget::Measurement(name) => write!(f, "measurement {}", name)?,
+        }
+        write!(f, ": raw points for {}", format_keep(self.raw))?;
+        if let Some(rollup) = &self.rollup {
+            write!(
+                f,
+                ", {}s rollups for {}",
+                rollup.resolution.num_seconds(),
+                format_keep(rollup.keep)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_keep(text: &str) -> Result<Option<ChronoDuration>, String> {
+    if text.trim().eq_ignore_ascii_case("forever") {
+        return Ok(None);
+    }
+    let duration = parse_duration(text)?;
+    if duration <= ChronoDuration::zero() {
+        return Err(format!("Retention {:?} must be positive.", text));
+    }
+    Ok(Some(duration))
+}
+
+/// Parses `target=raw[/resolution/keep]` entries separated by `;`, e.g.
+/// `*=30d/5m/104w; measurement:voegeli=forever`. Targets are `*`,
+/// `bucket:<name>` or `measurement:<name>`; durations may be `forever`,
+/// except raw retention when rolling up.
+pub fn parse_policies(spec: &str) -> Result<Vec<RetentionPolicy>, String> {
+    spec.split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (target, rules) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("Retention policy {:?} has no '='.", entry))?;
+            let target = match target.trim().split_once(':') {
+                None if target.trim() == "*" => PolicyTarget::Default,
+                Some(("bucket", name)) if !name.trim().is_empty() => {
+                    PolicyTarget::Bucket(name.trim().to_string())
+                }
+                Some(("measurement", name)) if !name.trim().is_empty() => {
+                    PolicyTarget::Measurement(name.trim().to_string())
+                }
+                _ => return Err(format!("Unknown retention target {:?}.", target.trim())),
+            };
+
+            let parts: Vec<&str> = rules.split('/').collect();
+            let (raw, rollup) = match parts.as_slice() {
+                [raw] => (parse_keep(raw)?, None),
+                [raw, resolution, keep] => {
+                    // Rollups are written as raw points are deleted.
+                    if parse_keep(raw)?.is_none() {
+                        return Err(format!(
+                            "Retention policy {:?} keeps raw points forever, so nothing would be rolled up.",
+                            entry
+                        ));
+                    }
+                    let resolution = parse_duration(resolution)?;
+                    if resolution <= ChronoDuration::zero() {
+                        return Err(format!(
+                            "Rollup resolution {:?} must be positive.",
+                            resolution
+                        ));
+                    }
+                    (
+                        parse_keep(raw)?,
+                        Some(Rollup {
+                            resolution,
+                            keep: parse_keep(keep)?,
+                        }),
+                    )
+                }
+                _ => {
+                    return Err(format!(
+                        "Retention policy {:?} must look like raw or raw/resolution/keep.",
+                        entry
+                    ))
+                }
+            };
+            Ok(RetentionPolicy {
+                target,
+                raw,
+                rollup,
+            })
+        })
+        .collect()
+}
+
+/// The most specific policy for a series: measurement, then bucket, then
+/// the default.
+pub fn policy_for<'a>(
+    policies: &'a [RetentionPolicy],
+    bucket: &str,
+    measurement: &str,
+) -> Option<&'a RetentionPolicy> {
+    let find = |wanted: &dyn Fn(&PolicyTarget) -> bool| policies.iter().find(|p| wanted(&p.target));
+    find(&|t| matches!(t, PolicyTarget::Measurement(m) if m == measurement))
+        .or_else(|| find(&|t| matches!(t, PolicyTarget::Bucket(b) if b == bucket)))
+        .or_else(|| find(&|t| *t == PolicyTarget::Default))
+}
+
+/// `now - keep`, rounded down to a multiple of `resolution` so a rollup
+/// bucket is never split between two runs.
+pub fn cutoff(
+    now: DateTime<Utc>,
+    keep: ChronoDuration,
+    resolution: Option<ChronoDuration>,
+) -> DateTime<Utc> {
+    let cutoff = now - keep;
+    let Some(step) = resolution.map(|r| r.num_seconds()).filter(|s| *s > 0) else {
+        return cutoff;
+    };
+    let aligned = cutoff.timestamp().div_euclid(step) * step;
+    DateTime::from_timestamp(aligned, 0).unwrap_or(cutoff)
+}
+
+pub fn policies_from_env() -> Vec<RetentionPolicy> {
+    let spec = std::env::var("RETENTION_POLICIES").unwrap_or_default();
+    parse_policies(&spec).unwrap_or_else(|e| {
+        eprintln!(
+            "[Retention] Invalid RETENTION_POLICIES, keeping all data: {}",
+            e
+        );
+        Vec::new()
+    })
+}
+
+/// Applies `policies` to every series in the store.
+pub async fn compact(
+    store: &PostgresTimeSeriesStore,
+    policies: &[RetentionPolicy],
+) -> CompactionRun {
+    let started_at = Utc::now();
+    let mut run = CompactionRun {
+        started_at,
+        finished_at: started_at,
+        deleted_raw: 0,
+        rolled_up: 0,
+        expired_rollups: 0,
+        error: None,
+    };
+
+    let result: Result<(), String> = async {
+        for (bucket, measurement) in store.series().await? {
+            let Some(policy) = policy_for(policies, &bucket, &measurement) else {
+                continue;
+            };
+            let resolution = policy.rollup.as_ref().map(|r| r.resolution);
+            if let Some(raw) = policy.raw {
+                let before = cutoff(started_at, raw, resolution);
+                let (deleted, rolled) = store
+                    .compact_raw(&bucket, &measurement, before, resolution)
+                    .await?;
+                run.deleted_raw += deleted;
+                run.rolled_up += rolled;
+            }
+            // Covers rollups at every resolution, including ones a previous
+            // policy wrote.
+            if let Some(Rollup {
+                keep: Some(keep), ..
+            }) = policy.rollup
+            {
+                run.expired_rollups += store
+                    .expire_rollups(&bucket, &measurement, started_at - keep)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+    .await;
+
+    run.error = result.err();
+    run.finished_at = Utc::now();
+    run
+}
+
+// Keeps a manual run from the admin page and the background task apart.
+static COMPACTION_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Runs [`compact`] once, logs the outcome and records it in the store.
+pub async fn run_and_record(
+    store: &PostgresTimeSeriesStore,
+    policies: &[RetentionPolicy],
+) -> CompactionRun {
+    let _guard = COMPACTION_LOCK.lock().await;
+    let run = compact(store, policies).await;
+    match &run.error {
+        Some(e) => eprintln!("[Retention] Compaction failed: {}", e),
+        None => println!(
+            "[Retention] Deleted {} raw points, wrote {} rollup rows, expired {} rollups",
+            run.deleted_raw, run.rolled_up, run.expired_rollups
+        ),
+    }
+    if let Err(e) = store.record_compaction(&run).await {
+        eprintln!("[Retention] Failed to record compaction run: {}", e);
+    }
+    run
+}
+
+/// Runs [`run_and_record`] every `RETENTION_INTERVAL` (default 1h) when any
+/// policies are configured.
+pub fn spawn_compactor(store: PostgresTimeSeriesStore) {
+    let policies = policies_from_env();
+    if policies.is_empty() {
+        println!("[Retention] No RETENTION_POLICIES set; keeping all data.");
+        return;
+    }
+    let interval = std::env::var("RETENTION_INTERVAL")
+        .ok()
+        .and_then(|v| parse_duration(&v).ok())
+        .and_then(|d| d.to_std().ok())
+        .filter(|d| !d.is_zero())
+        .unwrap_or(Duration::from_secs(3600));
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            run_and_record(&store, &policies).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cutoff, parse_policies, policy_for, PolicyTarget};
+    use chrono::{DateTime, Duration};
+
+    #[test]
+    fn test_parse_policies() {
+        let policies =
+            parse_policies("*=30d/5m/104w; bucket:voegeli=90d; measurement:birdhouse=forever")
+                .unwrap();
+        assert_eq!(policies.len(), 3);
+        assert_eq!(policies[0].target, PolicyTarget::Default);
+        assert_eq!(policies[0].raw, Some(Duration::days(30)));
+        let rollup = policies[0].rollup.as_ref().unwrap();
+        assert_eq!(rollup.resolution, Duration::minutes(5));
+        assert_eq!(rollup.keep, Some(Duration::weeks(104)));
+        assert!(policies[1].rollup.is_none());
+        assert_eq!(policies[2].raw, None);
+        assert!(policies[2].rollup.is_none());
+        let forever = parse_policies("measurement:x=365d/1h/forever").unwrap();
+        assert_eq!(forever[0].rollup.as_ref().unwrap().keep, None);
+
+        assert!(parse_policies("").unwrap().is_empty());
+        assert!(parse_policies("*=30d/5m").is_err());
+        assert!(parse_policies("table:x=30d").is_err());
+        assert!(parse_policies("*=0d").is_err());
+        assert!(parse_policies("measurement:x=forever/1h/forever").is_err());
+    }
+
+    #[test]
+    fn test_most_specific_policy_wins() {
+        let policies =
+            parse_policies("*=30d; bucket:voegeli=90d; measurement:visitors=7d").unwrap();
+        let raw = |bucket, measurement| policy_for(&policies, bucket, measurement).unwrap().raw;
+        assert_eq!(raw("voegeli", "visitors"), Some(Duration::days(7)));
+        assert_eq!(raw("voegeli", "sensors"), Some(Duration::days(90)));
+        assert_eq!(raw("other", "sensors"), Some(Duration::days(30)));
+
+        let without_default = parse_policies("bucket:voegeli=90d").unwrap();
+        assert!(policy_for(&without_default, "other", "sensors").is_none());
+    }
+
+    #[test]
+    fn test_cutoff_aligns_to_rollup_buckets() {
+        let now = DateTime::from_timestamp(1_700_000_123, 0).unwrap();
+        let aligned = cutoff(now, Duration::days(1), Some(Duration::minutes(5)));
+        assert_eq!(aligned.timestamp() % 300, 0);
+        assert!(aligned <= now - Duration::days(1));
+        assert!(now - Duration::days(1) - aligned < Duration::minutes(5));
+        assert_eq!(
+            cutoff(now, Duration::days(1), None),
+            now - Duration::days(1)
+        );
+    }
+}
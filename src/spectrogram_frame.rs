@@ -0,0 +1,254 @@
+//! Wire format for spectrogram frames.
+//!
+//! All integers are little-endian:
+//!
+//! | offset | size | field                                   |
+//! |--------|------|-----------------------------------------|
+//! | 0      | 2    | magic `b"SG"`                           |
+//! | 2      | 1    | version (`1`)                           |
+//! | 3      | 1    | bin encoding: `1` = u8, `2` = u16       |
+//! | 4      | 4    | sequence number                         |
+//! | 8      | 8    | timestamp, ms since the Unix epoch      |
+//! | 16     | 4    | sample rate in Hz                       |
+//! | 20     | 4    | FFT size                                |
+//! | 24     | 4    | `min` as f32                            |
+//! | 28     | 4    | `max` as f32                            |
+//! | 32     | ..   | bins, quantized linearly onto min..=max |
+//!
+//! Older Pi firmware sends a JSON array of floats instead; see
+//! [`SpectrogramFrame::from_json`].
+
+const MAGIC: &[u8; 2] = b"SG";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 32;
+
+/// Assumed for JSON frames, which carry no header.
+pub const LEGACY_SAMPLE_RATE: u32 = 44_100;
+pub const LEGACY_FFT_SIZE: u32 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinEncoding {
+    U8,
+    U16,
+}
+
+impl BinEncoding {
+    fn tag(self) -> u8 {
+        match self {
+            Self::U8 => 1,
+            Self::U16 => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(Self::U8),
+            2 => Some(Self::U16),
+            _ => None,
+        }
+    }
+
+    fn width(self) -> usize {
+        match self {
+            Self::U8 => 1,
+            Self::U16 => 2,
+        }
+    }
+
+    fn max_level(self) -> f32 {
+        match self {
+            Self::U8 => u8::MAX as f32,
+            Self::U16 => u16::MAX as f32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpectrogramFrame {
+    pub seq: u32,
+    pub timestamp_ms: u64,
+    pub sample_rate: u32,
+    pub fft_size: u32,
+    pub bins: Vec<f32>,
+}
+
+impl SpectrogramFrame {
+    /// Parses the legacy JSON frame (`[f64, ...]`).
+    pub fn from_json(text: &str, seq: u32, timestamp_ms: u64) -> Result<Self, String> {
+        let bins: Vec<f32> = serde_json::from_str::<Vec<f64>>(text)
+            .map_err(|e| format!("Invalid JSON spectrogram frame: {}", e))?
+            .into_iter()
+            .map(|v| v as f32)
+            .collect();
+        Ok(Self {
+            seq,
+            timestamp_ms,
+            sample_rate: LEGACY_SAMPLE_RATE,
+            fft_size: LEGACY_FFT_SIZE,
+            bins,
+        })
+    }
+
+    pub fn encode(&self, encoding: BinEncoding) -> Vec<u8> {
+        let (min, max) = self
+            .bins
+            .iter()
+            .filter(|v| v.is_finite())
+            .fold(None, |range: Option<(f32, f32)>, &v| match range {
+                Some((lo, hi)) => Some((lo.min(v), hi.max(v))),
+                None => Some((v, v)),
+            })
+            .unwrap_or((0.0, 0.0));
+        let span = max - min;
+        let levels = encoding.max_level();
+
+        let mut out = Vec::with_capacity(HEADER_LEN + self.bins.len() * encoding.width());
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.push(encoding.tag());
+        out.extend_from_slice(&self.seq.to_le_bytes());
+        out.extend_from_slice(&self.timestamp_ms.to_le_bytes());
+        out.extend_from_slice(&self.sample_rate.to_le_bytes());
+        out.extend_from_slice(&self.fft_size.to_le_bytes());
+        out.extend_from_slice(&min.to_le_bytes());
+        out.extend_from_slice(&max.to_le_bytes());
+
+        for &v in &self.bins {
+            let level = if span > 0.0 && v.is_finite() {
+                ((v - min) / span * levels).round().clamp(0.0, levels)
+            } else {
+                0.0
+            };
+            match encoding {
+                BinEncoding::U8 => out.push(level as u8),
+                BinEncoding::U16 => out.extend_from_slice(&(level as u16).to_le_bytes()),
+            }
+        }
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, String> {
+        if data.len() < HEADER_LEN {
+            return Err(format!("Spectrogram frame too short: {} bytes", data.len()));
+        }
+        if &data[0..2] != MAGIC {
+            return Err("Not a spectrogram frame".to_string());
+        }
+        if data[2] != VERSION {
+            return Err(format!("Unsupported spectrogram frame version {}", data[2]));
+        }
+        let encoding = BinEncoding::from_tag(data[3])
+            .ok_or_else(|| format!("Unknown spectrogram bin encoding {}", data[3]))?;
+
+        let u32_at = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        let f32_at = |at: usize| f32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        let seq = u32_at(4);
+        let timestamp_ms = u64::from_le_bytes(data[8..16].try_into().unwrap());
+        let sample_rate = u32_at(16);
+        let fft_size = u32_at(20);
+        let (min, max) = (f32_at(24), f32_at(28));
+
+        if sample_rate == 0 || fft_size == 0 {
+            return Err("Spectrogram frame has no sample rate or FFT size".to_string());
+        }
+
+        let payload = &data[HEADER_LEN..];
+        if !payload.len().is_multiple_of(encoding.width()) {
+            return Err("Spectrogram frame has a truncated bin".to_string());
+        }
+        let scale = (max - min) / encoding.max_level();
+        let bins = match encoding {
+            BinEncoding::U8 => payload.iter().map(|&q| min + q as f32 * scale).collect(),
+            BinEncoding::U16 => payload
+                .chunks_exact(2)
+                .map(|q| min + u16::from_le_bytes([q[0], q[1]]) as f32 * scale)
+                .collect(),
+        };
+
+        Ok(Self {
+            seq,
+            timestamp_ms,
+            sample_rate,
+            fft_size,
+            bins,
+        })
+    }
+
+    /// FFT bin holding `freq` Hz, clamped to the bins in this frame.
+    #[cfg(any(test, target_arch = "wasm32"))]
+    pub fn bin_for_frequency(&self, freq: f64) -> usize {
+        let bin = (freq * self.fft_size as f64 / self.sample_rate as f64).round() as usize;
+        bin.min(self.bins.len().saturating_sub(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame() -> SpectrogramFrame {
+        SpectrogramFrame {
+            seq: 7,
+            timestamp_ms: 1_700_000_000_123,
+            sample_rate: 48_000,
+            fft_size: 2048,
+            bins: (0..1025).map(|i| -6.0 + (i % 100) as f32 * 0.06).collect(),
+        }
+    }
+
+    #[test]
+    fn test_round_trip_within_quantization_error() {
+        let original = frame();
+        for (encoding, tolerance) in [(BinEncoding::U8, 6.0 / 255.0), (BinEncoding::U16, 1e-4)] {
+            let encoded = original.encode(encoding);
+            assert_eq!(encoded.len(), HEADER_LEN + 1025 * encoding.width());
+
+            let decoded = SpectrogramFrame::decode(&encoded).unwrap();
+            assert_eq!(decoded.seq, 7);
+            assert_eq!(decoded.timestamp_ms, 1_700_000_000_123);
+            assert_eq!(decoded.sample_rate, 48_000);
+            assert_eq!(decoded.fft_size, 2048);
+            assert_eq!(decoded.bins.len(), original.bins.len());
+            for (a, b) in original.bins.iter().zip(&decoded.bins) {
+                assert!((a - b).abs() <= tolerance, "{} vs {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_json_fallback() {
+        let frame = SpectrogramFrame::from_json("[-6.0, -3.5, 0.0]", 1, 2).unwrap();
+        assert_eq!(frame.sample_rate, LEGACY_SAMPLE_RATE);
+        assert_eq!(frame.fft_size, LEGACY_FFT_SIZE);
+        assert_eq!(frame.bins, vec![-6.0, -3.5, 0.0]);
+        assert!(SpectrogramFrame::from_json("{}", 1, 2).is_err());
+    }
+
+    #[test]
+    fn test_decode_rejects_malformed_frames() {
+        let encoded = frame().encode(BinEncoding::U16);
+        assert!(SpectrogramFrame::decode(&encoded[..10]).is_err());
+        assert!(SpectrogramFrame::decode(&encoded[..encoded.len() - 1]).is_err());
+
+        let mut bad_magic = encoded.clone();
+        bad_magic[0] = b'X';
+        assert!(SpectrogramFrame::decode(&bad_magic).is_err());
+
+        let mut bad_version = encoded;
+        bad_version[2] = 9;
+        assert!(SpectrogramFrame::decode(&bad_version).is_err());
+    }
+
+    #[test]
+    fn test_flat_frame_and_bin_lookup() {
+        let flat = SpectrogramFrame {
+            bins: vec![-2.0; 513],
+            ..frame()
+        };
+        let decoded = SpectrogramFrame::decode(&flat.encode(BinEncoding::U8)).unwrap();
+        assert!(decoded.bins.iter().all(|&v| v == -2.0));
+
+        assert_eq!(decoded.bin_for_frequency(1000.0), 43);
+        assert_eq!(decoded.bin_for_frequency(30_000.0), 512);
+    }
+}
@@ -0,0 +1,291 @@
+#![cfg(feature = "server")]
+
+use crate::spectrogram_frame::{BinEncoding, SpectrogramFrame};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::Query;
+use axum::response::IntoResponse;
+use bytes::Bytes;
+use futures_util::StreamExt;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio_tungstenite::tungstenite;
+
+/// How much history a new viewer receives before live frames.
+const HISTORY_MS: u64 = 20_000;
+const MAX_FPS: u32 = 30;
+
+/// One upstream spectrogram frame in the binary format of
+/// `spectrogram_frame`, ready to send to browsers.
+#[derive(Clone)]
+struct Frame {
+    received_at_ms: u64,
+    payload: Bytes,
+}
+
+impl Frame {
+    fn to_message(&self) -> Message {
+        Message::Binary(self.payload.clone())
+    }
+}
+
+static FRAMES: Lazy<broadcast::Sender<Frame>> = Lazy::new(|| broadcast::channel(256).0);
+
+// Frames from the last HISTORY_MS, oldest first.
+static HISTORY: Lazy<Mutex<VecDeque<Frame>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+
+// Numbers frames converted from the legacy JSON format.
+static NEXT_SEQ: AtomicU32 = AtomicU32::new(0);
+
+fn now_ms() -> u64 {
+    chrono::Utc::now().timestamp_millis().max(0) as u64
+}
+
+/// Converts an upstream message into a binary frame. Binary frames from the
+/// Pi are validated and passed through; JSON frames from older firmware are
+/// re-encoded.
+fn to_binary_frame(message: tungstenite::Message, received_at_ms: u64) -> Option<Bytes> {
+    match message {
+        tungstenite::Message::Binary(data) => match SpectrogramFrame::decode(&data) {
+            Ok(_) => Some(data),
+            Err(e) => {
+                eprintln!("[Spectrogram] Dropping upstream frame: {}", e);
+                None
+            }
+        },
+        tungstenite::Message::Text(text) => {
+            let seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+            match SpectrogramFrame::from_json(text.as_str(), seq, received_at_ms) {
+                Ok(frame) => Some(Bytes::from(frame.encode(BinEncoding::U8))),
+                Err(e) => {
+                    eprintln!("[Spectrogram] Dropping upstream frame: {}", e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+fn history() -> MutexGuard<'static, VecDeque<Frame>> {
+    HISTORY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Drops the frames older than `HISTORY_MS` before `now_ms`.
+fn trim_history(history: &mut VecDeque<Frame>, now_ms: u64) {
+    let cutoff = now_ms.saturating_sub(HISTORY_MS);
+    while history
+        .front()
+        .is_some_and(|oldest| oldest.received_at_ms < cutoff)
+    {
+        history.pop_front();
+    }
+}
+
+fn publish(frame: Frame) {
+    {
+        let mut history = history();
+        trim_history(&mut history, frame.received_at_ms);
+        history.push_back(frame.clone());
+    }
+    let _ = FRAMES.send(frame);
+}
+
+/// The frames of the last `HISTORY_MS`, oldest first. Frames stop arriving
+/// while the upstream is down, so the history is trimmed here as well.
+fn recent_history(now_ms: u64) -> Vec<Frame> {
+    let mut history = history();
+    trim_history(&mut history, now_ms);
+    history.iter().cloned().collect()
+}
+
+/// Keeps a single websocket to the Pi's spectrogram feed open and republishes
+/// its frames to every viewer.
+pub fn spawn_upstream(url: String) {
+    tokio::spawn(async move {
+        let mut delay = Duration::from_secs(1);
+
+        loop {
+            match tokio_tungstenite::connect_async(url.as_str()).await {
+                Ok((mut stream, _)) => {
+                    println!("[Spectrogram] Connected to upstream {}", url);
+                    delay = Duration::from_secs(1);
+
+                    while let Some(message) = stream.next().await {
+                        let message = match message {
+                            Ok(tungstenite::Message::Close(_)) => break,
+                            Ok(message) => message,
+                            Err(e) => {
+                                eprintln!("[Spectrogram] Upstream read error: {}", e);
+                                break;
+                            }
+                        };
+                        let received_at_ms = now_ms();
+                        if let Some(payload) = to_binary_frame(message, received_at_ms) {
+                            publish(Frame {
+                                received_at_ms,
+                                payload,
+                            });
+                        }
+                    }
+                    eprintln!("[Spectrogram] Upstream connection closed");
+                    history().clear();
+                }
+                Err(e) => eprintln!("[Spectrogram] Failed to connect to {}: {}", url, e),
+            }
+
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(Duration::from_secs(30));
+        }
+    });
+}
+
+/// Drops frames that arrive sooner than `1 / fps` after the last one sent.
+struct Decimator {
+    min_interval_ms: u64,
+    last_sent_ms: Option<u64>,
+}
+
+impl Decimator {
+    fn new(fps: u32) -> Self {
+        Self {
+            min_interval_ms: 1000 / u64::from(fps.clamp(1, MAX_FPS)),
+            last_sent_ms: None,
+        }
+    }
+
+    fn accept(&mut self, received_at_ms: u64) -> bool {
+        if let Some(last) = self.last_sent_ms {
+            // Allow a little jitter so 30 fps upstream is not halved at 30 fps.
+            if received_at_ms.saturating_sub(last) + 5 < self.min_interval_ms {
+                return false;
+            }
+        }
+        self.last_sent_ms = Some(received_at_ms);
+        true
+    }
+}
+
+/// `GET /ws/spectrogram?fps=N`: the last 20 seconds of frames, then live
+/// frames, at most `N` per second (default and maximum 30).
+pub async fn websocket_handler(
+    ws: WebSocketUpgrade,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let fps = params
+        .get("fps")
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(MAX_FPS);
+    ws.on_upgrade(move |socket| handle_viewer(socket, fps))
+}
+
+async fn handle_viewer(mut socket: WebSocket, fps: u32) {
+    let mut decimator = Decimator::new(fps);
+
+    // Subscribe before copying the history so no frame falls in between.
+    let mut rx = FRAMES.subscribe();
+    let history = recent_history(now_ms());
+    let mut last_history_ms = None;
+
+    for frame in history {
+        last_history_ms = Some(frame.received_at_ms);
+        if decimator.accept(frame.received_at_ms) && socket.send(frame.to_message()).await.is_err()
+        {
+            return;
+        }
+    }
+
+    loop {
+        tokio::select! {
+            frame = rx.recv() => {
+                let frame = match frame {
+                    Ok(frame) => frame,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                if last_history_ms.is_some_and(|last| frame.received_at_ms <= last) {
+                    continue;
+                }
+                if decimator.accept(frame.received_at_ms)
+                    && socket.send(frame.to_message()).await.is_err()
+                {
+                    return;
+                }
+            }
+
+            message = socket.recv() => {
+                match message {
+                    None | Some(Err(_)) | Some(Ok(Message::Close(_))) => return,
+                    Some(Ok(_)) => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        publish, recent_history, to_binary_frame, Decimator, Frame, SpectrogramFrame, HISTORY_MS,
+    };
+    use bytes::Bytes;
+    use tokio_tungstenite::tungstenite::Message;
+
+    #[test]
+    fn test_upstream_frames_become_binary() {
+        let from_json = to_binary_frame(Message::text("[-6.0, -6.0, 0.0]"), 42).unwrap();
+        let frame = SpectrogramFrame::decode(&from_json).unwrap();
+        assert_eq!(frame.timestamp_ms, 42);
+        assert_eq!(frame.bins, vec![-6.0, -6.0, 0.0]);
+
+        let passed_through = to_binary_frame(Message::binary(from_json.clone()), 43).unwrap();
+        assert_eq!(passed_through, from_json);
+
+        assert!(to_binary_frame(Message::text("not json"), 44).is_none());
+        assert!(to_binary_frame(Message::binary(vec![1, 2, 3]), 45).is_none());
+    }
+
+    #[test]
+    fn test_stale_history_is_not_replayed() {
+        let frame = |received_at_ms| Frame {
+            received_at_ms,
+            payload: Bytes::from_static(b"frame"),
+        };
+        publish(frame(1_000));
+        publish(frame(2_000));
+        let kept = |now_ms| {
+            recent_history(now_ms)
+                .iter()
+                .map(|f| f.received_at_ms)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(kept(2_000), [1_000, 2_000]);
+        // No frame arrived since, e.g. because the upstream dropped.
+        assert_eq!(kept(2_000 + HISTORY_MS), [2_000]);
+        assert!(kept(2_001 + HISTORY_MS).is_empty());
+    }
+
+    #[test]
+    fn test_decimator_limits_rate() {
+        let mut decimator = Decimator::new(10);
+        let sent: Vec<u64> = (0..30)
+            .map(|i| i * 33)
+            .filter(|&t| decimator.accept(t))
+            .collect();
+        assert_eq!(sent, vec![0, 99, 198, 297, 396, 495, 594, 693, 792, 891]);
+    }
+
+    #[test]
+    fn test_decimator_passes_full_rate() {
+        let mut decimator = Decimator::new(30);
+        assert!((0..30).map(|i| i * 33).all(|t| decimator.accept(t)));
+
+        let mut clamped = Decimator::new(0);
+        assert!(clamped.accept(0));
+        assert!(!clamped.accept(900));
+        assert!(clamped.accept(1000));
+    }
+}
@@ -1,160 +1,1471 @@
-use encryption::Cipher;
+use crate::device_link::{DeviceLinkState, DeviceSummary};
+use crate::device_protocol::{
+    split_message_id, tag_message, DeviceCommand, DeviceError, DeviceResponse,
+};
+use dashmap::DashMap;
+use encryption::handshake::{self, HandshakeError, ServerHandshake};
+use encryption::{Cipher, CipherKey, EncryptionError, Keyring, WireVersion};
 use once_cell::sync::Lazy;
+use std::fmt;
 use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
-use tokio::sync::{broadcast, Mutex};
+use tokio::sync::{broadcast, oneshot, watch, Mutex};
 use tokio::time::timeout;
 
-static MESSAGE_BROADCAST: Lazy<broadcast::Sender<String>> = Lazy::new(|| {
-    let (tx, _) = broadcast::channel(100);
-    tx
-});
+/// Connection settings for one device. Read from `TCP_DEVICES` (a comma
+/// separated list of ids) and `TCP_DEVICE_<ID>_ADDR`, `TCP_DEVICE_<ID>_KEY` and
+/// the optional `TCP_DEVICE_<ID>_NAME`, `_ENCRYPTION_VERSION` and `_HANDSHAKE`
+/// (defaulting to `TCP_ENCRYPTION_VERSION` and `TCP_HANDSHAKE`). Without
+/// `TCP_DEVICES` the legacy
+/// `TCP_SERVER_ADDR`/`TCP_ENCRYPTION_KEY` pair becomes the `birdhouse` device.
+/// Key variables hold a single key or a keyring (see [`Keyring`]); a keyring
+/// saved by [`rotate_key`] under `TCP_KEYRING_DIR` takes their place.
+#[derive(Debug, Clone)]
+pub struct DeviceConfig {
+    pub id: String,
+    pub name: String,
+    pub addr: String,
+    pub keyring: Keyring,
+    /// Where [`rotate_key`] saves the rotated keyring, if anywhere.
+    pub keyring_file: Option<PathBuf>,
+    /// Wire format for commands sent to the device. Replies are accepted in
+    /// any version. Ignored with the mutual handshake, whose session always
+    /// uses V2.
+    pub wire_version: WireVersion,
+    pub handshake: HandshakeMode,
+    /// Whether the Pi encrypts what it sends, from `TCP_INBOUND_ENCRYPTION`.
+    /// Frames that fail to decrypt are then dropped and counted, and with
+    /// the legacy handshake that includes the `authentication successful`
+    /// reply, which must be encrypted too. The mutual handshake always
+    /// encrypts.
+    pub inbound_encryption: bool,
+}
+
+/// How the server and a device authenticate each other on connect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeMode {
+    /// The server sends its IP encrypted with the shared key and waits for
+    /// `authentication successful`. Only the server proves anything.
+    Legacy,
+    /// Challenge-response in both directions followed by per-connection
+    /// session keys; see `encryption::handshake`.
+    Mutual,
+}
+
+impl FromStr for HandshakeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "legacy" => Ok(Self::Legacy),
+            "mutual" => Ok(Self::Mutual),
+            other => Err(format!(
+                "Unknown handshake '{}' (expected 'legacy' or 'mutual')",
+                other
+            )),
+        }
+    }
+}
+
+impl DeviceConfig {
+    pub fn all_from_env() -> Result<Vec<Self>, String> {
+        let default_version = match std::env::var("TCP_ENCRYPTION_VERSION") {
+            Ok(v) => v
+                .parse::<WireVersion>()
+                .map_err(|e| format!("TCP_ENCRYPTION_VERSION: {}", e))?,
+            Err(_) => WireVersion::Legacy,
+        };
+        let default_handshake = match std::env::var("TCP_HANDSHAKE") {
+            Ok(v) => v
+                .parse::<HandshakeMode>()
+                .map_err(|e| format!("TCP_HANDSHAKE: {}", e))?,
+            Err(_) => HandshakeMode::Legacy,
+        };
+
+        let inbound_encryption = inbound_encryption_from_env();
+        let keyring_dir =
+            std::env::var("TCP_KEYRING_DIR").unwrap_or_else(|_| KEYRING_DIR.to_string());
+        let keyring_file = |id: &str| {
+            (!keyring_dir.trim().is_empty())
+                .then(|| Path::new(keyring_dir.trim()).join(format!("{}.keyring", id)))
+        };
+
+        let Ok(list) = std::env::var("TCP_DEVICES") else {
+            let legacy = match (
+                std::env::var("TCP_SERVER_ADDR"),
+                std::env::var("TCP_ENCRYPTION_KEY"),
+            ) {
+                (Ok(addr), Ok(key)) => {
+                    let keyring_file = keyring_file("birdhouse");
+                    vec![Self {
+                        id: "birdhouse".to_string(),
+                        name: "Birdhouse".to_string(),
+                        addr,
+                        keyring: load_keyring(
+                            keyring_file.as_deref(),
+                            parse_key("TCP_ENCRYPTION_KEY", &key)?,
+                        ),
+                        keyring_file,
+                        wire_version: default_version,
+                        handshake: default_handshake,
+                        inbound_encryption,
+                    }]
+                }
+                _ => Vec::new(),
+            };
+            return Ok(legacy);
+        };
+
+        let mut configs: Vec<Self> = Vec::new();
+        for id in list.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+            if !is_valid_device_id(id) {
+                return Err(format!(
+                    "Invalid device id '{}' in TCP_DEVICES (use a-z, 0-9, '-' and '_')",
+                    id
+                ));
+            }
+            if configs.iter().any(|c| c.id == id) {
+                return Err(format!("Duplicate device id '{}' in TCP_DEVICES", id));
+            }
+
+            let prefix = format!("TCP_DEVICE_{}", id.to_ascii_uppercase().replace('-', "_"));
+            let var = |suffix: &str| std::env::var(format!("{}_{}", prefix, suffix));
+            let addr = var("ADDR").map_err(|_| format!("{}_ADDR is not set", prefix))?;
+            let key = var("KEY").map_err(|_| format!("{}_KEY is not set", prefix))?;
+            let name = var("NAME").unwrap_or_else(|_| id.to_string());
+            let wire_version = match var("ENCRYPTION_VERSION") {
+                Ok(v) => v
+                    .parse::<WireVersion>()
+                    .map_err(|e| format!("{}_ENCRYPTION_VERSION: {}", prefix, e))?,
+                Err(_) => default_version,
+            };
+            let handshake = match var("HANDSHAKE") {
+                Ok(v) => v
+                    .parse::<HandshakeMode>()
+                    .map_err(|e| format!("{}_HANDSHAKE: {}", prefix, e))?,
+                Err(_) => default_handshake,
+            };
+
+            let keyring_file = keyring_file(id);
+            configs.push(Self {
+                id: id.to_string(),
+                name,
+                addr,
+                keyring: load_keyring(
+                    keyring_file.as_deref(),
+                    parse_key(&format!("{}_KEY", prefix), &key)?,
+                ),
+                keyring_file,
+                wire_version,
+                handshake,
+                inbound_encryption,
+            });
+        }
+        Ok(configs)
+    }
+}
+
+fn parse_key(var: &str, value: &str) -> Result<Keyring, String> {
+    Keyring::parse(value).map_err(|e| format!("{}: {}", var, e))
+}
+
+const KEYRING_DIR: &str = "data/keyrings";
+
+/// Whether `keyring` holds the active key of `configured`, i.e. it grew out
+/// of the configured key by rotation rather than being replaced by hand.
+fn descends_from(keyring: &Keyring, configured: &Keyring) -> bool {
+    keyring
+        .get(configured.active_id())
+        .is_some_and(|key| key.to_base64() == configured.active().to_base64())
+}
+
+/// The keyring saved by a previous rotation, or `configured` when there is
+/// none. A saved keyring that lacks the configured key is stale: the key
+/// variable was changed since, so it wins.
+fn load_keyring(path: Option<&Path>, configured: Keyring) -> Keyring {
+    let Some(path) = path else {
+        return configured;
+    };
+    let Ok(spec) = std::fs::read_to_string(path) else {
+        return configured;
+    };
+    match Keyring::parse(&spec) {
+        Ok(saved) if descends_from(&saved, &configured) => {
+            println!(
+                "[TCP] Using rotated key {} from {:?}",
+                saved.active_id(),
+                path
+            );
+            saved
+        }
+        Ok(_) => {
+            eprintln!(
+                "[TCP] Ignoring {:?}: it does not contain the configured key",
+                path
+            );
+            configured
+        }
+        Err(e) => {
+            eprintln!("[TCP] Ignoring {:?}: {}", path, e);
+            configured
+        }
+    }
+}
+
+/// Writes `keyring` to `path` readable by its owner only. Goes through a
+/// temp file so a crash never leaves half a keyring behind.
+async fn save_keyring(path: &Path, keyring: &Keyring) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(|e| format!("Failed to create {:?}: {}", parent, e))?;
+    }
+    let tmp_path = path.with_extension("keyring.tmp");
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options
+        .open(&tmp_path)
+        .await
+        .map_err(|e| format!("Failed to write {:?}: {}", tmp_path, e))?;
+    file.write_all(keyring.to_spec().as_bytes())
+        .await
+        .and(file.sync_all().await)
+        .map_err(|e| format!("Failed to write {:?}: {}", tmp_path, e))?;
+    tokio::fs::rename(&tmp_path, path)
+        .await
+        .map_err(|e| format!("Failed to write {:?}: {}", path, e))
+}
+
+fn is_valid_device_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 32
+        && id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// A command waiting for its reply.
+struct PendingReply {
+    command: DeviceCommand,
+    reply_tx: oneshot::Sender<String>,
+}
+
+/// Everything the server keeps per registered device.
+struct Device {
+    id: String,
+    name: String,
+    connection: Mutex<Option<TcpConnection>>,
+    // Callers waiting for a reply, keyed by the message id sent with their command.
+    pending_replies: DashMap<u64, PendingReply>,
+    // Whether the Pi echoes message ids, as offered when authenticating.
+    // Without them commands go out untagged and replies are matched in order.
+    message_ids: AtomicBool,
+    messages: broadcast::Sender<String>,
+    link_state: watch::Sender<DeviceLinkState>,
+    // Inbound frames rejected by the cipher (bad encoding, expired, replayed, ...).
+    dropped_inbound_frames: AtomicU64,
+    wire_version: WireVersion,
+    handshake: HandshakeMode,
+    keyring_file: Option<PathBuf>,
+    // Keys for new connections and inbound frames. Replaced by `rotate_key`;
+    // readers pick up the change when the generation moves.
+    keyring: RwLock<Keyring>,
+    keyring_generation: AtomicU64,
+    rotation: Mutex<()>,
+}
+
+impl Device {
+    fn new(config: &DeviceConfig) -> Self {
+        Self {
+            id: config.id.clone(),
+            name: config.name.clone(),
+            connection: Mutex::new(None),
+            pending_replies: DashMap::new(),
+            message_ids: AtomicBool::new(false),
+            messages: broadcast::channel(100).0,
+            link_state: watch::channel(DeviceLinkState::Disconnected).0,
+            dropped_inbound_frames: AtomicU64::new(0),
+            wire_version: config.wire_version,
+            handshake: config.handshake,
+            keyring_file: config.keyring_file.clone(),
+            keyring: RwLock::new(config.keyring.clone()),
+            keyring_generation: AtomicU64::new(0),
+            rotation: Mutex::new(()),
+        }
+    }
+
+    fn set_link_state(&self, state: DeviceLinkState) {
+        self.link_state.send_replace(state);
+    }
+
+    fn keyring(&self) -> Keyring {
+        self.keyring
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn set_keyring(&self, keyring: Keyring) {
+        *self.keyring.write().unwrap_or_else(|e| e.into_inner()) = keyring;
+        self.keyring_generation.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+// Registered devices in configuration order; the first one is the default.
+static DEVICES: Lazy<RwLock<Vec<Arc<Device>>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+fn device(id: &str) -> Option<Arc<Device>> {
+    DEVICES
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .find(|d| d.id == id)
+        .cloned()
+}
+
+/// Id of the device used when a request does not name one.
+pub fn default_device_id() -> Option<String> {
+    DEVICES
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .first()
+        .map(|d| d.id.clone())
+}
+
+/// Resolves an optional device id from a request to a registered device id.
+pub fn resolve_device_id(id: Option<&str>) -> Result<String, DeviceError> {
+    match id.map(str::trim).filter(|id| !id.is_empty()) {
+        Some(id) => device(id)
+            .map(|d| d.id.clone())
+            .ok_or_else(|| DeviceError::UnknownDevice(id.to_string())),
+        None => default_device_id().ok_or(DeviceError::NotConnected),
+    }
+}
+
+pub fn devices() -> Vec<DeviceSummary> {
+    DEVICES
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .map(|d| DeviceSummary {
+            id: d.id.clone(),
+            name: d.name.clone(),
+            link: d.link_state.borrow().clone(),
+        })
+        .collect()
+}
+
+pub fn subscribe_to_tcp_messages(device_id: &str) -> Option<broadcast::Receiver<String>> {
+    device(device_id).map(|d| d.messages.subscribe())
+}
+
+pub fn subscribe_to_link_state(device_id: &str) -> Option<watch::Receiver<DeviceLinkState>> {
+    device(device_id).map(|d| d.link_state.subscribe())
+}
 
-pub fn subscribe_to_tcp_messages() -> broadcast::Receiver<String> {
-    MESSAGE_BROADCAST.subscribe()
+pub fn link_state(device_id: &str) -> DeviceLinkState {
+    device(device_id)
+        .map(|d| d.link_state.borrow().clone())
+        .unwrap_or(DeviceLinkState::Disconnected)
+}
+
+pub fn dropped_inbound_frames(device_id: &str) -> u64 {
+    device(device_id)
+        .map(|d| d.dropped_inbound_frames.load(Ordering::Relaxed))
+        .unwrap_or(0)
+}
+
+/// Exponential reconnect delay, configured through `TCP_RECONNECT_INITIAL_SECS`,
+/// `TCP_RECONNECT_MAX_SECS` and `TCP_RECONNECT_FACTOR`.
+#[derive(Debug, Clone)]
+struct ReconnectBackoff {
+    initial: Duration,
+    max: Duration,
+    factor: f64,
+}
+
+impl ReconnectBackoff {
+    fn from_env() -> Self {
+        fn env_f64(name: &str) -> Option<f64> {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.trim().parse::<f64>().ok())
+                .filter(|v| v.is_finite() && *v > 0.0)
+        }
+
+        let initial = env_f64("TCP_RECONNECT_INITIAL_SECS").unwrap_or(1.0);
+        let max = env_f64("TCP_RECONNECT_MAX_SECS")
+            .unwrap_or(60.0)
+            .max(initial);
+        let factor = env_f64("TCP_RECONNECT_FACTOR").unwrap_or(2.0).max(1.0);
+
+        Self {
+            initial: Duration::from_secs_f64(initial),
+            max: Duration::from_secs_f64(max),
+            factor,
+        }
+    }
+
+    /// Delay before reconnect attempt number `attempt` (starting at 1).
+    fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(64) as i32;
+        let secs = self.initial.as_secs_f64() * self.factor.powi(exponent);
+        Duration::from_secs_f64(secs.min(self.max.as_secs_f64()))
+    }
 }
 
 struct TcpConnection {
-    stream: TcpStream,
+    writer: OwnedWriteHalf,
     cipher: Cipher,
+    // Session ciphers from the mutual handshake keep their keys until the
+    // connection closes; the others follow key rotations.
+    session: bool,
+}
+
+/// Why a connection to a device could not be established.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectError {
+    AlreadyRegistered(String),
+    /// The address did not resolve.
+    Resolve(String),
+    /// The named step did not finish in time.
+    Timeout(&'static str),
+    Io(String),
+    /// The device closed the connection during authentication.
+    Closed,
+    Encryption(EncryptionError),
+    /// The device answered the legacy handshake with something other than
+    /// `authentication successful`.
+    Rejected(String),
+    Handshake(HandshakeError),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyRegistered(id) => write!(f, "Device '{}' is already registered", id),
+            Self::Resolve(e) => write!(f, "{}", e),
+            Self::Timeout(step) => write!(f, "Timed out during {}", step),
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Closed => write!(f, "Connection closed during authentication"),
+            Self::Encryption(e) => write!(f, "Failed to encrypt authentication: {}", e),
+            Self::Rejected(response) => write!(f, "Authentication failed: {}", response),
+            Self::Handshake(e) => write!(f, "Handshake failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+static NEXT_MESSAGE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// When `TCP_INBOUND_ENCRYPTION` is enabled the Pi encrypts its messages the
+/// same way we encrypt commands, and anything that fails to decrypt is dropped.
+fn inbound_encryption_from_env() -> bool {
+    std::env::var("TCP_INBOUND_ENCRYPTION")
+        .map(|v| {
+            matches!(
+                v.trim().to_ascii_lowercase().as_str(),
+                "1" | "true" | "yes" | "on"
+            )
+        })
+        .unwrap_or(false)
+}
+
+/// Upper bound for a single newline-terminated message from the Pi.
+const MAX_FRAME_LEN: usize = 64 * 1024;
+
+/// Reassembles newline-delimited messages from arbitrary TCP reads. Frames
+/// split across reads are buffered, several frames in one read are returned
+/// one by one, and frames longer than `max_frame_len` are skipped up to the
+/// next newline.
+struct LineDecoder {
+    buffer: Vec<u8>,
+    max_frame_len: usize,
+    discarding: bool,
+}
+
+#[derive(Debug, PartialEq)]
+enum FrameError {
+    TooLong,
+}
+
+impl LineDecoder {
+    fn new(max_frame_len: usize) -> Self {
+        Self {
+            buffer: Vec::new(),
+            max_frame_len,
+            discarding: false,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    fn next_frame(&mut self) -> Option<Result<String, FrameError>> {
+        loop {
+            let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') else {
+                if self.buffer.len() > self.max_frame_len {
+                    self.buffer.clear();
+                    if !self.discarding {
+                        self.discarding = true;
+                        return Some(Err(FrameError::TooLong));
+                    }
+                }
+                return None;
+            };
+
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            if self.discarding {
+                self.discarding = false;
+                continue;
+            }
+            if pos > self.max_frame_len {
+                return Some(Err(FrameError::TooLong));
+            }
+
+            let frame = String::from_utf8_lossy(&line[..pos]);
+            let frame = frame.trim_end_matches('\r');
+            if frame.trim().is_empty() {
+                continue;
+            }
+            return Some(Ok(frame.to_string()));
+        }
+    }
+}
+
+struct FramedReader {
+    device: Arc<Device>,
+    reader: OwnedReadHalf,
+    decoder: LineDecoder,
+    inbound_cipher: Option<Cipher>,
+    // Keyring generation the inbound cipher was built from, or `None` for a
+    // session cipher, which does not follow rotations.
+    keyring_generation: Option<u64>,
 }
 
-static TCP_CONNECTION: Lazy<Mutex<Option<TcpConnection>>> = Lazy::new(|| Mutex::new(None));
+impl FramedReader {
+    fn new(device: Arc<Device>, reader: OwnedReadHalf, inbound_cipher: Option<Cipher>) -> Self {
+        let keyring_generation = Some(device.keyring_generation.load(Ordering::Acquire));
+        Self {
+            device,
+            reader,
+            decoder: LineDecoder::new(MAX_FRAME_LEN),
+            inbound_cipher,
+            keyring_generation,
+        }
+    }
+
+    /// Like `next_frame`, but decrypts frames when inbound encryption is
+    /// enabled. Frames that fail to decrypt never leave this function.
+    async fn next_message(&mut self) -> std::io::Result<Option<String>> {
+        loop {
+            let Some(frame) = self.next_frame().await? else {
+                return Ok(None);
+            };
+            let Some(cipher) = self.inbound_cipher.as_mut() else {
+                return Ok(Some(frame));
+            };
+            if let Some(seen) = self.keyring_generation {
+                let generation = self.device.keyring_generation.load(Ordering::Acquire);
+                if generation != seen {
+                    cipher.set_keyring(self.device.keyring());
+                    self.keyring_generation = Some(generation);
+                }
+            }
+            match cipher.decrypt_message(frame.trim()) {
+                Ok(message) => return Ok(Some(message)),
+                Err(e) => {
+                    let dropped = self
+                        .device
+                        .dropped_inbound_frames
+                        .fetch_add(1, Ordering::Relaxed)
+                        + 1;
+                    eprintln!(
+                        "[TCP:{}] Dropping inbound frame that failed to decrypt: {:?} ({} dropped so far)",
+                        self.device.id, e, dropped
+                    );
+                }
+            }
+        }
+    }
+
+    /// Returns the next complete message, or `None` once the peer closed the
+    /// connection.
+    async fn next_frame(&mut self) -> std::io::Result<Option<String>> {
+        let mut buffer = [0u8; 4096];
+        loop {
+            match self.decoder.next_frame() {
+                Some(Ok(frame)) => return Ok(Some(frame)),
+                Some(Err(FrameError::TooLong)) => {
+                    eprintln!(
+                        "[TCP:{}] Dropping frame longer than {} bytes",
+                        self.device.id, self.decoder.max_frame_len
+                    );
+                    continue;
+                }
+                None => {}
+            }
+
+            let n = self.reader.read(&mut buffer).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.decoder.push(&buffer[..n]);
+        }
+    }
+}
 
-async fn establish_connection(addr: &str, key: &str) -> Result<(), String> {
-    let cipher = Cipher::new(key, 30);
+async fn establish_connection(
+    device: &Arc<Device>,
+    config: &DeviceConfig,
+) -> Result<FramedReader, ConnectError> {
+    let addr = config.addr.as_str();
+    device.set_link_state(DeviceLinkState::Connecting);
 
     let socket_addrs: Vec<_> = addr
         .to_socket_addrs()
-        .map_err(|e| format!("Failed to resolve address '{}': {}", addr, e))?
+        .map_err(|e| ConnectError::Resolve(format!("Failed to resolve address '{}': {}", addr, e)))?
         .collect();
 
     if socket_addrs.is_empty() {
-        return Err(format!("No valid addresses found for '{}'", addr));
+        return Err(ConnectError::Resolve(format!(
+            "No valid addresses found for '{}'",
+            addr
+        )));
     }
 
-    let mut stream = timeout(Duration::from_secs(5), TcpStream::connect(&socket_addrs[0]))
+    let stream = timeout(Duration::from_secs(5), TcpStream::connect(&socket_addrs[0]))
         .await
-        .map_err(|_| "Connection timeout".to_string())?
-        .map_err(|e| format!("Connection failed to '{}': {}", addr, e))?;
+        .map_err(|_| ConnectError::Timeout("connect"))?
+        .map_err(|e| ConnectError::Io(format!("Connection failed to '{}': {}", addr, e)))?;
 
     let local_ip = stream
         .local_addr()
-        .map_err(|e| format!("Failed to get local address: {}", e))?
+        .map_err(|e| ConnectError::Io(format!("Failed to get local address: {}", e)))?
         .ip()
         .to_string();
 
-    let mut auth_message = cipher.encrypt_message(&local_ip);
-    auth_message.push('\n');
+    device.set_link_state(DeviceLinkState::Authenticating);
+    let (reader, mut writer) = stream.into_split();
+    let keyring = device.keyring();
 
-    stream
-        .write_all(auth_message.as_bytes())
-        .await
-        .map_err(|e| format!("Failed to send authentication: {}", e))?;
+    let (reader, cipher, session) = match config.handshake {
+        HandshakeMode::Legacy => {
+            let inbound_cipher = config
+                .inbound_encryption
+                .then(|| Cipher::with_keyring(keyring.clone(), 30));
+            let mut reader = FramedReader::new(device.clone(), reader, inbound_cipher);
+            let cipher = Cipher::with_keyring(keyring, 30).with_version(config.wire_version);
+
+            let auth_message = cipher
+                .encrypt_message(&local_ip)
+                .map_err(ConnectError::Encryption)?;
+            write_line(&mut writer, &auth_message).await?;
+
+            let response = read_line(&mut reader, "authentication").await?;
+            let Ok(DeviceResponse::AuthenticationSuccessful { message_ids }) =
+                DeviceResponse::parse(&response)
+            else {
+                return Err(ConnectError::Rejected(response));
+            };
+            device.message_ids.store(message_ids, Ordering::Release);
+            (reader, cipher, false)
+        }
+        HandshakeMode::Mutual => {
+            let mut reader = FramedReader::new(device.clone(), reader, None);
+            let (mut handshake, hello) =
+                ServerHandshake::start(&keyring).map_err(ConnectError::Handshake)?;
+            write_line(&mut writer, &hello).await?;
+
+            let challenge = read_line(&mut reader, "handshake").await?;
+            let proof = handshake
+                .answer_challenge(&challenge)
+                .map_err(ConnectError::Handshake)?;
+            write_line(&mut writer, &proof).await?;
 
-    let mut buffer = vec![0u8; 256];
-    let n = timeout(Duration::from_secs(5), stream.read(&mut buffer))
+            let verdict = read_line(&mut reader, "handshake").await?;
+            let keys = handshake
+                .finish(&verdict)
+                .map_err(ConnectError::Handshake)?;
+            reader.inbound_cipher = Some(handshake::session_cipher(keys.inbound, 30));
+            reader.keyring_generation = None;
+            // Firmware that speaks the handshake always echoes message ids.
+            device.message_ids.store(true, Ordering::Release);
+            (reader, handshake::session_cipher(keys.outbound, 30), true)
+        }
+    };
+
+    *device.connection.lock().await = Some(TcpConnection {
+        writer,
+        cipher,
+        session,
+    });
+    device.set_link_state(DeviceLinkState::Connected {
+        since: chrono::Utc::now().timestamp(),
+    });
+
+    Ok(reader)
+}
+
+async fn write_line(writer: &mut OwnedWriteHalf, line: &str) -> Result<(), ConnectError> {
+    writer
+        .write_all(format!("{}\n", line).as_bytes())
         .await
-        .map_err(|_| "Authentication timeout".to_string())?
-        .map_err(|e| format!("Failed to read authentication response: {}", e))?;
+        .map_err(|e| ConnectError::Io(format!("Failed to send authentication: {}", e)))
+}
 
-    let response = String::from_utf8_lossy(&buffer[..n]).to_string();
+async fn read_line(reader: &mut FramedReader, step: &'static str) -> Result<String, ConnectError> {
+    timeout(Duration::from_secs(5), reader.next_message())
+        .await
+        .map_err(|_| ConnectError::Timeout(step))?
+        .map_err(|e| ConnectError::Io(format!("Failed to read authentication response: {}", e)))?
+        .ok_or(ConnectError::Closed)
+}
 
-    if !response.contains("authentication successful") {
-        return Err(format!("Authentication failed: {}", response));
+/// Routes a message from the Pi: replies go to the caller waiting on that
+/// message id, everything else is an unsolicited event for the broadcast.
+/// A Pi without message ids cannot tell replies from events, so each of its
+/// messages is broadcast, and also answers the oldest pending command that
+/// accepts it as a reply.
+fn dispatch_message(device: &Device, message: String) {
+    if !device.message_ids.load(Ordering::Acquire) {
+        if let Ok(response) = DeviceResponse::parse(&message) {
+            let oldest = device
+                .pending_replies
+                .iter()
+                .filter(|entry| entry.command.accepts(&response))
+                .map(|entry| *entry.key())
+                .min();
+            if let Some((_, pending)) = oldest.and_then(|id| device.pending_replies.remove(&id)) {
+                let _ = pending.reply_tx.send(message.clone());
+            }
+        }
+        let _ = device.messages.send(message);
+        return;
     }
+    match split_message_id(&message) {
+        (Some(id), payload) => match device.pending_replies.remove(&id) {
+            Some((_, pending)) => {
+                let _ = pending.reply_tx.send(payload.to_string());
+            }
+            None => eprintln!(
+                "[TCP:{}] Dropping reply for unknown message id {}",
+                device.id, id
+            ),
+        },
+        (None, _) => {
+            let _ = device.messages.send(message);
+        }
+    }
+}
 
-    *TCP_CONNECTION.lock().await = Some(TcpConnection { stream, cipher });
+async fn read_until_disconnected(reader: &mut FramedReader) {
+    let device = reader.device.clone();
+    let mut consecutive_errors = 0;
 
-    Ok(())
+    loop {
+        match reader.next_message().await {
+            Ok(Some(message)) => {
+                dispatch_message(&device, message);
+                consecutive_errors = 0;
+            }
+            Ok(None) => {
+                eprintln!("[TCP:{}] Connection closed by server", device.id);
+                return;
+            }
+            Err(e) => {
+                consecutive_errors += 1;
+                eprintln!(
+                    "[TCP:{}] Read error ({}): {}",
+                    device.id, consecutive_errors, e
+                );
+                if consecutive_errors > 3 {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+        }
+    }
 }
 
-pub async fn connect(addr: &str, key: &str) -> Result<(), String> {
-    establish_connection(addr, key).await?;
+/// Registers a device and keeps its link alive in the background. The
+/// returned result only reflects the first connection attempt; reconnects
+/// with exponential backoff happen either way.
+pub async fn connect(config: DeviceConfig) -> Result<(), ConnectError> {
+    let device = {
+        let mut devices = DEVICES.write().unwrap_or_else(|e| e.into_inner());
+        if devices.iter().any(|d| d.id == config.id) {
+            return Err(ConnectError::AlreadyRegistered(config.id.clone()));
+        }
+        let device = Arc::new(Device::new(&config));
+        devices.push(device.clone());
+        device
+    };
 
-    let addr_owned = addr.to_string();
-    let key_owned = key.to_string();
+    let initial = establish_connection(&device, &config).await;
+    let result = initial.as_ref().map(|_| ()).map_err(Clone::clone);
+    let backoff = ReconnectBackoff::from_env();
 
     tokio::spawn(async move {
+        let mut reader = initial.ok();
+        let mut attempt = 0u32;
+
         loop {
-            let mut buffer = vec![0u8; 256];
-            let mut consecutive_errors = 0;
+            if let Some(mut connected) = reader.take() {
+                attempt = 0;
+                read_until_disconnected(&mut connected).await;
 
-            loop {
-                let has_connection = TCP_CONNECTION.lock().await.is_some();
+                *device.connection.lock().await = None;
+                // Dropping the senders fails every in-flight command immediately.
+                device.pending_replies.clear();
+            }
 
-                if !has_connection {
-                    break;
-                }
+            attempt = attempt.saturating_add(1);
+            let delay = backoff.delay(attempt);
+            device.set_link_state(DeviceLinkState::Backoff {
+                attempt,
+                next_retry: chrono::Utc::now().timestamp() + delay.as_secs() as i64,
+            });
+            eprintln!(
+                "[TCP:{}] Attempting reconnection #{} in {:.1} seconds...",
+                device.id,
+                attempt,
+                delay.as_secs_f64()
+            );
+            tokio::time::sleep(delay).await;
 
-                let read_result = {
-                    let mut guard = TCP_CONNECTION.lock().await;
-                    let connection = match guard.as_mut() {
-                        Some(conn) => conn,
-                        None => break,
-                    };
-
-                    timeout(Duration::from_secs(2), connection.stream.read(&mut buffer)).await
-                };
-
-                match read_result {
-                    Ok(Ok(n)) if n > 0 => {
-                        let message = String::from_utf8_lossy(&buffer[..n]).to_string();
-                        let _ = MESSAGE_BROADCAST.send(message);
-                        consecutive_errors = 0;
-                    }
-                    Ok(Ok(_)) => {
-                        eprintln!("[TCP] Connection closed by server");
-                        break;
-                    }
-                    Ok(Err(e)) => {
-                        consecutive_errors += 1;
-                        eprintln!("[TCP] Read error ({}): {}", consecutive_errors, e);
-                        if consecutive_errors > 3 {
-                            break;
-                        }
-                        tokio::time::sleep(Duration::from_millis(500)).await;
-                    }
-                    Err(_) => continue,
+            match establish_connection(&device, &config).await {
+                Ok(new_reader) => {
+                    println!("[TCP:{}] Reconnected successfully", device.id);
+                    reader = Some(new_reader);
                 }
+                Err(e) => eprintln!("[TCP:{}] Reconnection failed: {}", device.id, e),
             }
+        }
+    });
 
-            *TCP_CONNECTION.lock().await = None;
-            eprintln!("[TCP] Attempting reconnection in 5 seconds...");
-            tokio::time::sleep(Duration::from_secs(5)).await;
+    result
+}
 
-            match establish_connection(&addr_owned, &key_owned).await {
-                Ok(_) => println!("[TCP] Reconnected successfully"),
-                Err(e) => eprintln!("[TCP] Reconnection failed: {}", e),
-            }
+pub async fn send_command(
+    device_id: &str,
+    cmd: &DeviceCommand,
+) -> Result<DeviceResponse, DeviceError> {
+    let device =
+        device(device_id).ok_or_else(|| DeviceError::UnknownDevice(device_id.to_string()))?;
+
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let Some(id) = write_command(&device, cmd, reply_tx).await? else {
+        return cmd.interpret(None);
+    };
+
+    let reply = match timeout(Duration::from_secs(3), reply_rx).await {
+        Ok(Ok(reply)) => Some(reply),
+        Ok(Err(_)) => return Err(DeviceError::Io("Connection lost".to_string())),
+        Err(_) => {
+            device.pending_replies.remove(&id);
+            None
         }
-    });
+    };
 
-    Ok(())
+    cmd.interpret(reply.as_deref())
 }
 
-pub async fn send_command(cmd: &str) -> Result<String, String> {
-    let mut guard = TCP_CONNECTION.lock().await;
-    let connection = guard.as_mut().ok_or("Not connected")?;
+/// Outcome of [`rotate_key`].
+#[derive(Debug)]
+pub struct KeyRotation {
+    pub keyring: Keyring,
+    /// Where the keyring was saved, or why it was not. Unsaved, it must go
+    /// into the device's key variable before the next restart.
+    pub saved_to: Result<PathBuf, String>,
+}
 
-    let mut encrypted = connection.cipher.encrypt_message(cmd);
+/// Generates a new key, announces it to the device over the encrypted link
+/// and makes it the active key once the device confirms. Both sides keep
+/// accepting the previous keys, so nothing in flight is lost.
+///
+/// The new keyring is saved to the device's keyring file, which is loaded
+/// again on startup.
+pub async fn rotate_key(device_id: &str) -> Result<KeyRotation, String> {
+    let device = device(device_id).ok_or_else(|| format!("Unknown device '{}'", device_id))?;
+    if device.handshake == HandshakeMode::Legacy && device.wire_version == WireVersion::Legacy {
+        return Err(
+            "Key rotation needs an authenticated wire format; set the device's encryption version to v1 or v2, or use the mutual handshake"
+                .to_string(),
+        );
+    }
+    let _rotation = device.rotation.lock().await;
+
+    let previous = device.keyring();
+    let key_id = previous
+        .iter()
+        .map(|(id, _)| id)
+        .max()
+        .and_then(|id| id.checked_add(1))
+        .ok_or_else(|| "No key id left".to_string())?;
+    let key = CipherKey::generate().map_err(|e| e.to_string())?;
+    let mut rotated = previous.clone();
+    rotated
+        .rotate(key_id, key.clone())
+        .map_err(|e| e.to_string())?;
+
+    // Accept the new key inbound before announcing it: the device may
+    // already use it for its reply.
+    device.set_keyring(rotated.clone());
+    let cmd = DeviceCommand::RotateKey {
+        key_id,
+        key: key.to_base64(),
+    };
+    match send_command(device_id, &cmd).await {
+        Ok(_) => {
+            if let Some(connection) = device.connection.lock().await.as_mut() {
+                if !connection.session {
+                    connection.cipher.set_keyring(rotated.clone());
+                }
+            }
+            println!("[TCP:{}] Rotated to key {}", device.id, key_id);
+            let saved_to = match &device.keyring_file {
+                Some(path) => save_keyring(path, &rotated).await.map(|_| path.clone()),
+                None => Err("TCP_KEYRING_DIR is empty, so keyrings are not saved".to_string()),
+            };
+            if let Err(e) = &saved_to {
+                eprintln!("[TCP:{}] Rotated keyring not saved: {}", device.id, e);
+            }
+            Ok(KeyRotation {
+                keyring: rotated,
+                saved_to,
+            })
+        }
+        Err(e) => {
+            device.set_keyring(previous);
+            Err(format!("Key rotation failed: {}", e))
+        }
+    }
+}
+
+/// Sends `cmd` and registers `reply_tx` for its reply under the returned id.
+/// Without message ids only commands with a fixed reply wait for one: the
+/// others cannot be told apart from events, so they return `None` and count
+/// as acknowledged once written.
+async fn write_command(
+    device: &Device,
+    cmd: &DeviceCommand,
+    reply_tx: oneshot::Sender<String>,
+) -> Result<Option<u64>, DeviceError> {
+    let mut guard = device.connection.lock().await;
+    let connection = guard.as_mut().ok_or(DeviceError::NotConnected)?;
+
+    let id = NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed);
+    let message_ids = device.message_ids.load(Ordering::Acquire);
+    let message = if message_ids {
+        tag_message(id, &cmd.to_wire())
+    } else {
+        cmd.to_wire()
+    };
+    let mut encrypted = connection
+        .cipher
+        .encrypt_message(&message)
+        .map_err(|e| DeviceError::Io(format!("Encryption failed: {}", e)))?;
     encrypted.push_str("\r\n");
 
-    timeout(
+    let awaits_reply = message_ids || cmd.has_fixed_reply();
+    if awaits_reply {
+        device.pending_replies.insert(
+            id,
+            PendingReply {
+                command: cmd.clone(),
+                reply_tx,
+            },
+        );
+    }
+    let written = timeout(
         Duration::from_secs(3),
-        connection.stream.write_all(encrypted.as_bytes()),
+        connection.writer.write_all(encrypted.as_bytes()),
     )
     .await
-    .map_err(|_| "Write timeout".to_string())?
-    .map_err(|e| format!("Write failed: {}", e))?;
-
-    let mut buffer = vec![0u8; 256];
-    match timeout(Duration::from_secs(3), connection.stream.read(&mut buffer)).await {
-        Ok(Ok(n)) if n > 0 => Ok(String::from_utf8_lossy(&buffer[..n]).to_string()),
-        Ok(Ok(_)) => Ok(String::new()),
-        Ok(Err(e)) => Err(format!("Read failed: {}", e)),
-        Err(_) => Ok(String::new()),
+    .map_err(|_| DeviceError::Io("Write timeout".to_string()))
+    .and_then(|result| result.map_err(|e| DeviceError::Io(format!("Write failed: {}", e))));
+    if let Err(e) = written {
+        device.pending_replies.remove(&id);
+        return Err(e);
+    }
+    Ok(awaits_reply.then_some(id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        connect, dropped_inbound_frames, is_valid_device_id, link_state, load_keyring, parse_key,
+        rotate_key, send_command, subscribe_to_tcp_messages, ConnectError, DeviceConfig,
+        FrameError, HandshakeError, HandshakeMode, Keyring, LineDecoder, ReconnectBackoff,
+        WireVersion,
+    };
+    use crate::device_link::DeviceLinkState;
+    use crate::device_protocol::{DeviceCommand, DeviceError, DeviceResponse};
+    use mock_device::{MockConfig, MockDevice};
+    use std::time::Duration;
+
+    const TEST_KEY: &str = "0123456789abcdef0123456789abcdef";
+
+    fn device_config(id: &str, mock: &MockDevice, key: &str) -> DeviceConfig {
+        DeviceConfig {
+            id: id.to_string(),
+            name: id.to_string(),
+            addr: mock.tcp_addr().to_string(),
+            keyring: Keyring::parse(key).unwrap(),
+            keyring_file: None,
+            wire_version: WireVersion::Legacy,
+            handshake: HandshakeMode::Legacy,
+            inbound_encryption: false,
+        }
+    }
+
+    async fn connect_to_mock(id: &str, config: MockConfig) -> MockDevice {
+        let mock = mock_device::spawn(config).await.unwrap();
+        connect(device_config(id, &mock, TEST_KEY)).await.unwrap();
+        mock
+    }
+
+    #[test]
+    fn test_invalid_key_names_the_variable() {
+        assert!(parse_key("TCP_ENCRYPTION_KEY", &format!(" {} ", TEST_KEY)).is_ok());
+        let err = parse_key("TCP_ENCRYPTION_KEY", "too-short").unwrap_err();
+        assert!(
+            err.starts_with("TCP_ENCRYPTION_KEY: Invalid encryption key"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_device_ids() {
+        assert!(is_valid_device_id("birdhouse"));
+        assert!(is_valid_device_id("feeder-cam_2"));
+        assert!(!is_valid_device_id(""));
+        assert!(!is_valid_device_id("Feeder"));
+        assert!(!is_valid_device_id("feeder cam"));
+        assert!(!is_valid_device_id(&"x".repeat(33)));
+    }
+
+    #[test]
+    fn test_reconnect_backoff_grows_and_caps() {
+        let backoff = ReconnectBackoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+            factor: 2.0,
+        };
+        let delays: Vec<u64> = (1..=6).map(|a| backoff.delay(a).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(10));
+    }
+
+    fn drain(decoder: &mut LineDecoder) -> Vec<Result<String, FrameError>> {
+        std::iter::from_fn(|| decoder.next_frame()).collect()
+    }
+
+    #[test]
+    fn test_reassembles_split_frames() {
+        let mut decoder = LineDecoder::new(64);
+        decoder.push(b"#3 IR STA");
+        assert!(drain(&mut decoder).is_empty());
+        decoder.push(b"TE IS ");
+        assert!(drain(&mut decoder).is_empty());
+        decoder.push(b"ON\r\n");
+        assert_eq!(
+            drain(&mut decoder),
+            vec![Ok("#3 IR STATE IS ON".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_splits_coalesced_frames() {
+        let mut decoder = LineDecoder::new(64);
+        decoder.push(b"IR LED STATE: ON\n#4 IMAGE SAVED\r\n\nIR FILTER");
+        assert_eq!(
+            drain(&mut decoder),
+            vec![
+                Ok("IR LED STATE: ON".to_string()),
+                Ok("#4 IMAGE SAVED".to_string()),
+            ]
+        );
+        decoder.push(b" STATE: OFF\n");
+        assert_eq!(
+            drain(&mut decoder),
+            vec![Ok("IR FILTER STATE: OFF".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_skips_oversized_frames() {
+        let mut decoder = LineDecoder::new(8);
+        decoder.push(b"0123456789");
+        assert_eq!(drain(&mut decoder), vec![Err(FrameError::TooLong)]);
+        decoder.push(b"abcdef");
+        assert!(drain(&mut decoder).is_empty());
+        decoder.push(b"\nshort\n0123456789\nok\n");
+        assert_eq!(
+            drain(&mut decoder),
+            vec![
+                Ok("short".to_string()),
+                Err(FrameError::TooLong),
+                Ok("ok".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_commands_against_mock_device() {
+        let mock = connect_to_mock("mock-commands", MockConfig::new(TEST_KEY)).await;
+        assert!(matches!(
+            link_state("mock-commands"),
+            DeviceLinkState::Connected { .. }
+        ));
+
+        assert_eq!(
+            send_command("mock-commands", &DeviceCommand::IrOn).await,
+            Ok(DeviceResponse::IrState(true))
+        );
+        assert!(mock.ir_enabled());
+        assert_eq!(
+            send_command("mock-commands", &DeviceCommand::GetIrState).await,
+            Ok(DeviceResponse::IrState(true))
+        );
+        assert!(matches!(
+            send_command("mock-commands", &DeviceCommand::SaveImage).await,
+            Ok(DeviceResponse::Acknowledged(Some(_)))
+        ));
+        assert_eq!(mock.images_saved(), 1);
+
+        let add = DeviceCommand::AddNewsletter {
+            email: "Bird@Example.ch".to_string(),
+        };
+        assert!(send_command("mock-commands", &add).await.is_ok());
+        assert_eq!(mock.subscribers(), vec!["bird@example.ch".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_mock_device_rejects_wrong_key() {
+        let mock = mock_device::spawn(MockConfig::new(TEST_KEY)).await.unwrap();
+        let config = device_config("mock-wrong-key", &mock, "fedcba9876543210fedcba9876543210");
+
+        let err = connect(config).await.unwrap_err();
+        assert!(matches!(err, ConnectError::Rejected(_)), "{}", err);
+        assert_eq!(
+            send_command("mock-wrong-key", &DeviceCommand::GetIrState).await,
+            Err(DeviceError::NotConnected)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_commands_in_v1_wire_format() {
+        let mock = mock_device::spawn(MockConfig::new(TEST_KEY)).await.unwrap();
+        let mut config = device_config("mock-v1", &mock, TEST_KEY);
+        config.wire_version = WireVersion::V1;
+        connect(config).await.unwrap();
+
+        assert_eq!(
+            send_command("mock-v1", &DeviceCommand::IrOn).await,
+            Ok(DeviceResponse::IrState(true))
+        );
+        assert!(mock.ir_enabled());
+    }
+
+    #[tokio::test]
+    async fn test_key_rotation() {
+        let mock = mock_device::spawn(MockConfig::new(TEST_KEY)).await.unwrap();
+        let keyring_file =
+            std::env::temp_dir().join(format!("birdhouse-keyring-{}", uuid::Uuid::new_v4()));
+        let mut config = device_config("mock-rotate", &mock, TEST_KEY);
+        config.wire_version = WireVersion::V2;
+        config.keyring_file = Some(keyring_file.clone());
+        connect(config).await.unwrap();
+
+        let rotation = rotate_key("mock-rotate").await.unwrap();
+        assert_eq!(rotation.saved_to, Ok(keyring_file.clone()));
+        let keyring = rotation.keyring;
+        assert_eq!(keyring.active_id(), 1);
+        assert_eq!(keyring.len(), 2);
+        assert_eq!(mock.active_key_id(), 1);
+        assert_eq!(
+            send_command("mock-rotate", &DeviceCommand::IrOn).await,
+            Ok(DeviceResponse::IrState(true))
+        );
+
+        let keyring = rotate_key("mock-rotate").await.unwrap().keyring;
+        assert_eq!(keyring.active_id(), 2);
+        assert_eq!(mock.active_key_id(), 2);
+        assert_eq!(
+            send_command("mock-rotate", &DeviceCommand::GetIrState).await,
+            Ok(DeviceResponse::IrState(true))
+        );
+
+        // After a restart the saved keyring replaces the configured key.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&keyring_file)
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let mut config = device_config("mock-rotate-again", &mock, TEST_KEY);
+        config.keyring = load_keyring(Some(&keyring_file), config.keyring);
+        assert_eq!(config.keyring.to_spec(), keyring.to_spec());
+        connect(config).await.unwrap();
+        let _ = std::fs::remove_file(&keyring_file);
+    }
+
+    #[test]
+    fn test_stale_keyring_file_is_ignored() {
+        let keyring_file =
+            std::env::temp_dir().join(format!("birdhouse-keyring-{}", uuid::Uuid::new_v4()));
+        let configured = Keyring::parse(TEST_KEY).unwrap();
+        assert_eq!(
+            load_keyring(Some(&keyring_file), configured.clone()).to_spec(),
+            configured.to_spec()
+        );
+
+        // Saved before the key variable was replaced by hand.
+        let other = Keyring::parse(&format!("3:{}", "b".repeat(32))).unwrap();
+        std::fs::write(&keyring_file, other.to_spec()).unwrap();
+        assert_eq!(
+            load_keyring(Some(&keyring_file), configured.clone()).to_spec(),
+            configured.to_spec()
+        );
+
+        std::fs::write(&keyring_file, "not a keyring").unwrap();
+        assert_eq!(
+            load_keyring(Some(&keyring_file), configured.clone()).to_spec(),
+            configured.to_spec()
+        );
+        let _ = std::fs::remove_file(&keyring_file);
+    }
+
+    #[tokio::test]
+    async fn test_key_rotation_needs_authenticated_format() {
+        let mock = connect_to_mock("mock-rotate-legacy", MockConfig::new(TEST_KEY)).await;
+        let err = rotate_key("mock-rotate-legacy").await.unwrap_err();
+        assert!(err.contains("authenticated wire format"), "{}", err);
+        assert_eq!(mock.active_key_id(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_mutual_handshake() {
+        let mock = mock_device::spawn(MockConfig::new(TEST_KEY)).await.unwrap();
+        let mut config = device_config("mock-mutual", &mock, TEST_KEY);
+        config.handshake = HandshakeMode::Mutual;
+        connect(config).await.unwrap();
+
+        assert_eq!(
+            send_command("mock-mutual", &DeviceCommand::IrOn).await,
+            Ok(DeviceResponse::IrState(true))
+        );
+
+        // Rotation works over the session, and the next handshake uses the
+        // new key.
+        let keyring = rotate_key("mock-mutual").await.unwrap().keyring;
+        assert_eq!(mock.active_key_id(), 1);
+        assert_eq!(
+            send_command("mock-mutual", &DeviceCommand::GetIrState).await,
+            Ok(DeviceResponse::IrState(true))
+        );
+        let mut config = device_config("mock-mutual-rotated", &mock, &keyring.to_spec());
+        config.handshake = HandshakeMode::Mutual;
+        connect(config).await.unwrap();
+        assert_eq!(
+            send_command("mock-mutual-rotated", &DeviceCommand::GetIrState).await,
+            Ok(DeviceResponse::IrState(true))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mutual_handshake_failures_are_typed() {
+        let mock = mock_device::spawn(MockConfig::new(TEST_KEY)).await.unwrap();
+
+        let mut config = device_config("mock-mutual-wrong-key", &mock, "passphrase:impostor");
+        config.handshake = HandshakeMode::Mutual;
+        assert_eq!(
+            connect(config).await,
+            Err(ConnectError::Handshake(HandshakeError::BadProof))
+        );
+
+        let spec = format!("5:{}", TEST_KEY);
+        let mut config = device_config("mock-mutual-unknown-id", &mock, &spec);
+        config.handshake = HandshakeMode::Mutual;
+        assert!(matches!(
+            connect(config).await,
+            Err(ConnectError::Handshake(HandshakeError::Denied(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_dropped_replies_time_out() {
+        let mut config = MockConfig::new(TEST_KEY);
+        config.faults.drop_rate = 1.0;
+        connect_to_mock("mock-drop", config).await;
+
+        assert_eq!(
+            send_command("mock-drop", &DeviceCommand::GetIrState).await,
+            Err(DeviceError::Timeout)
+        );
+        // Firmware that does not confirm saving an image may stay silent.
+        assert_eq!(
+            send_command("mock-drop", &DeviceCommand::SaveImage).await,
+            Ok(DeviceResponse::Acknowledged(None))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delayed_replies_still_match() {
+        let mut config = MockConfig::new(TEST_KEY);
+        config.faults.max_delay = Duration::from_millis(300);
+        connect_to_mock("mock-delay", config).await;
+
+        let (on, saved) = tokio::join!(
+            send_command("mock-delay", &DeviceCommand::IrOn),
+            send_command("mock-delay", &DeviceCommand::SaveImage),
+        );
+        assert_eq!(on, Ok(DeviceResponse::IrState(true)));
+        assert!(matches!(saved, Ok(DeviceResponse::Acknowledged(Some(_)))));
+    }
+
+    #[tokio::test]
+    async fn test_untagged_replies_skip_events() {
+        let mut config = MockConfig::new(TEST_KEY);
+        config.message_ids = false;
+        config.event_interval = Some(Duration::from_millis(1));
+        let mock = connect_to_mock("mock-untagged", config).await;
+        let mut events = subscribe_to_tcp_messages("mock-untagged").unwrap();
+
+        // Sensor and IR events keep arriving between the replies; only a
+        // reply the command accepts may answer it, and commands without a
+        // fixed reply never wait for one.
+        let add = DeviceCommand::AddNewsletter {
+            email: "bird@example.ch".into(),
+        };
+        let (on, saved, added) = tokio::join!(
+            send_command("mock-untagged", &DeviceCommand::IrOn),
+            send_command("mock-untagged", &DeviceCommand::SaveImage),
+            send_command("mock-untagged", &add),
+        );
+        assert_eq!(on, Ok(DeviceResponse::IrState(true)));
+        assert_eq!(saved, Ok(DeviceResponse::Acknowledged(None)));
+        assert_eq!(added, Ok(DeviceResponse::Acknowledged(None)));
+
+        // Replies to earlier commands are not left for later ones.
+        for _ in 0..3 {
+            assert_eq!(
+                send_command("mock-untagged", &DeviceCommand::IrOn).await,
+                Ok(DeviceResponse::IrState(true))
+            );
+        }
+        assert!(mock.ir_enabled());
+        assert_eq!(mock.images_saved(), 1);
+        assert_eq!(mock.subscribers(), ["bird@example.ch"]);
+        assert!(!matches!(
+            events.try_recv(),
+            Err(tokio::sync::broadcast::error::TryRecvError::Empty)
+        ));
+    }
+
+    async fn connect_encrypted(id: &str, faults: mock_device::Faults) -> MockDevice {
+        let mut mock_config = MockConfig::new(TEST_KEY);
+        mock_config.encrypt_replies = true;
+        mock_config.faults = faults;
+        let mock = mock_device::spawn(mock_config).await.unwrap();
+        let mut config = device_config(id, &mock, TEST_KEY);
+        config.wire_version = WireVersion::V1;
+        config.inbound_encryption = true;
+        connect(config).await.unwrap();
+        mock
+    }
+
+    #[tokio::test]
+    async fn test_inbound_encryption_needs_an_encrypted_auth_reply() {
+        let mock = mock_device::spawn(MockConfig::new(TEST_KEY)).await.unwrap();
+        let mut config = device_config("mock-plain-auth", &mock, TEST_KEY);
+        config.inbound_encryption = true;
+        assert_eq!(
+            connect(config).await,
+            Err(ConnectError::Timeout("authentication"))
+        );
+        assert_eq!(dropped_inbound_frames("mock-plain-auth"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_replayed_frames_are_dropped() {
+        let faults = mock_device::Faults {
+            replay_rate: 1.0,
+            ..Default::default()
+        };
+        connect_encrypted("mock-replay", faults).await;
+
+        // Every reply arrives twice; the copy is dropped and counted.
+        for expected in [true, true] {
+            assert_eq!(
+                send_command("mock-replay", &DeviceCommand::IrOn).await,
+                Ok(DeviceResponse::IrState(expected))
+            );
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(dropped_inbound_frames("mock-replay"), 2);
+        assert!(matches!(
+            link_state("mock-replay"),
+            DeviceLinkState::Connected { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tampered_frames_are_dropped() {
+        let faults = mock_device::Faults {
+            tamper_rate: 1.0,
+            ..Default::default()
+        };
+        let mock = connect_encrypted("mock-tamper", faults).await;
+
+        // The reply fails its authentication tag, but the link stays up and
+        // commands still reach the device.
+        assert_eq!(
+            send_command("mock-tamper", &DeviceCommand::IrOn).await,
+            Err(DeviceError::Timeout)
+        );
+        assert!(mock.ir_enabled());
+        assert_eq!(dropped_inbound_frames("mock-tamper"), 1);
+        assert!(matches!(
+            link_state("mock-tamper"),
+            DeviceLinkState::Connected { .. }
+        ));
+        assert_eq!(
+            send_command("mock-tamper", &DeviceCommand::IrOff).await,
+            Err(DeviceError::Timeout)
+        );
+        assert!(!mock.ir_enabled());
+        assert_eq!(dropped_inbound_frames("mock-tamper"), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unsolicited_events_are_broadcast() {
+        let mut config = MockConfig::new(TEST_KEY);
+        config.event_interval = Some(Duration::from_millis(50));
+        connect_to_mock("mock-events", config).await;
+
+        let mut rx = subscribe_to_tcp_messages("mock-events").unwrap();
+        let event = tokio::time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!event.starts_with('#'), "{}", event);
     }
 }
@@ -1,3 +1,4 @@
+use crate::device_link::DeviceLinkState;
 use dioxus::prelude::*;
 #[cfg(target_arch = "wasm32")]
 use gloo_timers::callback::Interval;
@@ -16,6 +17,14 @@ struct ViewerWsCallbacks {
     _on_error: Closure<dyn FnMut(web_sys::Event)>,
 }
 
+#[cfg(target_arch = "wasm32")]
+#[derive(serde::Deserialize)]
+struct DeviceLinkMessage {
+    #[serde(rename = "type")]
+    kind: String,
+    link: DeviceLinkState,
+}
+
 #[cfg(target_arch = "wasm32")]
 fn parse_bool_from_state_payload(
     payload: &str,
@@ -48,6 +57,11 @@ pub struct TcpState {
     pub ir_filter_enabled: Signal<bool>,
     pub is_admin: Signal<bool>,
     pub ws_connected: Signal<bool>,
+    /// Last known state of the server's link to the birdhouse, `None` until
+    /// the first update arrives over the websocket.
+    pub device_link: Signal<Option<DeviceLinkState>>,
+    /// Device the viewer websocket is subscribed to, `None` for the default.
+    pub device_id: Signal<Option<String>>,
     #[cfg(target_arch = "wasm32")]
     pub ws: Signal<Option<WebSocket>>,
     #[cfg(target_arch = "wasm32")]
@@ -65,6 +79,8 @@ impl TcpState {
             ir_filter_enabled: Signal::new(false),
             is_admin: Signal::new(false),
             ws_connected: Signal::new(false),
+            device_link: Signal::new(None),
+            device_id: Signal::new(None),
             #[cfg(target_arch = "wasm32")]
             ws: Signal::new(None),
             #[cfg(target_arch = "wasm32")]
@@ -76,6 +92,38 @@ impl TcpState {
         }
     }
 
+    /// Switches the viewer websocket to another device. Device state is reset
+    /// until the new device reports in.
+    pub fn select_device(&mut self, device_id: Option<String>) {
+        if *self.device_id.peek() == device_id {
+            return;
+        }
+        self.device_id.set(device_id);
+        self.device_link.set(None);
+        self.ir_enabled.set(false);
+        self.ir_filter_enabled.set(false);
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            let previous = self.ws.peek().clone();
+            if let Some(socket) = previous {
+                detach_viewer_socket_handlers(&socket);
+                let _ = socket.close();
+            }
+            self.ws.set(None);
+            self.ws_connected.set(false);
+            open_viewer_websocket(
+                self.ir_enabled,
+                self.ir_filter_enabled,
+                self.ws_connected,
+                self.device_link,
+                self.device_id,
+                self.ws,
+                self.ws_callbacks,
+            );
+        }
+    }
+
     #[cfg(target_arch = "wasm32")]
     pub fn init_websocket(&mut self) {
         if self.heartbeat.read().is_none() {
@@ -92,6 +140,8 @@ impl TcpState {
             let ir_enabled = self.ir_enabled;
             let ir_filter = self.ir_filter_enabled;
             let ws_connected = self.ws_connected;
+            let device_link = self.device_link;
+            let device_id = self.device_id;
             let ws_handle = self.ws;
             let ws_callbacks = self.ws_callbacks;
 
@@ -101,6 +151,8 @@ impl TcpState {
                         ir_enabled,
                         ir_filter,
                         ws_connected,
+                        device_link,
+                        device_id,
                         ws_handle,
                         ws_callbacks,
                     );
@@ -115,6 +167,8 @@ impl TcpState {
                 self.ir_enabled,
                 self.ir_filter_enabled,
                 self.ws_connected,
+                self.device_link,
+                self.device_id,
                 self.ws,
                 self.ws_callbacks,
             );
@@ -135,6 +189,8 @@ fn open_viewer_websocket(
     mut ir_enabled: Signal<bool>,
     mut ir_filter: Signal<bool>,
     mut ws_connected: Signal<bool>,
+    mut device_link: Signal<Option<DeviceLinkState>>,
+    device_id: Signal<Option<String>>,
     mut ws_handle: Signal<Option<WebSocket>>,
     mut ws_callbacks: Signal<Option<ViewerWsCallbacks>>,
 ) {
@@ -158,9 +214,20 @@ fn open_viewer_websocket(
     let session_id = get_or_create_session_id();
     web_sys::console::log_1(&format!("Session ID: {}", session_id).into());
 
+    // Device ids are plain slugs; anything else falls back to the default device.
+    let device_param = device_id
+        .peek()
+        .as_ref()
+        .filter(|id| {
+            id.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        .map(|id| format!("&device={}", id))
+        .unwrap_or_default();
+
     let socket = match WebSocket::new(&format!(
-        "{}://{}/ws/tcp?role=viewer&session_id={}",
-        ws_protocol, host, session_id
+        "{}://{}/ws/tcp?role=viewer&session_id={}{}",
+        ws_protocol, host, session_id, device_param
     )) {
         Ok(ws) => ws,
         Err(err) => {
@@ -171,6 +238,13 @@ fn open_viewer_websocket(
 
     let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
         if let Some(text) = event.data().as_string() {
+            if let Ok(msg) = serde_json::from_str::<DeviceLinkMessage>(&text) {
+                if msg.kind == "device_link" {
+                    device_link.set(Some(msg.link));
+                }
+                return;
+            }
+
             let payload = text.to_uppercase();
             if let Some(state) = parse_bool_from_state_payload(
                 &payload,
@@ -0,0 +1,192 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+#[cfg(feature = "server")]
+/// A stored measurement. Each row holds exactly one of these.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TimeSeriesValue {
+    Double(f64),
+    Bool(bool),
+    Text(String),
+}
+
+/// How the values in one time bucket are combined. All but `Count` only
+/// look at numeric values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregate {
+    Min,
+    Max,
+    Mean,
+    Last,
+    Count,
+}
+
+impl FromStr for Aggregate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "min" => Ok(Self::Min),
+            "max" => Ok(Self::Max),
+            "mean" | "avg" => Ok(Self::Mean),
+            "last" => Ok(Self::Last),
+            "count" => Ok(Self::Count),
+            other => Err(format!(
+                "Unknown aggregate '{}' (expected min, max, mean, last or count)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Min => "min",
+            Self::Max => "max",
+            Self::Mean => "mean",
+            Self::Last => "last",
+            Self::Count => "count",
+        })
+    }
+}
+
+#[cfg(feature = "server")]
+/// One stored value. Timestamps are unix seconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RangePoint {
+    pub ts: i64,
+    pub value: TimeSeriesValue,
+}
+
+/// One time bucket; buckets without values are left out. `bucket_start` is
+/// unix seconds, aligned to multiples of the bucket size.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AggregatePoint {
+    pub bucket_start: i64,
+    pub value: f64,
+}
+
+/// A field the public history endpoints serve, with how to label it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryField {
+    pub field: String,
+    pub label: String,
+    pub unit: String,
+}
+
+#[cfg(feature = "server")]
+/// Fields shown on the Home page unless `HISTORY_FIELDS` says otherwise.
+pub const DEFAULT_HISTORY_FIELDS: &str = "inside_temperature=Inside temperature=°C,\
+outside_temperature=Outside temperature=°C,\
+luminosity=Illuminance=lux,\
+visitors=Visitors=";
+
+#[cfg(feature = "server")]
+/// Parses `field=Label=unit` entries separated by commas. Label and unit
+/// may be left out.
+pub fn parse_history_fields(spec: &str) -> Result<Vec<HistoryField>, String> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let mut parts = entry.splitn(3, '=').map(str::trim);
+            let field = parts.next().unwrap_or_default();
+            if field.is_empty() {
+                return Err(format!("History field entry {:?} has no field name", entry));
+            }
+            let label = parts
+                .next()
+                .filter(|label| !label.is_empty())
+                .unwrap_or(field);
+            Ok(HistoryField {
+                field: field.to_string(),
+                label: label.to_string(),
+                unit: parts.next().unwrap_or_default().to_string(),
+            })
+        })
+        .collect()
+}
+
+/// The fields served publicly, from `HISTORY_FIELDS` or the defaults.
+#[cfg(feature = "server")]
+pub fn history_fields() -> Vec<HistoryField> {
+    let Ok(spec) = std::env::var("HISTORY_FIELDS") else {
+        return parse_history_fields(DEFAULT_HISTORY_FIELDS).unwrap_or_default();
+    };
+    parse_history_fields(&spec).unwrap_or_else(|e| {
+        eprintln!("Invalid HISTORY_FIELDS, using the defaults: {}", e);
+        parse_history_fields(DEFAULT_HISTORY_FIELDS).unwrap_or_default()
+    })
+}
+
+/// Only fields listed in [`history_fields`] may be queried publicly.
+#[cfg(feature = "server")]
+pub fn check_history_field(field: &str) -> Result<(), String> {
+    if history_fields().iter().any(|f| f.field == field) {
+        Ok(())
+    } else {
+        Err(format!("Unknown history field '{}'", field))
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::{parse_history_fields, Aggregate, TimeSeriesValue, DEFAULT_HISTORY_FIELDS};
+
+    #[test]
+    fn test_parse_aggregate() {
+        assert_eq!("min".parse(), Ok(Aggregate::Min));
+        assert_eq!(" MEAN ".parse(), Ok(Aggregate::Mean));
+        assert_eq!("avg".parse(), Ok(Aggregate::Mean));
+        assert_eq!("count".parse(), Ok(Aggregate::Count));
+        assert!("median".parse::<Aggregate>().is_err());
+        for agg in [
+            Aggregate::Min,
+            Aggregate::Max,
+            Aggregate::Mean,
+            Aggregate::Last,
+            Aggregate::Count,
+        ] {
+            assert_eq!(agg.to_string().parse(), Ok(agg));
+        }
+    }
+
+    #[test]
+    fn test_parse_history_fields() {
+        let fields = parse_history_fields(DEFAULT_HISTORY_FIELDS).unwrap();
+        let names: Vec<&str> = fields.iter().map(|f| f.field.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "inside_temperature",
+                "outside_temperature",
+                "luminosity",
+                "visitors"
+            ]
+        );
+        assert_eq!(fields[0].unit, "°C");
+        assert_eq!(fields[3].label, "Visitors");
+        assert_eq!(fields[3].unit, "");
+
+        let fields = parse_history_fields("co2, pressure=Air pressure=hPa").unwrap();
+        assert_eq!(fields[0].label, "co2");
+        assert_eq!(fields[1].unit, "hPa");
+        assert!(parse_history_fields("=Label").is_err());
+    }
+
+    #[test]
+    fn test_values_serialize_untagged() {
+        assert_eq!(
+            serde_json::to_string(&TimeSeriesValue::Double(21.5)).unwrap(),
+            "21.5"
+        );
+        assert_eq!(
+            serde_json::from_str::<TimeSeriesValue>("true").unwrap(),
+            TimeSeriesValue::Bool(true)
+        );
+    }
+}
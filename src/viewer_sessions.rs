@@ -0,0 +1,119 @@
+use crate::clock::{self, SharedClock};
+use dashmap::DashMap;
+
+/// Viewer sessions without a heartbeat for this long are dropped.
+pub const SESSION_TTL_SECS: i64 = 90;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionEntry {
+    pub connections: usize,
+    pub last_seen: i64,
+}
+
+/// Viewers with at least one open websocket, keyed by session id. A viewer
+/// with several tabs open is one session with several connections.
+pub struct ViewerSessions {
+    sessions: DashMap<String, SessionEntry>,
+    clock: SharedClock,
+    ttl_secs: i64,
+}
+
+impl ViewerSessions {
+    pub fn new(clock: SharedClock, ttl_secs: i64) -> Self {
+        Self {
+            sessions: DashMap::new(),
+            clock,
+            ttl_secs,
+        }
+    }
+
+    /// Counts a new connection and returns how many the session has open.
+    pub fn connect(&self, session_id: &str) -> usize {
+        let now = clock::unix_secs(self.clock.as_ref());
+        let mut entry = self
+            .sessions
+            .entry(session_id.to_string())
+            .or_insert(SessionEntry {
+                connections: 0,
+                last_seen: now,
+            });
+        entry.connections += 1;
+        entry.last_seen = now;
+        entry.connections
+    }
+
+    /// Marks the session as alive.
+    pub fn touch(&self, session_id: &str) {
+        if let Some(mut entry) = self.sessions.get_mut(session_id) {
+            entry.last_seen = clock::unix_secs(self.clock.as_ref());
+        }
+    }
+
+    pub fn get(&self, session_id: &str) -> Option<SessionEntry> {
+        self.sessions.get(session_id).map(|entry| entry.clone())
+    }
+
+    /// Closes one connection. Returns the connections still open, or `None`
+    /// if the session was already gone (e.g. pruned).
+    pub fn disconnect(&self, session_id: &str) -> Option<usize> {
+        let remaining = {
+            let mut entry = self.sessions.get_mut(session_id)?;
+            entry.connections = entry.connections.saturating_sub(1);
+            entry.connections
+        };
+        if remaining == 0 {
+            self.sessions.remove(session_id);
+        }
+        Some(remaining)
+    }
+
+    /// Drops sessions not seen within the TTL and returns how many.
+    pub fn prune(&self) -> usize {
+        let now = clock::unix_secs(self.clock.as_ref());
+        let before = self.sessions.len();
+        self.sessions
+            .retain(|_, entry| now - entry.last_seen <= self.ttl_secs);
+        before - self.sessions.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ViewerSessions, SESSION_TTL_SECS};
+    use crate::clock::MockClock;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_sessions_expire_without_heartbeat() {
+        let clock = MockClock::at_unix_secs(1_700_000_000);
+        let sessions = ViewerSessions::new(Arc::new(clock.clone()), SESSION_TTL_SECS);
+
+        assert_eq!(sessions.connect("a"), 1);
+        assert_eq!(sessions.connect("a"), 2);
+        assert_eq!(sessions.connect("b"), 1);
+
+        clock.advance(Duration::from_secs(60));
+        sessions.touch("a");
+        clock.advance(Duration::from_secs(SESSION_TTL_SECS as u64));
+        assert_eq!(sessions.prune(), 1);
+        assert!(sessions.get("b").is_none());
+        assert_eq!(sessions.get("a").unwrap().connections, 2);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(sessions.prune(), 1);
+        assert_eq!(sessions.disconnect("a"), None);
+    }
+
+    #[test]
+    fn test_last_connection_removes_session() {
+        let clock = MockClock::at_unix_secs(1_700_000_000);
+        let sessions = ViewerSessions::new(Arc::new(clock), SESSION_TTL_SECS);
+
+        sessions.connect("a");
+        sessions.connect("a");
+        assert_eq!(sessions.disconnect("a"), Some(1));
+        assert_eq!(sessions.disconnect("a"), Some(0));
+        assert!(sessions.get("a").is_none());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::components::DevicePicker;
 use dioxus::prelude::*;
 #[cfg(target_arch = "wasm32")]
 use gloo_net::http::Request;
@@ -101,6 +102,58 @@ fn format_gallery_timestamp(filename: &str) -> String {
 struct AdminDeviceStatus {
     ir_enabled: bool,
     luminosity_lux: Option<f64>,
+    dropped_device_frames: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct AdminKeyRotation {
+    key_id: u32,
+    saved_to: Option<String>,
+    // Only sent when saving failed, for the admin to store by hand.
+    keyring: Option<String>,
+    save_error: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct AdminQueuedCommand {
+    id: String,
+    device_id: String,
+    command: String,
+    failed: bool,
+    attempts: u32,
+    enqueued_at: i64,
+    last_error: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct AdminTableSize {
+    name: String,
+    bytes: i64,
+    rows: i64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct AdminCompactionRun {
+    started_at: i64,
+    duration_ms: i64,
+    deleted_raw: i64,
+    rolled_up: i64,
+    expired_rollups: i64,
+    error: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct AdminDatabaseOverview {
+    tables: Vec<AdminTableSize>,
+    policies: Vec<String>,
+    last_compaction: Option<AdminCompactionRun>,
+    pool_in_use: usize,
+    pool_max_size: usize,
+    pending_points: usize,
+    journaled_points: usize,
+    rejected_points: u64,
+    dropped_points: u64,
+    write_error: Option<String>,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -920,42 +973,32 @@ async fn admin_upload_gallery_image_server(
 }
 
 #[server]
-async fn admin_get_device_status_server(token: String) -> Result<AdminDeviceStatus, ServerFnError> {
+async fn admin_get_device_status_server(
+    token: String,
+    device_id: Option<String>,
+) -> Result<AdminDeviceStatus, ServerFnError> {
     #[cfg(feature = "server")]
     {
         if !crate::admin::admin_validate_session(&token) {
             return Err(ServerFnError::new("Unauthorized"));
         }
-
-        let response = crate::tcp_client::send_command("[CMD] GET IR STATE")
-            .await
+        let device_id = crate::tcp_client::resolve_device_id(device_id.as_deref())
             .map_err(ServerFnError::new)?;
-        let payload = response.to_uppercase();
 
-        let mut ir_enabled = None;
-        for raw_line in payload.lines() {
-            let line = raw_line.trim();
-            if line.is_empty() {
-                continue;
-            }
+        use crate::device_protocol::{DeviceCommand, DeviceResponse};
 
-            if line.contains("IR STATE IS ON") {
-                ir_enabled = Some(true);
-                break;
-            }
-
-            if line.contains("IR STATE IS OFF") {
-                ir_enabled = Some(false);
-                break;
+        let ir_enabled = match crate::tcp_client::send_command(&device_id, &DeviceCommand::GetIrState)
+            .await
+            .map_err(ServerFnError::new)?
+        {
+            DeviceResponse::IrState(enabled) => enabled,
+            response => {
+                return Err(ServerFnError::new(format!(
+                    "Unexpected IR state response from TCP: {:?}",
+                    response
+                )))
             }
-        }
-
-        let ir_enabled = ir_enabled.ok_or_else(|| {
-            ServerFnError::new(format!(
-                "Unexpected IR state response from TCP: {}",
-                response
-            ))
-        })?;
+        };
 
         let luminosity_lux = {
             let lock = crate::CURRENT_LUMINOSITY
@@ -967,6 +1010,7 @@ async fn admin_get_device_status_server(token: String) -> Result<AdminDeviceStat
         Ok(AdminDeviceStatus {
             ir_enabled,
             luminosity_lux,
+            dropped_device_frames: crate::tcp_client::dropped_inbound_frames(&device_id),
         })
     }
 
@@ -977,19 +1021,21 @@ async fn admin_get_device_status_server(token: String) -> Result<AdminDeviceStat
 }
 
 #[server]
-async fn admin_toggle_ir_led_server(token: String, enabled: bool) -> Result<bool, ServerFnError> {
+async fn admin_toggle_ir_led_server(
+    token: String,
+    device_id: Option<String>,
+    enabled: bool,
+) -> Result<bool, ServerFnError> {
     #[cfg(feature = "server")]
     {
         if !crate::admin::admin_validate_session(&token) {
             return Err(ServerFnError::new("Unauthorized"));
         }
+        let device_id = crate::tcp_client::resolve_device_id(device_id.as_deref())
+            .map_err(ServerFnError::new)?;
 
-        let cmd = if enabled {
-            "[CMD] IR ON"
-        } else {
-            "[CMD] IR OFF"
-        };
-        crate::tcp_client::send_command(cmd)
+        let cmd = crate::device_protocol::DeviceCommand::ir(enabled);
+        crate::tcp_client::send_command(&device_id, &cmd)
             .await
             .map(|_| enabled)
             .map_err(ServerFnError::new)
@@ -1002,14 +1048,22 @@ async fn admin_toggle_ir_led_server(token: String, enabled: bool) -> Result<bool
 }
 
 #[server]
-async fn admin_save_image_server(token: String) -> Result<String, ServerFnError> {
+async fn admin_save_image_server(
+    token: String,
+    device_id: Option<String>,
+) -> Result<String, ServerFnError> {
     #[cfg(feature = "server")]
     {
         if !crate::admin::admin_validate_session(&token) {
             return Err(ServerFnError::new("Unauthorized"));
         }
+        let device_id = crate::tcp_client::resolve_device_id(device_id.as_deref())
+            .map_err(ServerFnError::new)?;
 
-        crate::tcp_client::send_command("[CMD] save image")
+        crate::tcp_client::send_command(
+            &device_id,
+            &crate::device_protocol::DeviceCommand::SaveImage,
+        )
             .await
             .map_err(|e| ServerFnError::new(format!("Failed to save image: {}", e)))?;
 
@@ -1022,6 +1076,231 @@ async fn admin_save_image_server(token: String) -> Result<String, ServerFnError>
     }
 }
 
+/// Rotates the device's encryption key. The keyring itself is only returned
+/// when it could not be saved to disk.
+#[server]
+async fn admin_rotate_key_server(
+    token: String,
+    device_id: Option<String>,
+) -> Result<AdminKeyRotation, ServerFnError> {
+    #[cfg(feature = "server")]
+    {
+        if !crate::admin::admin_validate_session(&token) {
+            return Err(ServerFnError::new("Unauthorized"));
+        }
+        let device_id = crate::tcp_client::resolve_device_id(device_id.as_deref())
+            .map_err(ServerFnError::new)?;
+
+        let rotation = crate::tcp_client::rotate_key(&device_id)
+            .await
+            .map_err(ServerFnError::new)?;
+        let key_id = rotation.keyring.active_id();
+        Ok(match rotation.saved_to {
+            Ok(path) => AdminKeyRotation {
+                key_id,
+                saved_to: Some(path.display().to_string()),
+                keyring: None,
+                save_error: None,
+            },
+            Err(e) => AdminKeyRotation {
+                key_id,
+                saved_to: None,
+                keyring: Some(rotation.keyring.to_spec()),
+                save_error: Some(e),
+            },
+        })
+    }
+
+    #[cfg(not(feature = "server"))]
+    {
+        Err(ServerFnError::new("Not running on server"))
+    }
+}
+
+#[server]
+async fn admin_list_command_queue_server(
+    token: String,
+) -> Result<Vec<AdminQueuedCommand>, ServerFnError> {
+    #[cfg(feature = "server")]
+    {
+        if !crate::admin::admin_validate_session(&token) {
+            return Err(ServerFnError::new("Unauthorized"));
+        }
+
+        use crate::command_queue::QueuedCommandStatus;
+
+        Ok(crate::command_queue::list()
+            .await
+            .map_err(ServerFnError::new)?
+            .into_iter()
+            .map(|entry| AdminQueuedCommand {
+                id: entry.id,
+                device_id: entry.device_id,
+                command: entry.command.to_log(),
+                failed: entry.status == QueuedCommandStatus::Failed,
+                attempts: entry.attempts,
+                enqueued_at: entry.enqueued_at,
+                last_error: entry.last_error,
+            })
+            .collect())
+    }
+
+    #[cfg(not(feature = "server"))]
+    {
+        Err(ServerFnError::new("Not running on server"))
+    }
+}
+
+#[server]
+async fn admin_retry_queued_command_server(token: String, id: String) -> Result<(), ServerFnError> {
+    #[cfg(feature = "server")]
+    {
+        if !crate::admin::admin_validate_session(&token) {
+            return Err(ServerFnError::new("Unauthorized"));
+        }
+
+        let device_id = crate::command_queue::retry(&id)
+            .await
+            .map_err(ServerFnError::new)?;
+        tokio::spawn(crate::command_queue::flush(device_id));
+        Ok(())
+    }
+
+    #[cfg(not(feature = "server"))]
+    {
+        Err(ServerFnError::new("Not running on server"))
+    }
+}
+
+#[server]
+async fn admin_discard_queued_command_server(
+    token: String,
+    id: String,
+) -> Result<(), ServerFnError> {
+    #[cfg(feature = "server")]
+    {
+        if !crate::admin::admin_validate_session(&token) {
+            return Err(ServerFnError::new("Unauthorized"));
+        }
+
+        crate::command_queue::discard(&id)
+            .await
+            .map_err(ServerFnError::new)
+    }
+
+    #[cfg(not(feature = "server"))]
+    {
+        Err(ServerFnError::new("Not running on server"))
+    }
+}
+
+#[cfg(feature = "server")]
+impl From<crate::retention::CompactionRun> for AdminCompactionRun {
+    fn from(run: crate::retention::CompactionRun) -> Self {
+        Self {
+            started_at: run.started_at.timestamp(),
+            duration_ms: (run.finished_at - run.started_at).num_milliseconds(),
+            deleted_raw: run.deleted_raw,
+            rolled_up: run.rolled_up,
+            expired_rollups: run.expired_rollups,
+            error: run.error,
+        }
+    }
+}
+
+#[server]
+async fn admin_database_overview_server(
+    token: String,
+) -> Result<AdminDatabaseOverview, ServerFnError> {
+    #[cfg(feature = "server")]
+    {
+        if !crate::admin::admin_validate_session(&token) {
+            return Err(ServerFnError::new("Unauthorized"));
+        }
+
+        let store = crate::postgres_store::shared().map_err(ServerFnError::new)?;
+        let buffer = crate::write_buffer::shared().map_err(ServerFnError::new)?;
+        let tables = store
+            .table_sizes()
+            .await
+            .map_err(ServerFnError::new)?
+            .into_iter()
+            .map(|table| AdminTableSize {
+                name: table.name,
+                bytes: table.bytes,
+                rows: table.rows,
+            })
+            .collect();
+        let last_compaction = store
+            .last_compaction()
+            .await
+            .map_err(ServerFnError::new)?
+            .map(AdminCompactionRun::from);
+        let pool = store.pool_stats();
+        let writes = buffer.stats();
+
+        Ok(AdminDatabaseOverview {
+            tables,
+            policies: crate::retention::policies_from_env()
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            last_compaction,
+            pool_in_use: pool.in_use,
+            pool_max_size: pool.max_size,
+            pending_points: writes.pending,
+            journaled_points: writes.journaled,
+            rejected_points: writes.rejected,
+            dropped_points: writes.dropped,
+            write_error: writes.last_error,
+        })
+    }
+
+    #[cfg(not(feature = "server"))]
+    {
+        Err(ServerFnError::new("Not running on server"))
+    }
+}
+
+#[server]
+async fn admin_run_compaction_server(token: String) -> Result<AdminCompactionRun, ServerFnError> {
+    #[cfg(feature = "server")]
+    {
+        if !crate::admin::admin_validate_session(&token) {
+            return Err(ServerFnError::new("Unauthorized"));
+        }
+
+        let policies = crate::retention::policies_from_env();
+        if policies.is_empty() {
+            return Err(ServerFnError::new("No RETENTION_POLICIES configured"));
+        }
+        let store = crate::postgres_store::shared().map_err(ServerFnError::new)?;
+        Ok(crate::retention::run_and_record(&store, &policies)
+            .await
+            .into())
+    }
+
+    #[cfg(not(feature = "server"))]
+    {
+        Err(ServerFnError::new("Not running on server"))
+    }
+}
+
+fn format_bytes(bytes: i64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes.max(0) as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes.max(0), UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
 #[component]
 pub fn Admin() -> Element {
     #[cfg(target_arch = "wasm32")]
@@ -1040,6 +1319,9 @@ pub fn Admin() -> Element {
     let mut profile_refresh = use_signal(|| 0u64);
     let mut gallery_refresh = use_signal(|| 0u64);
     let mut device_refresh = use_signal(|| 0u64);
+    let mut queue_refresh = use_signal(|| 0u64);
+    let mut database_refresh = use_signal(|| 0u64);
+    let mut compaction_busy = use_signal(|| false);
 
     let mut settings_email = use_signal(String::new);
     let mut settings_new_password = use_signal(String::new);
@@ -1049,6 +1331,11 @@ pub fn Admin() -> Element {
     let mut admin_ir_busy = use_signal(|| false);
     let mut admin_ir_request_id = use_signal(|| 0u64);
     let mut admin_save_busy = use_signal(|| false);
+    let mut admin_rotate_busy = use_signal(|| false);
+    let mut key_rotation = use_signal(|| None::<AdminKeyRotation>);
+    let mut tcp_state = use_context::<crate::tcp_state::TcpState>();
+    let device_link = tcp_state.device_link;
+    let selected_device = tcp_state.device_id;
 
     let mut upload_filename = use_signal(|| None::<String>);
     let mut upload_bytes = use_signal(|| None::<Vec<u8>>);
@@ -1085,9 +1372,40 @@ pub fn Admin() -> Element {
     let device_resource = use_resource(move || {
         let _ = device_refresh();
         let token = admin_token();
+        let device_id = selected_device();
+        async move {
+            if let Some(token) = token {
+                admin_get_device_status_server(token, device_id)
+                    .await
+                    .map(Some)
+                    .map_err(|e| e.to_string())
+            } else {
+                Ok(None)
+            }
+        }
+    });
+
+    let queue_resource = use_resource(move || {
+        let _ = queue_refresh();
+        let token = admin_token();
         async move {
             if let Some(token) = token {
-                admin_get_device_status_server(token)
+                admin_list_command_queue_server(token)
+                    .await
+                    .map(Some)
+                    .map_err(|e| e.to_string())
+            } else {
+                Ok(None)
+            }
+        }
+    });
+
+    let database_resource = use_resource(move || {
+        let _ = database_refresh();
+        let token = admin_token();
+        async move {
+            if let Some(token) = token {
+                admin_database_overview_server(token)
                     .await
                     .map(Some)
                     .map_err(|e| e.to_string())
@@ -1113,6 +1431,7 @@ pub fn Admin() -> Element {
                         profile_refresh += 1;
                         gallery_refresh += 1;
                         device_refresh += 1;
+                        queue_refresh += 1;
                         is_valid = true;
                     }
                     _ => clear_admin_token_from_storage(),
@@ -1486,18 +1805,51 @@ pub fn Admin() -> Element {
 
                         div {
                             class: "rounded-xl border border-slate-700 bg-slate-800 p-6 space-y-4",
-                            h2 { class: "text-xl font-medium", "Device Controls" }
+                            div { class: "flex flex-wrap items-center justify-between gap-4",
+                                h2 { class: "text-xl font-medium", "Device Controls" }
+                                DevicePicker {
+                                    selected: selected_device(),
+                                    onselect: move |device_id: String| {
+                                        tcp_state.select_device(Some(device_id));
+                                    },
+                                }
+                            }
+                            {
+                                let link_text = device_link
+                                    .read()
+                                    .as_ref()
+                                    .map(|link| link.describe(chrono::Utc::now().timestamp()))
+                                    .unwrap_or_else(|| "unknown".to_string());
+                                let link_online = device_link
+                                    .read()
+                                    .as_ref()
+                                    .map(|link| link.is_online())
+                                    .unwrap_or(false);
+                                rsx! {
+                                    p {
+                                        class: if link_online { "text-emerald-300 text-sm" } else { "text-amber-300 text-sm" },
+                                        "Device link: {link_text}"
+                                    }
+                                }
+                            }
                             if let Some(Ok(Some(device_status))) = device_resource.read().as_ref() {
                                 {
                                     let lux_text = device_status
                                         .luminosity_lux
                                         .map(|lux| format!("{lux:.0} lux"))
                                         .unwrap_or_else(|| "unavailable".to_string());
+                                    let dropped_frames = device_status.dropped_device_frames;
                                     rsx! {
                                         p {
                                             class: "text-slate-300 text-sm",
                                             "Current luminosity: {lux_text}. Public IR control is limited below {IR_LUX_THRESHOLD:.0} lux, but these admin controls always remain available."
                                         }
+                                        if dropped_frames > 0 {
+                                            p {
+                                                class: "text-amber-300 text-sm",
+                                                "Dropped {dropped_frames} device messages that failed to decrypt."
+                                            }
+                                        }
                                     }
                                 }
                             } else if let Some(Err(err)) = device_resource.read().as_ref() {
@@ -1562,7 +1914,7 @@ pub fn Admin() -> Element {
                                                     return;
                                                 }
 
-                                                match admin_toggle_ir_led_server(token, next_state).await {
+                                                match admin_toggle_ir_led_server(token, selected_device(), next_state).await {
                                                     Ok(state) => {
                                                         if admin_ir_request_id_ack() == request_id {
                                                             admin_ir_enabled_ack.set(state);
@@ -1619,7 +1971,7 @@ pub fn Admin() -> Element {
                                         admin_save_busy.set(true);
                                         status.set(None);
                                         spawn(async move {
-                                            match admin_save_image_server(token).await {
+                                            match admin_save_image_server(token, selected_device()).await {
                                                 Ok(_msg) => {}
                                                 Err(err) => {
                                                     let text = err.to_string();
@@ -1635,6 +1987,296 @@ pub fn Admin() -> Element {
                                     },
                                     if admin_save_busy() { "Saving image..." } else { "Save Image" }
                                 }
+
+                                button {
+                                    r#type: "button",
+                                    class: format!(
+                                        "rounded-md px-4 py-2 font-medium {}",
+                                        if admin_rotate_busy() {
+                                            "bg-slate-500 text-white"
+                                        } else {
+                                            "bg-slate-600 hover:bg-slate-500 text-white"
+                                        }
+                                    ),
+                                    disabled: admin_rotate_busy(),
+                                    title: "Send the device a new encryption key",
+                                    onclick: move |_| {
+                                        if admin_rotate_busy() {
+                                            return;
+                                        }
+                                        let Some(token) = admin_token() else {
+                                            handle_unauthorized();
+                                            return;
+                                        };
+
+                                        admin_rotate_busy.set(true);
+                                        status.set(None);
+                                        key_rotation.set(None);
+                                        spawn(async move {
+                                            match admin_rotate_key_server(token, selected_device()).await {
+                                                Ok(rotation) => key_rotation.set(Some(rotation)),
+                                                Err(err) => {
+                                                    let text = err.to_string();
+                                                    if text.contains("Unauthorized") {
+                                                        handle_unauthorized();
+                                                    } else {
+                                                        status.set(Some(format!("Key rotation failed: {}", text)));
+                                                    }
+                                                }
+                                            }
+                                            admin_rotate_busy.set(false);
+                                        });
+                                    },
+                                    if admin_rotate_busy() { "Rotating key..." } else { "Rotate Key" }
+                                }
+                            }
+                            if let Some(rotation) = key_rotation() {
+                                if let Some(path) = rotation.saved_to {
+                                    p { class: "text-sm text-emerald-300",
+                                        "Rotated to key {rotation.key_id}. The keyring was saved to {path} and is loaded from there on restart."
+                                    }
+                                } else {
+                                    div { class: "space-y-2 rounded-md border border-amber-500/50 bg-amber-500/10 p-3",
+                                        p { class: "text-sm text-amber-200",
+                                            "Rotated to key {rotation.key_id}, but the keyring could not be saved: {rotation.save_error.clone().unwrap_or_default()}. Store it in the device's key variable before the next restart, and keep it secret:"
+                                        }
+                                        code { class: "block break-all text-xs text-amber-100", "{rotation.keyring.clone().unwrap_or_default()}" }
+                                    }
+                                }
+                            }
+                        }
+
+                        div {
+                            class: "rounded-xl border border-slate-700 bg-slate-800 p-6 space-y-4",
+                            div { class: "flex items-center justify-between gap-4",
+                                h2 { class: "text-xl font-medium", "Queued Device Commands" }
+                                button {
+                                    r#type: "button",
+                                    class: "rounded-md bg-slate-600 px-3 py-1 text-sm hover:bg-slate-500",
+                                    onclick: move |_| queue_refresh += 1,
+                                    "Refresh"
+                                }
+                            }
+                            p { class: "text-slate-300 text-sm", "Newsletter changes made while the birdhouse is offline are kept here and delivered once it reconnects." }
+                            match queue_resource.read().as_ref() {
+                                Some(Ok(Some(entries))) if entries.is_empty() => rsx! {
+                                    p { class: "text-slate-300 text-sm", "No pending or failed commands." }
+                                },
+                                Some(Ok(Some(entries))) => rsx! {
+                                    ul { class: "space-y-2",
+                                        for entry in entries.clone() {
+                                            {
+                                                let entry_id = entry.id.clone();
+                                                let retry_id = entry.id.clone();
+                                                let enqueued = chrono::DateTime::from_timestamp(entry.enqueued_at, 0)
+                                                    .map(|dt| dt.format("%Y-%m-%d %H:%M UTC").to_string())
+                                                    .unwrap_or_default();
+                                                rsx! {
+                                                    li {
+                                                        key: "{entry.id}",
+                                                        class: "rounded-md border border-slate-700 bg-slate-900 p-3 text-sm space-y-1",
+                                                        div { class: "flex flex-wrap items-center justify-between gap-2",
+                                                            span { class: "font-mono break-all", "{entry.device_id}: {entry.command}" }
+                                                            span {
+                                                                class: if entry.failed { "text-red-300" } else { "text-amber-300" },
+                                                                if entry.failed { "failed" } else { "pending" }
+                                                            }
+                                                        }
+                                                        p { class: "text-slate-400", "Queued {enqueued}, {entry.attempts} attempts" }
+                                                        if let Some(err) = entry.last_error.clone() {
+                                                            p { class: "text-slate-400 break-all", "Last error: {err}" }
+                                                        }
+                                                        div { class: "flex gap-2",
+                                                            if entry.failed {
+                                                                button {
+                                                                    r#type: "button",
+                                                                    class: "rounded-md bg-emerald-500 px-3 py-1 hover:bg-emerald-600",
+                                                                    onclick: move |_| {
+                                                                        let Some(token) = admin_token() else {
+                                                                            handle_unauthorized();
+                                                                            return;
+                                                                        };
+                                                                        let id = retry_id.clone();
+                                                                        spawn(async move {
+                                                                            match admin_retry_queued_command_server(token, id).await {
+                                                                                Ok(()) => queue_refresh += 1,
+                                                                                Err(err) => {
+                                                                                    let text = err.to_string();
+                                                                                    if text.contains("Unauthorized") {
+                                                                                        handle_unauthorized();
+                                                                                    } else {
+                                                                                        status.set(Some(format!("Retry failed: {}", text)));
+                                                                                    }
+                                                                                }
+                                                                            }
+                                                                        });
+                                                                    },
+                                                                    "Retry"
+                                                                }
+                                                            }
+                                                            button {
+                                                                r#type: "button",
+                                                                class: "rounded-md bg-red-500 px-3 py-1 hover:bg-red-600",
+                                                                onclick: move |_| {
+                                                                    let Some(token) = admin_token() else {
+                                                                        handle_unauthorized();
+                                                                        return;
+                                                                    };
+                                                                    let id = entry_id.clone();
+                                                                    spawn(async move {
+                                                                        match admin_discard_queued_command_server(token, id).await {
+                                                                            Ok(()) => queue_refresh += 1,
+                                                                            Err(err) => {
+                                                                                let text = err.to_string();
+                                                                                if text.contains("Unauthorized") {
+                                                                                    handle_unauthorized();
+                                                                                } else {
+                                                                                    status.set(Some(format!("Discard failed: {}", text)));
+                                                                                }
+                                                                            }
+                                                                        }
+                                                                    });
+                                                                },
+                                                                "Discard"
+                                                            }
+                                                        }
+                                                    }
+                                                }
+                                            }
+                                        }
+                                    }
+                                },
+                                Some(Err(err)) => rsx! {
+                                    p { class: "text-sm text-red-300 break-all", "Failed to load command queue: {err}" }
+                                },
+                                _ => rsx! {
+                                    p { class: "text-slate-300 text-sm", "Loading command queue..." }
+                                },
+                            }
+                        }
+
+                        div {
+                            class: "rounded-xl border border-slate-700 bg-slate-800 p-6 space-y-4",
+                            div { class: "flex items-center justify-between gap-4",
+                                h2 { class: "text-xl font-medium", "Database" }
+                                div { class: "flex gap-2",
+                                    button {
+                                        r#type: "button",
+                                        class: "rounded-md bg-slate-600 px-3 py-1 text-sm hover:bg-slate-500",
+                                        onclick: move |_| database_refresh += 1,
+                                        "Refresh"
+                                    }
+                                    button {
+                                        r#type: "button",
+                                        class: "rounded-md bg-amber-500 px-3 py-1 text-sm hover:bg-amber-600 disabled:opacity-50",
+                                        disabled: compaction_busy(),
+                                        onclick: move |_| {
+                                            let Some(token) = admin_token() else {
+                                                handle_unauthorized();
+                                                return;
+                                            };
+                                            compaction_busy.set(true);
+                                            spawn(async move {
+                                                match admin_run_compaction_server(token).await {
+                                                    Ok(run) => {
+                                                        status.set(Some(match run.error {
+                                                            Some(err) => format!("Compaction failed: {}", err),
+                                                            None => format!(
+                                                                "Compaction deleted {} raw points and {} rollups.",
+                                                                run.deleted_raw, run.expired_rollups
+                                                            ),
+                                                        }));
+                                                        database_refresh += 1;
+                                                    }
+                                                    Err(err) => {
+                                                        let text = err.to_string();
+                                                        if text.contains("Unauthorized") {
+                                                            handle_unauthorized();
+                                                        } else {
+                                                            status.set(Some(format!("Compaction failed: {}", text)));
+                                                        }
+                                                    }
+                                                }
+                                                compaction_busy.set(false);
+                                            });
+                                        },
+                                        if compaction_busy() { "Compacting..." } else { "Run compaction now" }
+                                    }
+                                }
+                            }
+                            match database_resource.read().as_ref() {
+                                Some(Ok(Some(overview))) => {
+                                    let overview = overview.clone();
+                                    rsx! {
+                                        table { class: "w-full text-sm",
+                                            thead {
+                                                tr { class: "text-left text-slate-400",
+                                                    th { class: "py-1", "Table" }
+                                                    th { class: "py-1 text-right", "Size" }
+                                                    th { class: "py-1 text-right", "Rows (est.)" }
+                                                }
+                                            }
+                                            tbody {
+                                                for table in overview.tables.clone() {
+                                                    tr { key: "{table.name}",
+                                                        td { class: "py-1 font-mono break-all", "{table.name}" }
+                                                        td { class: "py-1 text-right", "{format_bytes(table.bytes)}" }
+                                                        td { class: "py-1 text-right", "{table.rows}" }
+                                                    }
+                                                }
+                                            }
+                                        }
+                                        p { class: "text-slate-300 text-sm",
+                                            "Connections in use: {overview.pool_in_use}/{overview.pool_max_size}, buffered points: {overview.pending_points}, journaled points: {overview.journaled_points}"
+                                        }
+                                        if let Some(err) = overview.write_error.clone() {
+                                            p { class: "text-sm text-red-300 break-all", "Last write error: {err}" }
+                                        }
+                                        if overview.rejected_points > 0 {
+                                            p { class: "text-sm text-amber-300",
+                                                "{overview.rejected_points} points were rejected by PostgreSQL and moved to the dead-letter file."
+                                            }
+                                        }
+                                        if overview.dropped_points > 0 {
+                                            p { class: "text-sm text-red-300",
+                                                "The write journal is full; {overview.dropped_points} points were dropped."
+                                            }
+                                        }
+                                        div { class: "space-y-1 text-sm",
+                                            h3 { class: "font-medium", "Retention" }
+                                            if overview.policies.is_empty() {
+                                                p { class: "text-slate-400", "No policies configured; all data is kept." }
+                                            }
+                                            for policy in overview.policies.clone() {
+                                                p { key: "{policy}", class: "text-slate-300", "{policy}" }
+                                            }
+                                        }
+                                        match overview.last_compaction.clone() {
+                                            Some(run) => {
+                                                let started = chrono::DateTime::from_timestamp(run.started_at, 0)
+                                                    .map(|dt| dt.format("%Y-%m-%d %H:%M UTC").to_string())
+                                                    .unwrap_or_default();
+                                                rsx! {
+                                                    p { class: "text-slate-300 text-sm",
+                                                        "Last compaction {started} ({run.duration_ms} ms): {run.deleted_raw} raw points deleted, {run.rolled_up} rollup rows written, {run.expired_rollups} rollups expired"
+                                                    }
+                                                    if let Some(err) = run.error.clone() {
+                                                        p { class: "text-sm text-red-300 break-all", "Error: {err}" }
+                                                    }
+                                                }
+                                            }
+                                            None => rsx! {
+                                                p { class: "text-slate-400 text-sm", "No compaction has run yet." }
+                                            },
+                                        }
+                                    }
+                                }
+                                Some(Err(err)) => rsx! {
+                                    p { class: "text-sm text-red-300 break-all", "Failed to load database overview: {err}" }
+                                },
+                                _ => rsx! {
+                                    p { class: "text-slate-300 text-sm", "Loading database overview..." }
+                                },
                             }
                         }
                     }
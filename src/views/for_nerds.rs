@@ -1,5 +1,6 @@
 use dioxus::prelude::*;
 
+use crate::components::ExportPanel;
 use crate::views::home::get_stream_config;
 #[cfg(target_arch = "wasm32")]
 use js_sys::eval;
@@ -234,6 +235,13 @@ pub fn ForNerds() -> Element {
                 referrerpolicy: "no-referrer",
                 scrolling: "no",
             }
+
+            // DOWNLOAD
+            h1 {
+                class: "text-2xl",
+                "Download the Data"
+            }
+            ExportPanel {}
         }
     }
 }
@@ -1,16 +1,18 @@
 use dioxus::prelude::*;
 #[cfg(target_arch = "wasm32")]
 use std::cell::{Cell, RefCell};
+#[cfg(target_arch = "wasm32")]
+use std::collections::VecDeque;
 #[cfg(feature = "server")]
 use std::collections::HashMap;
 #[cfg(feature = "server")]
-use std::net::{IpAddr, SocketAddr};
+use std::net::SocketAddr;
 #[cfg(target_arch = "wasm32")]
 use std::rc::Rc;
 #[cfg(feature = "server")]
-use std::sync::Arc;
+use crate::clock::{self, SharedClock};
 #[cfg(feature = "server")]
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::future::Future;
 #[cfg(feature = "server")]
 use tokio::sync::Mutex;
 #[cfg(target_arch = "wasm32")]
@@ -102,78 +104,98 @@ fn clear_spectrogram_runtime() {
 }
 
 #[cfg(feature = "server")]
-static IMAGE_SAVE_TRACKER: once_cell::sync::Lazy<Arc<Mutex<HashMap<String, Vec<u64>>>>> =
-    once_cell::sync::Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+static IMAGE_SAVE_TRACKER: once_cell::sync::Lazy<ImageSaveTracker> =
+    once_cell::sync::Lazy::new(|| {
+        ImageSaveTracker::new(clock::system(), IMAGE_SAVE_LIMIT_PER_HOUR)
+    });
 
-#[derive(Clone, serde::Serialize, serde::Deserialize)]
-struct ImageSaveStatus {
-    count_last_hour: usize,
+/// Pictures saved per client IP, limited to a number per hour.
+#[cfg(feature = "server")]
+struct ImageSaveTracker {
+    saves: Mutex<HashMap<String, Vec<u64>>>,
     limit_per_hour: usize,
-    is_limited: bool,
+    clock: SharedClock,
 }
 
 #[cfg(feature = "server")]
-fn extract_real_ip(headers: &dioxus_fullstack::http::HeaderMap) -> Option<IpAddr> {
-    for header_name in [
-        "cf-connecting-ip",
-        "x-real-ip",
-        "x-forwarded-for",
-        "forwarded",
-    ] {
-        if let Some(value) = headers.get(header_name).and_then(|v| v.to_str().ok()) {
-            if header_name == "x-forwarded-for" {
-                let first = value.split(',').next()?.trim();
-                if let Ok(ip) = first.parse::<IpAddr>() {
-                    return Some(ip);
-                }
-            } else if let Ok(ip) = value.trim().parse::<IpAddr>() {
-                return Some(ip);
-            }
+impl ImageSaveTracker {
+    fn new(clock: SharedClock, limit_per_hour: usize) -> Self {
+        Self {
+            saves: Mutex::new(HashMap::new()),
+            limit_per_hour,
+            clock,
         }
     }
-    None
-}
 
-#[cfg(feature = "server")]
-async fn client_ip_key() -> String {
-    if let Ok(headers) =
-        dioxus_fullstack::FullstackContext::extract::<dioxus_fullstack::http::HeaderMap, _>().await
-    {
-        if let Some(ip) = extract_real_ip(&headers) {
-            return ip.to_string();
+    fn now(&self) -> u64 {
+        clock::unix_secs(self.clock.as_ref()).max(0) as u64
+    }
+
+    async fn status(&self, ip_key: &str) -> ImageSaveStatus {
+        let one_hour_ago = self.now().saturating_sub(3600);
+
+        let mut tracker = self.saves.lock().await;
+        let entries = tracker.entry(ip_key.to_string()).or_default();
+        entries.retain(|&ts| ts > one_hour_ago);
+        let count_last_hour = entries.len();
+
+        ImageSaveStatus {
+            count_last_hour,
+            limit_per_hour: self.limit_per_hour,
+            is_limited: count_last_hour >= self.limit_per_hour,
         }
     }
 
-    if let Ok(connect_info) = dioxus_fullstack::FullstackContext::extract::<
-        dioxus_fullstack::axum::extract::ConnectInfo<SocketAddr>,
-        _,
-    >()
-    .await
+    /// Runs `save` unless `ip_key` is at the limit and counts it if it
+    /// succeeds. Returns the number of saves in the last hour. Saves are
+    /// serialized so concurrent requests cannot overshoot the limit.
+    async fn save<F, Fut>(&self, ip_key: &str, save: F) -> Result<usize, String>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(), String>>,
     {
-        return connect_info.0.ip().to_string();
+        let mut tracker = self.saves.lock().await;
+        let now = self.now();
+        let one_hour_ago = now.saturating_sub(3600);
+        let entries = tracker.entry(ip_key.to_string()).or_default();
+        entries.retain(|&ts| ts > one_hour_ago);
+
+        if entries.len() >= self.limit_per_hour {
+            return Err(format!(
+                "Too many pictures taken in the last hour (limit: {}).",
+                self.limit_per_hour
+            ));
+        }
+
+        save().await?;
+
+        entries.push(now);
+        Ok(entries.len())
     }
+}
 
-    "unknown".to_string()
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct ImageSaveStatus {
+    count_last_hour: usize,
+    limit_per_hour: usize,
+    is_limited: bool,
 }
 
 #[cfg(feature = "server")]
-async fn image_save_status_for_ip(ip_key: &str) -> ImageSaveStatus {
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs();
-    let one_hour_ago = now.saturating_sub(3600);
-
-    let mut tracker = IMAGE_SAVE_TRACKER.lock().await;
-    let entries = tracker.entry(ip_key.to_string()).or_default();
-    entries.retain(|&ts| ts > one_hour_ago);
-    let count_last_hour = entries.len();
-
-    ImageSaveStatus {
-        count_last_hour,
-        limit_per_hour: IMAGE_SAVE_LIMIT_PER_HOUR,
-        is_limited: count_last_hour >= IMAGE_SAVE_LIMIT_PER_HOUR,
-    }
+async fn client_ip_key() -> String {
+    let Ok(connect_info) = dioxus_fullstack::FullstackContext::extract::<
+        dioxus_fullstack::axum::extract::ConnectInfo<SocketAddr>,
+        _,
+    >()
+    .await
+    else {
+        return "unknown".to_string();
+    };
+    let headers =
+        dioxus_fullstack::FullstackContext::extract::<dioxus_fullstack::http::HeaderMap, _>()
+            .await
+            .unwrap_or_default();
+    crate::client_ip::client_ip(&headers, connect_info.0.ip()).to_string()
 }
 
 #[server]
@@ -181,7 +203,7 @@ async fn get_image_save_status() -> Result<ImageSaveStatus, ServerFnError> {
     #[cfg(feature = "server")]
     {
         let ip_key = client_ip_key().await;
-        return Ok(image_save_status_for_ip(&ip_key).await);
+        return Ok(IMAGE_SAVE_TRACKER.status(&ip_key).await);
     }
 
     #[cfg(not(feature = "server"))]
@@ -191,33 +213,21 @@ async fn get_image_save_status() -> Result<ImageSaveStatus, ServerFnError> {
 }
 
 #[server]
-async fn save_image_to_gallery() -> Result<String, ServerFnError> {
+async fn save_image_to_gallery(device_id: Option<String>) -> Result<String, ServerFnError> {
     #[cfg(feature = "server")]
     {
+        let device_id =
+            tcp_client::resolve_device_id(device_id.as_deref()).map_err(ServerFnError::new)?;
         let ip_key = client_ip_key().await;
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs();
-
-        let mut tracker = IMAGE_SAVE_TRACKER.lock().await;
-        let one_hour_ago = now.saturating_sub(3600);
-        let entries = tracker.entry(ip_key).or_default();
-        entries.retain(|&ts| ts > one_hour_ago);
-
-        if entries.len() >= IMAGE_SAVE_LIMIT_PER_HOUR {
-            return Err(ServerFnError::new(format!(
-                "Too many pictures taken in the last hour (limit: {}).",
-                IMAGE_SAVE_LIMIT_PER_HOUR
-            )));
-        }
-
-        tcp_client::send_command("[CMD] save image")
+        let count = IMAGE_SAVE_TRACKER
+            .save(&ip_key, || async {
+                tcp_client::send_command(&device_id, &DeviceCommand::SaveImage)
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| format!("Failed to save image: {}", e))
+            })
             .await
-            .map_err(|e| ServerFnError::new(format!("Failed to save image: {}", e)))?;
-
-        entries.push(now);
-        let count = entries.len();
+            .map_err(ServerFnError::new)?;
         return Ok(format!(
             "Image saved successfully. {} images saved in the last hour.",
             count
@@ -234,12 +244,8 @@ pub async fn get_stream_config() -> Result<StreamConfig, ServerFnError> {
     Ok(StreamConfig {
         stream_url: std::env::var("STREAM_URL")
             .unwrap_or_else(|_| "http://127.0.0.1:8889/cam".to_string()),
-        websocket_url: std::env::var("WEBSOCKET_URL")
-            .unwrap_or_else(|_| "ws://127.0.0.1:8000/ws".to_string()),
         grafana_base_url: std::env::var("GRAFANA_BASE_URL")
             .unwrap_or_else(|_| "http://localhost:3000".to_string()),
-        grafana_dashboard: std::env::var("GRAFANA_DASHBOARD")
-            .unwrap_or_else(|_| "ad9bp5g/voegeli".to_string()),
         grafana_dashboard_nerds: std::env::var("GRAFANA_DASHBOARD_NERDS")
             .unwrap_or_else(|_| "no-dashboard".to_string()),
     })
@@ -248,22 +254,29 @@ pub async fn get_stream_config() -> Result<StreamConfig, ServerFnError> {
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct StreamConfig {
     pub stream_url: String,
-    pub websocket_url: String,
     pub grafana_base_url: String,
-    pub grafana_dashboard: String,
     pub grafana_dashboard_nerds: String,
 }
 
+use crate::components::{get_history_fields, DevicePicker, HistoryChart};
+#[cfg(target_arch = "wasm32")]
+use crate::spectrogram_frame::SpectrogramFrame;
+#[cfg(feature = "server")]
+use crate::device_protocol::{DeviceCommand, DeviceResponse};
 #[cfg(feature = "server")]
 use crate::tcp_client;
 use crate::tcp_state;
+use crate::Route;
 #[cfg(feature = "server")]
 use crate::CURRENT_LUMINOSITY;
 
 const IR_LUX_THRESHOLD: f64 = 1500.0;
 
 #[server]
-async fn toggle_ir_led(enabled: bool) -> Result<bool, ServerFnError> {
+async fn toggle_ir_led(device_id: Option<String>, enabled: bool) -> Result<bool, ServerFnError> {
+    let device_id =
+        tcp_client::resolve_device_id(device_id.as_deref()).map_err(ServerFnError::new)?;
+
     let current_lux = {
         let lock = CURRENT_LUMINOSITY
             .read()
@@ -279,48 +292,24 @@ async fn toggle_ir_led(enabled: bool) -> Result<bool, ServerFnError> {
         }
     }
 
-    let cmd = if enabled {
-        "[CMD] IR ON"
-    } else {
-        "[CMD] IR OFF"
-    };
-
-    tcp_client::send_command(cmd)
+    tcp_client::send_command(&device_id, &DeviceCommand::ir(enabled))
         .await
         .map(|_| enabled)
         .map_err(ServerFnError::new)
 }
 
 #[server]
-async fn get_ir_state() -> Result<bool, ServerFnError> {
-    let response = tcp_client::send_command("[CMD] GET IR STATE")
-        .await
-        .map_err(|e| ServerFnError::new(e))?;
-
-    println!("Received IR state response from TCP: '{:?}'", response);
-
-    let payload = response.to_uppercase();
-    for raw_line in payload.lines() {
-        let line = raw_line.trim();
-        if line.is_empty() {
-            continue;
-        }
-
-        println!("Checking line for IR state: '{:?}'", line);
-
-        if line.contains("IR STATE IS ON") {
-            return Ok(true);
-        }
-
-        if line.contains("IR STATE IS OFF") {
-            return Ok(false);
-        }
+async fn get_ir_state(device_id: Option<String>) -> Result<bool, ServerFnError> {
+    let device_id =
+        tcp_client::resolve_device_id(device_id.as_deref()).map_err(ServerFnError::new)?;
+    match tcp_client::send_command(&device_id, &DeviceCommand::GetIrState).await {
+        Ok(DeviceResponse::IrState(enabled)) => Ok(enabled),
+        Ok(response) => Err(ServerFnError::new(format!(
+            "Unexpected IR state response from TCP: {:?}",
+            response
+        ))),
+        Err(e) => Err(ServerFnError::new(e)),
     }
-
-    Err(ServerFnError::new(format!(
-        "Unexpected IR state response from TCP: {}",
-        response
-    )))
 }
 
 #[server]
@@ -342,7 +331,7 @@ async fn get_current_luminosity() -> Result<Option<f64>, ServerFnError> {
 #[cfg(target_arch = "wasm32")]
 fn init_webgl_spectrogram(
     canvas_id: &str,
-    ws_url: &str,
+    fps: u32,
 ) -> Result<SpectrogramRuntime, wasm_bindgen::JsValue> {
     use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement, ImageData};
 
@@ -359,32 +348,44 @@ fn init_webgl_spectrogram(
 
     const HEIGHT: u32 = 256;
     const SECONDS: u32 = 20;
-    const FPS: u32 = 30;
-    const WIDTH: u32 = SECONDS * FPS;
+    // One column per frame, so the canvas always spans SECONDS.
+    let fps = fps.clamp(1, 30);
+    let width = SECONDS * fps;
 
-    canvas.set_width(WIDTH);
+    canvas.set_width(width);
     canvas.set_height(HEIGHT);
 
-    const SAMPLE_RATE: f64 = 44100.0;
-    const FFT_SIZE: usize = 1024;
     const FREQ_MIN: f64 = 200.0;
     const FREQ_MAX: f64 = 12000.0;
 
-    let mut log_freq_map = vec![0usize; HEIGHT as usize];
+    // Frequency shown in each pixel row, top row highest. Bins are looked up
+    // per frame, since sample rate and FFT size come with the frame.
+    let mut row_freqs = vec![0.0f64; HEIGHT as usize];
     for y in 0..HEIGHT {
         let frac = y as f64 / HEIGHT as f64;
-        let freq = FREQ_MIN * (FREQ_MAX / FREQ_MIN).powf(frac);
-        let bin = (freq * FFT_SIZE as f64 / SAMPLE_RATE).round() as usize;
-        log_freq_map[(HEIGHT - 1 - y) as usize] = bin.min(FFT_SIZE / 2 - 1);
+        row_freqs[(HEIGHT - 1 - y) as usize] = FREQ_MIN * (FREQ_MAX / FREQ_MIN).powf(frac);
     }
 
-    let log_freq_map = Rc::new(log_freq_map);
-    let frame_buffer = Rc::new(RefCell::new(Vec::<Vec<f64>>::new()));
+    let row_freqs = Rc::new(row_freqs);
+    let frame_buffer = Rc::new(RefCell::new(VecDeque::<SpectrogramFrame>::new()));
     let socket_ref = Rc::new(RefCell::new(None::<WebSocket>));
     let socket_callbacks = Rc::new(RefCell::new(None::<SpectrogramSocketCallbacks>));
     let stop_flag = Rc::new(Cell::new(false));
     let timeout_id = Rc::new(RefCell::new(None::<i32>));
-    let ws_url = ws_url.to_string();
+
+    // The server relays the Pi's feed, decimated to our frame rate, and starts
+    // every connection with the last SECONDS of history.
+    let window = web_sys::window().expect("browser window");
+    let protocol = window
+        .location()
+        .protocol()
+        .unwrap_or_else(|_| "http:".into());
+    let host = window
+        .location()
+        .host()
+        .unwrap_or_else(|_| "127.0.0.1:8080".into());
+    let ws_protocol = if protocol == "https:" { "wss" } else { "ws" };
+    let ws_url = format!("{}://{}/ws/spectrogram?fps={}", ws_protocol, host, fps);
 
     let connect_websocket = {
         let frame_buffer = frame_buffer.clone();
@@ -410,16 +411,27 @@ fn init_webgl_spectrogram(
                 }
             };
 
+            socket.set_binary_type(web_sys::BinaryType::Arraybuffer);
+
             let frame_buffer_clone = frame_buffer.clone();
 
             let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
-                if let Some(text) = event.data().as_string() {
-                    if let Ok(fft) = serde_json::from_str::<Vec<f64>>(&text) {
-                        let mut buffer = frame_buffer_clone.borrow_mut();
-                        if buffer.len() < 10 {
-                            buffer.push(fft);
-                        }
+                let data = event.data();
+                let frame = if let Some(buffer) = data.dyn_ref::<js_sys::ArrayBuffer>() {
+                    SpectrogramFrame::decode(&js_sys::Uint8Array::new(buffer).to_vec())
+                } else if let Some(text) = data.as_string() {
+                    // Older servers forward the Pi's JSON frames as-is
+                    SpectrogramFrame::from_json(&text, 0, 0)
+                } else {
+                    return;
+                };
+
+                if let Ok(frame) = frame {
+                    let mut buffer = frame_buffer_clone.borrow_mut();
+                    if buffer.len() >= width as usize {
+                        buffer.pop_front();
                     }
+                    buffer.push_back(frame);
                 }
             }) as Box<dyn FnMut(_)>);
 
@@ -458,7 +470,7 @@ fn init_webgl_spectrogram(
 
     let ctx_render = ctx.clone();
     let canvas_render = canvas.clone();
-    let log_freq_map_render = log_freq_map.clone();
+    let row_freqs_render = row_freqs.clone();
     let pixel_buffer = Rc::new(RefCell::new(vec![0u8; HEIGHT as usize * 4]));
 
     let render_loop = Rc::new(RefCell::new(None::<Closure<dyn FnMut()>>));
@@ -478,44 +490,48 @@ fn init_webgl_spectrogram(
             connect_websocket();
         }
 
-        // Check if we have data and pop it in a separate scope
-        let fft_data = frame_buffer_render.borrow_mut().pop();
+        // Take everything queued so far; the history burst on connect is
+        // drawn in one go instead of replaying it in real time.
+        let frames: Vec<SpectrogramFrame> = frame_buffer_render.borrow_mut().drain(..).collect();
 
-        let delay = if let Some(fft) = fft_data {
-            // Process data at 30 FPS
-            let _ = ctx_render.draw_image_with_html_canvas_element(&canvas_render, -1.0, 0.0);
+        let delay = if frames.is_empty() {
+            1000 // 1 second when idle (no data)
+        } else {
+            let columns = frames.len() as f64;
+            let _ = ctx_render.draw_image_with_html_canvas_element(&canvas_render, -columns, 0.0);
 
             let mut pixels = pixel_buffer.borrow_mut();
-            for y in 0..HEIGHT as usize {
-                let bin = log_freq_map_render[y];
-                let v = fft.get(bin).copied().unwrap_or(0.0);
+            for (i, frame) in frames.iter().enumerate() {
+                for y in 0..HEIGHT as usize {
+                    let bin = frame.bin_for_frequency(row_freqs_render[y]);
+                    let v = frame.bins.get(bin).copied().unwrap_or(0.0) as f64;
 
-                let norm = ((v + 6.0) / 6.0).clamp(0.0, 1.0);
-                let enhanced = norm.powf(1.2);
+                    let norm = ((v + 6.0) / 6.0).clamp(0.0, 1.0);
+                    let enhanced = norm.powf(1.2);
 
-                // let contrast = 0.5;
-                // let contrasted = ((enhanced - 0.5) * contrast + 0.5).clamp(0.0, 1.0);
+                    // let contrast = 0.5;
+                    // let contrasted = ((enhanced - 0.5) * contrast + 0.5).clamp(0.0, 1.0);
 
-                let c = (enhanced * 255.0) as u8;
+                    let c = (enhanced * 255.0) as u8;
 
-                let idx = y * 4;
-                pixels[idx] = c;
-                pixels[idx + 1] = (c as f64 * 0.7) as u8;
-                pixels[idx + 2] = (c as u16 + 80).min(255) as u8;
-                pixels[idx + 3] = 255;
-            }
+                    let idx = y * 4;
+                    pixels[idx] = c;
+                    pixels[idx + 1] = (c as f64 * 0.7) as u8;
+                    pixels[idx + 2] = (c as u16 + 80).min(255) as u8;
+                    pixels[idx + 3] = 255;
+                }
 
-            if let Ok(image_data) = ImageData::new_with_u8_clamped_array_and_sh(
-                wasm_bindgen::Clamped(&pixels),
-                1,
-                HEIGHT,
-            ) {
-                let _ = ctx_render.put_image_data(&image_data, (WIDTH - 1) as f64, 0.0);
+                if let Ok(image_data) = ImageData::new_with_u8_clamped_array_and_sh(
+                    wasm_bindgen::Clamped(&pixels),
+                    1,
+                    HEIGHT,
+                ) {
+                    let x = width as f64 - columns + i as f64;
+                    let _ = ctx_render.put_image_data(&image_data, x, 0.0);
+                }
             }
 
-            33 // 30 FPS when rendering
-        } else {
-            1000 // 1 second when idle (no data)
+            (1000 / fps) as i32
         };
 
         let window = web_sys::window().unwrap();
@@ -545,7 +561,7 @@ fn init_webgl_spectrogram(
     *timeout_id.borrow_mut() = Some(id);
 
     ctx.set_fill_style(&"black".into());
-    ctx.fill_rect(0.0, 0.0, WIDTH as f64, HEIGHT as f64);
+    ctx.fill_rect(0.0, 0.0, width as f64, HEIGHT as f64);
 
     Ok(SpectrogramRuntime {
         stop_flag,
@@ -556,15 +572,40 @@ fn init_webgl_spectrogram(
     })
 }
 
+#[component]
 pub fn Home() -> Element {
+    rsx! {
+        DeviceHome { device_id: None }
+    }
+}
+
+/// The live page of a specific device, reachable at `/devices/:device_id`.
+#[component]
+pub fn Device(device_id: String) -> Element {
+    rsx! {
+        DeviceHome { device_id: Some(device_id) }
+    }
+}
+
+#[component]
+fn DeviceHome(device_id: Option<String>) -> Element {
     let config = use_resource(|| async move { get_stream_config().await.ok() });
+    let history_fields =
+        use_resource(|| async move { get_history_fields().await.unwrap_or_default() });
     let mut save_status_refresh = use_signal(|| 0u64);
     let save_status = use_resource(move || {
         let _ = save_status_refresh();
         async move { get_image_save_status().await.ok() }
     });
-    let tcp_state = use_context::<tcp_state::TcpState>();
+    let mut tcp_state = use_context::<tcp_state::TcpState>();
     let mut ir_enabled = tcp_state.ir_enabled;
+    let device_link = tcp_state.device_link;
+    let selected_device = tcp_state.device_id;
+    let navigator = use_navigator();
+
+    use_effect(use_reactive!(|device_id| {
+        tcp_state.select_device(device_id);
+    }));
     let mut saving = use_signal(|| false);
     let mut ir_request_id = use_signal(|| 0u64);
     let mut ir_feedback = use_signal(|| None::<String>);
@@ -576,7 +617,7 @@ pub fn Home() -> Element {
 
     // Load initial states in background without blocking render
     use_resource(move || async move {
-        if let Ok(state) = get_ir_state().await {
+        if let Ok(state) = get_ir_state(selected_device()).await {
             ir_enabled.set(state);
         }
     });
@@ -603,6 +644,7 @@ pub fn Home() -> Element {
             }
         };
     };
+    let history_fields = history_fields.read().clone().unwrap_or_default();
     let save_status_value = save_status.read();
     let save_status_current = save_status_value.as_ref().and_then(|s| s.as_ref().cloned());
     let is_save_limited = save_status_current
@@ -630,18 +672,27 @@ pub fn Home() -> Element {
         "toggle IR LED".to_string()
     };
 
+    let camera_offline = device_link
+        .read()
+        .as_ref()
+        .map(|link| !link.is_online())
+        .unwrap_or(false);
+
     let stream_url = cfg.stream_url.clone();
-    #[cfg(target_arch = "wasm32")]
-    let ws_url = cfg.websocket_url.clone();
 
     #[cfg(target_arch = "wasm32")]
     {
         let mut spec_initialized = use_signal(|| false);
-        let ws_url_clone = ws_url.clone();
 
         use_effect(move || {
             if !spec_initialized() {
-                match init_webgl_spectrogram("spectrogram", &ws_url_clone) {
+                // Narrow screens cannot show 30 columns per second anyway.
+                let narrow = web_sys::window()
+                    .and_then(|w| w.inner_width().ok())
+                    .and_then(|w| w.as_f64())
+                    .is_some_and(|w| w < 768.0);
+                let fps = if narrow { 15 } else { 30 };
+                match init_webgl_spectrogram("spectrogram", fps) {
                     Ok(runtime) => {
                         set_spectrogram_runtime(runtime);
                         spec_initialized.set(true);
@@ -666,6 +717,12 @@ pub fn Home() -> Element {
 
             div {
                 class: "w-full max-w-7xl flex flex-row flex-nowrap items-center justify-center gap-6 px-4 py-2 overflow-x-auto",
+                DevicePicker {
+                    selected: selected_device(),
+                    onselect: move |device_id: String| {
+                        let _ = navigator.push(Route::Device { device_id });
+                    },
+                }
                 div {
                     class: "flex items-center gap-2",
                     label {
@@ -715,7 +772,7 @@ pub fn Home() -> Element {
                                     return;
                                 }
 
-                                match toggle_ir_led(new_state).await {
+                                match toggle_ir_led(selected_device(), new_state).await {
                                     Ok(state) => {
                                         if ir_request_id_ack() == request_id {
                                             ir_enabled_ack.set(state);
@@ -777,7 +834,7 @@ pub fn Home() -> Element {
                             saving.set(true);
 
                             spawn(async move {
-                                match save_image_to_gallery().await {
+                                match save_image_to_gallery(selected_device()).await {
                                     Ok(_msg) => {
                                         #[cfg(target_arch = "wasm32")]
                                         web_sys::console::log_1(&_msg.into());
@@ -803,6 +860,13 @@ pub fn Home() -> Element {
                 }
             }
 
+            if camera_offline {
+                p {
+                    class: "rounded-lg bg-amber-500/20 border border-amber-400 px-4 py-2 text-sm text-amber-200",
+                    "The birdhouse camera is currently offline. We are trying to reconnect..."
+                }
+            }
+
             div {
                 class: "w-full flex flex-col items-center gap-6 px-4",
                 style: "--content-width: min(100%, 1280px); --stream-height: calc(var(--content-width) * 9 / 16); --spec-height: calc(var(--content-width) * 4 / 16);",
@@ -823,36 +887,46 @@ pub fn Home() -> Element {
             div {
                 class: "w-full max-w-7xl grid grid-cols-1 md:grid-cols-2 lg:grid-cols-3 gap-4",
 
-                iframe {
-                    src: format!("{}/d-solo/{}?orgId=1&timezone=browser&refresh=5s&panelId=panel-7&__feature.dashboardSceneSolo=true&from=now-24h&to=now", cfg.grafana_base_url, cfg.grafana_dashboard),
-                    class: "w-full h-64 rounded-lg border-2 border-slate-700",
-                }
-
-                iframe {
-                    src: format!("{}/d-solo/{}?orgId=1&timezone=browser&refresh=5s&panelId=panel-8&__feature.dashboardSceneSolo=true&from=now-24h&to=now", cfg.grafana_base_url, cfg.grafana_dashboard),
-                    class: "w-full h-64 rounded-lg border-2 border-slate-700",
-                }
-
-                iframe {
-                    src: format!("{}/d-solo/{}?orgId=1&timezone=browser&refresh=5s&panelId=panel-9&__feature.dashboardSceneSolo=true&from=now-24h&to=now", cfg.grafana_base_url, cfg.grafana_dashboard),
-                    class: "w-full h-64 rounded-lg border-2 border-slate-700",
-                }
-
-                iframe {
-                    src: format!("{}/d-solo/{}?orgId=1&timezone=browser&refresh=5s&panelId=panel-10&__feature.dashboardSceneSolo=true&from=now-24h&to=now", cfg.grafana_base_url, cfg.grafana_dashboard),
-                    class: "w-full h-64 rounded-lg border-2 border-slate-700",
-                }
-
-                iframe {
-                    src: format!("{}/d-solo/{}?orgId=1&timezone=browser&refresh=5s&panelId=panel-12&__feature.dashboardSceneSolo=true&from=now-24h&to=now", cfg.grafana_base_url, cfg.grafana_dashboard),
-                    class: "w-full h-64 rounded-lg border-2 border-slate-700",
-                }
-
-                iframe {
-                    src: format!("{}/d-solo/{}?orgId=1&timezone=browser&refresh=5s&panelId=panel-11&__feature.dashboardSceneSolo=true&from=now-24h&to=now", cfg.grafana_base_url, cfg.grafana_dashboard),
-                    class: "w-full h-64 rounded-lg border-2 border-slate-700",
+                for field in history_fields.iter() {
+                    HistoryChart { key: "{field.field}", field: field.clone() }
                 }
             }
         }
     }
 }
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::ImageSaveTracker;
+    use crate::clock::MockClock;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_hourly_save_limit() {
+        let clock = MockClock::at_unix_secs(1_700_000_000);
+        let tracker = ImageSaveTracker::new(Arc::new(clock.clone()), 3);
+
+        for expected in 1..=3 {
+            assert_eq!(tracker.save("1.2.3.4", || async { Ok(()) }).await, Ok(expected));
+            clock.advance(Duration::from_secs(60));
+        }
+        assert!(tracker.status("1.2.3.4").await.is_limited);
+        assert!(tracker.save("1.2.3.4", || async { Ok(()) }).await.is_err());
+        assert_eq!(tracker.save("5.6.7.8", || async { Ok(()) }).await, Ok(1));
+
+        // Failed saves do not count.
+        assert!(tracker
+            .save("5.6.7.8", || async { Err("device offline".to_string()) })
+            .await
+            .is_err());
+        assert_eq!(tracker.status("5.6.7.8").await.count_last_hour, 1);
+
+        // The first save leaves the window an hour after it was taken.
+        clock.advance(Duration::from_secs(3600 - 3 * 60 + 1));
+        let status = tracker.status("1.2.3.4").await;
+        assert_eq!(status.count_last_hour, 2);
+        assert!(!status.is_limited);
+        assert_eq!(tracker.save("1.2.3.4", || async { Ok(()) }).await, Ok(3));
+    }
+}
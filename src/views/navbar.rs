@@ -32,6 +32,7 @@ pub fn Navbar() -> Element {
 
     let selected = match current_route {
         Route::Home {} => "home",
+        Route::Device { .. } => "home",
         Route::Gallery {} => "gallery",
         Route::MakingOf {} => "making",
         Route::HowItWorks {} => "how",
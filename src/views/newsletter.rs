@@ -1,5 +1,9 @@
 use dioxus::prelude::*;
 
+#[cfg(feature = "server")]
+use crate::command_queue;
+#[cfg(feature = "server")]
+use crate::device_protocol::DeviceCommand;
 #[cfg(feature = "server")]
 use crate::tcp_client;
 
@@ -8,9 +12,16 @@ async fn add_newsletter_subscriber(email: String) -> Result<String, ServerFnErro
     let email = crate::newsletter::normalize_email(&email)
         .ok_or_else(|| ServerFnError::new("Please provide a valid email address."))?;
 
-    let cmd = format!("[CMD] add newsletter={}", email);
-    tcp_client::send_command(&cmd)
-        .await
+    let cmd = DeviceCommand::AddNewsletter {
+        email: email.clone(),
+    };
+    // The subscriber list lives on the default device. Queued on disk if it is
+    // offline and delivered once it reconnects.
+    let submitted = match tcp_client::resolve_device_id(None) {
+        Ok(device_id) => command_queue::submit(&device_id, cmd).await,
+        Err(e) => Err(e),
+    };
+    submitted
         .map_err(|e| ServerFnError::new(format!("Failed to add newsletter subscriber: {}", e)))?;
 
     let encoding_key = std::env::var("ENCODING")
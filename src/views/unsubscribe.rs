@@ -1,5 +1,9 @@
 use dioxus::prelude::*;
 
+#[cfg(feature = "server")]
+use crate::command_queue;
+#[cfg(feature = "server")]
+use crate::device_protocol::DeviceCommand;
 #[cfg(feature = "server")]
 use crate::tcp_client;
 
@@ -13,8 +17,12 @@ async fn remove_newsletter_subscriber_by_token(encoded_email: String) -> Result<
     let email = crate::newsletter::normalize_email(&decoded)
         .ok_or_else(|| ServerFnError::new("Invalid unsubscribe link."))?;
 
-    let cmd = format!("[CMD] remove newsletter={}", email);
-    tcp_client::send_command(&cmd).await.map_err(|e| {
+    let cmd = DeviceCommand::RemoveNewsletter { email };
+    let submitted = match tcp_client::resolve_device_id(None) {
+        Ok(device_id) => command_queue::submit(&device_id, cmd).await,
+        Err(e) => Err(e),
+    };
+    submitted.map_err(|e| {
         ServerFnError::new(format!("Failed to remove newsletter subscriber: {}", e))
     })?;
 
@@ -0,0 +1,740 @@
+#![cfg(feature = "server")]
+
+//! Collects points in memory and writes them with multi-row INSERTs once
+//! enough are waiting or the flush interval passed. Points that cannot be
+//! written because the database is unreachable are appended to an on-disk
+//! journal and replayed on a later flush, so readings survive a database
+//! restart. Points the database refuses are moved to a dead-letter file
+//! instead, so one bad row never holds up the rest.
+
+use crate::postgres_store::{self, InsertError, Point, PostgresTimeSeriesStore, INSERT_BATCH_ROWS};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::future::Future;
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+const JOURNAL_FILE: &str = "data/postgres_journal.ndjson";
+const JOURNAL_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Where buffered points end up.
+pub trait PointSink: Send + Sync + 'static {
+    fn insert_points(
+        &self,
+        points: &[Point],
+    ) -> impl Future<Output = Result<(), InsertError>> + Send;
+}
+
+impl PointSink for PostgresTimeSeriesStore {
+    fn insert_points(
+        &self,
+        points: &[Point],
+    ) -> impl Future<Output = Result<(), InsertError>> + Send {
+        PostgresTimeSeriesStore::insert_points(self, points)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WriteBufferConfig {
+    /// Flush as soon as this many points are waiting.
+    pub max_points: usize,
+    pub flush_interval: Duration,
+    pub journal_path: PathBuf,
+    /// Points that would grow the journal or the dead-letter file past this
+    /// size are dropped.
+    pub journal_max_bytes: u64,
+}
+
+impl WriteBufferConfig {
+    pub fn from_env() -> Self {
+        Self {
+            max_points: std::env::var("WRITE_BUFFER_MAX_POINTS")
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+                .filter(|max| *max > 0)
+                .unwrap_or(500),
+            flush_interval: Duration::from_secs(
+                std::env::var("WRITE_BUFFER_FLUSH_SECS")
+                    .ok()
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .filter(|secs| *secs > 0)
+                    .unwrap_or(5),
+            ),
+            journal_path: std::env::var("WRITE_BUFFER_JOURNAL")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| PathBuf::from(JOURNAL_FILE)),
+            journal_max_bytes: std::env::var("WRITE_BUFFER_JOURNAL_MAX_BYTES")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .filter(|max| *max > 0)
+                .unwrap_or(JOURNAL_MAX_BYTES),
+        }
+    }
+
+    /// How many bytes at the front of the journal were already replayed.
+    fn offset_path(&self) -> PathBuf {
+        self.journal_path.with_extension("offset")
+    }
+
+    /// Points the database refused, one `{error, point}` object per line.
+    pub fn dead_letter_path(&self) -> PathBuf {
+        self.journal_path.with_extension("rejected.ndjson")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WriteBufferStats {
+    pub pending: usize,
+    pub journaled: usize,
+    pub journal_bytes: u64,
+    /// Points moved to the dead-letter file since startup.
+    pub rejected: u64,
+    /// Points lost since startup because the journal was full.
+    pub dropped: u64,
+    pub last_error: Option<String>,
+}
+
+/// The part of the journal file that still has to be replayed: the bytes
+/// from `offset` to `len`, holding `rows` points.
+#[derive(Debug, Default)]
+struct JournalState {
+    offset: u64,
+    len: u64,
+    rows: usize,
+}
+
+/// How far [`WriteBuffer::write`] got.
+#[derive(Debug, Default)]
+struct Progress {
+    /// Points from the front that were stored or dead-lettered.
+    consumed: usize,
+    /// Of those, the points the sink stored.
+    stored: usize,
+    /// The outage that stopped the write early.
+    outage: Option<String>,
+}
+
+pub struct WriteBuffer<S: PointSink> {
+    sink: S,
+    config: WriteBufferConfig,
+    pending: Mutex<Vec<Point>>,
+    journal: Mutex<JournalState>,
+    rejected: AtomicU64,
+    dropped: AtomicU64,
+    last_error: Mutex<Option<String>>,
+    // Serializes flushes so the journal has a single writer.
+    flush_lock: tokio::sync::Mutex<()>,
+    full: Notify,
+}
+
+static SHARED: Lazy<Result<Arc<WriteBuffer<PostgresTimeSeriesStore>>, String>> = Lazy::new(|| {
+    postgres_store::shared()
+        .map(|store| Arc::new(WriteBuffer::new(store, WriteBufferConfig::from_env())))
+});
+
+/// The buffer in front of [`postgres_store::shared`].
+pub fn shared() -> Result<Arc<WriteBuffer<PostgresTimeSeriesStore>>, String> {
+    SHARED.clone()
+}
+
+impl<S: PointSink> WriteBuffer<S> {
+    /// Picks up a journal left behind by an earlier run, resuming at its
+    /// saved offset.
+    pub fn new(sink: S, config: WriteBufferConfig) -> Self {
+        let journal = load_journal(&config);
+        Self {
+            sink,
+            config,
+            pending: Mutex::new(Vec::new()),
+            journal: Mutex::new(journal),
+            rejected: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            last_error: Mutex::new(None),
+            flush_lock: tokio::sync::Mutex::new(()),
+            full: Notify::new(),
+        }
+    }
+
+    pub fn push(&self, point: Point) {
+        self.extend([point]);
+    }
+
+    pub fn extend(&self, points: impl IntoIterator<Item = Point>) {
+        let waiting = {
+            let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+            pending.extend(points);
+            pending.len()
+        };
+        if waiting >= self.config.max_points {
+            self.full.notify_one();
+        }
+    }
+
+    pub fn stats(&self) -> WriteBufferStats {
+        let (journaled, journal_bytes) = {
+            let journal = self.journal();
+            (journal.rows, journal.len - journal.offset)
+        };
+        WriteBufferStats {
+            pending: self
+                .pending
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .len(),
+            journaled,
+            journal_bytes,
+            rejected: self.rejected.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            last_error: self
+                .last_error
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone(),
+        }
+    }
+
+    fn journal(&self) -> MutexGuard<'_, JournalState> {
+        self.journal.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn set_last_error(&self, error: Option<String>) {
+        *self
+            .last_error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = error;
+    }
+
+    /// Replays the journal, then writes everything pending. While the journal
+    /// cannot be replayed new points go straight to it, so they are written
+    /// in order once the database is back. Returns how many points reached
+    /// the sink.
+    pub async fn flush(&self) -> usize {
+        let _guard = self.flush_lock.lock().await;
+        let (mut stored, outage) = self.replay().await;
+
+        let pending =
+            std::mem::take(&mut *self.pending.lock().unwrap_or_else(PoisonError::into_inner));
+        if let Some(e) = outage {
+            self.journal_points(&pending).await;
+            self.set_last_error(Some(e));
+            return stored;
+        }
+
+        let progress = self.write(&pending).await;
+        stored += progress.stored;
+        match progress.outage {
+            None => self.set_last_error(None),
+            Some(e) => {
+                eprintln!(
+                    "[WriteBuffer] Journaling {} points after a failed write: {}",
+                    pending.len() - progress.consumed,
+                    e
+                );
+                self.journal_points(&pending[progress.consumed..]).await;
+                self.set_last_error(Some(e));
+            }
+        }
+        stored
+    }
+
+    /// Replays the journal one batch at a time from the saved offset, which
+    /// moves past every batch as it is written. Returns how many points the
+    /// sink stored, and the outage if one stopped the replay.
+    ///
+    /// Only flushes touch the journal files, one at a time, so the state lock
+    /// is never held across file IO and [`stats`](Self::stats) never waits
+    /// on the disk.
+    async fn replay(&self) -> (usize, Option<String>) {
+        let path = &self.config.journal_path;
+        let (mut stored, mut replayed) = (0, 0);
+        loop {
+            let offset = {
+                let journal = self.journal();
+                if journal.rows == 0 {
+                    break;
+                }
+                journal.offset
+            };
+            let read_path = path.clone();
+            let lines =
+                match blocking(move || read_journal_lines(&read_path, offset, INSERT_BATCH_ROWS))
+                    .await
+                {
+                    Ok(lines) if !lines.is_empty() => lines,
+                    Ok(_) => break,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => break,
+                    Err(e) => {
+                        return (
+                            stored,
+                            Some(format!("Failed to read journal {:?}: {}", path, e)),
+                        )
+                    }
+                };
+
+            let points: Vec<Point> = lines.iter().filter_map(|(p, _)| p.clone()).collect();
+            let progress = self.write(&points).await;
+            stored += progress.stored;
+            let passed = lines_passed(&lines, progress.consumed);
+            if passed > 0 {
+                replayed += passed;
+                let offset = {
+                    let mut journal = self.journal();
+                    journal.offset = lines[passed - 1].1;
+                    journal.rows = journal.rows.saturating_sub(passed);
+                    journal.offset
+                };
+                save_offset(self.config.offset_path(), offset).await;
+            }
+            if progress.outage.is_some() {
+                return (stored, progress.outage);
+            }
+        }
+
+        if self.journal().len > 0 {
+            for file in [path.clone(), self.config.offset_path()] {
+                let remove_path = file.clone();
+                if let Err(e) = blocking(move || std::fs::remove_file(remove_path)).await {
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        eprintln!("[WriteBuffer] Failed to remove {:?}: {}", file, e);
+                    }
+                }
+            }
+            *self.journal() = JournalState::default();
+        }
+        if replayed > 0 {
+            println!("[WriteBuffer] Replayed {} journaled points", replayed);
+        }
+        (stored, None)
+    }
+
+    /// Writes `points` one INSERT batch at a time. A batch the sink rejects
+    /// is retried point by point, and the points it still rejects are moved
+    /// to the dead-letter file. Stops at the first outage.
+    async fn write(&self, points: &[Point]) -> Progress {
+        let mut progress = Progress::default();
+        for chunk in points.chunks(INSERT_BATCH_ROWS) {
+            match self.sink.insert_points(chunk).await {
+                Ok(()) => {
+                    progress.consumed += chunk.len();
+                    progress.stored += chunk.len();
+                }
+                Err(InsertError::Unavailable(e)) => {
+                    progress.outage = Some(e);
+                    return progress;
+                }
+                Err(InsertError::Rejected(_)) => {
+                    for point in chunk {
+                        match self.sink.insert_points(std::slice::from_ref(point)).await {
+                            Ok(()) => progress.stored += 1,
+                            Err(InsertError::Rejected(e)) => self.dead_letter(point, &e).await,
+                            Err(InsertError::Unavailable(e)) => {
+                                progress.outage = Some(e);
+                                return progress;
+                            }
+                        }
+                        progress.consumed += 1;
+                    }
+                }
+            }
+        }
+        progress
+    }
+
+    /// Appends `points` to the journal, or drops them with a warning when
+    /// that would grow it past `journal_max_bytes`.
+    async fn journal_points(&self, points: &[Point]) {
+        if points.is_empty() {
+            return;
+        }
+        let path = &self.config.journal_path;
+        let data = journal_lines(points);
+        let len = self.journal().len;
+        if len + data.len() as u64 > self.config.journal_max_bytes {
+            self.dropped
+                .fetch_add(points.len() as u64, Ordering::Relaxed);
+            eprintln!(
+                "[WriteBuffer] Journal {:?} is full ({} of {} bytes), dropping {} points",
+                path,
+                len,
+                self.config.journal_max_bytes,
+                points.len()
+            );
+            return;
+        }
+        let (bytes, rows) = (data.len() as u64, data.lines().count());
+        let append_path = path.clone();
+        match blocking(move || append_file(&append_path, &data)).await {
+            Ok(()) => {
+                let mut journal = self.journal();
+                journal.len += bytes;
+                journal.rows += rows;
+            }
+            Err(e) => {
+                self.dropped
+                    .fetch_add(points.len() as u64, Ordering::Relaxed);
+                eprintln!(
+                    "[WriteBuffer] Failed to journal {} points to {:?}, they are lost: {}",
+                    points.len(),
+                    path,
+                    e
+                );
+            }
+        }
+    }
+
+    async fn dead_letter(&self, point: &Point, error: &str) {
+        let path = self.config.dead_letter_path();
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        eprintln!(
+            "[WriteBuffer] PostgreSQL rejected a point of {}/{}, moving it to {:?}: {}",
+            point.measurement, point.field, path, error
+        );
+        let line = serde_json::json!({ "error": error, "point": point }).to_string() + "\n";
+        let max_bytes = self.config.journal_max_bytes;
+        let append_path = path.clone();
+        let appended = blocking(move || {
+            let size = std::fs::metadata(&append_path)
+                .map(|m| m.len())
+                .unwrap_or(0);
+            if size + line.len() as u64 > max_bytes {
+                return Ok(false);
+            }
+            append_file(&append_path, &line).map(|()| true)
+        })
+        .await;
+        match appended {
+            Ok(true) => {}
+            Ok(false) => eprintln!(
+                "[WriteBuffer] Dead-letter file {:?} is full, the point is lost",
+                path
+            ),
+            Err(e) => eprintln!(
+                "[WriteBuffer] Failed to write {:?}, the point is lost: {}",
+                path, e
+            ),
+        }
+    }
+
+    /// Flushes every interval, and early whenever the buffer fills up.
+    pub fn spawn_flusher(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(self.config.flush_interval);
+
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = self.full.notified() => {}
+                }
+                self.flush().await;
+            }
+        });
+    }
+}
+
+/// Size and row count of the journal past its saved offset. Reads the file
+/// once, at startup.
+fn load_journal(config: &WriteBufferConfig) -> JournalState {
+    let len = match std::fs::metadata(&config.journal_path) {
+        Ok(meta) => meta.len(),
+        Err(_) => return JournalState::default(),
+    };
+    let offset = std::fs::read_to_string(config.offset_path())
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .unwrap_or(0)
+        .min(len);
+    let rows = std::fs::File::open(&config.journal_path)
+        .and_then(|mut file| {
+            file.seek(SeekFrom::Start(offset))?;
+            let mut rows = 0;
+            for line in BufReader::new(file).split(b'\n') {
+                if !line?.trim_ascii().is_empty() {
+                    rows += 1;
+                }
+            }
+            Ok(rows)
+        })
+        .unwrap_or_else(|e| {
+            eprintln!(
+                "[WriteBuffer] Failed to read journal {:?}: {}",
+                config.journal_path, e
+            );
+            0
+        });
+    if rows > 0 {
+        println!(
+            "[WriteBuffer] Found {} journaled points in {:?}",
+            rows, config.journal_path
+        );
+    }
+    JournalState { offset, len, rows }
+}
+
+/// Up to `max` journal lines from byte `offset` on, each with the offset
+/// just past it. Lines that do not parse are logged and kept as `None`, so
+/// replay can step over them.
+fn read_journal_lines(
+    path: &Path,
+    offset: u64,
+    max: usize,
+) -> std::io::Result<Vec<(Option<Point>, u64)>> {
+    let mut file = std::fs::File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut reader = BufReader::new(file);
+    let (mut lines, mut end, mut line) = (Vec::new(), offset, Vec::new());
+    while lines.len() < max {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)?;
+        if read == 0 {
+            break;
+        }
+        end += read as u64;
+        if line.trim_ascii().is_empty() {
+            continue;
+        }
+        let point = match serde_json::from_slice(line.trim_ascii()) {
+            Ok(point) => Some(point),
+            Err(e) => {
+                eprintln!("[WriteBuffer] Skipping unreadable journal line: {}", e);
+                None
+            }
+        };
+        lines.push((point, end));
+    }
+    Ok(lines)
+}
+
+/// How many journal lines are behind the replay once `consumed` of their
+/// points were written. Unreadable lines count as soon as a later point
+/// was written.
+fn lines_passed(lines: &[(Option<Point>, u64)], consumed: usize) -> usize {
+    let mut left = consumed;
+    for (index, (point, _)) in lines.iter().enumerate() {
+        if point.is_some() {
+            if left == 0 {
+                return index;
+            }
+            left -= 1;
+        }
+    }
+    lines.len()
+}
+
+fn journal_lines(points: &[Point]) -> String {
+    points
+        .iter()
+        .filter_map(|point| serde_json::to_string(point).ok())
+        .map(|line| line + "\n")
+        .collect()
+}
+
+/// Runs one blocking file operation off the async runtime's workers.
+async fn blocking<T: Send + 'static>(
+    op: impl FnOnce() -> std::io::Result<T> + Send + 'static,
+) -> std::io::Result<T> {
+    tokio::task::spawn_blocking(op)
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)))
+}
+
+fn append_file(path: &Path, data: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.write_all(data.as_bytes())?;
+    file.sync_data()
+}
+
+/// Saves the replay offset via a temp file, so a crash never leaves it
+/// half written.
+async fn save_offset(path: PathBuf, offset: u64) {
+    let saved_path = path.clone();
+    let result = blocking(move || {
+        let tmp_path = saved_path.with_extension("offset.tmp");
+        std::fs::write(&tmp_path, offset.to_string())?;
+        std::fs::rename(&tmp_path, &saved_path)
+    })
+    .await;
+    if let Err(e) = result {
+        eprintln!("[WriteBuffer] Failed to save {:?}: {}", path, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PointSink, WriteBuffer, WriteBufferConfig};
+    use crate::postgres_store::{InsertError, Point, TimeSeriesValue};
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Clone, Default)]
+    struct FakeSink {
+        down: Arc<AtomicBool>,
+        written: Arc<Mutex<Vec<Point>>>,
+    }
+
+    impl PointSink for FakeSink {
+        async fn insert_points(&self, points: &[Point]) -> Result<(), InsertError> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(InsertError::Unavailable("database is down".to_string()));
+            }
+            let nul = |p: &Point| matches!(&p.value, TimeSeriesValue::Text(t) if t.contains('\0'));
+            if points.iter().any(nul) {
+                return Err(InsertError::Rejected("invalid byte sequence".to_string()));
+            }
+            self.written.lock().unwrap().extend_from_slice(points);
+            Ok(())
+        }
+    }
+
+    fn point(value: f64) -> Point {
+        Point::now(
+            "test",
+            "voegeli",
+            "visitors",
+            TimeSeriesValue::Double(value),
+        )
+    }
+
+    fn text_point(text: &str) -> Point {
+        Point::now(
+            "test",
+            "voegeli",
+            "note",
+            TimeSeriesValue::Text(text.to_string()),
+        )
+    }
+
+    fn values(points: &[Point]) -> Vec<TimeSeriesValue> {
+        points.iter().map(|p| p.value.clone()).collect()
+    }
+
+    fn config(journal_max_bytes: u64) -> WriteBufferConfig {
+        WriteBufferConfig {
+            max_points: 100,
+            flush_interval: Duration::from_secs(5),
+            journal_path: std::env::temp_dir().join(format!(
+                "birdhouse-journal-{}/points.ndjson",
+                uuid::Uuid::new_v4()
+            )),
+            journal_max_bytes,
+        }
+    }
+
+    fn remove_dir(path: &Path) {
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_outage_spills_to_journal_and_replays_in_order() {
+        let config = config(1 << 20);
+        let journal = config.journal_path.clone();
+        let sink = FakeSink::default();
+        let buffer = WriteBuffer::new(sink.clone(), config);
+
+        buffer.push(point(1.0));
+        assert_eq!(buffer.flush().await, 1);
+
+        sink.down.store(true, Ordering::SeqCst);
+        buffer.extend([point(2.0), point(3.0)]);
+        assert_eq!(buffer.flush().await, 0);
+        buffer.push(point(4.0));
+        assert_eq!(buffer.flush().await, 0);
+        let stats = buffer.stats();
+        assert_eq!((stats.pending, stats.journaled), (0, 3));
+        assert!(stats.last_error.is_some());
+
+        sink.down.store(false, Ordering::SeqCst);
+        buffer.push(point(5.0));
+        assert_eq!(buffer.flush().await, 4);
+        assert!(!journal.exists());
+        assert_eq!(buffer.stats().last_error, None);
+
+        let written = sink.written.lock().unwrap();
+        assert_eq!(
+            values(&written),
+            values(&[point(1.0), point(2.0), point(3.0), point(4.0), point(5.0)])
+        );
+        remove_dir(&journal);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_points_are_dead_lettered() {
+        let config = config(1 << 20);
+        let dead_letters = config.dead_letter_path();
+        let sink = FakeSink::default();
+        let buffer = WriteBuffer::new(sink.clone(), config.clone());
+
+        // Rejected while replaying the journal as well as when fresh.
+        sink.down.store(true, Ordering::SeqCst);
+        buffer.extend([point(1.0), text_point("a\0b")]);
+        assert_eq!(buffer.flush().await, 0);
+        sink.down.store(false, Ordering::SeqCst);
+        buffer.extend([point(2.0), text_point("c\0d"), point(3.0)]);
+        assert_eq!(buffer.flush().await, 3);
+
+        let stats = buffer.stats();
+        assert_eq!(
+            (stats.journaled, stats.rejected, stats.last_error),
+            (0, 2, None)
+        );
+        assert!(!config.journal_path.exists());
+        assert_eq!(
+            values(&sink.written.lock().unwrap()),
+            values(&[point(1.0), point(2.0), point(3.0)])
+        );
+        let rejected = std::fs::read_to_string(&dead_letters).unwrap();
+        let first: serde_json::Value =
+            serde_json::from_str(rejected.lines().next().unwrap()).unwrap();
+        assert_eq!(first["point"]["value"], "a\u{0}b");
+        assert_eq!(first["error"], "invalid byte sequence");
+        remove_dir(&config.journal_path);
+    }
+
+    #[tokio::test]
+    async fn test_journal_is_capped_and_reloaded() {
+        let line_len = super::journal_lines(&[point(1.0)]).len() as u64;
+        let config = config(line_len * 2);
+        let sink = FakeSink::default();
+        sink.down.store(true, Ordering::SeqCst);
+
+        let buffer = WriteBuffer::new(sink.clone(), config.clone());
+        buffer.extend([point(1.0), point(2.0)]);
+        buffer.flush().await;
+        buffer.push(point(3.0));
+        buffer.flush().await;
+        let stats = buffer.stats();
+        assert_eq!((stats.journaled, stats.dropped), (2, 1));
+        assert_eq!(stats.journal_bytes, line_len * 2);
+
+        // A restart picks the journal up again.
+        sink.down.store(false, Ordering::SeqCst);
+        let restarted = WriteBuffer::new(sink.clone(), config.clone());
+        assert_eq!(restarted.stats().journaled, 2);
+        assert_eq!(restarted.flush().await, 2);
+        assert_eq!(restarted.stats().journaled, 0);
+        assert!(!config.journal_path.exists());
+        remove_dir(&config.journal_path);
+    }
+
+    #[test]
+    fn test_journal_round_trip() {
+        let mut original = point(21.5);
+        original.unit = Some("°C".to_string());
+        let line = super::journal_lines(std::slice::from_ref(&original));
+        let parsed: Point = serde_json::from_str(line.trim()).unwrap();
+        assert_eq!(parsed.value, original.value);
+        assert_eq!(parsed.unit, original.unit);
+        assert_eq!(parsed.ts.timestamp_micros(), original.ts.timestamp_micros());
+    }
+}